@@ -0,0 +1,274 @@
+use crate::client_ip::ClientIp;
+use crate::listener::{ListenerName, PeerAddr};
+use crate::proxy::Proxied;
+use crate::request_limiter::ApiKey;
+use anyhow::{anyhow, Result};
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Query, Request, State};
+use axum::http::{header, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use serde::Serialize;
+use std::backtrace::Backtrace;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{error, info, Span};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogOptions {
+    pub enabled: bool,
+    /// File the entries are appended to as JSON lines instead of the log
+    pub path: Option<PathBuf>,
+}
+
+/// Tags every request with an id and, when enabled, records one entry per
+/// request once its response body is done, so streamed responses are
+/// logged with their full duration and size.
+pub struct AccessLog {
+    enabled: bool,
+    file: Option<Mutex<File>>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccessEntry {
+    request_id: Arc<str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listener: Option<Arc<str>>,
+    method: String,
+    path: String,
+    /// RPC query type, with `multiinfo` folded into `info`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpc_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpc_by: Option<&'static str>,
+    status: u16,
+    duration_ms: f64,
+    bytes: u64,
+    client_ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+    /// Name of the API key the request was sent with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<Arc<str>>,
+    /// Forwarded to the upstream by the pass-through proxy
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    proxied: bool,
+}
+
+impl AccessLog {
+    pub fn new(options: &AccessLogOptions) -> Result<Self> {
+        let file = match &options.path {
+            Some(path) if options.enabled => {
+                let file = File::options()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| anyhow!("Failed to open access log {}: {}", path.display(), e))?;
+                info!("Writing the access log to {}", path.display());
+                Some(Mutex::new(file))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            enabled: options.enabled,
+            file,
+        })
+    }
+
+    fn write(&self, entry: &AccessEntry) {
+        let Some(file) = &self.file else {
+            info!(
+                target: "access_log",
+                request_id = %entry.request_id,
+                listener = entry.listener.as_deref(),
+                method = %entry.method,
+                path = %entry.path,
+                rpc_type = entry.rpc_type,
+                rpc_by = entry.rpc_by,
+                status = entry.status,
+                duration_ms = entry.duration_ms,
+                bytes = entry.bytes,
+                client_ip = %entry.client_ip,
+                user_agent = entry.user_agent.as_deref(),
+                api_key = entry.api_key.as_deref(),
+                proxied = entry.proxied,
+            );
+            return;
+        };
+        let line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize access log entry: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+            error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// Middleware assigning the request id, sent back in `X-Request-Id` and
+/// recorded in the `request` span so every log line of the request carries
+/// it, and logging the request.
+pub async fn log_request(
+    State(log): State<Arc<AccessLog>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id: Arc<str> = Uuid::new_v4().simple().to_string().into();
+    Span::current().record("request_id", &*request_id);
+
+    let entry = log.enabled.then(|| {
+        let (rpc_type, rpc_by) = rpc_query(&request);
+        AccessEntry {
+            request_id: request_id.clone(),
+            listener: request
+                .extensions()
+                .get::<ListenerName>()
+                .map(|name| name.0.clone()),
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            rpc_type,
+            rpc_by,
+            status: 0,
+            duration_ms: 0.0,
+            bytes: 0,
+            client_ip: request
+                .extensions()
+                .get::<ClientIp>()
+                .map_or(peer, |client_ip| client_ip.0)
+                .to_string(),
+            user_agent: request
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+                .map(str::to_string),
+            api_key: None,
+            proxied: false,
+        }
+    });
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    let Some(mut entry) = entry else {
+        return response;
+    };
+    entry.status = response.status().as_u16();
+    entry.api_key = response
+        .extensions()
+        .get::<ApiKey>()
+        .map(|api_key| api_key.name.clone());
+    entry.proxied = response.extensions().get::<Proxied>().is_some();
+    response.map(|inner| {
+        Body::new(LoggedBody {
+            inner,
+            log,
+            entry,
+            started,
+        })
+    })
+}
+
+/// Normalized `type` and `by` of an RPC query string, to group requests
+/// without logging their arguments.
+fn rpc_query(request: &Request) -> (Option<&'static str>, Option<&'static str>) {
+    if !matches!(request.uri().path(), "/rpc" | "/rpc/" | "/rpc.php") {
+        return (None, None);
+    }
+    let Ok(Query(params)) = Query::<Vec<(String, String)>>::try_from_uri(request.uri()) else {
+        return (None, None);
+    };
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let rpc_type = param("type").map(|rpc_type| match rpc_type {
+        "info" | "multiinfo" => "info",
+        "search" => "search",
+        "msearch" => "msearch",
+        "suggest" => "suggest",
+        "suggest-pkgbase" => "suggest-pkgbase",
+        _ => "invalid",
+    });
+    let rpc_by = match rpc_type {
+        Some("search") => Some(match param("by").unwrap_or("name-desc") {
+            "name" => "name",
+            "name-desc" => "name-desc",
+            "depends" => "depends",
+            "makedepends" => "makedepends",
+            "optdepends" => "optdepends",
+            "checkdepends" => "checkdepends",
+            "maintainer" => "maintainer",
+            _ => "invalid",
+        }),
+        Some("msearch") => Some("maintainer"),
+        _ => None,
+    };
+    (rpc_type, rpc_by)
+}
+
+/// Response body counting the bytes sent; the entry is written when the
+/// body is dropped, i.e. after the last byte or when the client went away.
+struct LoggedBody {
+    inner: Body,
+    log: Arc<AccessLog>,
+    entry: AccessEntry,
+    started: Instant,
+}
+
+impl http_body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.entry.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // Microsecond precision is plenty
+        self.entry.duration_ms = (self.started.elapsed().as_secs_f64() * 1e6).round() / 1e3;
+        self.log.write(&self.entry);
+    }
+}
+
+/// Log panics with a backtrace through `tracing`, so a panic in a handler
+/// is logged within its `request` span and carries the request id.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        error!("{}\n{}", info, Backtrace::force_capture());
+    }));
+}
@@ -1,17 +1,236 @@
+use crate::access_log::AccessLogOptions;
+use crate::branch_filter::BranchFilter;
+use crate::client_ip::{ClientIpOptions, IpAccessOptions};
+use crate::concurrency_limiter::ConcurrencyOptions;
+use crate::config::Config;
 use crate::database::DatabaseOps;
+use crate::listener::UnixSocketOptions;
+use crate::metrics::Metrics;
+use crate::proxy::ProxyOptions;
+use crate::query_cache::QueryCacheOptions;
+use crate::request_limiter::RequestLimitOptions;
+use crate::request_timeout::RequestTimeoutOptions;
+use crate::rpc_server::{CorsOptions, GitUpstream, UrlPathTemplate};
+use crate::snapshot_cache::SnapshotCache;
+use crate::srcinfo_cache::SrcInfoCache;
+use crate::supplement_fetcher::SupplementOptions;
+use crate::sync_scheduler::SyncScheduleOptions;
+use crate::syncer::SyncPipelineOptions;
+use crate::systemd::SystemdNotifier;
+use crate::throttle::RateLimiter;
+use crate::tls::TlsOptions;
+use crate::webhooks::WebhookConfig;
 use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: DatabaseOps,
     pub github_token: Option<String>,
+    pub user_agent: String,
+    pub srcinfo_cache: Option<SrcInfoCache>,
+    pub decode_cache_bytes: usize,
+    pub rate_limiter: Option<RateLimiter>,
+    pub supplement_options: SupplementOptions,
+    pub history_branches: Vec<String>,
+    pub history_window: Duration,
+    pub store_srcinfo: bool,
+    pub url_path_template: UrlPathTemplate,
+    pub admin_token: Option<String>,
+    pub rpc_max_info_args: usize,
+    pub rpc_max_split_info_args: usize,
+    pub rpc_v6: bool,
+    pub rpc_commit_fields: bool,
+    pub rpc_max_body_bytes: usize,
+    pub rpc_max_query_bytes: usize,
+    pub rpc_max_arg_params: usize,
+    pub request_limit_options: RequestLimitOptions,
+    pub client_ip_options: ClientIpOptions,
+    pub ip_access_options: IpAccessOptions,
+    pub cors_options: CorsOptions,
+    pub git_upstream: GitUpstream,
+    pub snapshot_cache: Option<Arc<SnapshotCache>>,
+    pub metrics_allowlist: Vec<String>,
+    pub ready_max_sync_age: Option<Duration>,
+    pub stale_after: Option<Duration>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub sse_max_connections: usize,
+    /// Mount path of the HTML search pages, `None` when disabled
+    pub web_ui_prefix: Option<String>,
+    pub openapi_swagger_ui: bool,
+    pub query_cache_options: QueryCacheOptions,
+    pub unix_socket_options: UnixSocketOptions,
+    pub tls_options: Option<TlsOptions>,
+    pub redirect_http_to_https: bool,
+    pub shutdown_drain_timeout: Duration,
+    pub access_log_options: AccessLogOptions,
+    pub concurrency_options: ConcurrencyOptions,
+    pub request_timeout_options: RequestTimeoutOptions,
+    pub proxy_options: ProxyOptions,
+    pub sync_schedule_options: SyncScheduleOptions,
+    pub branch_filter: BranchFilter,
+    pub sync_pipeline_options: SyncPipelineOptions,
+    pub metrics: Arc<Metrics>,
+    pub systemd: Arc<SystemdNotifier>,
 }
 
 impl AppState {
-    pub async fn new(db_path: &str, github_token: Option<String>) -> Result<Self> {
+    /// Opens the database and builds the shared state from the resolved configuration
+    pub async fn from_config(
+        config: &Config,
+        db_path: &str,
+        db_read_only: bool,
+        github_token: Option<String>,
+    ) -> Result<Self> {
+        let user_agent = config.user_agent();
+        debug!("User-Agent: {}", user_agent);
+
+        let srcinfo_cache = config.srcinfo_cache_dir().map(|dir| {
+            info!(".SRCINFO cache directory: {}", dir.display());
+            SrcInfoCache::new(
+                dir,
+                config.srcinfo_cache_max_bytes(),
+                config.srcinfo_cache_max_age(),
+            )
+        });
+
+        let snapshot_cache = config.snapshot_cache_dir().map(|dir| {
+            info!("Snapshot cache directory: {}", dir.display());
+            Arc::new(SnapshotCache::new(dir, config.snapshot_cache_max_bytes()))
+        });
+
+        let rate_limiter = RateLimiter::new(config.download_rate_limit());
+        if rate_limiter.is_some() {
+            info!(
+                "Download rate limited to {} bytes/s",
+                config.download_rate_limit()
+            );
+        }
+
+        let branch_filter = BranchFilter::new(config.branch_include(), config.branch_exclude())?;
+        if !branch_filter.is_empty() {
+            info!(
+                "Syncing branches filtered by {} include and {} exclude patterns",
+                branch_filter.include().len(),
+                branch_filter.exclude().len()
+            );
+        }
+
+        let history_branches = config.history_branches();
+        if !history_branches.is_empty() {
+            info!("Tracking history of {} branches", history_branches.len());
+        }
+
+        let request_timeout_options = RequestTimeoutOptions {
+            default: config.request_timeout(),
+            routes: config.request_timeouts(),
+        };
+
         Ok(Self {
-            db: DatabaseOps::new(db_path).await?,
+            db: DatabaseOps::new(db_path, request_timeout_options.is_enabled(), db_read_only)
+                .await?,
             github_token,
+            user_agent,
+            srcinfo_cache,
+            decode_cache_bytes: config.decode_cache_bytes(),
+            rate_limiter,
+            supplement_options: SupplementOptions {
+                retries: config.supplement_retries(),
+                merge: config.supplement_merge(),
+                max_bad_fraction: config.supplement_max_bad_fraction(),
+                connect_timeout: config.supplement_connect_timeout(),
+                timeout: config.supplement_timeout(),
+                max_bytes: config.supplement_max_bytes(),
+                cache_path: config.supplement_cache_path(),
+                cache_max_age: config.supplement_cache_max_age(),
+                max_data_age: config.supplement_max_data_age(),
+                stale_data_action: config.supplement_stale_data_action(),
+                live_lookup: config.supplement_live_lookup(),
+                live_lookup_ttl: config.supplement_live_lookup_ttl(),
+                refresh_interval: config.supplement_refresh_interval(),
+            },
+            history_branches,
+            history_window: config.history_window(),
+            store_srcinfo: config.store_srcinfo(),
+            url_path_template: config.url_path_template(),
+            admin_token: config.admin_token(),
+            rpc_max_info_args: config.rpc_max_info_args(),
+            rpc_max_split_info_args: config.rpc_max_split_info_args(),
+            rpc_v6: config.rpc_v6(),
+            rpc_commit_fields: config.rpc_commit_fields(),
+            rpc_max_body_bytes: config.rpc_max_body_bytes(),
+            rpc_max_query_bytes: config.rpc_max_query_bytes(),
+            rpc_max_arg_params: config.rpc_max_arg_params(),
+            request_limit_options: RequestLimitOptions {
+                requests: config.rate_limit_requests(),
+                window: config.rate_limit_window(),
+                allowlist: config.rate_limit_allowlist(),
+                api_keys: config.api_keys(),
+            },
+            client_ip_options: ClientIpOptions {
+                trusted_proxy_headers: config.trusted_proxy_headers(),
+                trusted_proxies: config.trusted_proxies(),
+            },
+            ip_access_options: IpAccessOptions {
+                denylist: config.ip_denylist(),
+                allowlist: config.ip_allowlist(),
+            },
+            cors_options: CorsOptions {
+                allowed_origins: config.cors_allowed_origins(),
+                max_age: config.cors_max_age(),
+            },
+            git_upstream: config.git_upstream(),
+            snapshot_cache,
+            metrics_allowlist: config.metrics_allowlist(),
+            ready_max_sync_age: config.ready_max_sync_age(),
+            stale_after: config.stale_after(),
+            webhooks: config.webhooks(),
+            sse_max_connections: config.sse_max_connections(),
+            web_ui_prefix: config.web_ui().then(|| config.web_ui_prefix()),
+            openapi_swagger_ui: config.openapi_swagger_ui(),
+            query_cache_options: QueryCacheOptions {
+                capacity: config.query_cache_capacity(),
+                ttl: config.query_cache_ttl(),
+            },
+            unix_socket_options: UnixSocketOptions {
+                mode: config.unix_socket_mode(),
+                group: config.unix_socket_group(),
+            },
+            tls_options: config.tls_options(),
+            redirect_http_to_https: config.redirect_http_to_https(),
+            shutdown_drain_timeout: config.shutdown_drain_timeout(),
+            access_log_options: AccessLogOptions {
+                enabled: config.access_log(),
+                path: config.access_log_path(),
+            },
+            concurrency_options: ConcurrencyOptions {
+                global: config.concurrency_limit(),
+                routes: config.concurrency_limits(),
+                queue: config.concurrency_queue(),
+            },
+            request_timeout_options,
+            proxy_options: ProxyOptions {
+                upstream: config.proxy_upstream(),
+                path_prefixes: config.proxy_path_prefixes(),
+                timeout: config.proxy_timeout(),
+                rate_limit: config.proxy_rate_limit(),
+            },
+            sync_schedule_options: SyncScheduleOptions {
+                interval: config.sync_interval(),
+                jitter: config.sync_jitter(),
+                min_spacing: config.sync_min_spacing(),
+                on_start: config.sync_on_start(),
+            },
+            branch_filter,
+            sync_pipeline_options: SyncPipelineOptions {
+                fetch_concurrency: config.sync_fetch_concurrency(),
+                decode_workers: config.sync_decode_workers(),
+                write_batch_size: config.sync_write_batch_size(),
+            },
+            metrics: Arc::new(Metrics::new()?),
+            systemd: Arc::new(SystemdNotifier::new()),
         })
     }
 }
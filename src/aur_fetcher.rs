@@ -10,8 +10,17 @@ use gix_packetline::read::ProgressAction;
 use gix_packetline::PacketLineRef;
 use reqwest::{header, Client};
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio_util::compat::TokioAsyncReadCompatExt as _;
-use tracing::{error, trace};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, trace, warn};
+
+use crate::metrics::Metrics;
+use crate::srcinfo_cache::SrcInfoCache;
+use crate::throttle::{RateLimiter, ThrottledRead};
 
 const AUR_GIT_UPLOAD_PACK_GET_URL: &str =
     "https://github.com/archlinux/aur.git/info/refs?service=git-upload-pack";
@@ -21,6 +30,13 @@ const AUR_GIT_UPLOAD_PACK_POST_URL: &str = "https://github.com/archlinux/aur.git
 pub struct AurFetcher {
     client: Client,
     github_token: Option<String>,
+    user_agent: String,
+    srcinfo_cache: Option<SrcInfoCache>,
+    decode_cache_bytes: usize,
+    decode_workers: usize,
+    rate_limiter: Option<RateLimiter>,
+    stats: Arc<FetchStats>,
+    metrics: Arc<Metrics>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -29,55 +45,505 @@ pub struct FetchedSrcInfo {
     pub committed_at: i64,
 }
 
+/// Returned when a fetch is stopped through its [`CancellationToken`].
+///
+/// Callers can detect it with `err.is::<Cancelled>()` to roll back instead of
+/// reporting a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// An `ERR` packet line or sideband error message sent by the upstream server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamError {
+    pub message: String,
+}
+
+impl UpstreamError {
+    /// The server refused a wanted object, typically because the branch was
+    /// force-pushed after its refs were listed.
+    pub fn is_not_our_ref(&self) -> bool {
+        self.message.contains("not our ref")
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream error: {}", self.message)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+fn check_err_line(line: &PacketLineRef<'_>) -> Result<()> {
+    match line.check_error() {
+        Some(err) => Err(UpstreamError {
+            message: String::from_utf8_lossy(err.0).trim_end().to_string(),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Run `fut` to completion unless `cancel` fires first.
+pub async fn cancellable<F: Future>(cancel: &CancellationToken, fut: F) -> Result<F::Output> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+        output = fut => Ok(output),
+    }
+}
+
+/// Counters of the network and decoding work done by an [`AurFetcher`].
+///
+/// Shared by all clones of a fetcher, so a sync can report the totals of
+/// every fetch task it spawned.
+#[derive(Debug, Default)]
+pub struct FetchStats {
+    ref_list_bytes: AtomicU64,
+    commit_pack_bytes: AtomicU64,
+    blob_pack_bytes: AtomicU64,
+    ref_list_requests: AtomicU64,
+    upload_pack_requests: AtomicU64,
+    commits_decoded: AtomicU64,
+    trees_decoded: AtomicU64,
+    blobs_decoded: AtomicU64,
+    other_objects_decoded: AtomicU64,
+    srcinfo_cache_hits: AtomicU64,
+    blob_cache_hits: AtomicU64,
+    decode_cache_hits: AtomicU64,
+    decode_cache_misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FetchStatsSnapshot {
+    pub ref_list_bytes: u64,
+    pub commit_pack_bytes: u64,
+    pub blob_pack_bytes: u64,
+    pub ref_list_requests: u64,
+    pub upload_pack_requests: u64,
+    pub commits_decoded: u64,
+    pub trees_decoded: u64,
+    pub blobs_decoded: u64,
+    pub other_objects_decoded: u64,
+    pub srcinfo_cache_hits: u64,
+    pub blob_cache_hits: u64,
+    pub decode_cache_hits: u64,
+    pub decode_cache_misses: u64,
+}
+
+/// A commit fetched while walking a branch's recent history.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FetchedHistoryEntry {
+    pub commit_id: String,
+    pub srcinfo: FetchedSrcInfo,
+}
+
+/// All files of a commit's tree, as needed to assemble a snapshot.
+#[derive(Clone, Debug)]
+pub struct FetchedTree {
+    pub committed_at: i64,
+    pub files: Vec<FetchedTreeFile>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FetchedTreeFile {
+    /// Path relative to the tree root, `/`-separated
+    pub path: String,
+    pub kind: TreeFileKind,
+    pub blob_id: String,
+    /// File content, or the target of a symlink
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeFileKind {
+    Regular,
+    Executable,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Deepen {
+    /// Only the wanted commits themselves (`deepen 1`)
+    Tip,
+    /// All commits newer than the given unix timestamp (`deepen-since`)
+    Since(i64),
+}
+
+#[derive(Debug, Clone)]
+struct CommitInfo {
+    srcinfo_blob_id: Option<ObjectId>,
+    committed_at: i64,
+    parents: Vec<ObjectId>,
+}
+
+/// Delta base cache of one decoding worker, reused by all pack decodes of a
+/// batch and recording its hits and misses into the fetch stats.
+struct DecodeCache {
+    inner: Box<dyn gix_pack::cache::DecodeEntry + Send>,
+    stats: Arc<FetchStats>,
+}
+
+impl DecodeCache {
+    fn new(capacity_bytes: usize, stats: Arc<FetchStats>) -> Self {
+        let inner: Box<dyn gix_pack::cache::DecodeEntry + Send> = if capacity_bytes == 0 {
+            Box::new(gix_pack::cache::Never)
+        } else {
+            Box::new(gix_pack::cache::lru::MemoryCappedHashmap::new(
+                capacity_bytes,
+            ))
+        };
+        Self { inner, stats }
+    }
+}
+
+impl gix_pack::cache::DecodeEntry for DecodeCache {
+    fn put(
+        &mut self,
+        pack_id: u32,
+        offset: u64,
+        data: &[u8],
+        kind: gix_object::Kind,
+        compressed_size: usize,
+    ) {
+        self.inner.put(pack_id, offset, data, kind, compressed_size);
+    }
+
+    fn get(
+        &mut self,
+        pack_id: u32,
+        offset: u64,
+        out: &mut Vec<u8>,
+    ) -> Option<(gix_object::Kind, usize)> {
+        let result = self.inner.get(pack_id, offset, out);
+        match result {
+            Some(_) => &self.stats.decode_cache_hits,
+            None => &self.stats.decode_cache_misses,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FetchPhase {
+    RefList,
+    CommitPack,
+    BlobPack,
+}
+
+impl FetchPhase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FetchPhase::RefList => "ref_list",
+            FetchPhase::CommitPack => "commit_pack",
+            FetchPhase::BlobPack => "blob_pack",
+        }
+    }
+}
+
+impl FetchStats {
+    pub fn snapshot(&self) -> FetchStatsSnapshot {
+        FetchStatsSnapshot {
+            ref_list_bytes: self.ref_list_bytes.load(Ordering::Relaxed),
+            commit_pack_bytes: self.commit_pack_bytes.load(Ordering::Relaxed),
+            blob_pack_bytes: self.blob_pack_bytes.load(Ordering::Relaxed),
+            ref_list_requests: self.ref_list_requests.load(Ordering::Relaxed),
+            upload_pack_requests: self.upload_pack_requests.load(Ordering::Relaxed),
+            commits_decoded: self.commits_decoded.load(Ordering::Relaxed),
+            trees_decoded: self.trees_decoded.load(Ordering::Relaxed),
+            blobs_decoded: self.blobs_decoded.load(Ordering::Relaxed),
+            other_objects_decoded: self.other_objects_decoded.load(Ordering::Relaxed),
+            srcinfo_cache_hits: self.srcinfo_cache_hits.load(Ordering::Relaxed),
+            blob_cache_hits: self.blob_cache_hits.load(Ordering::Relaxed),
+            decode_cache_hits: self.decode_cache_hits.load(Ordering::Relaxed),
+            decode_cache_misses: self.decode_cache_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_request(&self, phase: FetchPhase) {
+        match phase {
+            FetchPhase::RefList => &self.ref_list_requests,
+            FetchPhase::CommitPack | FetchPhase::BlobPack => &self.upload_pack_requests,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_bytes(&self, phase: FetchPhase, bytes: u64) {
+        match phase {
+            FetchPhase::RefList => &self.ref_list_bytes,
+            FetchPhase::CommitPack => &self.commit_pack_bytes,
+            FetchPhase::BlobPack => &self.blob_pack_bytes,
+        }
+        .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_decoded(&self, kind: gix_object::Kind) {
+        match kind {
+            gix_object::Kind::Commit => &self.commits_decoded,
+            gix_object::Kind::Tree => &self.trees_decoded,
+            gix_object::Kind::Blob => &self.blobs_decoded,
+            gix_object::Kind::Tag => &self.other_objects_decoded,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl FetchStatsSnapshot {
+    pub fn total_bytes(&self) -> u64 {
+        self.ref_list_bytes + self.commit_pack_bytes + self.blob_pack_bytes
+    }
+}
+
+impl fmt::Display for FetchStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes downloaded (refs {}, commit packs {}, blob packs {}) in {} requests \
+             ({} ref listings, {} upload-pack); decoded {} commits, {} trees, {} blobs, {} other objects; \
+             {} .SRCINFO cache hits ({} by blob); delta cache {} hits / {} misses",
+            self.total_bytes(),
+            self.ref_list_bytes,
+            self.commit_pack_bytes,
+            self.blob_pack_bytes,
+            self.ref_list_requests + self.upload_pack_requests,
+            self.ref_list_requests,
+            self.upload_pack_requests,
+            self.commits_decoded,
+            self.trees_decoded,
+            self.blobs_decoded,
+            self.other_objects_decoded,
+            self.srcinfo_cache_hits,
+            self.blob_cache_hits,
+            self.decode_cache_hits,
+            self.decode_cache_misses,
+        )
+    }
+}
+
 impl AurFetcher {
-    pub fn new(github_token: Option<String>) -> Self {
+    pub fn new(
+        github_token: Option<String>,
+        user_agent: String,
+        srcinfo_cache: Option<SrcInfoCache>,
+        decode_cache_bytes: usize,
+        decode_workers: usize,
+        rate_limiter: Option<RateLimiter>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let client = Client::new();
         Self {
             client,
             github_token,
+            user_agent,
+            srcinfo_cache,
+            decode_cache_bytes,
+            decode_workers: decode_workers.max(1),
+            rate_limiter,
+            stats: Arc::default(),
+            metrics,
         }
     }
 
+    pub fn stats(&self) -> &Arc<FetchStats> {
+        &self.stats
+    }
+
     pub fn github_token(&self) -> Option<&str> {
         self.github_token.as_deref()
     }
 
-    pub fn user_agent() -> String {
-        format!("AUR-Mirror-Meta/{}", env!("CARGO_PKG_VERSION"))
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn srcinfo_cache(&self) -> Option<&SrcInfoCache> {
+        self.srcinfo_cache.as_ref()
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// One delta cache per decoding worker, splitting the configured budget.
+    fn new_decode_caches(&self) -> Vec<DecodeCache> {
+        let workers = self.decode_workers;
+        (0..workers)
+            .map(|_| DecodeCache::new(self.decode_cache_bytes / workers, self.stats.clone()))
+            .collect()
     }
 
     pub async fn fetch_srcinfo_batch(
         &self,
         commits: impl Iterator<Item = impl AsRef<str>>,
+        cancel: &CancellationToken,
     ) -> Result<impl Iterator<Item = Option<FetchedSrcInfo>>> {
         let commit_ids: Vec<ObjectId> = commits
             .map(|c| ObjectId::from_hex(c.as_ref().as_bytes()).unwrap())
             .collect();
-        let commit_data = self
-            .fetch_srcinfo_blob_ids_and_timestamps(commit_ids.iter())
-            .await?;
-        let blob_ids: Vec<_> = commit_data.values().map(|(blob_id, _)| blob_id).collect();
-        let mut blobs = self.fetch_srcinfo_blobs(blob_ids.into_iter()).await?;
-        Ok(commit_ids.into_iter().map(move |commit_id| {
-            commit_data.get(&commit_id).map(|(blob_id, timestamp)| {
-                let srcinfo = blobs.remove(blob_id).unwrap_or_default();
-                FetchedSrcInfo {
-                    srcinfo_text: srcinfo,
-                    committed_at: *timestamp,
+
+        let mut srcinfo_map = self.load_cached_srcinfo(&commit_ids).await;
+        self.stats
+            .srcinfo_cache_hits
+            .fetch_add(srcinfo_map.len() as u64, Ordering::Relaxed);
+        let missing_commit_ids: Vec<&ObjectId> = commit_ids
+            .iter()
+            .filter(|commit_id| !srcinfo_map.contains_key(*commit_id))
+            .collect();
+        debug!(
+            "{} of {} commits served from .SRCINFO cache",
+            commit_ids.len() - missing_commit_ids.len(),
+            commit_ids.len()
+        );
+
+        if !missing_commit_ids.is_empty() {
+            let mut decode_caches = self.new_decode_caches();
+            let commit_data = self
+                .fetch_srcinfo_blob_ids_and_timestamps(
+                    missing_commit_ids.into_iter(),
+                    &mut decode_caches,
+                    cancel,
+                )
+                .await?;
+            let blob_ids: Vec<_> = commit_data.values().map(|(blob_id, _)| blob_id).collect();
+            let blobs = self
+                .fetch_srcinfo_blobs(blob_ids.into_iter(), &mut decode_caches, cancel)
+                .await?;
+            for (commit_id, (blob_id, timestamp)) in commit_data {
+                // Several commits may share one blob, so don't move it out of the map
+                let srcinfo_text = blobs.get(&blob_id).cloned();
+                let fetched = FetchedSrcInfo {
+                    srcinfo_text: srcinfo_text.clone().unwrap_or_default(),
+                    committed_at: timestamp,
+                };
+                if srcinfo_text.is_some() {
+                    self.store_cached_srcinfo(&commit_id, &fetched).await;
                 }
-            })
+                srcinfo_map.insert(commit_id, fetched);
+            }
+        }
+
+        Ok(commit_ids
+            .into_iter()
+            .map(move |commit_id| srcinfo_map.remove(&commit_id)))
+    }
+
+    async fn load_cached_srcinfo(
+        &self,
+        commit_ids: &[ObjectId],
+    ) -> gix_hashtable::HashMap<ObjectId, FetchedSrcInfo> {
+        let mut cached = gix_hashtable::HashMap::default();
+        if let Some(cache) = &self.srcinfo_cache {
+            for commit_id in commit_ids {
+                if let Some(srcinfo) = cache.get(commit_id).await {
+                    cached.insert(*commit_id, srcinfo);
+                }
+            }
+        }
+        cached
+    }
+
+    async fn store_cached_srcinfo(&self, commit_id: &oid, srcinfo: &FetchedSrcInfo) {
+        if let Some(cache) = &self.srcinfo_cache {
+            if let Err(e) = cache.put(commit_id, srcinfo).await {
+                warn!("Failed to cache .SRCINFO of commit {}: {}", commit_id, e);
+            }
+        }
+    }
+
+    /// Fetch the recent history of each given branch tip using `deepen-since`.
+    ///
+    /// Yields, per tip, every commit reachable through first parents that was
+    /// committed at or after `since` and carries a `.SRCINFO`, newest first.
+    pub async fn fetch_srcinfo_history(
+        &self,
+        tips: impl Iterator<Item = impl AsRef<str>>,
+        since: i64,
+        cancel: &CancellationToken,
+    ) -> Result<impl Iterator<Item = Vec<FetchedHistoryEntry>>> {
+        let tip_ids: Vec<ObjectId> = tips
+            .map(|c| ObjectId::from_hex(c.as_ref().as_bytes()))
+            .collect::<Result<_, _>>()?;
+        let mut decode_caches = self.new_decode_caches();
+        let commit_infos = self
+            .fetch_commit_infos(
+                tip_ids.iter(),
+                Deepen::Since(since),
+                &mut decode_caches,
+                cancel,
+            )
+            .await?;
+
+        let histories: Vec<Vec<(ObjectId, ObjectId, i64)>> = tip_ids
+            .iter()
+            .map(|tip| walk_first_parent_history(&commit_infos, tip, since))
+            .collect();
+        let blob_ids: gix_hashtable::HashSet<ObjectId> = histories
+            .iter()
+            .flatten()
+            .map(|(_, blob_id, _)| *blob_id)
+            .collect();
+        let blobs = if blob_ids.is_empty() {
+            Default::default()
+        } else {
+            self.fetch_srcinfo_blobs(blob_ids.iter(), &mut decode_caches, cancel)
+                .await?
+        };
+
+        Ok(histories.into_iter().map(move |history| {
+            history
+                .into_iter()
+                .filter_map(|(commit_id, blob_id, committed_at)| {
+                    blobs.get(&blob_id).map(|srcinfo_text| FetchedHistoryEntry {
+                        commit_id: commit_id.to_string(),
+                        srcinfo: FetchedSrcInfo {
+                            srcinfo_text: srcinfo_text.clone(),
+                            committed_at,
+                        },
+                    })
+                })
+                .collect()
         }))
     }
 
     async fn fetch_srcinfo_blob_ids_and_timestamps(
         &self,
         commits: impl Iterator<Item = impl AsRef<oid>>,
+        decode_caches: &mut Vec<DecodeCache>,
+        cancel: &CancellationToken,
     ) -> Result<gix_hashtable::HashMap<ObjectId, (ObjectId, i64)>> {
+        let commit_infos = self
+            .fetch_commit_infos(commits, Deepen::Tip, decode_caches, cancel)
+            .await?;
+        Ok(commit_infos
+            .into_iter()
+            .filter_map(|(commit_id, info)| {
+                info.srcinfo_blob_id
+                    .map(|blob_id| (commit_id, (blob_id, info.committed_at)))
+            })
+            .collect())
+    }
+
+    async fn fetch_commit_infos(
+        &self,
+        commits: impl Iterator<Item = impl AsRef<oid>>,
+        deepen: Deepen,
+        decode_caches: &mut Vec<DecodeCache>,
+        cancel: &CancellationToken,
+    ) -> Result<gix_hashtable::HashMap<ObjectId, CommitInfo>> {
         let mut request_builder = self
             .client
             .post(AUR_GIT_UPLOAD_PACK_POST_URL)
             .header("Git-Protocol", "version=2")
-            .header(header::USER_AGENT, &Self::user_agent());
+            .header(header::USER_AGENT, self.user_agent());
         if let Some(token) = &self.github_token {
             request_builder = request_builder.basic_auth(token, None::<&str>);
         }
@@ -91,43 +557,98 @@ impl AurFetcher {
                     .await?;
             }
             encode::text_to_write(b"ofs-delta", &mut body).await?;
-            encode::text_to_write(b"deepen 1", &mut body).await?;
+            let deepen_line = match deepen {
+                Deepen::Tip => "deepen 1".to_string(),
+                Deepen::Since(timestamp) => format!("deepen-since {}", timestamp),
+            };
+            encode::text_to_write(deepen_line.as_bytes(), &mut body).await?;
             encode::text_to_write(b"filter blob:none", &mut body).await?;
             encode::text_to_write(b"no-progress", &mut body).await?;
             encode::text_to_write(b"done", &mut body).await?;
             encode::flush_to_write(&mut body).await?;
             request_builder = request_builder.body(body);
         }
-        let response = request_builder.send().await?;
+        self.stats.record_request(FetchPhase::CommitPack);
+        let response = cancellable(cancel, request_builder.send()).await??;
         if !response.status().is_success() {
             return Err(anyhow!("Failed to fetch commits: {}", response.status()));
         }
 
         let mut rd = StreamingPeekableIter::new(
-            response
-                .bytes_stream()
-                .map_err(std::io::Error::other)
-                .into_async_read(),
+            self.response_reader(response, FetchPhase::CommitPack),
             &[PacketLineRef::Flush, PacketLineRef::Delimiter],
             false,
         );
 
         let mut packfile = TempFile::new().await?;
-        read_packfile_from_fetch_response(&mut rd, &mut (&mut packfile).compat()).await?;
-        let commit_to_blob_and_timestamp =
-            map_commit_id_to_srcinfo_blob_id_and_timestamp(packfile.file_path())?;
-        Ok(commit_to_blob_and_timestamp)
+        read_packfile_from_fetch_response(&mut rd, &mut (&mut packfile).compat(), cancel).await?;
+        let packfile_path = packfile.file_path().to_owned();
+        let stats = self.stats.clone();
+        let cancel = cancel.clone();
+        run_decode(decode_caches, move |decode_caches| {
+            map_commit_id_to_commit_info(&packfile_path, &stats, decode_caches, &cancel)
+        })
+        .await
     }
 
+    /// Retrieve `.SRCINFO` blobs, downloading only those missing from the
+    /// disk cache. The result contains cached and downloaded blobs alike.
     async fn fetch_srcinfo_blobs(
         &self,
         blobs: impl Iterator<Item = impl AsRef<oid>>,
+        decode_caches: &mut Vec<DecodeCache>,
+        cancel: &CancellationToken,
+    ) -> Result<gix_hashtable::HashMap<ObjectId, std::string::String>> {
+        let mut blob_map = gix_hashtable::HashMap::default();
+        let mut missing_blob_ids = gix_hashtable::HashSet::default();
+        for blob_id in blobs.map(|blob| blob.as_ref().to_owned()) {
+            if blob_map.contains_key(&blob_id) || missing_blob_ids.contains(&blob_id) {
+                continue;
+            }
+            let cached = match &self.srcinfo_cache {
+                Some(cache) => cache.get_blob(&blob_id).await,
+                None => None,
+            };
+            match cached {
+                Some(srcinfo_text) => {
+                    blob_map.insert(blob_id, srcinfo_text);
+                }
+                None => {
+                    missing_blob_ids.insert(blob_id);
+                }
+            }
+        }
+        self.stats
+            .blob_cache_hits
+            .fetch_add(blob_map.len() as u64, Ordering::Relaxed);
+
+        if !missing_blob_ids.is_empty() {
+            let downloaded = self
+                .download_srcinfo_blobs(missing_blob_ids.iter(), decode_caches, cancel)
+                .await?;
+            if let Some(cache) = &self.srcinfo_cache {
+                for (blob_id, srcinfo_text) in &downloaded {
+                    if let Err(e) = cache.put_blob(blob_id, srcinfo_text).await {
+                        warn!("Failed to cache .SRCINFO blob {}: {}", blob_id, e);
+                    }
+                }
+            }
+            blob_map.extend(downloaded);
+        }
+        Ok(blob_map)
+    }
+
+    async fn download_srcinfo_blobs(
+        &self,
+        blobs: impl Iterator<Item = impl AsRef<oid>>,
+        decode_caches: &mut Vec<DecodeCache>,
+        cancel: &CancellationToken,
     ) -> Result<gix_hashtable::HashMap<ObjectId, std::string::String>> {
         let mut request_builder = self
             .client
             .post(AUR_GIT_UPLOAD_PACK_POST_URL)
             .header("Git-Protocol", "version=2")
-            .header(header::USER_AGENT, &Self::user_agent());
+            .header(header::USER_AGENT, self.user_agent());
         if let Some(token) = &self.github_token {
             request_builder = request_builder.basic_auth(token, None::<&str>);
         }
@@ -146,54 +667,152 @@ impl AurFetcher {
             encode::flush_to_write(&mut body).await?;
             request_builder = request_builder.body(body);
         }
-        let response = request_builder.send().await?;
+        self.stats.record_request(FetchPhase::BlobPack);
+        let response = cancellable(cancel, request_builder.send()).await??;
         if !response.status().is_success() {
-            return Err(anyhow!("Failed to fetch commits: {}", response.status()));
+            return Err(anyhow!("Failed to fetch blobs: {}", response.status()));
+        }
+
+        let mut rd = StreamingPeekableIter::new(
+            self.response_reader(response, FetchPhase::BlobPack),
+            &[PacketLineRef::Flush, PacketLineRef::Delimiter],
+            false,
+        );
+
+        let mut packfile = TempFile::new().await?;
+        read_packfile_from_fetch_response(&mut rd, &mut (&mut packfile).compat(), cancel).await?;
+        let packfile_path = packfile.file_path().to_owned();
+        let stats = self.stats.clone();
+        let cancel = cancel.clone();
+        run_decode(decode_caches, move |decode_caches| {
+            map_blob_id_to_content(
+                &packfile_path,
+                String::from_utf8,
+                &stats,
+                decode_caches,
+                &cancel,
+            )
+        })
+        .await
+    }
+
+    /// Fetch every file of a commit's tree in one pack without blob filter.
+    ///
+    /// Fails if any object of the tree is missing from the pack, so callers
+    /// never see a partial tree. Submodule entries are skipped.
+    pub async fn fetch_tree_files(
+        &self,
+        commit: &str,
+        cancel: &CancellationToken,
+    ) -> Result<FetchedTree> {
+        let commit_id = ObjectId::from_hex(commit.as_bytes())?;
+        let mut request_builder = self
+            .client
+            .post(AUR_GIT_UPLOAD_PACK_POST_URL)
+            .header("Git-Protocol", "version=2")
+            .header(header::USER_AGENT, self.user_agent());
+        if let Some(token) = &self.github_token {
+            request_builder = request_builder.basic_auth(token, None::<&str>);
+        }
+        {
+            let mut body = Vec::new();
+            encode::text_to_write(b"command=fetch", &mut body).await?;
+            encode::text_to_write(b"agent=git/aur-mirror", &mut body).await?;
+            encode::delim_to_write(&mut body).await?;
+            encode::text_to_write(format!("want {}", commit_id).as_bytes(), &mut body).await?;
+            encode::text_to_write(b"ofs-delta", &mut body).await?;
+            encode::text_to_write(b"deepen 1", &mut body).await?;
+            encode::text_to_write(b"no-progress", &mut body).await?;
+            encode::text_to_write(b"done", &mut body).await?;
+            encode::flush_to_write(&mut body).await?;
+            request_builder = request_builder.body(body);
+        }
+        self.stats.record_request(FetchPhase::BlobPack);
+        let response = cancellable(cancel, request_builder.send()).await??;
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to fetch tree: {}", response.status()));
         }
 
         let mut rd = StreamingPeekableIter::new(
-            response
-                .bytes_stream()
-                .map_err(std::io::Error::other)
-                .into_async_read(),
+            self.response_reader(response, FetchPhase::BlobPack),
             &[PacketLineRef::Flush, PacketLineRef::Delimiter],
             false,
         );
 
         let mut packfile = TempFile::new().await?;
-        read_packfile_from_fetch_response(&mut rd, &mut (&mut packfile).compat()).await?;
-        let blob_id_to_content_map =
-            map_blob_id_to_content(packfile.file_path(), String::from_utf8)?;
-        Ok(blob_id_to_content_map)
+        read_packfile_from_fetch_response(&mut rd, &mut (&mut packfile).compat(), cancel).await?;
+        // Decoding is CPU-bound and this runs while serving, so keep it off
+        // the async workers
+        let packfile_path = packfile.file_path().to_owned();
+        let stats = self.stats.clone();
+        let mut decode_caches = self.new_decode_caches();
+        let cancel = cancel.clone();
+        tokio::task::spawn_blocking(move || {
+            let objects = decode_pack_entries(
+                &packfile_path,
+                &stats,
+                &mut decode_caches,
+                &cancel,
+                |object_id, kind, out| Ok(Some((object_id, (kind, out)))),
+            )?;
+            collect_tree_files(&objects.into_iter().collect(), &commit_id)
+        })
+        .await?
+    }
+
+    fn response_reader(
+        &self,
+        response: reqwest::Response,
+        phase: FetchPhase,
+    ) -> impl futures::io::AsyncRead + Unpin {
+        let stats = self.stats.clone();
+        let fetch_bytes = self
+            .metrics
+            .fetch_bytes
+            .with_label_values(&[phase.as_str()]);
+        let body = response
+            .bytes_stream()
+            .inspect_ok(move |chunk| {
+                stats.record_bytes(phase, chunk.len() as u64);
+                fetch_bytes.inc_by(chunk.len() as u64);
+            })
+            .map_err(std::io::Error::other)
+            .into_async_read();
+        ThrottledRead::new(body, self.rate_limiter.clone())
     }
 
-    pub async fn fetch_branch_list(&self) -> Result<HashMap<String, String>> {
-        let mut request_builder = self.client.get(AUR_GIT_UPLOAD_PACK_GET_URL);
+    pub async fn fetch_branch_list(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<HashMap<String, String>> {
+        let mut request_builder = self
+            .client
+            .get(AUR_GIT_UPLOAD_PACK_GET_URL)
+            .header(header::USER_AGENT, self.user_agent());
         if let Some(token) = &self.github_token {
             request_builder = request_builder.basic_auth(token, None::<&str>);
         }
-        let response = request_builder.send().await?;
+        self.stats.record_request(FetchPhase::RefList);
+        let response = cancellable(cancel, request_builder.send()).await??;
         if !response.status().is_success() {
             return Err(anyhow!("Failed to fetch refs: {}", response.status()));
         }
         let mut rd = StreamingPeekableIter::new(
-            response
-                .bytes_stream()
-                .map_err(std::io::Error::other)
-                .into_async_read(),
+            self.response_reader(response, FetchPhase::RefList),
             &[PacketLineRef::Flush],
             false,
         );
         let mut branches = HashMap::new();
-        while rd.read_line().await.is_some() {
+        while let Some(line_res) = cancellable(cancel, rd.read_line()).await? {
             // skip first part
+            check_err_line(&line_res??)?;
         }
         rd.reset();
 
-        while let Some(line_res) = rd.read_line().await {
-            let line_str = line_res??
-                .as_bstr()
-                .and_then(|b| std::str::from_utf8(b).ok());
+        while let Some(line_res) = cancellable(cancel, rd.read_line()).await? {
+            let line = line_res??;
+            check_err_line(&line)?;
+            let line_str = line.as_bstr().and_then(|b| std::str::from_utf8(b).ok());
             let line = match line_str {
                 Some(l) => l,
                 None => continue,
@@ -212,35 +831,40 @@ impl AurFetcher {
 async fn read_packfile_from_fetch_response<S, D>(
     rd: &mut StreamingPeekableIter<S>,
     dest: &mut D,
+    cancel: &CancellationToken,
 ) -> anyhow::Result<()>
 where
     S: futures::io::AsyncRead + Unpin,
     D: futures::io::AsyncWrite + Unpin,
 {
     loop {
-        let section_header = rd.read_line().await;
+        let section_header = cancellable(cancel, rd.read_line()).await?;
         if section_header.is_none() {
             return Err(anyhow!("Missing section header"));
         }
+        let section_header = section_header.unwrap()??;
+        check_err_line(&section_header)?;
         let section_header = section_header
-            .unwrap()??
             .as_bstr()
             .and_then(|x| std::str::from_utf8(x).ok())
             .map(|x: &str| x.trim())
             .ok_or_else(|| anyhow!("Invalid section header"))?;
         if section_header != "packfile" {
             // read all lines and reset
-            while rd.read_line().await.is_some() {
-                // skip
+            while let Some(line_res) = cancellable(cancel, rd.read_line()).await? {
+                check_err_line(&line_res??)?;
             }
             rd.reset();
             continue;
         }
         // packfile section
-        futures::io::copy(
+        let mut sideband_error = None;
+        let copy = futures::io::copy(
             rd.as_read_with_sidebands(|is_error, msg| {
                 if is_error {
-                    error!("Packfile fetch error: {}", String::from_utf8_lossy(msg));
+                    let message = String::from_utf8_lossy(msg).trim_end().to_string();
+                    error!("Packfile fetch error: {}", message);
+                    sideband_error = Some(message);
                     ProgressAction::Interrupt
                 } else {
                     trace!("Packfile fetch progress: {}", String::from_utf8_lossy(msg));
@@ -248,52 +872,225 @@ where
                 }
             }),
             dest,
-        )
-        .await?;
+        );
+        let copied = cancellable(cancel, copy).await?;
+        if let Some(message) = sideband_error {
+            return Err(UpstreamError { message }.into());
+        }
+        copied?;
         break;
     }
 
     Ok(())
 }
 
-fn map_commit_id_to_srcinfo_blob_id_and_timestamp(
+/// Resolve the tree of `commit_id` from the decoded objects of a pack.
+fn collect_tree_files(
+    objects: &gix_hashtable::HashMap<ObjectId, (gix_object::Kind, Vec<u8>)>,
+    commit_id: &oid,
+) -> Result<FetchedTree> {
+    let lookup = |id: &oid, expected: gix_object::Kind| match objects.get(id) {
+        Some((kind, data)) if *kind == expected => Ok(data),
+        _ => Err(anyhow!("{} {} missing from fetched pack", expected, id)),
+    };
+
+    let mut tree_id = None;
+    let mut committed_at = None;
+    for token in CommitRefIter::from_bytes(lookup(commit_id, gix_object::Kind::Commit)?) {
+        match token? {
+            commit::ref_iter::Token::Tree { id } => tree_id = Some(id),
+            commit::ref_iter::Token::Committer { signature } => {
+                committed_at = signature.time().ok().map(|time| time.seconds);
+            }
+            _ => {}
+        }
+    }
+    let tree_id = tree_id.ok_or_else(|| anyhow!("Commit {} missing tree", commit_id))?;
+    let committed_at = committed_at.ok_or_else(|| anyhow!("Commit {} missing time", commit_id))?;
+
+    let mut files = Vec::new();
+    let mut pending = vec![(String::new(), tree_id)];
+    while let Some((prefix, tree_id)) = pending.pop() {
+        for entry in TreeRefIter::from_bytes(lookup(&tree_id, gix_object::Kind::Tree)?) {
+            let entry = entry?;
+            let path = format!("{}{}", prefix, entry.filename);
+            let kind = match entry.mode.kind() {
+                gix_object::tree::EntryKind::Tree => {
+                    pending.push((format!("{}/", path), entry.oid.to_owned()));
+                    continue;
+                }
+                gix_object::tree::EntryKind::Commit => continue,
+                gix_object::tree::EntryKind::Blob => TreeFileKind::Regular,
+                gix_object::tree::EntryKind::BlobExecutable => TreeFileKind::Executable,
+                gix_object::tree::EntryKind::Link => TreeFileKind::Symlink,
+            };
+            files.push(FetchedTreeFile {
+                path,
+                kind,
+                blob_id: entry.oid.to_string(),
+                content: lookup(entry.oid, gix_object::Kind::Blob)?.clone(),
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(FetchedTree {
+        committed_at,
+        files,
+    })
+}
+
+fn walk_first_parent_history(
+    commit_infos: &gix_hashtable::HashMap<ObjectId, CommitInfo>,
+    tip: &oid,
+    since: i64,
+) -> Vec<(ObjectId, ObjectId, i64)> {
+    let mut history = Vec::new();
+    let mut current = Some(tip.to_owned());
+    // The shallow boundary ends the walk, since its parents were not sent
+    while let Some((commit_id, info)) =
+        current.and_then(|commit_id| commit_infos.get(&commit_id).map(|info| (commit_id, info)))
+    {
+        if info.committed_at < since {
+            break;
+        }
+        if let Some(blob_id) = info.srcinfo_blob_id {
+            history.push((commit_id, blob_id, info.committed_at));
+        }
+        current = info.parents.first().copied();
+    }
+    history
+}
+
+/// Decode every entry of a packfile on a pool of worker threads, passing each
+/// object to `visit` and collecting the values it returns.
+///
+/// Entries are split into contiguous ranges, one per decode cache. Every worker
+/// opens its own view of the pack and resolves OFS delta chains by itself, so a
+/// base owned by another worker's range is just decoded again (bases usually sit
+/// close before their deltas, where the worker's own cache catches them).
+fn decode_pack_entries<T, F>(
     packfile_path: &std::path::Path,
-) -> anyhow::Result<gix_hashtable::HashMap<ObjectId, (ObjectId, i64)>> {
-    let entries_offset = BytesToEntriesIter::new_from_header(
+    stats: &FetchStats,
+    decode_caches: &mut [DecodeCache],
+    cancel: &CancellationToken,
+    visit: F,
+) -> anyhow::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(ObjectId, gix_object::Kind, Vec<u8>) -> anyhow::Result<Option<T>> + Sync,
+{
+    let entries_offset: Vec<u64> = BytesToEntriesIter::new_from_header(
         std::io::BufReader::with_capacity(4096 * 8, std::fs::File::open(packfile_path)?),
         input::Mode::AsIs,
         EntryDataMode::Ignore,
         gix_hash::Kind::Sha1,
     )?
-    .filter_map(|x| x.ok().map(|e| e.pack_offset));
+    .filter_map(|x| x.ok().map(|e| e.pack_offset))
+    .collect();
+    let chunk_size = entries_offset
+        .len()
+        .div_ceil(decode_caches.len().max(1))
+        .max(1);
 
-    let mut commit_to_tree_and_timestamp =
-        gix_hashtable::HashMap::<ObjectId, (ObjectId, i64)>::default();
-    let mut tree_to_srcinfo_blob_map = gix_hashtable::HashMap::<ObjectId, ObjectId>::default();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = entries_offset
+            .chunks(chunk_size)
+            .zip(decode_caches.iter_mut())
+            .map(|(offsets, decode_cache)| {
+                let visit = &visit;
+                scope.spawn(move || -> anyhow::Result<Vec<T>> {
+                    let pack = data::File::at(packfile_path, gix_hash::Kind::Sha1)?;
+                    let mut inflate = Default::default();
+                    let mut decoded = Vec::new();
+                    for &pack_offset in offsets {
+                        if cancel.is_cancelled() {
+                            return Err(Cancelled.into());
+                        }
+                        let entry = pack.entry(pack_offset)?;
+                        let mut out = Vec::with_capacity(entry.decompressed_size as usize);
+                        let outcome = pack.decode_entry(
+                            entry,
+                            &mut out,
+                            &mut inflate,
+                            &|_, _| None,
+                            decode_cache,
+                        )?;
+                        let object_id =
+                            gix_object::compute_hash(gix_hash::Kind::Sha1, outcome.kind, &out)?;
+                        stats.record_decoded(outcome.kind);
+                        decoded.extend(visit(object_id, outcome.kind, out)?);
+                    }
+                    Ok(decoded)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_fold(Vec::new(), |mut decoded, worker| {
+                let worker_decoded = worker
+                    .join()
+                    .map_err(|_| anyhow!("Pack decoding worker panicked"))??;
+                decoded.extend(worker_decoded);
+                Ok(decoded)
+            })
+    })
+}
 
-    let pack = data::File::at(packfile_path, gix_hash::Kind::Sha1)?;
-    let mut delta_cache = gix_pack::cache::lru::MemoryCappedHashmap::new(1024 * 1024 * 10);
-    for pack_offset in entries_offset {
-        let entry = pack.entry(pack_offset)?;
-        let mut out = Vec::with_capacity(entry.decompressed_size as usize);
-        let outcome = pack.decode_entry(
-            entry,
-            &mut out,
-            &mut Default::default(),
-            &|_, _| None,
-            &mut delta_cache,
-        )?;
-        let object_id = gix_object::compute_hash(gix_hash::Kind::Sha1, outcome.kind, &out)?;
-        match outcome.kind {
+/// Run a CPU-bound pack decode on the blocking pool, so the async workers
+/// keep serving meanwhile. The delta caches move there and back, staying
+/// shared by the fetches of a batch.
+async fn run_decode<T, F>(decode_caches: &mut Vec<DecodeCache>, decode: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut [DecodeCache]) -> Result<T> + Send + 'static,
+{
+    let mut caches = std::mem::take(decode_caches);
+    let (result, caches) = tokio::task::spawn_blocking(move || {
+        let result = decode(&mut caches);
+        (result, caches)
+    })
+    .await?;
+    *decode_caches = caches;
+    result
+}
+
+enum CommitPackObject {
+    Commit {
+        commit_id: ObjectId,
+        tree_id: ObjectId,
+        info: CommitInfo,
+    },
+    Tree {
+        tree_id: ObjectId,
+        srcinfo_blob_id: ObjectId,
+    },
+}
+
+fn map_commit_id_to_commit_info(
+    packfile_path: &std::path::Path,
+    stats: &FetchStats,
+    decode_caches: &mut [DecodeCache],
+    cancel: &CancellationToken,
+) -> anyhow::Result<gix_hashtable::HashMap<ObjectId, CommitInfo>> {
+    let objects = decode_pack_entries(
+        packfile_path,
+        stats,
+        decode_caches,
+        cancel,
+        |object_id, kind, out| match kind {
             gix_object::Kind::Commit => {
                 let mut tree_id = None;
                 let mut commit_time = None;
+                let mut parents = Vec::new();
 
                 for token in CommitRefIter::from_bytes(&out) {
                     match token {
                         Ok(commit::ref_iter::Token::Tree { id }) => {
                             tree_id = Some(id);
                         }
+                        Ok(commit::ref_iter::Token::Parent { id }) => {
+                            parents.push(id);
+                        }
                         Ok(commit::ref_iter::Token::Committer { signature }) => {
                             commit_time = signature.time().ok();
                         }
@@ -311,7 +1108,15 @@ fn map_commit_id_to_srcinfo_blob_id_and_timestamp(
                     tree_id,
                     commit_time
                 );
-                commit_to_tree_and_timestamp.insert(object_id, (tree_id, commit_time.seconds));
+                Ok(Some(CommitPackObject::Commit {
+                    commit_id: object_id,
+                    tree_id,
+                    info: CommitInfo {
+                        srcinfo_blob_id: None,
+                        committed_at: commit_time.seconds,
+                        parents,
+                    },
+                }))
             }
             gix_object::Kind::Tree => {
                 let srcinfo_blob_id = TreeRefIter::from_bytes(&out)
@@ -328,70 +1133,142 @@ fn map_commit_id_to_srcinfo_blob_id_and_timestamp(
                         }
                     })
                     .next();
-                if let Some(srcinfo_blob_id) = srcinfo_blob_id {
+                Ok(srcinfo_blob_id.map(|srcinfo_blob_id| {
                     trace!(
                         "Mapping tree {} to .SRCINFO blob {}",
                         object_id,
                         srcinfo_blob_id
                     );
-                    tree_to_srcinfo_blob_map.insert(object_id, srcinfo_blob_id.to_owned());
-                }
+                    CommitPackObject::Tree {
+                        tree_id: object_id,
+                        srcinfo_blob_id: srcinfo_blob_id.to_owned(),
+                    }
+                }))
             }
-            _ => {}
-        }
-    }
+            _ => Ok(None),
+        },
+    )?;
 
-    let mut commit_to_srcinfo_and_timestamp =
-        gix_hashtable::HashMap::<ObjectId, (ObjectId, i64)>::default();
-    for (commit_id, (tree_id, timestamp)) in commit_to_tree_and_timestamp {
-        if let Some(srcinfo_blob_id) = tree_to_srcinfo_blob_map.remove(&tree_id) {
-            commit_to_srcinfo_and_timestamp.insert(commit_id, (srcinfo_blob_id, timestamp));
+    let mut commit_to_tree_and_info =
+        gix_hashtable::HashMap::<ObjectId, (ObjectId, CommitInfo)>::default();
+    let mut tree_to_srcinfo_blob_map = gix_hashtable::HashMap::<ObjectId, ObjectId>::default();
+    for object in objects {
+        match object {
+            CommitPackObject::Commit {
+                commit_id,
+                tree_id,
+                info,
+            } => {
+                commit_to_tree_and_info.insert(commit_id, (tree_id, info));
+            }
+            CommitPackObject::Tree {
+                tree_id,
+                srcinfo_blob_id,
+            } => {
+                tree_to_srcinfo_blob_map.insert(tree_id, srcinfo_blob_id);
+            }
         }
     }
-    Ok(commit_to_srcinfo_and_timestamp)
+
+    // Several commits may point at the same tree, so look trees up without removing them
+    Ok(commit_to_tree_and_info
+        .into_iter()
+        .map(|(commit_id, (tree_id, mut info))| {
+            info.srcinfo_blob_id = tree_to_srcinfo_blob_map.get(&tree_id).copied();
+            (commit_id, info)
+        })
+        .collect())
 }
 
 fn map_blob_id_to_content<T, E>(
     packfile_path: &std::path::Path,
     content_parser: fn(Vec<u8>) -> Result<T, E>,
+    stats: &FetchStats,
+    decode_caches: &mut [DecodeCache],
+    cancel: &CancellationToken,
 ) -> anyhow::Result<gix_hashtable::HashMap<ObjectId, T>>
 where
+    T: Send,
     E: std::error::Error,
 {
-    let entries_offset = BytesToEntriesIter::new_from_header(
-        std::io::BufReader::with_capacity(4096 * 8, std::fs::File::open(packfile_path)?),
-        input::Mode::AsIs,
-        EntryDataMode::Ignore,
-        gix_hash::Kind::Sha1,
-    )?
-    .filter_map(|x| x.ok().map(|e| e.pack_offset));
-
-    let mut blob_id_to_content_map = gix_hashtable::HashMap::<ObjectId, T>::default();
-
-    let pack = data::File::at(packfile_path, gix_hash::Kind::Sha1)?;
-    let mut delta_cache = gix_pack::cache::lru::MemoryCappedHashmap::new(1024 * 1024 * 10);
-    for pack_offset in entries_offset {
-        let entry = pack.entry(pack_offset)?;
-        let mut out = Vec::with_capacity(entry.decompressed_size as usize);
-        let outcome = pack.decode_entry(
-            entry,
-            &mut out,
-            &mut Default::default(),
-            &|_, _| None,
-            &mut delta_cache,
-        )?;
-        let object_id = gix_object::compute_hash(gix_hash::Kind::Sha1, outcome.kind, &out)?;
-        if outcome.kind == gix_object::Kind::Blob {
+    let blobs = decode_pack_entries(
+        packfile_path,
+        stats,
+        decode_caches,
+        cancel,
+        |object_id, kind, out| {
+            if kind != gix_object::Kind::Blob {
+                return Ok(None);
+            }
             match content_parser(out) {
-                Ok(content) => {
-                    blob_id_to_content_map.insert(object_id, content);
-                }
+                Ok(content) => Ok(Some((object_id, content))),
                 Err(err) => {
                     error!("Failed to parse blob {}: {}", object_id, err);
+                    Ok(None)
                 }
             }
-        }
+        },
+    )?;
+
+    Ok(blobs.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// Serve the start of a packfile section, then stall with the
+    /// connection open, like an upstream that stopped sending.
+    async fn spawn_stalling_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let body: &[u8] = b"000dpackfile\n0009\x01PACK";
+            let head = "HTTP/1.1 200 OK\r\n\
+                Content-Type: application/x-git-upload-pack-result\r\n\
+                Transfer-Encoding: chunked\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket
+                .write_all(format!("{:x}\r\n", body.len()).as_bytes())
+                .await
+                .unwrap();
+            socket.write_all(body).await.unwrap();
+            socket.write_all(b"\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            drop(socket);
+        });
+        format!("http://{}/git-upload-pack", addr)
     }
 
-    Ok(blob_id_to_content_map)
+    #[tokio::test]
+    async fn cancel_stops_a_stalled_packfile_stream() {
+        let url = spawn_stalling_server().await;
+        let client = Client::builder().no_proxy().build().unwrap();
+        let response = client.get(url).send().await.unwrap();
+        let body = response
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .into_async_read();
+        let mut rd = StreamingPeekableIter::new(body, &[PacketLineRef::Flush], false);
+
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                cancel.cancel();
+            }
+        });
+        let started = Instant::now();
+        let mut packfile = Vec::new();
+        let result = read_packfile_from_fetch_response(&mut rd, &mut packfile, &cancel).await;
+
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(packfile, b"PACK");
+    }
 }
@@ -0,0 +1,103 @@
+use crate::types::BranchFilterStats;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+/// Limits the branches a sync considers, for a mirror that is partial on
+/// purpose. Patterns are globs (`*`, `?`) matching the whole branch name, or
+/// regexes when prefixed with `re:`, which match anywhere unless anchored.
+#[derive(Debug, Clone, Default)]
+pub struct BranchFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    /// All include patterns in one, `None` to include every branch
+    include_regex: Option<Regex>,
+    /// All exclude patterns in one, `None` to exclude none
+    exclude_regex: Option<Regex>,
+}
+
+impl BranchFilter {
+    /// A branch is synced if it matches any `include` pattern, or there are
+    /// none, and no `exclude` pattern.
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            include_regex: compile(&include)?,
+            exclude_regex: compile(&exclude)?,
+            include,
+            exclude,
+        })
+    }
+
+    /// Whether all branches are synced.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, branch: &str) -> bool {
+        self.include_regex
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(branch))
+            && !self
+                .exclude_regex
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(branch))
+    }
+
+    pub fn include(&self) -> &[String] {
+        &self.include
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    /// The patterns as reported by `/api/stats` and the `stats` command,
+    /// `None` when all branches are synced.
+    pub fn stats(&self) -> Option<BranchFilterStats> {
+        (!self.is_empty()).then(|| BranchFilterStats {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        })
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Option<Regex>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut parts = Vec::with_capacity(patterns.len());
+    for pattern in patterns {
+        let part = match pattern.strip_prefix("re:") {
+            Some(regex) => format!("(?:{})", regex),
+            None => format!("^(?:{})$", glob_to_regex(pattern)),
+        };
+        // Checked one by one, so the error names the broken pattern
+        Regex::new(&part).map_err(|e| anyhow!("Invalid branch pattern {}: {}", pattern, e))?;
+        parts.push(part);
+    }
+    Ok(Some(Regex::new(&parts.join("|"))?))
+}
+
+/// One regex matching a name that any of `globs` (`*`, `?`) matches whole,
+/// `None` without any.
+pub fn compile_globs(globs: &[String]) -> Result<Option<Regex>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let parts: Vec<_> = globs
+        .iter()
+        .map(|glob| format!("^(?:{})$", glob_to_regex(glob)))
+        .collect();
+    Ok(Some(Regex::new(&parts.join("|"))?))
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::with_capacity(glob.len() * 2);
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex
+}
@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use axum::http::{HeaderMap, HeaderName};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpOptions {
+    /// Headers set by a trusted reverse proxy carrying the client address
+    pub trusted_proxy_headers: Vec<String>,
+    /// Addresses or CIDR ranges of the proxies whose headers are believed,
+    /// required along with the headers
+    pub trusted_proxies: Vec<String>,
+}
+
+/// Where the client address of a request comes from: the connection peer,
+/// or a header of a trusted reverse proxy.
+#[derive(Debug, Clone)]
+pub struct ClientIpSource {
+    headers: Vec<HeaderName>,
+    trusted_proxies: Vec<IpRange>,
+}
+
+/// The client address of a request, added to its extensions before any
+/// other middleware runs.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[derive(Debug, Clone, Default)]
+pub struct IpAccessOptions {
+    /// Addresses or CIDR ranges answered with 403
+    pub denylist: Vec<String>,
+    /// When not empty, addresses or CIDR ranges allowed; all others get 403
+    pub allowlist: Vec<String>,
+}
+
+/// Which clients may use the server at all.
+pub struct IpAccess {
+    denylist: Vec<IpRange>,
+    allowlist: Vec<IpRange>,
+}
+
+/// An address with a prefix length, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl ClientIpSource {
+    pub fn new(options: &ClientIpOptions) -> Result<Self> {
+        let headers = options
+            .trusted_proxy_headers
+            .iter()
+            .map(|name| HeaderName::try_from(name.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Invalid trusted proxy header: {}", e))?;
+        let trusted_proxies = options
+            .trusted_proxies
+            .iter()
+            .map(|range| parse_ip_range(range))
+            .collect::<Result<Vec<_>>>()?;
+        // Believing the headers from any peer would let every client that
+        // reaches the server directly pick its own address
+        if !headers.is_empty() && trusted_proxies.is_empty() {
+            return Err(anyhow!(
+                "trusted_proxy_headers needs trusted_proxies, the addresses of the proxies allowed to set them"
+            ));
+        }
+        Ok(Self {
+            headers,
+            trusted_proxies,
+        })
+    }
+
+    /// The client address: from the first trusted proxy header present, else
+    /// the peer of the connection. IPv4-mapped IPv6 addresses are reported
+    /// as IPv4, so they match IPv4 ranges and share counters.
+    ///
+    /// Headers are only believed from trusted proxies. For
+    /// `X-Forwarded-For`-style lists, whose earlier entries are
+    /// client-controlled, the entries appended by trusted proxies are
+    /// skipped from the end and the first other one is the client. An entry
+    /// that isn't an address ends the walk, so garbage can't smuggle in an
+    /// address before it.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.is_trusted_proxy(peer) {
+            return peer;
+        }
+        let Some(name) = self.headers.iter().find(|name| headers.contains_key(*name)) else {
+            return peer;
+        };
+        // Proxies may add their own header line instead of extending one
+        let mut hops = Vec::new();
+        for value in headers.get_all(name) {
+            let Ok(value) = value.to_str() else {
+                return peer;
+            };
+            hops.extend(value.split(',').map(parse_hop));
+        }
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !self.is_trusted_proxy(hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+}
+
+impl IpAccess {
+    /// Returns `None` when every client is allowed.
+    pub fn new(options: &IpAccessOptions) -> Result<Option<Self>> {
+        if options.denylist.is_empty() && options.allowlist.is_empty() {
+            return Ok(None);
+        }
+        let parse = |ranges: &[String]| {
+            ranges
+                .iter()
+                .map(|range| parse_ip_range(range))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Some(Self {
+            denylist: parse(&options.denylist)?,
+            allowlist: parse(&options.allowlist)?,
+        }))
+    }
+
+    /// Denied addresses are refused even when also allowed.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.denylist.iter().any(|range| range.contains(ip))
+            && (self.allowlist.is_empty() || self.allowlist.iter().any(|range| range.contains(ip)))
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => prefix_matches(
+                range.to_bits().into(),
+                ip.to_bits().into(),
+                self.prefix + 96,
+            ),
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                prefix_matches(range.to_bits(), ip.to_bits(), self.prefix)
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => ip
+                .to_ipv4_mapped()
+                .is_some_and(|ip| self.contains(IpAddr::V4(ip))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+fn prefix_matches(range: u128, ip: u128, prefix: u32) -> bool {
+    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+    range & mask == ip & mask
+}
+
+/// Parse an address (`192.0.2.1`) or a CIDR range (`192.0.2.0/24`).
+pub fn parse_ip_range(range: &str) -> Result<IpRange> {
+    let (addr, prefix) = match range.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (range, None),
+    };
+    let addr: IpAddr = addr
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("Invalid address range {}: {}", range, e))?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&prefix| prefix <= max_prefix)
+            .ok_or_else(|| anyhow!("Invalid prefix length in address range {}", range))?,
+        None => max_prefix,
+    };
+    // Client addresses are compared as IPv4 when mapped, so ranges are too
+    if let IpAddr::V6(v6) = addr {
+        if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix >= 96) {
+            return Ok(IpRange {
+                addr: IpAddr::V4(v4),
+                prefix: prefix - 96,
+            });
+        }
+    }
+    Ok(IpRange { addr, prefix })
+}
+
+/// One entry of a forwarding header: an address, optionally quoted or
+/// with a port (`192.0.2.1:1234`, `[2001:db8::1]:1234`).
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn source(proxies: &[&str]) -> ClientIpSource {
+        ClientIpSource::new(&ClientIpOptions {
+            trusted_proxy_headers: vec!["x-forwarded-for".to_string(), "x-real-ip".to_string()],
+            trusted_proxies: proxies.iter().map(|proxy| proxy.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    fn headers(lines: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in lines {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn headers_without_trusted_proxies_are_refused() {
+        let options = ClientIpOptions {
+            trusted_proxy_headers: vec!["x-forwarded-for".to_string()],
+            trusted_proxies: Vec::new(),
+        };
+        assert!(ClientIpSource::new(&options).is_err());
+        assert!(ClientIpSource::new(&ClientIpOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn headers_of_other_peers_are_ignored() {
+        let source = source(&["10.0.0.0/8"]);
+        let spoofed = headers(&[("x-forwarded-for", "192.0.2.7"), ("x-real-ip", "192.0.2.7")]);
+        assert_eq!(
+            source.client_ip(&spoofed, ip("198.51.100.9")),
+            ip("198.51.100.9")
+        );
+        // A mapped peer is still that IPv4 peer, not a trusted one
+        assert_eq!(
+            source.client_ip(&spoofed, ip("::ffff:198.51.100.9")),
+            ip("198.51.100.9")
+        );
+    }
+
+    #[test]
+    fn spoofed_hops_before_the_client_are_skipped() {
+        let source = source(&["10.0.0.0/8"]);
+        let peer = ip("10.0.0.1");
+        let cases = [
+            (headers(&[("x-forwarded-for", "192.0.2.7")]), "192.0.2.7"),
+            (
+                headers(&[("x-forwarded-for", "1.1.1.1, 192.0.2.7")]),
+                "192.0.2.7",
+            ),
+            // Hops appended by trusted proxies along the way
+            (
+                headers(&[("x-forwarded-for", "1.1.1.1, 192.0.2.7, 10.0.0.2, 10.0.0.3")]),
+                "192.0.2.7",
+            ),
+            // Proxies adding their own line instead of extending one
+            (
+                headers(&[
+                    ("x-forwarded-for", "1.1.1.1, 192.0.2.7"),
+                    ("x-forwarded-for", "10.0.0.2"),
+                ]),
+                "192.0.2.7",
+            ),
+            // Garbage ends the walk at the last trusted hop before it
+            (
+                headers(&[("x-forwarded-for", "1.1.1.1, unknown, 10.0.0.2")]),
+                "10.0.0.2",
+            ),
+            (
+                headers(&[("x-forwarded-for", "1.1.1.1, unknown")]),
+                "10.0.0.1",
+            ),
+            // All hops trusted: the first one
+            (
+                headers(&[("x-forwarded-for", "10.0.0.5, 10.0.0.2")]),
+                "10.0.0.5",
+            ),
+            (
+                headers(&[("x-forwarded-for", "\"[2001:db8::1]:4711\", 192.0.2.7:1234")]),
+                "192.0.2.7",
+            ),
+            (headers(&[("x-real-ip", "192.0.2.7")]), "192.0.2.7"),
+            (headers(&[]), "10.0.0.1"),
+        ];
+        for (headers, client) in cases {
+            assert_eq!(
+                source.client_ip(&headers, peer),
+                ip(client),
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_ipv4() {
+        let forwarded = headers(&[("x-forwarded-for", "1.1.1.1, ::ffff:192.0.2.7")]);
+        // A mapped peer matches an IPv4 range, and a mapped range an IPv4 peer
+        for (proxies, peer) in [
+            (["10.0.0.0/8"], "::ffff:10.0.0.1"),
+            (["::ffff:10.0.0.0/104"], "10.0.0.1"),
+        ] {
+            assert_eq!(
+                source(&proxies).client_ip(&forwarded, ip(peer)),
+                ip("192.0.2.7")
+            );
+        }
+        // A mapped hop of a trusted proxy is skipped like the IPv4 one
+        let source = source(&["10.0.0.0/8"]);
+        let forwarded = headers(&[("x-forwarded-for", "1.1.1.1, 192.0.2.7, ::ffff:10.0.0.2")]);
+        assert_eq!(
+            source.client_ip(&forwarded, ip("10.0.0.1")),
+            ip("192.0.2.7")
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use crate::app_state::AppState;
+use crate::rpc_server::{rpc_package_details, rpc_package_info};
+use crate::supplement_fetcher::{Freshness, SupplementFetchOutcome, SupplementFetcher};
+use crate::syncer::DryRunReport;
+use crate::types::{
+    DatabasePackageDetailsWithSupplement, LocalStats, SearchType, SortBy, SortField,
+};
+use anyhow::{anyhow, Result};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use futures::TryStreamExt;
+use std::io::{BufWriter, Write};
+use tracing::info;
+
+/// Packages fetched per query when exporting the full metadata
+const EXPORT_CHUNK_SIZE: usize = 500;
+
+/// Dump formats of aurweb, as served under the same names.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// Names of all packages, one per line (`packages.gz`)
+    Packages,
+    /// Names of all package bases, one per line (`pkgbase.gz`)
+    Pkgbase,
+    /// JSON array of the full metadata of all packages (`packages-meta-ext-v1.json.gz`)
+    MetaExt,
+}
+
+/// How far `sync --dry-run` goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DryRunMode {
+    /// Only compare the branch list to the indexed commits
+    Diff,
+    /// Also download and parse the `.SRCINFO` of new and changed branches
+    Fetch,
+}
+
+/// Parser of `--by`, listing the search types for help and shell completion.
+pub fn search_type_parser() -> impl TypedValueParser<Value = SearchType> {
+    PossibleValuesParser::new(SearchType::NAMES)
+        .map(|by| SearchType::from_str(&by).expect("listed search type"))
+}
+
+/// Print the packages matching a search, ordered by name.
+pub async fn search(
+    app_state: &AppState,
+    keyword: &str,
+    search_type: SearchType,
+    json: bool,
+) -> Result<()> {
+    let sort = SortBy {
+        field: SortField::Name,
+        descending: false,
+    };
+    let rows = app_state
+        .db
+        .search_packages(search_type, keyword, Some(sort), None)
+        .await?;
+    let mut out = std::io::stdout().lock();
+    if json {
+        let packages: Vec<_> = rows
+            .into_iter()
+            .map(|row| {
+                rpc_package_info(
+                    &app_state.url_path_template,
+                    app_state.rpc_commit_fields,
+                    row,
+                )
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut out, &packages)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    for row in rows {
+        write!(
+            out,
+            "{} {} (+{} {:.2})",
+            row.pkg_name,
+            row.version,
+            row.num_votes.unwrap_or(0),
+            row.popularity.unwrap_or(0.0)
+        )?;
+        if row.out_of_date.is_some() {
+            write!(out, " [out of date]")?;
+        }
+        writeln!(out)?;
+        if let Some(desc) = row.pkg_desc {
+            writeln!(out, "    {}", desc)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the details of packages by name; fails after printing the found
+/// ones if any is unknown.
+pub async fn info(app_state: &AppState, names: &[String], json: bool) -> Result<()> {
+    let details = app_state.db.get_package_details(names).await?;
+    let missing: Vec<_> = names
+        .iter()
+        .filter(|name| !details.iter().any(|d| &d.info.pkg_name == *name))
+        .map(String::as_str)
+        .collect();
+    let mut out = std::io::stdout().lock();
+    if json {
+        let packages: Vec<_> = details
+            .into_iter()
+            .map(|details| {
+                rpc_package_details(
+                    &app_state.url_path_template,
+                    app_state.rpc_commit_fields,
+                    details,
+                )
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut out, &packages)?;
+        writeln!(out)?;
+    } else {
+        for (i, details) in details.into_iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            write_details(&mut out, details)?;
+        }
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!("Packages not found: {}", missing.join(", ")));
+    }
+    Ok(())
+}
+
+/// Write a dump to `output`, `-` for stdout; gzip-compressed when the path
+/// ends with `.gz`.
+pub async fn export(app_state: &AppState, format: ExportFormat, output: &str) -> Result<()> {
+    let file: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(std::fs::File::create(output)?)
+    };
+    let out = BufWriter::new(file);
+    let count = if output.ends_with(".gz") {
+        let mut encoder = GzEncoder::new(out, flate2::Compression::default());
+        let count = write_dump(app_state, format, &mut encoder).await?;
+        encoder.finish()?.flush()?;
+        count
+    } else {
+        let mut out = out;
+        let count = write_dump(app_state, format, &mut out).await?;
+        out.flush()?;
+        count
+    };
+    info!("Exported {} entries", count);
+    Ok(())
+}
+
+/// Write a dump, returning the number of entries written.
+async fn write_dump(
+    app_state: &AppState,
+    format: ExportFormat,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    let db = &app_state.db;
+    let count = match format {
+        ExportFormat::Packages | ExportFormat::Pkgbase => {
+            let mut names = match format {
+                ExportFormat::Packages => db.stream_package_names(),
+                _ => db.stream_pkgbases(),
+            };
+            let mut count = 0usize;
+            while let Some(name) = names.try_next().await? {
+                writeln!(out, "{}", name)?;
+                count += 1;
+            }
+            count
+        }
+        ExportFormat::MetaExt => {
+            let names: Vec<String> = db.stream_package_names().try_collect().await?;
+            out.write_all(b"[")?;
+            let mut count = 0usize;
+            for chunk in names.chunks(EXPORT_CHUNK_SIZE) {
+                for details in db.get_package_details(chunk).await? {
+                    if count > 0 {
+                        out.write_all(b",\n")?;
+                    }
+                    let package = rpc_package_details(&app_state.url_path_template, false, details);
+                    serde_json::to_writer(&mut *out, &package)?;
+                    count += 1;
+                }
+            }
+            out.write_all(b"]\n")?;
+            count
+        }
+    };
+    Ok(count)
+}
+
+/// Replace the supplement data with a metadata dump from the first of
+/// `sources` that can be read, like a sync would, but failing instead of
+/// keeping the current data.
+pub async fn import(app_state: &AppState, sources: &[String]) -> Result<()> {
+    let fetcher = SupplementFetcher::new(
+        app_state.user_agent.clone(),
+        app_state.rate_limiter.clone(),
+        app_state.supplement_options.clone(),
+    )?;
+    let outcome = if app_state.supplement_options.merge {
+        fetcher.fetch_merged_supplement_data(sources).await?
+    } else {
+        fetcher.fetch_supplement_data(sources, None).await?
+    };
+    let SupplementFetchOutcome::Fetched {
+        data,
+        validators,
+        freshness,
+        stale,
+        ..
+    } = outcome
+    else {
+        return Err(anyhow!("Supplement data was not modified"));
+    };
+    if matches!(freshness, Freshness::Cached) {
+        return Err(anyhow!("No source could be read"));
+    }
+    if data.is_empty() {
+        return Err(anyhow!("No supplement records found"));
+    }
+    app_state
+        .db
+        .store_supplement_data(&data, Some(&validators), !stale)
+        .await?;
+    info!("Imported {} supplement records", data.len());
+    Ok(())
+}
+
+/// Print what a sync would do.
+pub fn print_dry_run(report: &DryRunReport, json: bool) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut out, report)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    writeln!(out, "Upstream branches:  {}", report.branches_upstream)?;
+    writeln!(out, "New branches:       {}", report.branches_new)?;
+    writeln!(out, "Changed branches:   {}", report.branches_changed)?;
+    writeln!(out, "Deleted branches:   {}", report.branches_deleted)?;
+    writeln!(out, "Packages to fetch:  ~{}", report.packages_estimate)?;
+    if let Some(fetch) = &report.fetch {
+        writeln!(out, "Branches fetched:   {}", fetch.branches_fetched)?;
+        writeln!(out, "Branches failed:    {}", fetch.branches_failed)?;
+        writeln!(out, "Branches empty:     {}", fetch.branches_empty)?;
+        writeln!(out, "Packages parsed:    {}", fetch.packages_parsed)?;
+        writeln!(out, "Bytes fetched:      {}", fetch.bytes_fetched)?;
+    }
+    Ok(())
+}
+
+/// Print the counts and data ages `/api/stats` serves, read from the
+/// database alone, so it works without the server and read-only.
+pub async fn stats(app_state: &AppState, json: bool) -> Result<()> {
+    let stats = LocalStats {
+        database: app_state.db.get_stats().await?,
+        branch_filters: app_state.branch_filter.stats(),
+    };
+    let mut out = std::io::stdout().lock();
+    if json {
+        serde_json::to_writer_pretty(&mut out, &stats)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    let LocalStats {
+        database: stats,
+        branch_filters,
+    } = stats;
+    let now = chrono::Utc::now().timestamp();
+    let time = |timestamp: Option<i64>| match timestamp {
+        Some(timestamp) => format!(
+            "{} ({} ago)",
+            format_time(Some(timestamp)),
+            format_age(now - timestamp)
+        ),
+        None => "None".to_string(),
+    };
+    writeln!(out, "Packages:           {}", stats.packages)?;
+    writeln!(out, "Package bases:      {}", stats.pkgbases)?;
+    writeln!(out, "Branches:           {}", stats.branches)?;
+    writeln!(out, "Orphans:            {}", stats.orphans)?;
+    writeln!(out, "Out-of-date:        {}", stats.out_of_date)?;
+    writeln!(
+        out,
+        "Last sync:          {}",
+        time(stats.last_successful_sync)
+    )?;
+    writeln!(
+        out,
+        "Supplement data:    {}",
+        time(stats.supplement_last_modified)
+    )?;
+    writeln!(out, "Database size:      {} bytes", stats.db_size_bytes)?;
+    writeln!(out, "History rows:       {}", stats.history_rows)?;
+    match branch_filters {
+        Some(filters) => {
+            let patterns = |patterns: Vec<String>, none: &str| {
+                if patterns.is_empty() {
+                    none.to_string()
+                } else {
+                    patterns.join("  ")
+                }
+            };
+            writeln!(
+                out,
+                "Branch include:     {}",
+                patterns(filters.include, "All")
+            )?;
+            writeln!(
+                out,
+                "Branch exclude:     {}",
+                patterns(filters.exclude, "None")
+            )?;
+        }
+        None => writeln!(out, "Branch filters:     None")?,
+    }
+    Ok(())
+}
+
+/// Largest whole unit of an age in seconds, e.g. `3h`.
+fn format_age(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn format_time(timestamp: Option<i64>) -> String {
+    timestamp
+        .filter(|&timestamp| timestamp > 0)
+        .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "None".to_string())
+}
+
+/// Details in the layout of `pacman -Si`.
+fn write_details(
+    out: &mut impl Write,
+    details: DatabasePackageDetailsWithSupplement,
+) -> Result<()> {
+    let info = details.info;
+    let list = |items: Vec<String>| {
+        if items.is_empty() {
+            "None".to_string()
+        } else {
+            items.join("  ")
+        }
+    };
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "None".to_string());
+    let time = format_time;
+    let fields = [
+        ("Name", info.pkg_name),
+        ("Version", info.version),
+        ("Description", optional(info.pkg_desc)),
+        ("URL", optional(info.url)),
+        ("Package Base", info.branch),
+        ("Licenses", list(details.licenses)),
+        ("Groups", list(details.groups)),
+        ("Provides", list(details.provides)),
+        ("Depends On", list(details.depends)),
+        ("Make Deps", list(details.make_depends)),
+        ("Check Deps", list(details.check_depends)),
+        ("Optional Deps", list(details.opt_depends)),
+        ("Conflicts With", list(details.conflicts)),
+        ("Replaces", list(details.replaces)),
+        ("Keywords", list(details.keywords)),
+        ("Maintainer", optional(info.maintainer)),
+        ("Co-Maintainers", list(details.co_maintainers)),
+        ("Submitter", optional(info.submitter)),
+        ("Votes", info.num_votes.unwrap_or(0).to_string()),
+        (
+            "Popularity",
+            format!("{:.2}", info.popularity.unwrap_or(0.0)),
+        ),
+        ("First Submitted", time(info.first_submitted)),
+        ("Last Modified", time(info.last_modified)),
+        ("Out-of-date", time(info.out_of_date)),
+    ];
+    for (key, value) in fields {
+        writeln!(out, "{:<16}: {}", key, value)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+/// Longest a queued request waits for a slot before it is shed
+const MAX_QUEUE_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyOptions {
+    /// Requests handled at once over all routes; 0 disables the limit
+    pub global: usize,
+    /// Requests handled at once per matched route pattern, e.g. `/rpc`
+    pub routes: HashMap<String, usize>,
+    /// Requests waiting for a slot per limit before new ones are shed
+    pub queue: usize,
+}
+
+/// Bounds the requests handled at once, globally and per route, so a burst
+/// gets fast rejections instead of slowing everyone down.
+///
+/// A request over a limit waits in a short queue; when the queue is full
+/// or the wait takes too long, the request is shed.
+pub struct ConcurrencyLimiter {
+    global: Option<Limit>,
+    routes: HashMap<String, Limit>,
+}
+
+struct Limit {
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+/// Slots held while a request is handled.
+pub struct Permits {
+    _global: Option<OwnedSemaphorePermit>,
+    _route: Option<OwnedSemaphorePermit>,
+}
+
+impl ConcurrencyLimiter {
+    /// Returns `None` when no limit is configured.
+    pub fn new(options: &ConcurrencyOptions) -> Option<Self> {
+        let global = (options.global > 0).then(|| Limit::new(options.global, options.queue));
+        let routes: HashMap<_, _> = options
+            .routes
+            .iter()
+            .filter(|(_, &limit)| limit > 0)
+            .map(|(route, &limit)| (route.clone(), Limit::new(limit, options.queue)))
+            .collect();
+        if global.is_none() && routes.is_empty() {
+            return None;
+        }
+        info!(
+            "Limiting concurrent requests to {} overall and {} routes individually",
+            options.global,
+            routes.len()
+        );
+        Some(Self { global, routes })
+    }
+
+    /// Slots for a request to `route`, or `None` if it has to be shed.
+    pub async fn acquire(&self, route: &str) -> Option<Permits> {
+        // Route first, so requests queued for a busy route hold no global slot
+        let route = match self.routes.get(route) {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        let global = match &self.global {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        Some(Permits {
+            _global: global,
+            _route: route,
+        })
+    }
+}
+
+impl Limit {
+    fn new(slots: usize, max_queued: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(slots)),
+            queued: AtomicUsize::new(0),
+            max_queued,
+        }
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        let permit = tokio::time::timeout(MAX_QUEUE_WAIT, self.slots.clone().acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+        permit.ok()?.ok()
+    }
+}
@@ -0,0 +1,97 @@
+use axum::http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+/// Cache validators of a response, checked against the conditional headers
+/// of a request before the response body is built.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    /// Opaque tag, sent as a weak ETag since compression alters the bytes
+    pub etag: String,
+    /// Whether the ETag is sent as a strong one, for byte-identical bodies
+    /// which are never compressed
+    pub strong: bool,
+    /// Unix timestamp of the newest data the response is built from
+    pub last_modified: Option<i64>,
+}
+
+impl Validators {
+    /// Validators with an ETag hashed from `parts`.
+    pub fn from_parts<I, P>(parts: I, last_modified: Option<i64>) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<[u8]>,
+    {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part.as_ref());
+            // Separate parts so ("ab", "c") and ("a", "bc") differ
+            hasher.update([0]);
+        }
+        let digest = hasher.finalize();
+        let etag = digest[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Self {
+            etag,
+            strong: false,
+            last_modified,
+        }
+    }
+
+    /// Send the ETag as a strong one.
+    pub fn strong(self) -> Self {
+        Self {
+            strong: true,
+            ..self
+        }
+    }
+
+    /// Whether the client's copy is still current. `If-None-Match` takes
+    /// precedence; `If-Modified-Since` is only consulted without it.
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            return if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == self.etag
+            });
+        }
+        let since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok());
+        match (since, self.last_modified) {
+            (Some(since), Some(last_modified)) => last_modified <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// Add the validators to a response.
+    pub fn apply<B>(&self, response: &mut Response<B>) {
+        let headers = response.headers_mut();
+        let weak = if self.strong { "" } else { "W/" };
+        if let Ok(etag) = HeaderValue::from_str(&format!("{}\"{}\"", weak, self.etag)) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Some(last_modified) = self.last_modified.and_then(http_date) {
+            headers.insert(header::LAST_MODIFIED, last_modified);
+        }
+    }
+
+    /// An empty 304 response carrying the validators.
+    pub fn not_modified(&self) -> Response<String> {
+        let mut response = Response::new(String::new());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        self.apply(&mut response);
+        response
+    }
+}
+
+fn http_date(timestamp: i64) -> Option<HeaderValue> {
+    let date = DateTime::<Utc>::from_timestamp(timestamp, 0)?;
+    HeaderValue::from_str(&date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).ok()
+}
@@ -1,34 +1,250 @@
+use crate::logging::{LogFormat, LogRotation};
+use crate::request_limiter::ApiKeyConfig;
+use crate::rpc_server::{GitUpstream, UrlPathTemplate};
+use crate::supplement_fetcher::StaleDataAction;
+use crate::tls::TlsOptions;
+use crate::webhooks::WebhookConfig;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+const DEFAULT_SRCINFO_CACHE_MAX_BYTES: u64 = 512 * 1024 * 1024;
+const DEFAULT_SRCINFO_CACHE_MAX_AGE_DAYS: u64 = 30;
+const DEFAULT_SNAPSHOT_CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_HISTORY_DAYS: u64 = 90;
+const DEFAULT_DECODE_CACHE_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_SUPPLEMENT_RETRIES: u32 = 3;
+const DEFAULT_SUPPLEMENT_MAX_BAD_FRACTION: f64 = 0.01;
+const DEFAULT_SUPPLEMENT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_SUPPLEMENT_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_SUPPLEMENT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+const DEFAULT_SUPPLEMENT_CACHE_MAX_AGE_HOURS: u64 = 72;
+const DEFAULT_SUPPLEMENT_MAX_DATA_AGE_DAYS: u64 = 7;
+const DEFAULT_SUPPLEMENT_LIVE_LOOKUP_TTL_SECS: u64 = 3600;
+const DEFAULT_RPC_MAX_INFO_ARGS: usize = 500;
+const DEFAULT_RPC_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_RPC_MAX_QUERY_BYTES: usize = 64 * 1024;
+const DEFAULT_RPC_MAX_ARG_PARAMS: usize = 2000;
+const DEFAULT_RATE_LIMIT_REQUESTS: u64 = 4000;
+const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 86400;
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 86400;
+const DEFAULT_READY_MAX_SYNC_AGE_SECS: u64 = 2 * 86400;
+const DEFAULT_SSE_MAX_CONNECTIONS: usize = 100;
+const DEFAULT_WEB_UI_PREFIX: &str = "/web";
+const DEFAULT_QUERY_CACHE_TTL_SECS: u64 = 300;
+const DEFAULT_LISTEN: &str = "[::]:3000";
+const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_CONCURRENCY_QUEUE: usize = 16;
+const DEFAULT_PROXY_UPSTREAM: &str = "https://aur.archlinux.org";
+const DEFAULT_PROXY_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_SYNC_MIN_SPACING_SECS: u64 = 300;
+const DEFAULT_SYNC_FETCH_CONCURRENCY: usize = 1;
+const DEFAULT_SYNC_WRITE_BATCH_SIZE: usize = 3000;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_LOG_FILE_KEEP: usize = 5;
+const DEFAULT_SUPPLEMENT_SOURCE: &str = "https://aur.archlinux.org/packages-meta-ext-v1.json.gz";
+/// Prefix of the environment variables setting config keys
+const ENV_PREFIX: &str = "AMM_";
+/// Shown by `--check-config` in place of secrets
+const REDACTED: &str = "<redacted>";
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ConfigFileModel {
     pub db_path: Option<String>,
+    pub db_read_only: Option<bool>,
     pub github_token: Option<String>,
+    pub github_token_file: Option<String>,
+    pub user_agent: Option<String>,
+    pub user_agent_suffix: Option<String>,
+    pub srcinfo_cache_dir: Option<String>,
+    pub srcinfo_cache_max_bytes: Option<u64>,
+    pub srcinfo_cache_max_age_days: Option<u64>,
+    pub history_branches: Option<Vec<String>>,
+    pub history_days: Option<u64>,
+    pub store_srcinfo: Option<bool>,
+    pub url_path_template: Option<String>,
+    pub decode_cache_bytes: Option<usize>,
+    pub download_rate_limit: Option<u64>,
+    pub supplement_sources: Option<Vec<String>>,
+    pub supplement_retries: Option<u32>,
+    pub supplement_merge: Option<bool>,
+    pub supplement_max_bad_fraction: Option<f64>,
+    pub supplement_connect_timeout_secs: Option<u64>,
+    pub supplement_timeout_secs: Option<u64>,
+    pub supplement_max_bytes: Option<u64>,
+    pub supplement_cache_path: Option<String>,
+    pub supplement_cache_max_age_hours: Option<u64>,
+    pub supplement_max_data_age_days: Option<u64>,
+    pub supplement_stale_data_action: Option<StaleDataAction>,
+    pub supplement_live_lookup: Option<bool>,
+    pub supplement_live_lookup_ttl_secs: Option<u64>,
+    pub supplement_refresh_interval_mins: Option<u64>,
+    pub admin_token: Option<String>,
+    pub rpc_max_info_args: Option<usize>,
+    pub rpc_max_split_info_args: Option<usize>,
+    pub rpc_v6: Option<bool>,
+    pub rpc_commit_fields: Option<bool>,
+    pub rpc_max_body_bytes: Option<usize>,
+    pub rpc_max_query_bytes: Option<usize>,
+    pub rpc_max_arg_params: Option<usize>,
+    pub rate_limit_requests: Option<u64>,
+    pub rate_limit_window_secs: Option<u64>,
+    pub rate_limit_allowlist: Option<Vec<String>>,
+    pub api_keys: Option<Vec<ApiKeyConfig>>,
+    pub trusted_proxy_headers: Option<Vec<String>>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub ip_denylist: Option<Vec<String>>,
+    pub ip_allowlist: Option<Vec<String>>,
+    pub cors_allowed_origins: Option<Vec<String>>,
+    pub cors_max_age_secs: Option<u64>,
+    pub git_upstream: Option<String>,
+    pub snapshot_cache_dir: Option<String>,
+    pub snapshot_cache_max_bytes: Option<u64>,
+    pub metrics_allowlist: Option<Vec<String>>,
+    pub ready_max_sync_age_secs: Option<u64>,
+    pub stale_after_secs: Option<u64>,
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    pub sse_max_connections: Option<usize>,
+    pub web_ui: Option<bool>,
+    pub web_ui_prefix: Option<String>,
+    pub openapi_swagger_ui: Option<bool>,
+    pub query_cache_capacity: Option<u64>,
+    pub query_cache_ttl_secs: Option<u64>,
+    pub listen: Option<Vec<String>>,
+    pub unix_socket_mode: Option<String>,
+    pub unix_socket_group: Option<String>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub redirect_http_to_https: Option<bool>,
+    pub shutdown_drain_timeout_secs: Option<u64>,
+    pub access_log: Option<bool>,
+    pub access_log_path: Option<String>,
+    pub concurrency_limit: Option<usize>,
+    pub concurrency_limits: Option<HashMap<String, usize>>,
+    pub concurrency_queue: Option<usize>,
+    pub request_timeout_secs: Option<u64>,
+    pub request_timeouts: Option<HashMap<String, u64>>,
+    pub proxy_upstream: Option<String>,
+    pub proxy_path_prefixes: Option<Vec<String>>,
+    pub proxy_timeout_secs: Option<u64>,
+    pub proxy_rate_limit: Option<bool>,
+    pub sync_interval_mins: Option<u64>,
+    pub sync_jitter_secs: Option<u64>,
+    pub sync_min_spacing_secs: Option<u64>,
+    pub sync_on_start: Option<bool>,
+    pub branch_include: Option<Vec<String>>,
+    pub branch_exclude: Option<Vec<String>>,
+    pub sync_fetch_concurrency: Option<usize>,
+    pub sync_decode_workers: Option<usize>,
+    pub sync_write_batch_size: Option<usize>,
+    pub log_format: Option<LogFormat>,
+    pub log_level: Option<String>,
+    pub log_file: Option<String>,
+    pub log_file_level: Option<String>,
+    pub log_file_rotation: Option<LogRotation>,
+    pub log_file_max_bytes: Option<u64>,
+    pub log_file_keep: Option<usize>,
+    /// Keys this version doesn't know, kept so `login` doesn't drop them
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
 }
 
 pub struct Config {
     config_path: Option<PathBuf>,
+    /// The config file with the environment layered over it
+    settings: ConfigFileModel,
+    /// Keys set in the config file, for `source()`
+    file_keys: BTreeSet<String>,
 }
 
 impl Config {
-    pub fn new(config_path: Option<PathBuf>) -> Self {
-        Config {
-            config_path: config_path.or_else(get_default_config_path),
-        }
+    /// Read the config file and the environment, once. Every key can be set
+    /// as `AMM_<KEY>`, or as `AMM_<KEY>_FILE` naming a file that holds the
+    /// value, e.g. a mounted secret. Fails on a config file that can't be
+    /// read or parsed, or an invalid environment variable, which would
+    /// otherwise be ignored along with the rest of the config.
+    pub fn new(config_path: Option<PathBuf>) -> Result<Self> {
+        let config_path = config_path.or_else(get_default_config_path);
+        let file = toml::Table::try_from(load_file(config_path.as_deref())?.unwrap_or_default())?;
+        let file_keys = file.keys().cloned().collect();
+        let settings = layer_env(file, env_layer(env::vars())?)?;
+        Ok(Config {
+            config_path,
+            settings,
+            file_keys,
+        })
     }
 
     pub fn config_path(&self) -> Option<&Path> {
         self.config_path.as_deref()
     }
 
-    fn read_from_file(&self) -> Option<ConfigFileModel> {
-        self.config_path
-            .as_deref()
-            .and_then(|p| std::fs::read_to_string(p).ok())
-            .and_then(|content| toml::from_str::<ConfigFileModel>(&content).ok())
+    /// Mark each key of `effective`, the TOML of `effective()`, with the
+    /// layer its value came from as a trailing comment; the keys in
+    /// `from_cli` were given on the command line.
+    pub fn annotate_sources(&self, effective: &str, from_cli: &[&str]) -> String {
+        let mut annotated = String::with_capacity(effective.len() * 2);
+        // Tables come after all plain keys, and their own keys are left be
+        let mut in_tables = false;
+        for line in effective.lines() {
+            annotated.push_str(line);
+            let key = if line.starts_with('[') {
+                in_tables = true;
+                Some(line.trim_matches(['[', ']']))
+            } else if in_tables {
+                None
+            } else {
+                line.split_once(" = ").map(|(key, _)| key)
+            };
+            if let Some(key) = key {
+                let source = if from_cli.contains(&key) {
+                    "command line".to_string()
+                } else {
+                    self.source(key)
+                };
+                annotated.push_str(&format!(" # {}", source));
+            }
+            annotated.push('\n');
+        }
+        annotated
+    }
+
+    /// Where the value of `key` comes from: the environment variable, the
+    /// config file or the default. Keys setting the same value another way,
+    /// like `github_token_file`, count too.
+    fn source(&self, key: &str) -> String {
+        let related = [
+            key.to_string(),
+            format!("{}_file", key),
+            format!("{}_suffix", key),
+        ];
+        for key in &related {
+            let name = format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase());
+            for name in [name.clone(), format!("{}_FILE", name)] {
+                if env::var_os(&name).is_some() {
+                    return format!("env {}", name);
+                }
+            }
+        }
+        if related.iter().any(|key| self.file_keys.contains(key)) {
+            return "file".to_string();
+        }
+        if key == "github_token" && env::var_os("GITHUB_TOKEN").is_some() {
+            return "env GITHUB_TOKEN".to_string();
+        }
+        "default".to_string()
+    }
+
+    /// Warn about keys of the config file this version doesn't know.
+    pub fn warn_unknown_keys(&self) {
+        if !self.settings.unknown.is_empty() {
+            let keys: Vec<_> = self.settings.unknown.keys().map(String::as_str).collect();
+            warn!("Ignoring unknown config keys: {}", keys.join(", "));
+        }
     }
 
     pub fn modify_file<M>(&self, modifier: M) -> Result<()>
@@ -39,7 +255,11 @@ impl Config {
             .config_path
             .as_deref()
             .ok_or(anyhow!("No config path found."))?;
-        let mut model = self.read_from_file().unwrap_or_default();
+        // The file alone, so the environment isn't written into it
+        let mut model = load_file(self.config_path.as_deref())
+            .ok()
+            .flatten()
+            .unwrap_or_default();
         modifier(&mut model);
         let toml_str = toml::to_string_pretty(&model)?;
         if let Some(parent) = config_path.parent() {
@@ -50,9 +270,9 @@ impl Config {
     }
 
     pub fn db_path(&self) -> Option<String> {
-        self.read_from_file()
-            .and_then(|config| config.db_path)
-            .or_else(|| env::var("AMM_DB_PATH").ok())
+        self.settings
+            .db_path
+            .clone()
             .or_else(|| get_default_db_path().map(|p| p.to_string_lossy().to_string()))
             .filter(|path| {
                 PathBuf::from(path)
@@ -62,12 +282,913 @@ impl Config {
             })
     }
 
+    /// Whether the database is opened read-only, e.g. a replica's or one
+    /// owned by another user.
+    pub fn db_read_only(&self) -> bool {
+        self.settings.db_read_only.unwrap_or(false)
+    }
+
+    /// The token given directly or in a file; the generic `GITHUB_TOKEN` is
+    /// the last resort.
     pub fn github_token(&self) -> Option<String> {
-        self.read_from_file()
-            .and_then(|config| config.github_token)
-            .or_else(|| env::var("AMM_GITHUB_TOKEN").ok())
+        self.settings
+            .github_token
+            .clone()
+            .or_else(|| {
+                self.settings
+                    .github_token_file
+                    .as_deref()
+                    .and_then(read_token_file)
+            })
             .or_else(|| env::var("GITHUB_TOKEN").ok())
     }
+
+    pub fn user_agent(&self) -> String {
+        if let Some(user_agent) = &self.settings.user_agent {
+            return user_agent.clone();
+        }
+        let suffix = self
+            .settings
+            .user_agent_suffix
+            .as_deref()
+            .map(str::trim)
+            .filter(|suffix| !suffix.is_empty());
+        match suffix {
+            Some(suffix) => format!("{} {}", get_default_user_agent(), suffix),
+            None => get_default_user_agent(),
+        }
+    }
+
+    pub fn srcinfo_cache_dir(&self) -> Option<PathBuf> {
+        non_empty_path(&self.settings.srcinfo_cache_dir)
+    }
+
+    pub fn srcinfo_cache_max_bytes(&self) -> u64 {
+        self.settings
+            .srcinfo_cache_max_bytes
+            .unwrap_or(DEFAULT_SRCINFO_CACHE_MAX_BYTES)
+    }
+
+    pub fn srcinfo_cache_max_age(&self) -> Duration {
+        let days = self
+            .settings
+            .srcinfo_cache_max_age_days
+            .unwrap_or(DEFAULT_SRCINFO_CACHE_MAX_AGE_DAYS);
+        Duration::from_secs(days * 86400)
+    }
+
+    pub fn decode_cache_bytes(&self) -> usize {
+        self.settings
+            .decode_cache_bytes
+            .unwrap_or(DEFAULT_DECODE_CACHE_BYTES)
+    }
+
+    /// Download rate limit in bytes per second, 0 when unlimited.
+    pub fn download_rate_limit(&self) -> u64 {
+        self.settings.download_rate_limit.unwrap_or(0)
+    }
+
+    /// Supplement sources of `sync` and `serve` unless given on the command
+    /// line.
+    pub fn supplement_sources(&self) -> Vec<String> {
+        self.settings
+            .supplement_sources
+            .clone()
+            .filter(|sources| !sources.is_empty())
+            .unwrap_or_else(|| vec![DEFAULT_SUPPLEMENT_SOURCE.to_string()])
+    }
+
+    pub fn supplement_retries(&self) -> u32 {
+        self.settings
+            .supplement_retries
+            .unwrap_or(DEFAULT_SUPPLEMENT_RETRIES)
+    }
+
+    /// Whether all supplement sources are merged instead of using the first
+    /// one that succeeds.
+    pub fn supplement_merge(&self) -> bool {
+        self.settings.supplement_merge.unwrap_or(false)
+    }
+
+    pub fn supplement_max_bad_fraction(&self) -> f64 {
+        self.settings
+            .supplement_max_bad_fraction
+            .unwrap_or(DEFAULT_SUPPLEMENT_MAX_BAD_FRACTION)
+    }
+
+    pub fn supplement_connect_timeout(&self) -> Duration {
+        let secs = self
+            .settings
+            .supplement_connect_timeout_secs
+            .unwrap_or(DEFAULT_SUPPLEMENT_CONNECT_TIMEOUT_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Upper bound for a whole supplement download, from connecting until the
+    /// last byte of the body.
+    pub fn supplement_timeout(&self) -> Duration {
+        let secs = self
+            .settings
+            .supplement_timeout_secs
+            .unwrap_or(DEFAULT_SUPPLEMENT_TIMEOUT_SECS);
+        Duration::from_secs(secs)
+    }
+
+    pub fn supplement_max_bytes(&self) -> u64 {
+        self.settings
+            .supplement_max_bytes
+            .unwrap_or(DEFAULT_SUPPLEMENT_MAX_BYTES)
+    }
+
+    /// Where the raw data of the last successful supplement fetch is kept;
+    /// an empty path disables the cache.
+    pub fn supplement_cache_path(&self) -> Option<PathBuf> {
+        self.settings
+            .supplement_cache_path
+            .clone()
+            .map(PathBuf::from)
+            .or_else(get_default_supplement_cache_path)
+            .filter(|path| !path.as_os_str().is_empty())
+    }
+
+    pub fn supplement_cache_max_age(&self) -> Duration {
+        let hours = self
+            .settings
+            .supplement_cache_max_age_hours
+            .unwrap_or(DEFAULT_SUPPLEMENT_CACHE_MAX_AGE_HOURS);
+        Duration::from_secs(hours * 3600)
+    }
+
+    pub fn supplement_max_data_age(&self) -> Duration {
+        let days = self
+            .settings
+            .supplement_max_data_age_days
+            .unwrap_or(DEFAULT_SUPPLEMENT_MAX_DATA_AGE_DAYS);
+        Duration::from_secs(days * 86400)
+    }
+
+    pub fn supplement_stale_data_action(&self) -> StaleDataAction {
+        self.settings
+            .supplement_stale_data_action
+            .unwrap_or_default()
+    }
+
+    /// Whether `serve` looks packages lacking supplement data up through the
+    /// official AUR RPC.
+    pub fn supplement_live_lookup(&self) -> bool {
+        self.settings.supplement_live_lookup.unwrap_or(false)
+    }
+
+    pub fn supplement_live_lookup_ttl(&self) -> Duration {
+        let secs = self
+            .settings
+            .supplement_live_lookup_ttl_secs
+            .unwrap_or(DEFAULT_SUPPLEMENT_LIVE_LOOKUP_TTL_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Interval of the supplement refresh while serving; unset or 0 disables it.
+    pub fn supplement_refresh_interval(&self) -> Option<Duration> {
+        self.settings
+            .supplement_refresh_interval_mins
+            .filter(|&mins| mins > 0)
+            .map(|mins| Duration::from_secs(mins * 60))
+    }
+
+    /// Bearer token for the admin endpoints, which are disabled without one.
+    pub fn admin_token(&self) -> Option<String> {
+        self.settings
+            .admin_token
+            .clone()
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Most distinct package names accepted by a single info request.
+    pub fn rpc_max_info_args(&self) -> usize {
+        self.settings
+            .rpc_max_info_args
+            .unwrap_or(DEFAULT_RPC_MAX_INFO_ARGS)
+    }
+
+    /// Most distinct package names of an info request served by splitting
+    /// it into chunks of `rpc_max_info_args`; 0 (the default) rejects
+    /// requests above that limit like aurweb.
+    pub fn rpc_max_split_info_args(&self) -> usize {
+        self.settings.rpc_max_split_info_args.unwrap_or(0)
+    }
+
+    /// Largest accepted form body of a `POST` RPC request.
+    pub fn rpc_max_body_bytes(&self) -> usize {
+        self.settings
+            .rpc_max_body_bytes
+            .unwrap_or(DEFAULT_RPC_MAX_BODY_BYTES)
+    }
+
+    /// Longest accepted query string of an RPC request.
+    pub fn rpc_max_query_bytes(&self) -> usize {
+        self.settings
+            .rpc_max_query_bytes
+            .unwrap_or(DEFAULT_RPC_MAX_QUERY_BYTES)
+    }
+
+    /// Most `arg`/`arg[]` parameters of an RPC request, repeated ones included.
+    pub fn rpc_max_arg_params(&self) -> usize {
+        self.settings
+            .rpc_max_arg_params
+            .unwrap_or(DEFAULT_RPC_MAX_ARG_PARAMS)
+    }
+
+    /// Whether the `/api/v6` routes are served next to the v5 ones.
+    pub fn rpc_v6(&self) -> bool {
+        self.settings.rpc_v6.unwrap_or(false)
+    }
+
+    /// Whether `/rpc` results carry the `CommitId` and `CommittedAt`
+    /// extensions; off by default to answer exactly like aurweb. The v6
+    /// routes always carry them.
+    pub fn rpc_commit_fields(&self) -> bool {
+        self.settings.rpc_commit_fields.unwrap_or(false)
+    }
+
+    /// Requests per client within the rate limit window; 0 disables the limit.
+    pub fn rate_limit_requests(&self) -> u64 {
+        self.settings
+            .rate_limit_requests
+            .unwrap_or(DEFAULT_RATE_LIMIT_REQUESTS)
+    }
+
+    pub fn rate_limit_window(&self) -> Duration {
+        let secs = self
+            .settings
+            .rate_limit_window_secs
+            .unwrap_or(DEFAULT_RATE_LIMIT_WINDOW_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Client addresses or CIDR ranges exempt from the rate limit.
+    pub fn rate_limit_allowlist(&self) -> Vec<String> {
+        self.settings
+            .rate_limit_allowlist
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// API keys with a request limit of their own, exempting their
+    /// requests from the limit by address.
+    pub fn api_keys(&self) -> Vec<ApiKeyConfig> {
+        self.settings.api_keys.clone().unwrap_or_default()
+    }
+
+    /// Headers of a trusted reverse proxy carrying the client address, e.g.
+    /// `X-Forwarded-For`; without any the connection peer is the client.
+    pub fn trusted_proxy_headers(&self) -> Vec<String> {
+        self.settings
+            .trusted_proxy_headers
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Addresses or CIDR ranges of the reverse proxies whose
+    /// `trusted_proxy_headers` are believed, required along with them.
+    pub fn trusted_proxies(&self) -> Vec<String> {
+        self.settings.trusted_proxies.clone().unwrap_or_default()
+    }
+
+    /// Client addresses or CIDR ranges refused with 403.
+    pub fn ip_denylist(&self) -> Vec<String> {
+        self.settings.ip_denylist.clone().unwrap_or_default()
+    }
+
+    /// Client addresses or CIDR ranges allowed; when any are set, all other
+    /// clients are refused with 403.
+    pub fn ip_allowlist(&self) -> Vec<String> {
+        self.settings.ip_allowlist.clone().unwrap_or_default()
+    }
+
+    /// Origins allowed to call the server from a browser; `*` allows any and
+    /// an empty list disables CORS.
+    pub fn cors_allowed_origins(&self) -> Vec<String> {
+        self.settings
+            .cors_allowed_origins
+            .clone()
+            .unwrap_or_else(|| vec!["*".to_string()])
+    }
+
+    pub fn cors_max_age(&self) -> Duration {
+        let secs = self
+            .settings
+            .cors_max_age_secs
+            .unwrap_or(DEFAULT_CORS_MAX_AGE_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Where git clones of a pkgbase are served from: `github` (the AUR
+    /// monorepo mirror, default) or the base URL of a per-package git host.
+    pub fn git_upstream(&self) -> GitUpstream {
+        self.settings
+            .git_upstream
+            .as_deref()
+            .map(GitUpstream::parse)
+            .unwrap_or_default()
+    }
+
+    /// Where generated snapshot tarballs are kept; `None` disables caching.
+    pub fn snapshot_cache_dir(&self) -> Option<PathBuf> {
+        non_empty_path(&self.settings.snapshot_cache_dir)
+    }
+
+    pub fn snapshot_cache_max_bytes(&self) -> u64 {
+        self.settings
+            .snapshot_cache_max_bytes
+            .unwrap_or(DEFAULT_SNAPSHOT_CACHE_MAX_BYTES)
+    }
+
+    /// Addresses or CIDR ranges allowed to scrape `/metrics`; empty allows
+    /// any client.
+    pub fn metrics_allowlist(&self) -> Vec<String> {
+        self.settings.metrics_allowlist.clone().unwrap_or_default()
+    }
+
+    /// Longest time since the last successful sync for `/readyz` to report
+    /// ready; `None` only requires any successful sync.
+    pub fn ready_max_sync_age(&self) -> Option<Duration> {
+        let secs = self
+            .settings
+            .ready_max_sync_age_secs
+            .unwrap_or(DEFAULT_READY_MAX_SYNC_AGE_SECS);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Age of the last successful sync or of the supplement data above
+    /// which API responses carry a `Warning: 110`; `None` never warns.
+    pub fn stale_after(&self) -> Option<Duration> {
+        let secs = self.settings.stale_after_secs.unwrap_or(0);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Whether the HTML search pages are served.
+    pub fn web_ui(&self) -> bool {
+        self.settings.web_ui.unwrap_or(true)
+    }
+
+    /// Path the HTML search pages are mounted at, with a leading and
+    /// without a trailing slash, or `/` for the root.
+    pub fn web_ui_prefix(&self) -> String {
+        let prefix = self
+            .settings
+            .web_ui_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_WEB_UI_PREFIX);
+        format!("/{}", prefix.trim().trim_matches('/'))
+    }
+
+    /// Whether Swagger UI for `/openapi.json` is served under `/docs`.
+    pub fn openapi_swagger_ui(&self) -> bool {
+        self.settings.openapi_swagger_ui.unwrap_or(false)
+    }
+
+    /// Most search and info results kept in memory; 0 disables the cache.
+    pub fn query_cache_capacity(&self) -> u64 {
+        self.settings.query_cache_capacity.unwrap_or(0)
+    }
+
+    pub fn query_cache_ttl(&self) -> Duration {
+        let secs = self
+            .settings
+            .query_cache_ttl_secs
+            .unwrap_or(DEFAULT_QUERY_CACHE_TTL_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Addresses `serve` listens on unless given on the command line.
+    pub fn listen(&self) -> Vec<String> {
+        self.settings
+            .listen
+            .clone()
+            .filter(|addrs| !addrs.is_empty())
+            .unwrap_or_else(|| vec![DEFAULT_LISTEN.to_string()])
+    }
+
+    /// Octal permissions of Unix sockets listened on.
+    pub fn unix_socket_mode(&self) -> Option<String> {
+        self.settings.unix_socket_mode.clone()
+    }
+
+    /// Group name or id Unix sockets listened on are handed to.
+    pub fn unix_socket_group(&self) -> Option<String> {
+        self.settings.unix_socket_group.clone()
+    }
+
+    /// Certificate and key for `https://` listeners, if both are configured.
+    pub fn tls_options(&self) -> Option<TlsOptions> {
+        Some(TlsOptions {
+            cert_path: non_empty_path(&self.settings.tls_cert_path)?,
+            key_path: non_empty_path(&self.settings.tls_key_path)?,
+        })
+    }
+
+    /// Whether plain HTTP listeners redirect to the HTTPS one instead of
+    /// serving.
+    pub fn redirect_http_to_https(&self) -> bool {
+        self.settings.redirect_http_to_https.unwrap_or(false)
+    }
+
+    /// How long in-flight requests may take to finish on shutdown.
+    pub fn shutdown_drain_timeout(&self) -> Duration {
+        let secs = self
+            .settings
+            .shutdown_drain_timeout_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Whether every request is logged; on by default when a file is given.
+    pub fn access_log(&self) -> bool {
+        self.settings
+            .access_log
+            .unwrap_or_else(|| self.access_log_path().is_some())
+    }
+
+    /// File the access log is appended to instead of the regular log.
+    pub fn access_log_path(&self) -> Option<PathBuf> {
+        non_empty_path(&self.settings.access_log_path)
+    }
+
+    /// Requests handled at once over all routes; 0 disables the limit.
+    pub fn concurrency_limit(&self) -> usize {
+        self.settings.concurrency_limit.unwrap_or(0)
+    }
+
+    /// Requests handled at once per route pattern, e.g. `"/rpc" = 64`.
+    pub fn concurrency_limits(&self) -> HashMap<String, usize> {
+        self.settings.concurrency_limits.clone().unwrap_or_default()
+    }
+
+    /// Requests waiting for a slot per limit before new ones are rejected.
+    pub fn concurrency_queue(&self) -> usize {
+        self.settings
+            .concurrency_queue
+            .unwrap_or(DEFAULT_CONCURRENCY_QUEUE)
+    }
+
+    /// Time a request may take until its response starts; `None` when 0.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        let secs = self.settings.request_timeout_secs.unwrap_or(0);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Timeouts in seconds per route pattern, e.g. `"/rpc" = 10`; 0 exempts
+    /// a route from the default.
+    pub fn request_timeouts(&self) -> HashMap<String, Duration> {
+        self.settings
+            .request_timeouts
+            .iter()
+            .flatten()
+            .map(|(route, &secs)| (route.clone(), Duration::from_secs(secs)))
+            .collect()
+    }
+
+    /// Where requests the mirror doesn't serve are forwarded to.
+    pub fn proxy_upstream(&self) -> String {
+        self.settings
+            .proxy_upstream
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PROXY_UPSTREAM.to_string())
+    }
+
+    /// Path prefixes of unmatched requests forwarded upstream, e.g.
+    /// `/account`; none (the default) disables the proxy.
+    pub fn proxy_path_prefixes(&self) -> Vec<String> {
+        self.settings
+            .proxy_path_prefixes
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Limit for a proxied exchange, including the response body.
+    pub fn proxy_timeout(&self) -> Duration {
+        let secs = self
+            .settings
+            .proxy_timeout_secs
+            .unwrap_or(DEFAULT_PROXY_TIMEOUT_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Whether proxied requests count towards the request limit.
+    pub fn proxy_rate_limit(&self) -> bool {
+        self.settings.proxy_rate_limit.unwrap_or(false)
+    }
+
+    /// Interval of the syncs run while serving; unset or 0 disables them.
+    pub fn sync_interval(&self) -> Option<Duration> {
+        self.settings
+            .sync_interval_mins
+            .filter(|&mins| mins > 0)
+            .map(|mins| Duration::from_secs(mins * 60))
+    }
+
+    /// Upper bound of the random delay added to each scheduled sync;
+    /// defaults to a tenth of the interval.
+    pub fn sync_jitter(&self) -> Duration {
+        self.settings
+            .sync_jitter_secs
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| self.sync_interval().unwrap_or_default() / 10)
+    }
+
+    /// Least time between the end of a scheduled sync that overran the
+    /// interval and the start of the next one.
+    pub fn sync_min_spacing(&self) -> Duration {
+        let secs = self
+            .settings
+            .sync_min_spacing_secs
+            .unwrap_or(DEFAULT_SYNC_MIN_SPACING_SECS);
+        Duration::from_secs(secs)
+    }
+
+    /// Whether the first scheduled sync runs right after startup instead of
+    /// one interval later.
+    pub fn sync_on_start(&self) -> bool {
+        self.settings.sync_on_start.unwrap_or(true)
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.settings.log_format.unwrap_or_default()
+    }
+
+    /// `EnvFilter` directives, `info` by default.
+    pub fn log_level(&self) -> String {
+        self.settings
+            .log_level
+            .clone()
+            .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string())
+    }
+
+    pub fn log_file(&self) -> Option<PathBuf> {
+        non_empty_path(&self.settings.log_file)
+    }
+
+    /// `EnvFilter` directives of the log file, `None` to use `log_level`.
+    pub fn log_file_level(&self) -> Option<String> {
+        self.settings.log_file_level.clone()
+    }
+
+    pub fn log_file_rotation(&self) -> LogRotation {
+        self.settings.log_file_rotation.unwrap_or_default()
+    }
+
+    /// Size the log file is rotated at, 0 for no limit.
+    pub fn log_file_max_bytes(&self) -> u64 {
+        self.settings.log_file_max_bytes.unwrap_or(0)
+    }
+
+    /// Rotated log files kept.
+    pub fn log_file_keep(&self) -> usize {
+        self.settings.log_file_keep.unwrap_or(DEFAULT_LOG_FILE_KEEP)
+    }
+
+    /// Chunks of commits a sync fetches and decodes at once.
+    pub fn sync_fetch_concurrency(&self) -> usize {
+        self.settings
+            .sync_fetch_concurrency
+            .unwrap_or(DEFAULT_SYNC_FETCH_CONCURRENCY)
+            .max(1)
+    }
+
+    /// Threads decoding each fetched pack, one per CPU by default.
+    pub fn sync_decode_workers(&self) -> usize {
+        self.settings
+            .sync_decode_workers
+            .filter(|&workers| workers > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// Branches a sync indexes per transaction.
+    pub fn sync_write_batch_size(&self) -> usize {
+        self.settings
+            .sync_write_batch_size
+            .unwrap_or(DEFAULT_SYNC_WRITE_BATCH_SIZE)
+            .max(1)
+    }
+
+    /// Globs or `re:` regexes of the branches to sync; all when empty.
+    pub fn branch_include(&self) -> Vec<String> {
+        self.settings.branch_include.clone().unwrap_or_default()
+    }
+
+    /// Globs or `re:` regexes of the branches never to sync, even if
+    /// included.
+    pub fn branch_exclude(&self) -> Vec<String> {
+        self.settings.branch_exclude.clone().unwrap_or_default()
+    }
+
+    /// Most concurrent `/api/events` streams.
+    pub fn sse_max_connections(&self) -> usize {
+        self.settings
+            .sse_max_connections
+            .unwrap_or(DEFAULT_SSE_MAX_CONNECTIONS)
+    }
+
+    pub fn webhooks(&self) -> Vec<WebhookConfig> {
+        self.settings.webhooks.clone().unwrap_or_default()
+    }
+
+    pub fn history_branches(&self) -> Vec<String> {
+        self.settings.history_branches.clone().unwrap_or_default()
+    }
+
+    pub fn history_window(&self) -> Duration {
+        let days = self.settings.history_days.unwrap_or(DEFAULT_HISTORY_DAYS);
+        Duration::from_secs(days * 86400)
+    }
+
+    /// Whether the raw `.SRCINFO` of every indexed branch is kept.
+    pub fn store_srcinfo(&self) -> bool {
+        self.settings.store_srcinfo.unwrap_or(false)
+    }
+
+    /// The `URLPath` reported for packages, with `{pkgbase}` and `{pkgname}`
+    /// placeholders; defaults to this server's snapshot route.
+    pub fn url_path_template(&self) -> UrlPathTemplate {
+        self.settings
+            .url_path_template
+            .as_deref()
+            .map(UrlPathTemplate::parse)
+            .unwrap_or_default()
+    }
+
+    /// Every setting as resolved from the command line, the environment,
+    /// the config file and the defaults, in the config file's format; for
+    /// `--check-config`. Secrets are redacted, unset options left out.
+    pub fn effective(
+        &self,
+        listen: Vec<String>,
+        supplement_sources: Vec<String>,
+    ) -> ConfigFileModel {
+        let secs = |duration: Duration| duration.as_secs();
+        let path = |path: PathBuf| path.to_string_lossy().into_owned();
+        let redact = |secret: Option<String>| secret.map(|_| REDACTED.to_string());
+        let tls_options = self.tls_options();
+        ConfigFileModel {
+            db_path: self.db_path(),
+            db_read_only: Some(self.db_read_only()),
+            github_token: redact(self.github_token()),
+            github_token_file: None,
+            user_agent: Some(self.user_agent()),
+            user_agent_suffix: None,
+            srcinfo_cache_dir: self.srcinfo_cache_dir().map(path),
+            srcinfo_cache_max_bytes: Some(self.srcinfo_cache_max_bytes()),
+            srcinfo_cache_max_age_days: Some(secs(self.srcinfo_cache_max_age()) / 86400),
+            history_branches: Some(self.history_branches()),
+            history_days: Some(secs(self.history_window()) / 86400),
+            store_srcinfo: Some(self.store_srcinfo()),
+            url_path_template: Some(self.url_path_template().as_str().to_string()),
+            decode_cache_bytes: Some(self.decode_cache_bytes()),
+            download_rate_limit: Some(self.download_rate_limit()),
+            supplement_sources: Some(supplement_sources),
+            supplement_retries: Some(self.supplement_retries()),
+            supplement_merge: Some(self.supplement_merge()),
+            supplement_max_bad_fraction: Some(self.supplement_max_bad_fraction()),
+            supplement_connect_timeout_secs: Some(secs(self.supplement_connect_timeout())),
+            supplement_timeout_secs: Some(secs(self.supplement_timeout())),
+            supplement_max_bytes: Some(self.supplement_max_bytes()),
+            supplement_cache_path: Some(self.supplement_cache_path().map(path).unwrap_or_default()),
+            supplement_cache_max_age_hours: Some(secs(self.supplement_cache_max_age()) / 3600),
+            supplement_max_data_age_days: Some(secs(self.supplement_max_data_age()) / 86400),
+            supplement_stale_data_action: Some(self.supplement_stale_data_action()),
+            supplement_live_lookup: Some(self.supplement_live_lookup()),
+            supplement_live_lookup_ttl_secs: Some(secs(self.supplement_live_lookup_ttl())),
+            supplement_refresh_interval_mins: Some(
+                self.supplement_refresh_interval()
+                    .map_or(0, |interval| secs(interval) / 60),
+            ),
+            admin_token: redact(self.admin_token()),
+            rpc_max_info_args: Some(self.rpc_max_info_args()),
+            rpc_max_split_info_args: Some(self.rpc_max_split_info_args()),
+            rpc_v6: Some(self.rpc_v6()),
+            rpc_commit_fields: Some(self.rpc_commit_fields()),
+            rpc_max_body_bytes: Some(self.rpc_max_body_bytes()),
+            rpc_max_query_bytes: Some(self.rpc_max_query_bytes()),
+            rpc_max_arg_params: Some(self.rpc_max_arg_params()),
+            rate_limit_requests: Some(self.rate_limit_requests()),
+            rate_limit_window_secs: Some(secs(self.rate_limit_window())),
+            rate_limit_allowlist: Some(self.rate_limit_allowlist()),
+            api_keys: Some(self.api_keys()),
+            trusted_proxy_headers: Some(self.trusted_proxy_headers()),
+            trusted_proxies: Some(self.trusted_proxies()),
+            ip_denylist: Some(self.ip_denylist()),
+            ip_allowlist: Some(self.ip_allowlist()),
+            cors_allowed_origins: Some(self.cors_allowed_origins()),
+            cors_max_age_secs: Some(secs(self.cors_max_age())),
+            git_upstream: Some(match self.git_upstream() {
+                GitUpstream::GitHub => "github".to_string(),
+                GitUpstream::PerPackage(base_url) => base_url,
+            }),
+            snapshot_cache_dir: self.snapshot_cache_dir().map(path),
+            snapshot_cache_max_bytes: Some(self.snapshot_cache_max_bytes()),
+            metrics_allowlist: Some(self.metrics_allowlist()),
+            ready_max_sync_age_secs: Some(self.ready_max_sync_age().map_or(0, secs)),
+            stale_after_secs: Some(self.stale_after().map_or(0, secs)),
+            webhooks: Some(
+                self.webhooks()
+                    .into_iter()
+                    .map(|webhook| WebhookConfig {
+                        secret: redact(webhook.secret),
+                        ..webhook
+                    })
+                    .collect(),
+            ),
+            sse_max_connections: Some(self.sse_max_connections()),
+            web_ui: Some(self.web_ui()),
+            web_ui_prefix: Some(self.web_ui_prefix()),
+            openapi_swagger_ui: Some(self.openapi_swagger_ui()),
+            query_cache_capacity: Some(self.query_cache_capacity()),
+            query_cache_ttl_secs: Some(secs(self.query_cache_ttl())),
+            listen: Some(listen),
+            unix_socket_mode: self.unix_socket_mode(),
+            unix_socket_group: self.unix_socket_group(),
+            tls_cert_path: tls_options
+                .as_ref()
+                .map(|options| path(options.cert_path.clone())),
+            tls_key_path: tls_options.map(|options| path(options.key_path)),
+            redirect_http_to_https: Some(self.redirect_http_to_https()),
+            shutdown_drain_timeout_secs: Some(secs(self.shutdown_drain_timeout())),
+            access_log: Some(self.access_log()),
+            access_log_path: self.access_log_path().map(path),
+            concurrency_limit: Some(self.concurrency_limit()),
+            concurrency_limits: Some(self.concurrency_limits()),
+            concurrency_queue: Some(self.concurrency_queue()),
+            request_timeout_secs: Some(self.request_timeout().map_or(0, secs)),
+            request_timeouts: Some(
+                self.request_timeouts()
+                    .into_iter()
+                    .map(|(route, timeout)| (route, secs(timeout)))
+                    .collect(),
+            ),
+            proxy_upstream: Some(self.proxy_upstream()),
+            proxy_path_prefixes: Some(self.proxy_path_prefixes()),
+            proxy_timeout_secs: Some(secs(self.proxy_timeout())),
+            proxy_rate_limit: Some(self.proxy_rate_limit()),
+            sync_interval_mins: Some(
+                self.sync_interval()
+                    .map_or(0, |interval| secs(interval) / 60),
+            ),
+            sync_jitter_secs: Some(secs(self.sync_jitter())),
+            sync_min_spacing_secs: Some(secs(self.sync_min_spacing())),
+            sync_on_start: Some(self.sync_on_start()),
+            branch_include: Some(self.branch_include()),
+            branch_exclude: Some(self.branch_exclude()),
+            sync_fetch_concurrency: Some(self.sync_fetch_concurrency()),
+            sync_decode_workers: Some(self.sync_decode_workers()),
+            sync_write_batch_size: Some(self.sync_write_batch_size()),
+            log_format: Some(self.log_format()),
+            log_level: Some(self.log_level()),
+            log_file: self
+                .log_file()
+                .map(|path| path.to_string_lossy().into_owned()),
+            log_file_level: Some(self.log_file_level().unwrap_or_else(|| self.log_level())),
+            log_file_rotation: Some(self.log_file_rotation()),
+            log_file_max_bytes: Some(self.log_file_max_bytes()),
+            log_file_keep: Some(self.log_file_keep()),
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+/// The config file, `None` if there is none.
+fn load_file(path: Option<&Path>) -> Result<Option<ConfigFileModel>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+    };
+    let model = toml::from_str::<ConfigFileModel>(&content)
+        .map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))?;
+    Ok(Some(model))
+}
+
+/// `file`, the table of the config file, with the keys of `env`, the
+/// `env_layer()`, set over it. A key set
+/// in the environment also hides its `_file` variant in the config file and
+/// the other way round, e.g. `AMM_GITHUB_TOKEN_FILE` wins over a
+/// `github_token` in the file.
+fn layer_env(mut file: toml::Table, env: toml::Table) -> Result<ConfigFileModel> {
+    for key in env.keys() {
+        let sibling = match key.strip_suffix("_file") {
+            Some(key) => key.to_string(),
+            None => format!("{}_file", key),
+        };
+        if is_config_key(&sibling) {
+            file.remove(&sibling);
+        }
+    }
+    file.extend(env);
+    Ok(toml::Value::Table(file).try_into()?)
+}
+
+/// Config keys set in the environment `vars`, as TOML values of their
+/// type. A value is read as a TOML value, e.g. `10`, `true` or
+/// `{ "/rpc" = 64 }`, falling back to `1`/`0` for booleans, a plain string
+/// and a comma-separated list, whichever the key takes first. Variables not
+/// named after a key are left alone.
+fn env_layer(vars: impl IntoIterator<Item = (String, String)>) -> Result<toml::Table> {
+    let mut direct = toml::Table::new();
+    let mut from_files = toml::Table::new();
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_ascii_lowercase();
+        if is_config_key(&key) {
+            direct.insert(key.clone(), env_value(&name, &key, &value)?);
+            continue;
+        }
+        let Some(key) = key.strip_suffix("_file").filter(|key| is_config_key(key)) else {
+            continue;
+        };
+        let content = std::fs::read_to_string(&value)
+            .map_err(|e| anyhow!("Failed to read {} ({}): {}", value, name, e))?;
+        from_files.insert(key.to_string(), env_value(&name, key, content.trim())?);
+    }
+    // A value given directly wins over one in a file
+    from_files.extend(direct);
+    Ok(from_files)
+}
+
+/// Whether `key` is a config key, i.e. not collected as unknown.
+fn is_config_key(key: &str) -> bool {
+    let probe = toml::Table::from_iter([(key.to_string(), toml::Value::Table(Default::default()))]);
+    toml::Value::Table(probe)
+        .try_into::<ConfigFileModel>()
+        .map_or(true, |model| model.unknown.is_empty())
+}
+
+fn env_value(name: &str, key: &str, value: &str) -> Result<toml::Value> {
+    let mut candidates = Vec::new();
+    if let Ok(mut parsed) = toml::from_str::<toml::Table>(&format!("value = {}", value)) {
+        candidates.extend(parsed.remove("value"));
+    }
+    match value {
+        "1" => candidates.push(toml::Value::Boolean(true)),
+        "0" => candidates.push(toml::Value::Boolean(false)),
+        _ => {}
+    }
+    candidates.push(toml::Value::String(value.to_string()));
+    candidates.push(toml::Value::Array(
+        split_list(value)
+            .into_iter()
+            .map(toml::Value::String)
+            .collect(),
+    ));
+    // The first error is the most telling, e.g. a TOML table of the wrong shape
+    let mut error = None;
+    for candidate in candidates {
+        let table = toml::Table::from_iter([(key.to_string(), candidate.clone())]);
+        match toml::Value::Table(table).try_into::<ConfigFileModel>() {
+            Ok(_) => return Ok(candidate),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    Err(anyhow!(
+        "Invalid {}: {}",
+        name,
+        error.map(|e| e.to_string()).unwrap_or_default().trim()
+    ))
+}
+
+/// The first line of a token file, `None` (with a warning) if unreadable.
+fn read_token_file(path: &str) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .next()
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty()),
+        Err(e) => {
+            warn!("Failed to read GitHub token file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// A configured path, `None` if unset or empty.
+fn non_empty_path(path: &Option<String>) -> Option<PathBuf> {
+    path.as_deref()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Split a comma-separated environment value, dropping empty items.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
 }
 
 fn get_default_config_path() -> Option<PathBuf> {
@@ -85,3 +1206,85 @@ fn get_default_db_path() -> Option<PathBuf> {
         path
     })
 }
+
+fn get_default_supplement_cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|mut path| {
+        path.push("aur-mirror-meta");
+        path.push("supplement-meta");
+        path
+    })
+}
+
+fn get_default_user_agent() -> String {
+    format!("AUR-Mirror-Meta/{}", env!("CARGO_PKG_VERSION"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn environment_is_read_like_the_config_file() {
+        let env = env_layer(vars(&[
+            ("AMM_RPC_V6", "1"),
+            ("AMM_WEB_UI", "false"),
+            ("AMM_RATE_LIMIT_REQUESTS", "20"),
+            ("AMM_TRUSTED_PROXIES", "10.0.0.0/8, ::1"),
+            ("AMM_LISTEN", r#"["127.0.0.1:8080"]"#),
+            ("AMM_CONCURRENCY_LIMITS", r#"{ "/rpc" = 64 }"#),
+            ("AMM_LOG_FORMAT", "json"),
+            ("AMM_GITHUB_TOKEN_FILE", "/run/secrets/token"),
+            ("AMM_NOT_A_KEY", "ignored"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        let file = toml::from_str(
+            r#"
+            github_token = "from-file"
+            rate_limit_requests = 10
+            rate_limit_window_secs = 30
+            "#,
+        )
+        .unwrap();
+        let settings = layer_env(file, env).unwrap();
+
+        assert_eq!(settings.rpc_v6, Some(true));
+        assert_eq!(settings.web_ui, Some(false));
+        assert_eq!(settings.rate_limit_requests, Some(20));
+        assert_eq!(settings.rate_limit_window_secs, Some(30));
+        assert_eq!(
+            settings.trusted_proxies,
+            Some(vec!["10.0.0.0/8".to_string(), "::1".to_string()])
+        );
+        assert_eq!(settings.listen, Some(vec!["127.0.0.1:8080".to_string()]));
+        assert_eq!(
+            settings.concurrency_limits,
+            Some(HashMap::from([("/rpc".to_string(), 64)]))
+        );
+        assert_eq!(settings.log_format, Some(LogFormat::Json));
+        assert_eq!(settings.github_token, None);
+        assert_eq!(
+            settings.github_token_file.as_deref(),
+            Some("/run/secrets/token")
+        );
+        assert!(settings.unknown.is_empty());
+    }
+
+    #[test]
+    fn invalid_environment_values_are_errors() {
+        for (name, value) in [
+            ("AMM_RATE_LIMIT_REQUESTS", "ten"),
+            ("AMM_RPC_V6", "yes"),
+            ("AMM_CONCURRENCY_LIMITS", r#"{ "/rpc" = "many" }"#),
+        ] {
+            let error = env_layer(vars(&[(name, value)])).unwrap_err();
+            assert!(error.to_string().contains(name), "{}", error);
+        }
+    }
+}
@@ -1,15 +1,43 @@
+use crate::request_timeout::{arm_connection, disarm_connection};
 use crate::types::{
-    DatabasePackageDetails, DatabasePackageDetailsWithSupplement,
-    DatabasePackageInfoWithSupplement, DatabaseSupplementData, SearchType,
+    DatabaseBranchSummary, DatabaseDataVersion, DatabaseHistoryDetails, DatabaseHistoryVersion,
+    DatabasePackageChange, DatabasePackageDetails, DatabasePackageDetailsWithSupplement,
+    DatabasePackageInfoWithSupplement, DatabaseRequestCounter, DatabaseSrcInfo, DatabaseStats,
+    DatabaseSupplementData, DatabaseSyncLock, DatabaseSyncRun, SearchPage, SearchType, SortBy,
+    SortField, SupplementSourceValidators, SyncRunStatus,
+};
+use anyhow::{anyhow, Result};
+use futures::channel::oneshot;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use futures::SinkExt;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    Row, SqliteConnection, SqlitePool,
 };
-use anyhow::Result;
-use futures::stream::TryStreamExt;
-use sqlx::{sqlite::SqliteConnectOptions, Row, SqlitePool};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::info;
 
 const CURRENT_DB_VERSION: i32 = 2;
+/// Matches of a streamed search read ahead of the response
+const SEARCH_STREAM_BUFFER: usize = 64;
+
+/// Join supplement data by package name, falling back to a package of the
+/// same base. Newly split packages only show up in the supplement data once
+/// aurweb regenerates it, but their siblings already carry the base's votes,
+/// popularity and maintainers.
+const SUPPLEMENT_JOIN: &str = r#"
+    LEFT JOIN pkg_supplement s ON s.pkgname = COALESCE(
+        (SELECT pkgname FROM pkg_supplement WHERE pkgname = p.pkg_name),
+        (SELECT pkgname FROM pkg_supplement WHERE package_base = p.branch
+         ORDER BY pkgname LIMIT 1)
+    )
+"#;
+const SUPPLEMENT_FROM_BASE_COLUMN: &str =
+    "(s.pkgname IS NOT NULL AND s.pkgname != p.pkg_name) AS supplement_from_base";
+
+/// Search matches read while they are consumed.
+pub type PackageInfoStream = BoxStream<'static, Result<DatabasePackageInfoWithSupplement>>;
 
 #[derive(Clone)]
 pub struct DatabaseOps {
@@ -17,21 +45,65 @@ pub struct DatabaseOps {
 }
 
 impl DatabaseOps {
-    pub async fn new(db_path: &str) -> Result<Self> {
-        let pool = SqlitePool::connect_with(
-            SqliteConnectOptions::new()
-                .filename(db_path)
+    /// With `interruptible`, queries of requests running under a deadline
+    /// are interrupted once it passes, see [`crate::request_timeout`].
+    /// With `read_only`, the file must exist at the current schema version;
+    /// nothing is created or migrated, and every write fails.
+    pub async fn new(db_path: &str, interruptible: bool, read_only: bool) -> Result<Self> {
+        let mut pool_options = SqlitePoolOptions::new();
+        if interruptible {
+            pool_options = pool_options
+                .after_connect(|conn, _| Box::pin(arm_connection(conn)))
+                .before_acquire(|conn, _| {
+                    Box::pin(async move { arm_connection(conn).await.map(|()| true) })
+                })
+                .after_release(|conn, _| Box::pin(disarm_connection(conn)));
+        }
+        let connect_options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .busy_timeout(Duration::from_secs(30));
+        // Switching to WAL is a write, left to the writable opens
+        let connect_options = if read_only {
+            connect_options.read_only(true)
+        } else {
+            connect_options
                 .create_if_missing(true)
                 .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-                .busy_timeout(Duration::from_secs(30)),
-        )
-        .await?;
+        };
+        let pool = pool_options.connect_with(connect_options).await?;
         let result = Self { pool };
+        if read_only {
+            result.check_version().await?;
+            return Ok(result);
+        }
         result.check_and_migrate().await?;
         result.init_index_tables().await?;
         Ok(result)
     }
 
+    /// Close the pool once every connection is returned, checkpointing the
+    /// WAL so the next start has nothing to recover.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    /// Fail unless the schema is the current one, for opens that can't
+    /// migrate it.
+    async fn check_version(&self) -> Result<()> {
+        let version: i32 = sqlx::query("PRAGMA user_version")
+            .fetch_one(&self.pool)
+            .await?
+            .get(0);
+        if version != CURRENT_DB_VERSION {
+            return Err(anyhow!(
+                "Database version {} is not the current version {}; open it writable once to migrate it",
+                version,
+                CURRENT_DB_VERSION
+            ));
+        }
+        Ok(())
+    }
+
     async fn check_and_migrate(&self) -> Result<()> {
         let version: i32 = sqlx::query("PRAGMA user_version")
             .fetch_one(&self.pool)
@@ -76,7 +148,16 @@ impl DatabaseOps {
                     "pkg_conflicts",
                     "pkg_replaces",
                     "pkg_groups",
+                    "pkg_licenses",
                     "pkg_supplement",
+                    "pkg_history",
+                    "history_tips",
+                    "pkg_srcinfo",
+                    "supplement_source",
+                    "request_counters",
+                    "data_version",
+                    "sync_runs",
+                    "pkg_changes",
                 ];
                 for table in tables {
                     sqlx::query(&format!("DROP TABLE IF EXISTS {}", table))
@@ -158,6 +239,12 @@ impl DatabaseOps {
                 group_name TEXT NOT NULL,
                 PRIMARY KEY (branch, pkg_name, group_name)
             )"#,
+            r#"CREATE TABLE IF NOT EXISTS pkg_licenses (
+                branch TEXT NOT NULL,
+                pkg_name TEXT NOT NULL,
+                license TEXT NOT NULL,
+                PRIMARY KEY (branch, pkg_name, license)
+            )"#,
             r#"CREATE TABLE IF NOT EXISTS pkg_supplement (
                 pkgname TEXT NOT NULL PRIMARY KEY,
                 version TEXT NOT NULL,
@@ -169,7 +256,77 @@ impl DatabaseOps {
                 co_maintainers TEXT,
                 keywords TEXT,
                 first_submitted INTEGER,
-                last_modified INTEGER
+                last_modified INTEGER,
+                licenses TEXT,
+                package_base TEXT,
+                live_fetched_at INTEGER
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS pkg_history (
+                branch TEXT NOT NULL,
+                commit_id TEXT NOT NULL,
+                committed_at INTEGER NOT NULL,
+                pkg_name TEXT NOT NULL,
+                version TEXT NOT NULL,
+                PRIMARY KEY (branch, commit_id, pkg_name)
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS history_tips (
+                branch TEXT NOT NULL PRIMARY KEY,
+                commit_id TEXT NOT NULL,
+                since INTEGER NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS pkg_srcinfo (
+                branch TEXT NOT NULL PRIMARY KEY,
+                commit_id TEXT NOT NULL,
+                blob_id TEXT NOT NULL,
+                srcinfo TEXT NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS supplement_source (
+                source TEXT NOT NULL PRIMARY KEY,
+                etag TEXT,
+                last_modified TEXT
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS data_version (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 0),
+                generation INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS request_counters (
+                ip TEXT NOT NULL PRIMARY KEY,
+                window_start INTEGER NOT NULL,
+                requests INTEGER NOT NULL,
+                previous_requests INTEGER NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS pkg_changes (
+                id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                sync_run_id INTEGER NOT NULL,
+                branch TEXT NOT NULL,
+                pkg_name TEXT NOT NULL,
+                old_version TEXT,
+                new_version TEXT NOT NULL,
+                commit_id TEXT NOT NULL,
+                committed_at INTEGER NOT NULL,
+                recorded_at INTEGER NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS sync_runs (
+                id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                started_at INTEGER NOT NULL,
+                finished_at INTEGER,
+                status TEXT NOT NULL,
+                error TEXT
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS sync_progress (
+                run_id INTEGER NOT NULL,
+                branch TEXT NOT NULL,
+                done INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (run_id, branch)
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS sync_lock (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                token TEXT NOT NULL,
+                pid INTEGER NOT NULL,
+                hostname TEXT NOT NULL,
+                acquired_at INTEGER NOT NULL,
+                heartbeat_at INTEGER NOT NULL
             )"#,
         ];
 
@@ -177,11 +334,36 @@ impl DatabaseOps {
             sqlx::query(table_sql).execute(&self.pool).await?;
         }
 
+        // Columns added without a version bump, so existing data survives
+        self.add_column_if_missing("pkg_supplement", "licenses", "TEXT")
+            .await?;
+        self.add_column_if_missing("pkg_supplement", "package_base", "TEXT")
+            .await?;
+        self.add_column_if_missing("pkg_supplement", "live_fetched_at", "INTEGER")
+            .await?;
+        self.add_column_if_missing("branch_commits", "indexed_at", "INTEGER")
+            .await?;
+        self.add_column_if_missing("sync_runs", "resumed_from", "INTEGER")
+            .await?;
+        if self
+            .add_column_if_missing("pkg_history", "details", "TEXT")
+            .await?
+        {
+            // Older rows lack the details, so the next sync fetches them again
+            sqlx::query("DELETE FROM history_tips")
+                .execute(&self.pool)
+                .await?;
+        }
+
         let indexes = vec![
             // Query based on pkg name
             "CREATE INDEX IF NOT EXISTS idx_pkg_info_name ON pkg_info(pkg_name)",
+            // Case-insensitive prefix LIKE on pkg name
+            "CREATE INDEX IF NOT EXISTS idx_pkg_info_name_nocase ON pkg_info(pkg_name COLLATE NOCASE)",
             // Query based on branch
             "CREATE INDEX IF NOT EXISTS idx_pkg_info_branch ON pkg_info(branch)",
+            // Case-insensitive prefix LIKE on branch
+            "CREATE INDEX IF NOT EXISTS idx_pkg_info_branch_nocase ON pkg_info(branch COLLATE NOCASE)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_depends_branch ON pkg_depends(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_make_depends_branch ON pkg_make_depends(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_opt_depends_branch ON pkg_opt_depends(branch)",
@@ -190,6 +372,10 @@ impl DatabaseOps {
             "CREATE INDEX IF NOT EXISTS idx_pkg_conflicts_branch ON pkg_conflicts(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_replaces_branch ON pkg_replaces(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_groups_branch ON pkg_groups(branch)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_licenses_branch ON pkg_licenses(branch)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_history_branch ON pkg_history(branch, committed_at)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_supplement_package_base ON pkg_supplement(package_base)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_changes_sync_run_id ON pkg_changes(sync_run_id)",
             // For reverse lookups
             "CREATE INDEX IF NOT EXISTS idx_pkg_depends_depend ON pkg_depends(depend)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_make_depends_make_depend ON pkg_make_depends(make_depend)",
@@ -204,6 +390,27 @@ impl DatabaseOps {
         Ok(())
     }
 
+    /// Whether the column was added.
+    async fn add_column_if_missing(&self, table: &str, column: &str, decl: &str) -> Result<bool> {
+        let exists = sqlx::query("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(&self.pool)
+            .await?
+            .get::<i32, _>(0)
+            != 0;
+        if !exists {
+            info!("Adding column {} to table {}", column, table);
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, decl
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(!exists)
+    }
+
     pub async fn get_existing_commits(&self) -> Result<HashMap<String, String>> {
         let mut rows =
             sqlx::query("SELECT branch, commit_id FROM branch_commits").fetch(&self.pool);
@@ -216,10 +423,485 @@ impl DatabaseOps {
         Ok(commits)
     }
 
+    /// Number of indexed packages per branch.
+    pub async fn get_package_counts(&self) -> Result<HashMap<String, i64>> {
+        let counts = sqlx::query_as("SELECT branch, COUNT(*) FROM pkg_info GROUP BY branch")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(counts.into_iter().collect())
+    }
+
+    /// Indexed branches starting with `prefix`, ignoring ASCII case, in
+    /// ascending order.
+    pub async fn list_branches(
+        &self,
+        prefix: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<DatabaseBranchSummary>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT b.branch, b.commit_id, b.indexed_at,
+                MAX(p.committed_at) AS committed_at, COUNT(p.pkg_name) AS package_count
+            FROM branch_commits b
+            LEFT JOIN pkg_info p ON p.branch = b.branch
+            WHERE b.branch LIKE ? ESCAPE '\'
+            GROUP BY b.branch
+            ORDER BY b.branch
+            LIMIT ? OFFSET ?
+            "#,
+        )
+        .bind(format!("{}%", escape_like(prefix)))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| DatabaseBranchSummary {
+                branch: row.get("branch"),
+                commit_id: row.get("commit_id"),
+                committed_at: row.get("committed_at"),
+                package_count: row.get("package_count"),
+                indexed_at: row.get("indexed_at"),
+            })
+            .collect())
+    }
+
     pub async fn begin_transaction(&self) -> Result<sqlx::Transaction<'_, sqlx::Sqlite>> {
         Ok(self.pool.begin().await?)
     }
 
+    /// A read transaction whose reads all see the data as of the first one,
+    /// whatever syncs commit meanwhile.
+    pub async fn read_snapshot(&self) -> Result<ReadSnapshot> {
+        Ok(ReadSnapshot {
+            tx: self.pool.begin().await?,
+        })
+    }
+
+    /// Record a change of the served data; call in every transaction that
+    /// changes indexed or supplement data.
+    pub async fn bump_data_version_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO data_version (id, generation, updated_at) VALUES (0, 1, ?)
+            ON CONFLICT (id) DO UPDATE SET generation = generation + 1, updated_at = excluded.updated_at
+        "#,
+        )
+        .bind(unix_now())
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_data_version(&self) -> Result<Option<DatabaseDataVersion>> {
+        let row = sqlx::query("SELECT generation, updated_at FROM data_version WHERE id = 0")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| DatabaseDataVersion {
+            generation: row.get("generation"),
+            updated_at: row.get("updated_at"),
+        }))
+    }
+
+    /// Record the start of a sync run and return its id.
+    pub async fn start_sync_run(&self) -> Result<i64> {
+        let id = sqlx::query("INSERT INTO sync_runs (started_at, status) VALUES (?, ?)")
+            .bind(unix_now())
+            .bind(SyncRunStatus::Running.as_str())
+            .execute(&self.pool)
+            .await?
+            .last_insert_rowid();
+        Ok(id)
+    }
+
+    pub async fn finish_sync_run(
+        &self,
+        id: i64,
+        status: SyncRunStatus,
+        error: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE sync_runs SET finished_at = ?, status = ?, error = ? WHERE id = ?")
+            .bind(unix_now())
+            .bind(status.as_str())
+            .bind(error)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The holder of the sync lock, if any.
+    pub async fn get_sync_lock(&self) -> Result<Option<DatabaseSyncLock>> {
+        let row = sqlx::query(
+            "SELECT token, pid, hostname, acquired_at, heartbeat_at FROM sync_lock WHERE id = 1",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| DatabaseSyncLock {
+            token: row.get("token"),
+            pid: row.get("pid"),
+            hostname: row.get("hostname"),
+            acquired_at: row.get("acquired_at"),
+            heartbeat_at: row.get("heartbeat_at"),
+        }))
+    }
+
+    /// Take the sync lock under `token` if it is free, or held under
+    /// `stale_token`; in one statement, so only one contender wins.
+    pub async fn try_acquire_sync_lock(
+        &self,
+        token: &str,
+        pid: i64,
+        hostname: &str,
+        now: i64,
+        stale_token: Option<&str>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"INSERT INTO sync_lock (id, token, pid, hostname, acquired_at, heartbeat_at)
+               VALUES (1, ?, ?, ?, ?, ?)
+               ON CONFLICT (id) DO UPDATE SET
+                   token = excluded.token,
+                   pid = excluded.pid,
+                   hostname = excluded.hostname,
+                   acquired_at = excluded.acquired_at,
+                   heartbeat_at = excluded.heartbeat_at
+               WHERE sync_lock.token = ?"#,
+        )
+        .bind(token)
+        .bind(pid)
+        .bind(hostname)
+        .bind(now)
+        .bind(now)
+        .bind(stale_token)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Renew the sync lock; false if it is no longer held under `token`.
+    pub async fn heartbeat_sync_lock(&self, token: &str, now: i64) -> Result<bool> {
+        let result = sqlx::query("UPDATE sync_lock SET heartbeat_at = ? WHERE token = ?")
+            .bind(now)
+            .bind(token)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn release_sync_lock(&self, token: &str) -> Result<()> {
+        sqlx::query("DELETE FROM sync_lock WHERE token = ?")
+            .bind(token)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The newest run before `run_id` that left branches of its plan
+    /// unindexed, with those branches.
+    pub async fn get_interrupted_sync_run(
+        &self,
+        run_id: i64,
+    ) -> Result<Option<(i64, Vec<String>)>> {
+        let Some(interrupted) = sqlx::query_scalar::<_, Option<i64>>(
+            "SELECT MAX(run_id) FROM sync_progress WHERE run_id < ? AND done = 0",
+        )
+        .bind(run_id)
+        .fetch_one(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let branches =
+            sqlx::query_scalar("SELECT branch FROM sync_progress WHERE run_id = ? AND done = 0")
+                .bind(interrupted)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(Some((interrupted, branches)))
+    }
+
+    /// Record `run_id` as the continuation of `interrupted` and store the
+    /// branches it is going to index. The progress of earlier runs is
+    /// dropped, and `interrupted` is closed if it never got to record its
+    /// outcome, e.g. because the process was killed.
+    pub async fn start_sync_plan(
+        &self,
+        run_id: i64,
+        interrupted: Option<i64>,
+        branches: &[(String, String)],
+    ) -> Result<()> {
+        let mut tx = self.begin_transaction().await?;
+        if let Some(interrupted) = interrupted {
+            sqlx::query("UPDATE sync_runs SET resumed_from = ? WHERE id = ?")
+                .bind(interrupted)
+                .bind(run_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                "UPDATE sync_runs SET finished_at = ?, status = ?, error = ? WHERE id = ? AND status = ?",
+            )
+            .bind(unix_now())
+            .bind(SyncRunStatus::Failed.as_str())
+            .bind(format!("Interrupted, resumed by run {}", run_id))
+            .bind(interrupted)
+            .bind(SyncRunStatus::Running.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("DELETE FROM sync_progress WHERE run_id < ?")
+            .bind(run_id)
+            .execute(&mut *tx)
+            .await?;
+        for (branch, _) in branches {
+            sqlx::query("INSERT OR IGNORE INTO sync_progress (run_id, branch) VALUES (?, ?)")
+                .bind(run_id)
+                .bind(branch)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Check off branches of the plan of `run_id`, in the transaction that
+    /// indexes them.
+    pub async fn mark_synced_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        run_id: i64,
+        branches: &[&str],
+    ) -> Result<()> {
+        for branch in branches {
+            sqlx::query("UPDATE sync_progress SET done = 1 WHERE run_id = ? AND branch = ?")
+                .bind(run_id)
+                .bind(branch)
+                .execute(&mut **tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Drop the plan of a run that got through it.
+    pub async fn clear_sync_progress(&self, run_id: i64) -> Result<()> {
+        sqlx::query("DELETE FROM sync_progress WHERE run_id = ?")
+            .bind(run_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Versions of the indexed packages of a branch, by package name.
+    pub async fn get_branch_versions_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+    ) -> Result<HashMap<String, String>> {
+        let rows = sqlx::query("SELECT pkg_name, version FROM pkg_info WHERE branch = ?")
+            .bind(branch)
+            .fetch_all(&mut **tx)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("pkg_name"), row.get("version")))
+            .collect())
+    }
+
+    pub async fn store_changes_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        changes: &[DatabasePackageChange],
+    ) -> Result<()> {
+        for change in changes {
+            sqlx::query(
+                r#"
+                INSERT INTO pkg_changes (sync_run_id, branch, pkg_name, old_version, new_version,
+                    commit_id, committed_at, recorded_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+            )
+            .bind(change.sync_run_id)
+            .bind(&change.branch)
+            .bind(&change.pkg_name)
+            .bind(&change.old_version)
+            .bind(&change.new_version)
+            .bind(&change.commit_id)
+            .bind(change.committed_at)
+            .bind(change.recorded_at)
+            .execute(&mut **tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_changes_for_run(
+        &self,
+        sync_run_id: i64,
+    ) -> Result<Vec<DatabasePackageChange>> {
+        let rows = sqlx::query("SELECT * FROM pkg_changes WHERE sync_run_id = ? ORDER BY id")
+            .bind(sync_run_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.iter().map(change_from_row).collect())
+    }
+
+    /// Changes with an id above `after`, oldest first.
+    pub async fn get_changes_after(
+        &self,
+        after: i64,
+        limit: u32,
+    ) -> Result<Vec<DatabasePackageChange>> {
+        let rows = sqlx::query("SELECT * FROM pkg_changes WHERE id > ? ORDER BY id LIMIT ?")
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.iter().map(change_from_row).collect())
+    }
+
+    /// Id of the newest recorded change, 0 if there is none.
+    pub async fn get_last_change_id(&self) -> Result<i64> {
+        let id = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM pkg_changes")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    pub async fn count_changes_for_run(&self, sync_run_id: i64) -> Result<i64> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM pkg_changes WHERE sync_run_id = ?")
+            .bind(sync_run_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    /// Drop changes recorded before `before`.
+    pub async fn prune_changes(&self, before: i64) -> Result<()> {
+        sqlx::query("DELETE FROM pkg_changes WHERE recorded_at < ?")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_sync_run(&self, id: i64) -> Result<Option<DatabaseSyncRun>> {
+        let row = sqlx::query(
+            "SELECT id, started_at, finished_at, status, error, resumed_from FROM sync_runs WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.as_ref().map(sync_run_from_row))
+    }
+
+    /// Finished sync runs with an id above `after`, oldest first.
+    pub async fn get_finished_sync_runs_after(&self, after: i64) -> Result<Vec<DatabaseSyncRun>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, started_at, finished_at, status, error, resumed_from FROM sync_runs
+            WHERE id > ? AND finished_at IS NOT NULL
+            ORDER BY id
+        "#,
+        )
+        .bind(after)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(sync_run_from_row).collect())
+    }
+
+    /// Id of the newest finished sync run, 0 if there is none.
+    pub async fn get_last_finished_sync_run_id(&self) -> Result<i64> {
+        let id = sqlx::query_scalar(
+            "SELECT COALESCE(MAX(id), 0) FROM sync_runs WHERE finished_at IS NOT NULL",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// When the last successful sync run finished.
+    pub async fn get_last_successful_sync(&self) -> Result<Option<i64>> {
+        let finished_at =
+            sqlx::query_scalar("SELECT MAX(finished_at) FROM sync_runs WHERE status = ?")
+                .bind(SyncRunStatus::Succeeded.as_str())
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(finished_at)
+    }
+
+    /// Newest `LastModified` of the supplement data, `None` without any.
+    pub async fn get_supplement_last_modified(&self) -> Result<Option<i64>> {
+        let last_modified = sqlx::query_scalar("SELECT MAX(last_modified) FROM pkg_supplement")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(last_modified)
+    }
+
+    /// Numbers of listed packages and of package bases with at least one.
+    pub async fn count_listed(&self) -> Result<(i64, i64)> {
+        let counts = sqlx::query_as(
+            "SELECT COUNT(DISTINCT pkg_name), COUNT(DISTINCT branch) FROM pkg_info WHERE is_listed = 1",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(counts)
+    }
+
+    /// Counts and data ages of the index, as shown by `/api/stats` and the
+    /// `stats` command.
+    pub async fn get_stats(&self) -> Result<DatabaseStats> {
+        let (packages, pkgbases) = self.count_listed().await?;
+        let branches = sqlx::query_scalar("SELECT COUNT(*) FROM branch_commits")
+            .fetch_one(&self.pool)
+            .await?;
+        // Per package base, like aurweb counts them; a base is orphaned or
+        // flagged when the supplement says so for any of its listed packages
+        let (orphans, out_of_date) = sqlx::query_as(
+            r#"SELECT
+                COUNT(DISTINCT CASE WHEN s.maintainer IS NULL THEN p.branch END),
+                COUNT(DISTINCT CASE WHEN s.out_of_date IS NOT NULL THEN p.branch END)
+            FROM pkg_info p
+            JOIN pkg_supplement s ON s.pkgname = p.pkg_name
+            WHERE p.is_listed = 1"#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        let history_rows = sqlx::query_scalar("SELECT COUNT(*) FROM pkg_history")
+            .fetch_one(&self.pool)
+            .await?;
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(DatabaseStats {
+            packages,
+            pkgbases,
+            branches,
+            orphans,
+            out_of_date,
+            last_successful_sync: self.get_last_successful_sync().await?,
+            supplement_last_modified: self.get_supplement_last_modified().await?,
+            db_size_bytes: page_count * page_size,
+            history_rows,
+        })
+    }
+
+    /// Check that the database answers queries at all.
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Open and idle connections of the pool.
+    pub fn pool_stats(&self) -> (u32, usize) {
+        (self.pool.size(), self.pool.num_idle())
+    }
+
     pub async fn update_branch_commit_with_tx(
         &self,
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
@@ -228,12 +910,13 @@ impl DatabaseOps {
     ) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT OR REPLACE INTO branch_commits (branch, commit_id) 
-            VALUES (?, ?)
+            INSERT OR REPLACE INTO branch_commits (branch, commit_id, indexed_at)
+            VALUES (?, ?, ?)
         "#,
         )
         .bind(branch)
         .bind(commit_id)
+        .bind(unix_now())
         .execute(&mut **tx)
         .await?;
         Ok(())
@@ -254,6 +937,8 @@ impl DatabaseOps {
             "pkg_conflicts",
             "pkg_replaces",
             "pkg_groups",
+            "pkg_licenses",
+            "pkg_srcinfo",
         ];
         for table in tables {
             let query = format!("DELETE FROM {} WHERE branch = ?", table);
@@ -262,24 +947,87 @@ impl DatabaseOps {
         Ok(())
     }
 
-    pub async fn update_index_with_tx(
+    /// Remove a branch from the index entirely, with its commit and history.
+    pub async fn delete_branch_with_tx(
         &self,
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
-        packages: &[DatabasePackageDetails],
+        branch: &str,
     ) -> Result<()> {
-        if packages.is_empty() {
-            return Ok(());
+        self.clear_index_with_tx(tx, branch).await?;
+        for table in ["branch_commits", "pkg_history", "history_tips"] {
+            let query = format!("DELETE FROM {} WHERE branch = ?", table);
+            sqlx::query(&query).bind(branch).execute(&mut **tx).await?;
         }
+        Ok(())
+    }
 
-        for pkg in packages {
-            sqlx::query(
-                r#"
-                INSERT OR REPLACE INTO pkg_info 
-                (branch, pkg_name, pkg_desc, version, url, commit_id, committed_at) 
-                VALUES (?, ?, ?, ?, ?, ?, ?)
-            "#,
-            )
-            .bind(&pkg.branch)
+    pub async fn store_srcinfo_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        srcinfo: &DatabaseSrcInfo,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO pkg_srcinfo (branch, commit_id, blob_id, srcinfo)
+            VALUES (?, ?, ?, ?)
+        "#,
+        )
+        .bind(&srcinfo.branch)
+        .bind(&srcinfo.commit_id)
+        .bind(&srcinfo.blob_id)
+        .bind(&srcinfo.srcinfo)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Stored `.SRCINFO`s of `branches`; branches without one are left out.
+    pub async fn get_srcinfos(&self, branches: &[String]) -> Result<Vec<DatabaseSrcInfo>> {
+        get_srcinfos_with_conn(&mut *self.pool.acquire().await?, branches).await
+    }
+
+    /// Package base of each of `package_names` that is indexed.
+    pub async fn get_package_branches(
+        &self,
+        package_names: &[String],
+    ) -> Result<HashMap<String, String>> {
+        if package_names.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let placeholders = vec!["?"; package_names.len()].join(", ");
+        let query = format!(
+            "SELECT pkg_name, branch FROM pkg_info WHERE pkg_name IN ({})",
+            placeholders
+        );
+        let rows = package_names
+            .iter()
+            .fold(sqlx::query(&query), |query, name| query.bind(name))
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("pkg_name"), row.get("branch")))
+            .collect())
+    }
+
+    pub async fn update_index_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        packages: &[DatabasePackageDetails],
+    ) -> Result<()> {
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        for pkg in packages {
+            sqlx::query(
+                r#"
+                INSERT OR REPLACE INTO pkg_info 
+                (branch, pkg_name, pkg_desc, version, url, commit_id, committed_at) 
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+            )
+            .bind(&pkg.branch)
             .bind(&pkg.pkg_name)
             .bind(&pkg.pkg_desc)
             .bind(&pkg.version)
@@ -361,8 +1109,134 @@ impl DatabaseOps {
                 &pkg.groups,
             )
             .await?;
+            self.store_array_tx(
+                tx,
+                &pkg.branch,
+                &pkg.pkg_name,
+                "pkg_licenses",
+                "license",
+                &pkg.licenses,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// When the current commit of `branch` was made, if it's indexed.
+    pub async fn get_branch_committed_at(&self, branch: &str) -> Result<Option<i64>> {
+        let row =
+            sqlx::query("SELECT MAX(committed_at) AS committed_at FROM pkg_info WHERE branch = ?")
+                .bind(branch)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(row.get("committed_at"))
+    }
+
+    /// The tip the history of `branch` was last fetched at and the start of
+    /// the window it was fetched for. Kept apart from `pkg_history`, which
+    /// has no rows for a branch without commits in the window.
+    pub async fn get_history_tip(&self, branch: &str) -> Result<Option<(String, i64)>> {
+        let tip = sqlx::query_as("SELECT commit_id, since FROM history_tips WHERE branch = ?")
+            .bind(branch)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(tip)
+    }
+
+    pub async fn set_history_tip_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+        commit_id: &str,
+        since: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO history_tips (branch, commit_id, since) VALUES (?, ?, ?)",
+        )
+        .bind(branch)
+        .bind(commit_id)
+        .bind(since)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// For each of `pkg_names`, the newest history row committed at or
+    /// before `as_of`; names without one, or whose newest row predates the
+    /// recorded details, are left out.
+    pub async fn get_history_versions(
+        &self,
+        pkg_names: &[String],
+        as_of: i64,
+    ) -> Result<Vec<DatabaseHistoryVersion>> {
+        get_history_versions_with_conn(&mut *self.pool.acquire().await?, pkg_names, as_of).await
+    }
+
+    pub async fn store_history_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        packages: &[DatabasePackageDetails],
+    ) -> Result<()> {
+        for pkg in packages {
+            let details = DatabaseHistoryDetails {
+                pkg_desc: pkg.pkg_desc.clone(),
+                url: pkg.url.clone(),
+                depends: pkg.depends.clone(),
+                make_depends: pkg.make_depends.clone(),
+                opt_depends: pkg.opt_depends.clone(),
+                check_depends: pkg.check_depends.clone(),
+                provides: pkg.provides.clone(),
+                conflicts: pkg.conflicts.clone(),
+                replaces: pkg.replaces.clone(),
+                groups: pkg.groups.clone(),
+                licenses: pkg.licenses.clone(),
+            };
+            // Rows of an older version lack the details, so fill them in
+            sqlx::query(
+                r#"
+                INSERT INTO pkg_history
+                (branch, commit_id, committed_at, pkg_name, version, details)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT (branch, commit_id, pkg_name) DO UPDATE SET details = excluded.details
+            "#,
+            )
+            .bind(&pkg.branch)
+            .bind(&pkg.commit_id)
+            .bind(pkg.committed_at)
+            .bind(&pkg.pkg_name)
+            .bind(&pkg.version)
+            .bind(serde_json::to_string(&details)?)
+            .execute(&mut **tx)
+            .await?;
         }
+        Ok(())
+    }
 
+    /// Drop history rows committed before `since`, plus rows and tips of
+    /// branches no longer tracked.
+    pub async fn prune_history_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        since: i64,
+        tracked_branches: &[String],
+    ) -> Result<()> {
+        sqlx::query("DELETE FROM pkg_history WHERE committed_at < ?")
+            .bind(since)
+            .execute(&mut **tx)
+            .await?;
+        let placeholders = vec!["?"; tracked_branches.len()].join(", ");
+        for table in ["pkg_history", "history_tips"] {
+            let query = format!(
+                "DELETE FROM {} WHERE branch NOT IN ({})",
+                table, placeholders
+            );
+            tracked_branches
+                .iter()
+                .fold(sqlx::query(&query), |query, branch| query.bind(branch))
+                .execute(&mut **tx)
+                .await?;
+        }
         Ok(())
     }
 
@@ -390,275 +1264,182 @@ impl DatabaseOps {
         Ok(())
     }
 
+    /// Packages matching a search, in no particular order unless `sort` is
+    /// given. With a `page`, only that window of the results is returned,
+    /// ordered by name by default so pages are stable.
     pub async fn search_packages(
         &self,
         search_type: SearchType,
         keyword: &str,
+        sort: Option<SortBy>,
+        page: Option<SearchPage>,
     ) -> Result<Vec<DatabasePackageInfoWithSupplement>> {
-        let (query, param, count) = match search_type {
-            SearchType::Name => (
-                r#"
-                    SELECT DISTINCT p.*, s.popularity, s.num_votes, s.out_of_date,
-                           s.maintainer, s.submitter, s.first_submitted, s.last_modified
-                    FROM pkg_info p
-                    LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-                    WHERE p.pkg_name LIKE ? AND p.is_listed = 1
-                "#,
-                format!("%{}%", keyword),
-                1,
-            ),
-            SearchType::NameDesc => (
-                r#"
-                    SELECT DISTINCT p.*, s.popularity, s.num_votes, s.out_of_date,
-                           s.maintainer, s.submitter, s.first_submitted, s.last_modified
-                    FROM pkg_info p
-                    LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-                    WHERE (p.pkg_name LIKE ? OR p.pkg_desc LIKE ?) AND p.is_listed = 1
-                "#,
-                format!("%{}%", keyword),
-                2,
-            ),
-            SearchType::Depends => (
-                r#"
-                    SELECT DISTINCT p.*, s.popularity, s.num_votes, s.out_of_date,
-                           s.maintainer, s.submitter, s.first_submitted, s.last_modified
-                    FROM pkg_info p
-                    LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-                    JOIN pkg_depends d ON p.pkg_name = d.pkg_name AND p.branch = d.branch
-                    WHERE d.depend = ? AND p.is_listed = 1
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-            SearchType::MakeDepends => (
-                r#"
-                    SELECT DISTINCT p.*, s.popularity, s.num_votes, s.out_of_date,
-                           s.maintainer, s.submitter, s.first_submitted, s.last_modified
-                    FROM pkg_info p
-                    LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-                    JOIN pkg_make_depends md ON p.pkg_name = md.pkg_name AND p.branch = md.branch
-                    WHERE md.make_depend = ? AND p.is_listed = 1
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-            SearchType::OptDepends => (
-                r#"
-                    SELECT DISTINCT p.*, s.popularity, s.num_votes, s.out_of_date,
-                           s.maintainer, s.submitter, s.first_submitted, s.last_modified
-                    FROM pkg_info p
-                    LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-                    JOIN pkg_opt_depends od ON p.pkg_name = od.pkg_name AND p.branch = od.branch
-                    WHERE od.opt_depend = ? AND p.is_listed = 1
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-            SearchType::CheckDepends => (
-                r#"
-                    SELECT DISTINCT p.*, s.popularity, s.num_votes, s.out_of_date,
-                           s.maintainer, s.submitter, s.first_submitted, s.last_modified
-                    FROM pkg_info p
-                    LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-                    JOIN pkg_check_depends cd ON p.pkg_name = cd.pkg_name AND p.branch = cd.branch
-                    WHERE cd.check_depend = ? AND p.is_listed = 1
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-        };
+        let mut conn = self.pool.acquire().await?;
+        search_packages_with_conn(&mut conn, search_type, keyword, sort, page).await
+    }
+
+    /// One page of a search along with the number of all its matches, read
+    /// in one transaction so a sync committing in between can't make the
+    /// two disagree.
+    pub async fn search_page(
+        &self,
+        search_type: SearchType,
+        keyword: &str,
+        sort: Option<SortBy>,
+        page: SearchPage,
+    ) -> Result<(u64, Vec<DatabasePackageInfoWithSupplement>)> {
+        let mut tx = self.begin_transaction().await?;
+        let total = count_search_results_with_conn(&mut tx, search_type, keyword).await?;
+        let packages =
+            search_packages_with_conn(&mut tx, search_type, keyword, sort, Some(page)).await?;
+        tx.commit().await?;
+        Ok((total, packages))
+    }
 
-        let mut query_builder = sqlx::query(query);
+    /// The first `limit` matches of an unpaginated search, in the order
+    /// [`Self::stream_search`] streams them.
+    pub async fn search_packages_up_to(
+        &self,
+        search_type: SearchType,
+        keyword: &str,
+        sort: Option<SortBy>,
+        limit: u64,
+    ) -> Result<Vec<DatabasePackageInfoWithSupplement>> {
+        let (query, param, count) = search_query(search_type, keyword, sort, None);
+        let query = format!("{} LIMIT {}", query, limit);
+        let mut query_builder = sqlx::query(&query);
         for _ in 0..count {
             query_builder = query_builder.bind(&param);
         }
         query_builder
             .fetch(&self.pool)
-            .map_ok(|row| {
-                // Apply the logic from the spec: use time-sensitive fields only if version matches
-                let pkg_version: String = row.get("version");
-                let supplement_version: Option<String> = row.try_get("s.version").ok();
-                let version_matches = supplement_version
-                    .as_ref()
-                    .map(|v| v == &pkg_version)
-                    .unwrap_or(false);
-
-                DatabasePackageInfoWithSupplement {
-                    commit_id: row.get("commit_id"),
-                    committed_at: row.get("committed_at"),
-                    branch: row.get("branch"),
-                    pkg_name: row.get("pkg_name"),
-                    pkg_desc: row.get("pkg_desc"),
-                    version: pkg_version,
-                    url: row.get("url"),
-                    popularity: row.try_get("popularity").ok(),
-                    num_votes: row.try_get("num_votes").ok(),
-                    out_of_date: if version_matches {
-                        row.try_get("out_of_date").ok().flatten()
-                    } else {
-                        None
-                    },
-                    maintainer: row.try_get("maintainer").ok().flatten(),
-                    submitter: row.try_get("submitter").ok().flatten(),
-                    first_submitted: row.try_get("first_submitted").ok(),
-                    last_modified: if version_matches {
-                        row.try_get("last_modified").ok()
-                    } else {
-                        None
-                    },
-                }
-            })
+            .map_ok(|row| package_info_from_row(&row))
             .try_collect::<Vec<_>>()
             .await
             .map_err(Into::into)
     }
 
-    pub async fn get_package_details(
+    /// The number of matches of an unpaginated search and, unless there are
+    /// more than `max_results`, the matches themselves. Both are read from
+    /// one snapshot by a task feeding the matches through a small channel,
+    /// so they never all sit in memory; dropping the stream ends the read.
+    pub async fn stream_search(
         &self,
-        package_names: &[String],
-    ) -> Result<Vec<DatabasePackageDetailsWithSupplement>> {
-        if package_names.is_empty() {
-            return Ok(Vec::new());
-        }
-
-        let placeholders: Vec<String> = package_names.iter().map(|_| "?".to_string()).collect();
-        let placeholders_str = placeholders.join(",");
-
-        let query = format!(
-            r#"
-            SELECT p.*, s.version as s_version, s.popularity, s.num_votes, s.out_of_date,
-                   s.maintainer, s.submitter, s.first_submitted, s.last_modified,
-                   s.co_maintainers, s.keywords
-            FROM pkg_info p
-            LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
-            WHERE p.pkg_name IN ({}) AND p.is_listed = 1
-            "#,
-            placeholders_str
-        );
-
-        let mut query_builder = sqlx::query(&query);
-        for name in package_names {
-            query_builder = query_builder.bind(name);
-        }
-
-        let mut tx = self.begin_transaction().await?;
-        let rows = query_builder.fetch_all(&mut *tx).await?;
-        let mut results = Vec::with_capacity(rows.len());
-
-        for row in rows {
-            let pkg_version: String = row.get("version");
-            let supplement_version: Option<String> = row.try_get("s_version").ok();
-            let version_matches = supplement_version
-                .as_ref()
-                .map(|v| v == &pkg_version)
-                .unwrap_or(false);
-
-            let info = DatabasePackageInfoWithSupplement {
-                commit_id: row.get("commit_id"),
-                committed_at: row.get("committed_at"),
-                branch: row.get("branch"),
-                pkg_name: row.get("pkg_name"),
-                pkg_desc: row.get("pkg_desc"),
-                version: pkg_version,
-                url: row.get("url"),
-                popularity: row.try_get("popularity").ok(),
-                num_votes: row.try_get("num_votes").ok(),
-                out_of_date: if version_matches {
-                    row.try_get("out_of_date").ok().flatten()
-                } else {
-                    None
-                },
-                maintainer: row.try_get("maintainer").ok().flatten(),
-                submitter: row.try_get("submitter").ok().flatten(),
-                first_submitted: row.try_get("first_submitted").ok(),
-                last_modified: if version_matches {
-                    row.try_get("last_modified").ok()
-                } else {
-                    None
-                },
+        search_type: SearchType,
+        keyword: &str,
+        sort: Option<SortBy>,
+        max_results: u64,
+    ) -> Result<(u64, Option<PackageInfoStream>)> {
+        let (total_sender, total_receiver) = oneshot::channel();
+        let (mut sender, receiver) = futures::channel::mpsc::channel(SEARCH_STREAM_BUFFER);
+        let db = self.clone();
+        let keyword = keyword.to_string();
+        tokio::spawn(async move {
+            let counted = async {
+                let mut tx = db.begin_transaction().await?;
+                let total = count_search_results_with_conn(&mut tx, search_type, &keyword).await?;
+                anyhow::Ok((tx, total))
+            }
+            .await;
+            let (mut tx, total) = match counted {
+                Ok(counted) => counted,
+                Err(e) => {
+                    let _ = total_sender.send(Err(e));
+                    return;
+                }
             };
-
-            let package_name: String = row.get("pkg_name");
-            let pkg_branch: String = row.get("branch");
-
-            let tables = vec![
-                ("pkg_depends", "depend"),
-                ("pkg_make_depends", "make_depend"),
-                ("pkg_opt_depends", "opt_depend"),
-                ("pkg_check_depends", "check_depend"),
-                ("pkg_provides", "provide"),
-                ("pkg_conflicts", "conflict"),
-                ("pkg_replaces", "replace"),
-                ("pkg_groups", "group_name"),
-            ];
-
-            let mut depends = Vec::new();
-            let mut make_depends = Vec::new();
-            let mut opt_depends = Vec::new();
-            let mut check_depends = Vec::new();
-            let mut provides = Vec::new();
-            let mut conflicts = Vec::new();
-            let mut replaces = Vec::new();
-            let mut groups = Vec::new();
-
-            for (table, column) in tables {
-                let query = format!(
-                    "SELECT {} FROM {} WHERE pkg_name = ? AND branch = ?",
-                    column, table
-                );
-                let values = sqlx::query(&query)
-                    .bind(&package_name)
-                    .bind(&pkg_branch)
-                    .fetch(&mut *tx)
-                    .map_ok(|row| row.get::<String, _>(column))
-                    .try_collect()
-                    .await?;
-
-                match column {
-                    "depend" => depends = values,
-                    "make_depend" => make_depends = values,
-                    "opt_depend" => opt_depends = values,
-                    "check_depend" => check_depends = values,
-                    "provide" => provides = values,
-                    "conflict" => conflicts = values,
-                    "replace" => replaces = values,
-                    "group_name" => groups = values,
-                    _ => {}
+            if total_sender.send(Ok(total)).is_err() || total > max_results {
+                return;
+            }
+            let (query, param, count) = search_query(search_type, &keyword, sort, None);
+            let mut query_builder = sqlx::query(&query);
+            for _ in 0..count {
+                query_builder = query_builder.bind(&param);
+            }
+            let mut rows = query_builder.fetch(&mut *tx);
+            while let Some(row) = rows.next().await {
+                let row = row
+                    .map(|row| package_info_from_row(&row))
+                    .map_err(Into::into);
+                if sender.send(row).await.is_err() {
+                    return; // Client went away
                 }
             }
+        });
+        let total = total_receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("Search ended before counting its matches"))??;
+        Ok((total, (total <= max_results).then(|| receiver.boxed())))
+    }
 
-            // Parse keywords and co_maintainers from JSON
-            let keywords: Vec<String> = row
-                .try_get::<Option<String>, _>("keywords")
-                .ok()
-                .flatten()
-                .and_then(|s| serde_json::from_str(&s).ok())
-                .unwrap_or_default();
+    /// Names of all listed packages in ascending order, read as a single
+    /// statement so a concurrent sync commit is either fully seen or not.
+    pub fn stream_package_names(&self) -> BoxStream<'_, Result<String>> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT pkg_name FROM pkg_info WHERE is_listed = 1 ORDER BY pkg_name",
+        )
+        .fetch(&self.pool)
+        .map_err(Into::into)
+        .boxed()
+    }
 
-            let co_maintainers: Vec<String> = row
-                .try_get::<Option<String>, _>("co_maintainers")
-                .ok()
-                .flatten()
-                .and_then(|s| serde_json::from_str(&s).ok())
-                .unwrap_or_default();
+    /// Distinct package bases with at least one listed package in ascending
+    /// order, read as a single statement like [`Self::stream_package_names`].
+    pub fn stream_pkgbases(&self) -> BoxStream<'_, Result<String>> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT branch FROM pkg_info WHERE is_listed = 1 ORDER BY branch",
+        )
+        .fetch(&self.pool)
+        .map_err(Into::into)
+        .boxed()
+    }
 
-            results.push(DatabasePackageDetailsWithSupplement {
-                info,
-                depends,
-                make_depends,
-                opt_depends,
-                check_depends,
-                provides,
-                conflicts,
-                replaces,
-                groups,
-                keywords,
-                co_maintainers,
-            });
-        }
+    /// Names of listed packages starting with `prefix`, ignoring ASCII case,
+    /// in ascending order.
+    pub async fn suggest_names(&self, prefix: &str, limit: u32) -> Result<Vec<String>> {
+        let rows = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT pkg_name FROM pkg_info
+            WHERE pkg_name LIKE ? ESCAPE '\' AND is_listed = 1
+            ORDER BY pkg_name
+            LIMIT ?
+            "#,
+        )
+        .bind(format!("{}%", escape_like(prefix)))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
 
-        tx.commit().await?;
-        Ok(results)
+    /// Package bases (branches) with at least one listed package starting
+    /// with `prefix`, ignoring ASCII case, in ascending order.
+    pub async fn suggest_pkgbases(&self, prefix: &str, limit: u32) -> Result<Vec<String>> {
+        let rows = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT branch FROM pkg_info
+            WHERE branch LIKE ? ESCAPE '\' AND is_listed = 1
+            ORDER BY branch
+            LIMIT ?
+            "#,
+        )
+        .bind(format!("{}%", escape_like(prefix)))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// Details of the listed packages among `package_names`, read in one
+    /// transaction.
+    pub async fn get_package_details(
+        &self,
+        package_names: &[String],
+    ) -> Result<Vec<DatabasePackageDetailsWithSupplement>> {
+        self.read_snapshot()
+            .await?
+            .get_package_details(package_names)
+            .await
     }
 
     pub async fn get_branch_commit_id(&self, branch: &str) -> Result<Option<String>> {
@@ -670,9 +1451,65 @@ impl DatabaseOps {
         Ok(row.map(|r| r.get("commit_id")))
     }
 
+    pub async fn get_supplement_source_validators(
+        &self,
+    ) -> Result<Option<SupplementSourceValidators>> {
+        let row = sqlx::query("SELECT source, etag, last_modified FROM supplement_source LIMIT 1")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| SupplementSourceValidators {
+            source: row.get("source"),
+            etag: row.get("etag"),
+            last_modified: row.get("last_modified"),
+        }))
+    }
+
+    pub async fn load_request_counters(&self) -> Result<Vec<DatabaseRequestCounter>> {
+        sqlx::query("SELECT ip, window_start, requests, previous_requests FROM request_counters")
+            .fetch(&self.pool)
+            .map_ok(|row| DatabaseRequestCounter {
+                ip: row.get("ip"),
+                window_start: row.get("window_start"),
+                requests: row.get::<i64, _>("requests") as u64,
+                previous_requests: row.get::<i64, _>("previous_requests") as u64,
+            })
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Replace all stored request counters.
+    pub async fn store_request_counters(&self, counters: &[DatabaseRequestCounter]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM request_counters")
+            .execute(&mut *tx)
+            .await?;
+        for counter in counters {
+            sqlx::query(
+                r#"
+                INSERT INTO request_counters (ip, window_start, requests, previous_requests)
+                VALUES (?, ?, ?, ?)
+            "#,
+            )
+            .bind(&counter.ip)
+            .bind(counter.window_start)
+            .bind(counter.requests as i64)
+            .bind(counter.previous_requests as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Replace the supplement data. `validators` describes the source it came
+    /// from; `None` keeps the stored ones, e.g. when restoring a cached copy.
+    /// Listing status is recomputed from the new data if `update_listed`.
     pub async fn store_supplement_data(
         &self,
         supplements: &[DatabaseSupplementData],
+        validators: Option<&SupplementSourceValidators>,
+        update_listed: bool,
     ) -> Result<()> {
         if supplements.is_empty() {
             return Ok(());
@@ -683,32 +1520,121 @@ impl DatabaseOps {
             .execute(&mut *tx)
             .await?;
         for supplement in supplements {
+            self.insert_supplement_with_tx(&mut tx, supplement, None)
+                .await?;
+        }
+
+        if let Some(validators) = validators {
+            // Only the source that served the stored data may answer conditionally
+            sqlx::query("DELETE FROM supplement_source")
+                .execute(&mut *tx)
+                .await?;
             sqlx::query(
-                r#"
-                INSERT OR REPLACE INTO pkg_supplement
-                (pkgname, version, popularity, num_votes, out_of_date, maintainer,
-                 submitter, co_maintainers, keywords, first_submitted, last_modified)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-                "#,
+                "INSERT INTO supplement_source (source, etag, last_modified) VALUES (?, ?, ?)",
             )
-            .bind(&supplement.pkgname)
-            .bind(&supplement.version)
-            .bind(supplement.popularity)
-            .bind(supplement.num_votes)
-            .bind(supplement.out_of_date)
-            .bind(&supplement.maintainer)
-            .bind(&supplement.submitter)
-            .bind(&serde_json::to_string(&supplement.co_maintainers)?)
-            .bind(&serde_json::to_string(&supplement.keywords)?)
-            .bind(supplement.first_submitted)
-            .bind(supplement.last_modified)
+            .bind(&validators.source)
+            .bind(&validators.etag)
+            .bind(&validators.last_modified)
             .execute(&mut *tx)
             .await?;
         }
 
-        self.update_is_listed_status_with_tx(&mut tx).await?;
+        if update_listed {
+            self.update_is_listed_status_with_tx(&mut tx).await?;
+        }
+        self.bump_data_version_with_tx(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Names among `package_names` of listed packages without supplement
+    /// data, or whose live looked up data was fetched before `stale_before`.
+    pub async fn get_names_lacking_supplement(
+        &self,
+        package_names: &[String],
+        stale_before: i64,
+    ) -> Result<Vec<String>> {
+        if package_names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; package_names.len()].join(",");
+        let query = format!(
+            r#"
+            SELECT DISTINCT p.pkg_name
+            FROM pkg_info p
+            LEFT JOIN pkg_supplement s ON p.pkg_name = s.pkgname
+            WHERE p.pkg_name IN ({}) AND p.is_listed = 1
+              AND (s.pkgname IS NULL OR s.live_fetched_at < ?)
+            "#,
+            placeholders
+        );
+        package_names
+            .iter()
+            .fold(sqlx::query(&query), |query, name| query.bind(name))
+            .bind(stale_before)
+            .fetch(&self.pool)
+            .map_ok(|row| row.get::<String, _>("pkg_name"))
+            .try_collect()
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Store supplement data looked up live for single packages, marked with
+    /// the time of the lookup. Rows from a supplement archive are never
+    /// replaced; the next archive replaces all live rows in turn.
+    pub async fn store_live_supplement_data(
+        &self,
+        supplements: &[DatabaseSupplementData],
+        fetched_at: i64,
+    ) -> Result<()> {
+        let mut tx = self.begin_transaction().await?;
+        for supplement in supplements {
+            sqlx::query(
+                "DELETE FROM pkg_supplement WHERE pkgname = ? AND live_fetched_at IS NOT NULL",
+            )
+            .bind(&supplement.pkgname)
+            .execute(&mut *tx)
+            .await?;
+            self.insert_supplement_with_tx(&mut tx, supplement, Some(fetched_at))
+                .await?;
+        }
+        self.bump_data_version_with_tx(&mut tx).await?;
         tx.commit().await?;
+        Ok(())
+    }
 
+    async fn insert_supplement_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        supplement: &DatabaseSupplementData,
+        live_fetched_at: Option<i64>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO pkg_supplement
+            (pkgname, version, popularity, num_votes, out_of_date, maintainer,
+             submitter, co_maintainers, keywords, first_submitted, last_modified, licenses,
+             package_base, live_fetched_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&supplement.pkgname)
+        .bind(&supplement.version)
+        .bind(supplement.popularity)
+        .bind(supplement.num_votes)
+        .bind(supplement.out_of_date)
+        .bind(&supplement.maintainer)
+        .bind(&supplement.submitter)
+        .bind(&serde_json::to_string(&supplement.co_maintainers)?)
+        .bind(&serde_json::to_string(&supplement.keywords)?)
+        .bind(supplement.first_submitted)
+        .bind(supplement.last_modified)
+        .bind(&serde_json::to_string(&supplement.licenses)?)
+        .bind(&supplement.package_base)
+        .bind(live_fetched_at)
+        .execute(&mut **tx)
+        .await?;
         Ok(())
     }
 
@@ -746,3 +1672,602 @@ impl DatabaseOps {
         Ok(())
     }
 }
+
+/// Escape the wildcards of a LIKE pattern, to be used with `ESCAPE '\'`.
+fn escape_like(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn sync_run_from_row(row: &sqlx::sqlite::SqliteRow) -> DatabaseSyncRun {
+    DatabaseSyncRun {
+        id: row.get("id"),
+        started_at: row.get("started_at"),
+        finished_at: row.get("finished_at"),
+        status: row.get("status"),
+        error: row.get("error"),
+        resumed_from: row.get("resumed_from"),
+    }
+}
+
+fn change_from_row(row: &sqlx::sqlite::SqliteRow) -> DatabasePackageChange {
+    DatabasePackageChange {
+        id: row.get("id"),
+        sync_run_id: row.get("sync_run_id"),
+        branch: row.get("branch"),
+        pkg_name: row.get("pkg_name"),
+        old_version: row.get("old_version"),
+        new_version: row.get("new_version"),
+        commit_id: row.get("commit_id"),
+        committed_at: row.get("committed_at"),
+        recorded_at: row.get("recorded_at"),
+    }
+}
+
+/// Sort key of a search; `modified` matches the `LastModified` reported,
+/// which is only taken from supplement data of the same version.
+fn sort_column(field: SortField) -> &'static str {
+    match field {
+        SortField::Popularity => "COALESCE(s.popularity, 0)",
+        SortField::Votes => "COALESCE(s.num_votes, 0)",
+        SortField::Name => "p.pkg_name",
+        SortField::Modified => "CASE WHEN s.version = p.version THEN s.last_modified ELSE 0 END",
+    }
+}
+
+async fn search_packages_with_conn(
+    conn: &mut SqliteConnection,
+    search_type: SearchType,
+    keyword: &str,
+    sort: Option<SortBy>,
+    page: Option<SearchPage>,
+) -> Result<Vec<DatabasePackageInfoWithSupplement>> {
+    let (query, param, count) = search_query(search_type, keyword, sort, page);
+    let mut query_builder = sqlx::query(&query);
+    for _ in 0..count {
+        query_builder = query_builder.bind(&param);
+    }
+    query_builder
+        .fetch(&mut *conn)
+        .map_ok(|row| package_info_from_row(&row))
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(Into::into)
+}
+
+/// The statement of a search with the parameter to bind and how often.
+fn search_query(
+    search_type: SearchType,
+    keyword: &str,
+    sort: Option<SortBy>,
+    page: Option<SearchPage>,
+) -> (String, String, usize) {
+    let (filter, param, count) = search_filter(search_type, keyword);
+    let sort = sort.or(page.map(|_| SortBy {
+        field: SortField::Name,
+        descending: false,
+    }));
+    let mut window = match sort {
+        Some(sort) => format!(
+            "ORDER BY {} {}, p.pkg_name, p.branch",
+            sort_column(sort.field),
+            if sort.descending { "DESC" } else { "ASC" }
+        ),
+        None => String::new(),
+    };
+    if let Some(page) = page {
+        window += &format!(" LIMIT {} OFFSET {}", page.limit, page.offset);
+    }
+
+    let query = format!(
+        r#"
+        SELECT DISTINCT p.*, s.version as s_version, s.popularity, s.num_votes, s.out_of_date,
+               s.maintainer, s.submitter, s.first_submitted, s.last_modified,
+               {}
+        FROM pkg_info p
+        {}
+        {}
+        {}
+        "#,
+        SUPPLEMENT_FROM_BASE_COLUMN, SUPPLEMENT_JOIN, filter, window
+    );
+    (query, param, count)
+}
+
+fn package_info_from_row(row: &SqliteRow) -> DatabasePackageInfoWithSupplement {
+    // Apply the logic from the spec: use time-sensitive fields only if version matches
+    let pkg_version: String = row.get("version");
+    let supplement_version: Option<String> = row.try_get("s_version").ok();
+    let version_matches = supplement_version
+        .as_ref()
+        .map(|v| v == &pkg_version)
+        .unwrap_or(false);
+
+    DatabasePackageInfoWithSupplement {
+        commit_id: row.get("commit_id"),
+        committed_at: row.get("committed_at"),
+        branch: row.get("branch"),
+        pkg_name: row.get("pkg_name"),
+        pkg_desc: row.get("pkg_desc"),
+        version: pkg_version,
+        url: row.get("url"),
+        popularity: row.try_get("popularity").ok(),
+        num_votes: row.try_get("num_votes").ok(),
+        out_of_date: if version_matches {
+            row.try_get("out_of_date").ok().flatten()
+        } else {
+            None
+        },
+        maintainer: row.try_get("maintainer").ok().flatten(),
+        submitter: row.try_get("submitter").ok().flatten(),
+        first_submitted: row.try_get("first_submitted").ok(),
+        last_modified: if version_matches {
+            row.try_get("last_modified").ok()
+        } else {
+            None
+        },
+        supplement_from_base: row.get("supplement_from_base"),
+    }
+}
+
+/// Reads of one transaction, from [`DatabaseOps::read_snapshot`]; dropping
+/// it ends the transaction.
+pub struct ReadSnapshot {
+    tx: sqlx::Transaction<'static, sqlx::Sqlite>,
+}
+
+impl ReadSnapshot {
+    pub async fn get_package_details(
+        &mut self,
+        package_names: &[String],
+    ) -> Result<Vec<DatabasePackageDetailsWithSupplement>> {
+        get_package_details_with_conn(&mut self.tx, package_names).await
+    }
+
+    pub async fn get_srcinfos(&mut self, branches: &[String]) -> Result<Vec<DatabaseSrcInfo>> {
+        get_srcinfos_with_conn(&mut self.tx, branches).await
+    }
+
+    pub async fn get_history_versions(
+        &mut self,
+        pkg_names: &[String],
+        as_of: i64,
+    ) -> Result<Vec<DatabaseHistoryVersion>> {
+        get_history_versions_with_conn(&mut self.tx, pkg_names, as_of).await
+    }
+}
+
+/// [`DatabaseOps::get_package_details`] on `conn`.
+async fn get_package_details_with_conn(
+    conn: &mut SqliteConnection,
+    package_names: &[String],
+) -> Result<Vec<DatabasePackageDetailsWithSupplement>> {
+    if package_names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let placeholders: Vec<String> = package_names.iter().map(|_| "?".to_string()).collect();
+    let placeholders_str = placeholders.join(",");
+
+    let query = format!(
+        r#"
+        SELECT p.*, s.version as s_version, s.popularity, s.num_votes, s.out_of_date,
+               s.maintainer, s.submitter, s.first_submitted, s.last_modified,
+               s.co_maintainers, s.keywords, s.licenses as s_licenses,
+               {}
+        FROM pkg_info p
+        {}
+        WHERE p.pkg_name IN ({}) AND p.is_listed = 1
+        "#,
+        SUPPLEMENT_FROM_BASE_COLUMN, SUPPLEMENT_JOIN, placeholders_str
+    );
+
+    let mut query_builder = sqlx::query(&query);
+    for name in package_names {
+        query_builder = query_builder.bind(name);
+    }
+
+    let rows = query_builder.fetch_all(&mut *conn).await?;
+    let mut results = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let pkg_version: String = row.get("version");
+        let supplement_version: Option<String> = row.try_get("s_version").ok();
+        let version_matches = supplement_version
+            .as_ref()
+            .map(|v| v == &pkg_version)
+            .unwrap_or(false);
+
+        let info = DatabasePackageInfoWithSupplement {
+            commit_id: row.get("commit_id"),
+            committed_at: row.get("committed_at"),
+            branch: row.get("branch"),
+            pkg_name: row.get("pkg_name"),
+            pkg_desc: row.get("pkg_desc"),
+            version: pkg_version,
+            url: row.get("url"),
+            popularity: row.try_get("popularity").ok(),
+            num_votes: row.try_get("num_votes").ok(),
+            out_of_date: if version_matches {
+                row.try_get("out_of_date").ok().flatten()
+            } else {
+                None
+            },
+            maintainer: row.try_get("maintainer").ok().flatten(),
+            submitter: row.try_get("submitter").ok().flatten(),
+            first_submitted: row.try_get("first_submitted").ok(),
+            last_modified: if version_matches {
+                row.try_get("last_modified").ok()
+            } else {
+                None
+            },
+            supplement_from_base: row.get("supplement_from_base"),
+        };
+
+        let package_name: String = row.get("pkg_name");
+        let pkg_branch: String = row.get("branch");
+
+        let tables = vec![
+            ("pkg_depends", "depend"),
+            ("pkg_make_depends", "make_depend"),
+            ("pkg_opt_depends", "opt_depend"),
+            ("pkg_check_depends", "check_depend"),
+            ("pkg_provides", "provide"),
+            ("pkg_conflicts", "conflict"),
+            ("pkg_replaces", "replace"),
+            ("pkg_groups", "group_name"),
+            ("pkg_licenses", "license"),
+        ];
+
+        let mut depends = Vec::new();
+        let mut make_depends = Vec::new();
+        let mut opt_depends = Vec::new();
+        let mut check_depends = Vec::new();
+        let mut provides = Vec::new();
+        let mut conflicts = Vec::new();
+        let mut replaces = Vec::new();
+        let mut groups = Vec::new();
+        let mut licenses = Vec::new();
+
+        for (table, column) in tables {
+            let query = format!(
+                "SELECT {} FROM {} WHERE pkg_name = ? AND branch = ?",
+                column, table
+            );
+            let values = sqlx::query(&query)
+                .bind(&package_name)
+                .bind(&pkg_branch)
+                .fetch(&mut *conn)
+                .map_ok(|row| row.get::<String, _>(column))
+                .try_collect()
+                .await?;
+
+            match column {
+                "depend" => depends = values,
+                "make_depend" => make_depends = values,
+                "opt_depend" => opt_depends = values,
+                "check_depend" => check_depends = values,
+                "provide" => provides = values,
+                "conflict" => conflicts = values,
+                "replace" => replaces = values,
+                "group_name" => groups = values,
+                "license" => licenses = values,
+                _ => {}
+            }
+        }
+
+        // Parse keywords and co_maintainers from JSON
+        let keywords: Vec<String> = row
+            .try_get::<Option<String>, _>("keywords")
+            .ok()
+            .flatten()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+
+        let co_maintainers: Vec<String> = row
+            .try_get::<Option<String>, _>("co_maintainers")
+            .ok()
+            .flatten()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+
+        // Branches indexed before licenses were parsed have none until
+        // their next change, so fall back to the supplement data
+        if licenses.is_empty() {
+            licenses = row
+                .try_get::<Option<String>, _>("s_licenses")
+                .ok()
+                .flatten()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default();
+        }
+
+        results.push(DatabasePackageDetailsWithSupplement {
+            info,
+            depends,
+            make_depends,
+            opt_depends,
+            check_depends,
+            provides,
+            conflicts,
+            replaces,
+            groups,
+            licenses,
+            keywords,
+            co_maintainers,
+        });
+    }
+
+    Ok(results)
+}
+
+/// [`DatabaseOps::get_srcinfos`] on `conn`.
+async fn get_srcinfos_with_conn(
+    conn: &mut SqliteConnection,
+    branches: &[String],
+) -> Result<Vec<DatabaseSrcInfo>> {
+    if branches.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders = vec!["?"; branches.len()].join(", ");
+    let query = format!(
+        "SELECT branch, commit_id, blob_id, srcinfo FROM pkg_srcinfo WHERE branch IN ({})",
+        placeholders
+    );
+    let rows = branches
+        .iter()
+        .fold(sqlx::query(&query), |query, branch| query.bind(branch))
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| DatabaseSrcInfo {
+            branch: row.get("branch"),
+            commit_id: row.get("commit_id"),
+            blob_id: row.get("blob_id"),
+            srcinfo: row.get("srcinfo"),
+        })
+        .collect())
+}
+
+/// [`DatabaseOps::get_history_versions`] on `conn`.
+async fn get_history_versions_with_conn(
+    conn: &mut SqliteConnection,
+    pkg_names: &[String],
+    as_of: i64,
+) -> Result<Vec<DatabaseHistoryVersion>> {
+    if pkg_names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders = vec!["?"; pkg_names.len()].join(", ");
+    let query = format!(
+        r#"
+        SELECT h.pkg_name, h.commit_id, h.committed_at, h.version, h.details
+        FROM pkg_history h
+        WHERE h.pkg_name IN ({}) AND h.details IS NOT NULL
+        AND h.committed_at = (
+            SELECT MAX(committed_at) FROM pkg_history
+            WHERE pkg_name = h.pkg_name AND committed_at <= ?
+        )
+        ORDER BY h.pkg_name, h.commit_id
+    "#,
+        placeholders
+    );
+    let rows = pkg_names
+        .iter()
+        .fold(sqlx::query(&query), |query, name| query.bind(name))
+        .bind(as_of)
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut versions: Vec<DatabaseHistoryVersion> = Vec::new();
+    for row in rows {
+        let pkg_name: String = row.get("pkg_name");
+        // Several commits may share a timestamp; keep one per package
+        if versions
+            .last()
+            .is_some_and(|last| last.pkg_name == pkg_name)
+        {
+            continue;
+        }
+        versions.push(DatabaseHistoryVersion {
+            pkg_name,
+            commit_id: row.get("commit_id"),
+            committed_at: row.get("committed_at"),
+            version: row.get("version"),
+            details: serde_json::from_str(row.get("details"))?,
+        });
+    }
+    Ok(versions)
+}
+
+/// Number of packages matching a search.
+async fn count_search_results_with_conn(
+    conn: &mut SqliteConnection,
+    search_type: SearchType,
+    keyword: &str,
+) -> Result<u64> {
+    let (filter, param, count) = search_filter(search_type, keyword);
+    let query = format!(
+        "SELECT COUNT(*) FROM (SELECT DISTINCT p.branch, p.pkg_name FROM pkg_info p {})",
+        filter
+    );
+    let mut query_builder = sqlx::query_scalar::<_, i64>(&query);
+    for _ in 0..count {
+        query_builder = query_builder.bind(&param);
+    }
+    Ok(query_builder.fetch_one(&mut *conn).await? as u64)
+}
+
+/// Joins and conditions of a search, with the bound parameter and how often
+/// it is bound.
+fn search_filter(search_type: SearchType, keyword: &str) -> (&'static str, String, usize) {
+    match search_type {
+        SearchType::Name => (
+            r#"
+                WHERE p.pkg_name LIKE ? AND p.is_listed = 1
+            "#,
+            format!("%{}%", keyword),
+            1,
+        ),
+        SearchType::NameDesc => (
+            r#"
+                WHERE (p.pkg_name LIKE ? OR p.pkg_desc LIKE ?) AND p.is_listed = 1
+            "#,
+            format!("%{}%", keyword),
+            2,
+        ),
+        SearchType::Depends => (
+            r#"
+                JOIN pkg_depends d ON p.pkg_name = d.pkg_name AND p.branch = d.branch
+                WHERE d.depend = ? AND p.is_listed = 1
+            "#,
+            keyword.to_string(),
+            1,
+        ),
+        SearchType::MakeDepends => (
+            r#"
+                JOIN pkg_make_depends md ON p.pkg_name = md.pkg_name AND p.branch = md.branch
+                WHERE md.make_depend = ? AND p.is_listed = 1
+            "#,
+            keyword.to_string(),
+            1,
+        ),
+        SearchType::OptDepends => (
+            r#"
+                JOIN pkg_opt_depends od ON p.pkg_name = od.pkg_name AND p.branch = od.branch
+                WHERE od.opt_depend = ? AND p.is_listed = 1
+            "#,
+            keyword.to_string(),
+            1,
+        ),
+        SearchType::CheckDepends => (
+            r#"
+                JOIN pkg_check_depends cd ON p.pkg_name = cd.pkg_name AND p.branch = cd.branch
+                WHERE cd.check_depend = ? AND p.is_listed = 1
+            "#,
+            keyword.to_string(),
+            1,
+        ),
+        // Maintainers are per package base, so packages split off after the
+        // last supplement update match through their siblings
+        SearchType::Maintainer => (
+            r#"
+                WHERE EXISTS (
+                    SELECT 1 FROM pkg_supplement ms
+                    WHERE ms.package_base = p.branch AND ms.maintainer = ? COLLATE NOCASE
+                ) AND p.is_listed = 1
+            "#,
+            keyword.to_string(),
+            1,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DatabasePackageDetails;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// The package as of rewrite `n`: every list names the rewrite, so a
+    /// mix of two rewrites shows in the results.
+    fn rewrite(n: usize) -> DatabasePackageDetails {
+        let list = |kind: &str| vec![format!("{}-{}", kind, n), format!("{}-{}-b", kind, n)];
+        DatabasePackageDetails {
+            commit_id: format!("{:040}", n),
+            committed_at: n as i64,
+            version: format!("{}-1", n),
+            depends: list("depend"),
+            make_depends: list("make"),
+            opt_depends: list("opt"),
+            check_depends: list("check"),
+            provides: list("provide"),
+            conflicts: list("conflict"),
+            replaces: list("replace"),
+            groups: list("group"),
+            licenses: list("license"),
+            ..DatabasePackageDetails::test_package("foo")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn package_details_are_read_from_one_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let db = DatabaseOps::new(db_path.to_str().unwrap(), false, false)
+            .await
+            .unwrap();
+        let mut tx = db.begin_transaction().await.unwrap();
+        db.update_index_with_tx(&mut tx, &[rewrite(0)])
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = tokio::spawn({
+            let db = db.clone();
+            let done = done.clone();
+            async move {
+                for n in 1..=200 {
+                    let mut tx = db.begin_transaction().await.unwrap();
+                    db.clear_index_with_tx(&mut tx, "foo").await.unwrap();
+                    db.update_index_with_tx(&mut tx, &[rewrite(n)])
+                        .await
+                        .unwrap();
+                    tx.commit().await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+                done.store(true, Ordering::Relaxed);
+            }
+        });
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let db = db.clone();
+                let done = done.clone();
+                tokio::spawn(async move {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let details = db.get_package_details(&["foo".to_string()]).await.unwrap();
+                        let [details] = &details[..] else {
+                            panic!("foo is always there, got {} results", details.len());
+                        };
+                        let n: usize = details.info.commit_id.parse().unwrap();
+                        let expected = rewrite(n);
+                        assert_eq!(details.info.version, expected.version);
+                        assert_eq!(details.depends, expected.depends);
+                        assert_eq!(details.make_depends, expected.make_depends);
+                        assert_eq!(details.opt_depends, expected.opt_depends);
+                        assert_eq!(details.check_depends, expected.check_depends);
+                        assert_eq!(details.provides, expected.provides);
+                        assert_eq!(details.conflicts, expected.conflicts);
+                        assert_eq!(details.replaces, expected.replaces);
+                        assert_eq!(details.groups, expected.groups);
+                        assert_eq!(details.licenses, expected.licenses);
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+
+        writer.await.unwrap();
+        for reader in readers {
+            assert!(reader.await.unwrap() > 0);
+        }
+    }
+}
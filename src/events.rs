@@ -0,0 +1,224 @@
+use crate::database::DatabaseOps;
+use crate::types::{DatabasePackageChange, DatabaseSyncRun};
+use crate::webhooks::ChangedPackage;
+use anyhow::Result;
+use axum::response::sse::Event;
+use futures::stream::{self, Stream, StreamExt};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+/// Comment lines sent on idle streams, so proxies don't drop them
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the database is checked for new changes and finished runs
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Events buffered per client; slower clients catch up from the database
+const CHANNEL_CAPACITY: usize = 1024;
+/// Changes read from the database at once
+const PAGE_SIZE: u32 = 500;
+
+#[derive(Debug)]
+enum PackageEvent {
+    Changed(DatabasePackageChange),
+    SyncFinished {
+        run: DatabaseSyncRun,
+        changed_packages: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct SyncSummary<'a> {
+    #[serde(flatten)]
+    run: &'a DatabaseSyncRun,
+    changed_packages: i64,
+}
+
+/// Broadcasts package changes and finished sync runs to event stream
+/// clients.
+///
+/// Syncs may run in another process, so instead of being told by the
+/// syncer, the hub polls the `pkg_changes` and `sync_runs` tables.
+pub struct EventHub {
+    db: DatabaseOps,
+    sender: broadcast::Sender<Arc<PackageEvent>>,
+    /// Newest change broadcast so far
+    last_change_id: AtomicI64,
+    /// Newest finished sync run broadcast so far
+    last_run_id: AtomicI64,
+    connections: Arc<Semaphore>,
+    /// Ends all streams, so they don't hold up a graceful shutdown
+    shutdown: CancellationToken,
+}
+
+/// State of one client's stream.
+struct Subscription {
+    hub: Arc<EventHub>,
+    receiver: broadcast::Receiver<Arc<PackageEvent>>,
+    /// Set while changes after this id are read from the database
+    replay_after: Option<i64>,
+    last_sent_change_id: i64,
+    buffer: VecDeque<Event>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl EventHub {
+    /// Only changes recorded from now on are broadcast.
+    pub async fn new(
+        db: DatabaseOps,
+        max_connections: usize,
+        shutdown: CancellationToken,
+    ) -> Result<Self> {
+        let last_change_id = db.get_last_change_id().await?;
+        let last_run_id = db.get_last_finished_sync_run_id().await?;
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Ok(Self {
+            db,
+            sender,
+            last_change_id: AtomicI64::new(last_change_id),
+            last_run_id: AtomicI64::new(last_run_id),
+            connections: Arc::new(Semaphore::new(max_connections)),
+            shutdown,
+        })
+    }
+
+    pub fn spawn_watch(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                if let Err(e) = self.poll().await {
+                    error!("Failed to check for package changes: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn poll(&self) -> Result<()> {
+        // Runs first: once a run is seen finished, all its changes are stored
+        let runs = self
+            .db
+            .get_finished_sync_runs_after(self.last_run_id.load(Ordering::Acquire))
+            .await?;
+        loop {
+            let after = self.last_change_id.load(Ordering::Acquire);
+            let changes = self.db.get_changes_after(after, PAGE_SIZE).await?;
+            let Some(last_id) = changes.last().map(|change| change.id) else {
+                break;
+            };
+            for change in changes {
+                // Fails only without subscribers
+                let _ = self.sender.send(Arc::new(PackageEvent::Changed(change)));
+            }
+            self.last_change_id.store(last_id, Ordering::Release);
+        }
+        for run in runs {
+            let changed_packages = self.db.count_changes_for_run(run.id).await?;
+            self.last_run_id.store(run.id, Ordering::Release);
+            let _ = self.sender.send(Arc::new(PackageEvent::SyncFinished {
+                run,
+                changed_packages,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Changes after `last_event_id` read from the database, then live
+    /// events. `None` when all connection slots are taken.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        last_event_id: Option<i64>,
+    ) -> Option<impl Stream<Item = Result<Event, Infallible>>> {
+        let permit = self.connections.clone().try_acquire_owned().ok()?;
+        let receiver = self.sender.subscribe();
+        let current = self.last_change_id.load(Ordering::Acquire);
+        // Ids from the future would filter out every live event
+        let replay_after = last_event_id.map(|id| id.min(current));
+        let subscription = Subscription {
+            hub: self.clone(),
+            receiver,
+            replay_after,
+            last_sent_change_id: replay_after.unwrap_or(current),
+            buffer: VecDeque::new(),
+            _permit: permit,
+        };
+        Some(
+            stream::unfold(subscription, |mut subscription| async move {
+                let event = subscription.next_event().await?;
+                Some((Ok(event), subscription))
+            })
+            .take_until(self.shutdown.clone().cancelled_owned()),
+        )
+    }
+}
+
+impl Subscription {
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.buffer.pop_front() {
+                return Some(event);
+            }
+            if let Some(after) = self.replay_after {
+                match self.hub.db.get_changes_after(after, PAGE_SIZE).await {
+                    Ok(changes) => {
+                        self.replay_after = (changes.len() == PAGE_SIZE as usize)
+                            .then(|| changes.last().map(|change| change.id))
+                            .flatten();
+                        for change in &changes {
+                            self.last_sent_change_id = change.id;
+                            self.buffer.extend(change_event(change));
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to replay package changes: {}", e);
+                        self.replay_after = None;
+                    }
+                }
+                continue;
+            }
+            match self.receiver.recv().await {
+                Ok(event) => match &*event {
+                    // Already sent while replaying
+                    PackageEvent::Changed(change) if change.id <= self.last_sent_change_id => {}
+                    PackageEvent::Changed(change) => {
+                        self.last_sent_change_id = change.id;
+                        self.buffer.extend(change_event(change));
+                    }
+                    PackageEvent::SyncFinished {
+                        run,
+                        changed_packages,
+                    } => self.buffer.extend(sync_event(run, *changed_packages)),
+                },
+                // Missed changes are still in the database; summaries are lost
+                Err(RecvError::Lagged(_)) => self.replay_after = Some(self.last_sent_change_id),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+fn change_event(change: &DatabasePackageChange) -> Option<Event> {
+    Event::default()
+        .event("package")
+        .id(change.id.to_string())
+        .json_data(ChangedPackage::new(change))
+        .inspect_err(|e| error!("Failed to serialize package event: {}", e))
+        .ok()
+}
+
+/// Without an id, so `Last-Event-ID` keeps pointing at the last change.
+fn sync_event(run: &DatabaseSyncRun, changed_packages: i64) -> Option<Event> {
+    Event::default()
+        .event("sync")
+        .json_data(SyncSummary {
+            run,
+            changed_packages,
+        })
+        .inspect_err(|e| error!("Failed to serialize sync event: {}", e))
+        .ok()
+}
@@ -0,0 +1,71 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+
+/// Result fields requested through the `fields` parameter, in the order
+/// the result type declares them.
+#[derive(Debug, Clone)]
+pub struct FieldSelection(Vec<&'static str>);
+
+impl FieldSelection {
+    /// Parse a comma-separated list of field names out of `known`, ignoring
+    /// ASCII case. Unknown names and an empty list are rejected.
+    pub fn parse(fields: &str, known: &[&'static str]) -> Result<Self, &'static str> {
+        let mut requested = Vec::new();
+        for name in fields
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let field = known
+                .iter()
+                .find(|field| field.eq_ignore_ascii_case(name))
+                .ok_or("Incorrect fields specified.")?;
+            requested.push(*field);
+        }
+        if requested.is_empty() {
+            return Err("Incorrect fields specified.");
+        }
+        Ok(Self(
+            known
+                .iter()
+                .copied()
+                .filter(|field| requested.contains(field))
+                .collect(),
+        ))
+    }
+
+    /// Reduce every result to the selected fields. Fields a result omits,
+    /// like unset extensions, stay omitted.
+    pub fn select<T: Serialize>(&self, results: Vec<T>) -> Vec<SelectedFields> {
+        results
+            .into_iter()
+            .map(|result| self.select_one(result))
+            .collect()
+    }
+
+    pub fn select_one<T: Serialize>(&self, result: T) -> SelectedFields {
+        let Ok(Value::Object(mut object)) = serde_json::to_value(result) else {
+            return SelectedFields(Vec::new());
+        };
+        SelectedFields(
+            self.0
+                .iter()
+                .filter_map(|field| Some((*field, object.remove(*field)?)))
+                .collect(),
+        )
+    }
+}
+
+/// A result reduced to some of its fields; serialized as an object keeping
+/// the declaration order, which a `serde_json::Map` would sort.
+pub struct SelectedFields(Vec<(&'static str, Value)>);
+
+impl Serialize for SelectedFields {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (field, value) in &self.0 {
+            map.serialize_entry(field, value)?;
+        }
+        map.end()
+    }
+}
@@ -0,0 +1,132 @@
+use crate::database::DatabaseOps;
+use anyhow::Result;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// How often the database is checked for finished syncs and changed data
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub const LAST_SYNC_HEADER: HeaderName = HeaderName::from_static("x-mirror-last-sync");
+pub const SUPPLEMENT_AGE_HEADER: HeaderName = HeaderName::from_static("x-mirror-supplement-age");
+const STALE_WARNING: HeaderValue = HeaderValue::from_static("110 - \"Response is Stale\"");
+
+/// How fresh the served data is, kept in memory so every response can
+/// tell without asking the database.
+///
+/// Syncs may run in another process, so like the event hub this polls the
+/// database: the times are only read again once a sync run finished or the
+/// data version moved.
+pub struct MirrorFreshness {
+    db: DatabaseOps,
+    /// Age of either time above which responses are marked stale
+    stale_after: Option<Duration>,
+    state: RwLock<FreshnessState>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FreshnessState {
+    last_run_id: i64,
+    generation: i64,
+    /// When the last successful sync finished
+    last_sync: Option<i64>,
+    /// Newest `LastModified` of the supplement data
+    supplement_last_modified: Option<i64>,
+}
+
+impl MirrorFreshness {
+    pub async fn new(db: DatabaseOps, stale_after: Option<Duration>) -> Result<Self> {
+        let state = FreshnessState::load(&db).await?;
+        Ok(Self {
+            db,
+            stale_after,
+            state: RwLock::new(state),
+        })
+    }
+
+    pub fn spawn_watch(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                if let Err(e) = self.poll().await {
+                    error!("Failed to check the freshness of the mirror: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn poll(&self) -> Result<()> {
+        let last_run_id = self.db.get_last_finished_sync_run_id().await?;
+        let generation = data_generation(&self.db).await?;
+        let current = *self.state.read().unwrap();
+        if current.last_run_id == last_run_id && current.generation == generation {
+            return Ok(());
+        }
+        let state = FreshnessState::load(&self.db).await?;
+        *self.state.write().unwrap() = state;
+        Ok(())
+    }
+
+    /// Add `X-Mirror-Last-Sync` and `X-Mirror-Supplement-Age`, each left out
+    /// while unknown, plus a `Warning: 110` once either is older than the
+    /// threshold. Without any successful sync the data counts as stale.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let state = *self.state.read().unwrap();
+        let now = unix_now();
+        let mut stale = false;
+        match state.last_sync {
+            Some(last_sync) => {
+                if let Some(value) =
+                    DateTime::<Utc>::from_timestamp(last_sync, 0).and_then(|time| {
+                        HeaderValue::from_str(&time.to_rfc3339_opts(SecondsFormat::Secs, true)).ok()
+                    })
+                {
+                    headers.insert(LAST_SYNC_HEADER, value);
+                }
+                stale |= self.is_stale(now - last_sync);
+            }
+            None => stale = self.stale_after.is_some(),
+        }
+        if let Some(last_modified) = state.supplement_last_modified {
+            let age = (now - last_modified).max(0);
+            headers.insert(SUPPLEMENT_AGE_HEADER, HeaderValue::from(age));
+            stale |= self.is_stale(age);
+        }
+        if stale {
+            headers.append(header::WARNING, STALE_WARNING);
+        }
+    }
+
+    fn is_stale(&self, age: i64) -> bool {
+        self.stale_after
+            .is_some_and(|stale_after| age > stale_after.as_secs() as i64)
+    }
+}
+
+impl FreshnessState {
+    async fn load(db: &DatabaseOps) -> Result<Self> {
+        Ok(Self {
+            last_run_id: db.get_last_finished_sync_run_id().await?,
+            generation: data_generation(db).await?,
+            last_sync: db.get_last_successful_sync().await?,
+            supplement_last_modified: db.get_supplement_last_modified().await?,
+        })
+    }
+}
+
+async fn data_generation(db: &DatabaseOps) -> Result<i64> {
+    Ok(db
+        .get_data_version()
+        .await?
+        .map(|version| version.generation)
+        .unwrap_or_default())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
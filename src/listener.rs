@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Result};
+use axum::extract::{connect_info::Connected, Request};
+use axum::middleware::{self, Next};
+use axum::serve::IncomingStream;
+use axum::Router;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{info, info_span, warn, Instrument};
+
+/// Socket permissions unless configured: the owner and its group, e.g. a
+/// reverse proxy added to the service's group
+const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Where the server listens: a TCP address (optionally prefixed with
+/// `http://`), with an `https://` prefix a TCP address serving TLS, or with
+/// a `unix:` prefix the path of a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Tls(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(addr: &str) -> Self {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            Self::Unix(PathBuf::from(path))
+        } else if let Some(addr) = addr.strip_prefix("https://") {
+            Self::Tls(addr.to_string())
+        } else {
+            Self::Tcp(addr.strip_prefix("http://").unwrap_or(addr).to_string())
+        }
+    }
+}
+
+impl ListenAddr {
+    /// The IPv4 port of a literal `a.b.c.d:port` TCP address.
+    fn ipv4_port(&self) -> Option<u16> {
+        match self {
+            Self::Tcp(addr) | Self::Tls(addr) => match addr.parse() {
+                Ok(SocketAddr::V4(addr)) => Some(addr.port()),
+                _ => None,
+            },
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Whether an IPv6 socket for `addr` must leave IPv4 connections to
+    /// another listener in `addrs` on the same port. Otherwise it accepts
+    /// them too, so `[::]:3000` alone serves both families.
+    pub fn needs_v6_only(addr: &str, addrs: &[ListenAddr]) -> bool {
+        addr.parse::<SocketAddr>().is_ok_and(|addr| {
+            addr.is_ipv6()
+                && addrs
+                    .iter()
+                    .any(|other| other.ipv4_port() == Some(addr.port()))
+        })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "http://{}", addr),
+            Self::Tls(addr) => write!(f, "https://{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UnixSocketOptions {
+    /// Octal permission bits, e.g. `660`
+    pub mode: Option<String>,
+    /// Group name or id the socket is handed to
+    pub group: Option<String>,
+}
+
+/// Address of a connection's peer. Unix socket peers are on the same host
+/// and count as loopback; behind a local proxy the client address comes
+/// from the trusted proxy headers anyway.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub IpAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(stream.remote_addr().ip())
+    }
+}
+
+/// Used by the TLS listeners, which are served by `axum_server`
+impl Connected<SocketAddr> for PeerAddr {
+    fn connect_info(addr: SocketAddr) -> Self {
+        Self(addr.ip())
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Self(IpAddr::V6(Ipv6Addr::LOCALHOST))
+    }
+}
+
+/// The listener a request arrived on, as a request extension.
+#[derive(Debug, Clone)]
+pub struct ListenerName(pub Arc<str>);
+
+/// Mark requests served by `app` as arriving on `addr`: the request gets a
+/// [`ListenerName`] extension and is handled within a `request` span naming
+/// the listener, so every log line it causes records the listener. The
+/// span's `request_id` is filled in by the access log middleware.
+pub fn tag_listener(app: Router, addr: &ListenAddr) -> Router {
+    let name = ListenerName(addr.to_string().into());
+    app.layer(middleware::from_fn(
+        move |mut request: Request, next: Next| {
+            let name = name.clone();
+            async move {
+                let span = info_span!(
+                    "request",
+                    listener = %name.0,
+                    request_id = tracing::field::Empty
+                );
+                request.extensions_mut().insert(name);
+                next.run(request).instrument(span).await
+            }
+        },
+    ))
+}
+
+/// Bind a TCP address, trying each address it resolves to in turn like
+/// [`TcpListener::bind`]. With `v6_only`, IPv6 sockets don't accept IPv4
+/// connections.
+pub async fn bind_tcp(addr: &str, v6_only: bool) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match bind_tcp_addr(addr, v6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+fn bind_tcp_addr(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // Like tokio, so a restart doesn't wait for TIME_WAIT connections
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Socket file of a bound Unix socket, removed when dropped.
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a stale socket file left by an
+/// unclean exit, and apply the configured permissions.
+pub fn bind_unix(path: &Path, options: &UnixSocketOptions) -> Result<(UnixListener, SocketFile)> {
+    let mode = match &options.mode {
+        Some(mode) => u32::from_str_radix(mode.trim(), 8)
+            .ok()
+            .filter(|&mode| mode <= 0o777)
+            .ok_or_else(|| anyhow!("Invalid Unix socket mode {}", mode))?,
+        None => DEFAULT_UNIX_SOCKET_MODE,
+    };
+    let gid = options.group.as_deref().map(resolve_group).transpose()?;
+
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow!("Failed to bind unix:{}: {}", path.display(), e))?;
+    let socket_file = SocketFile(path.to_path_buf());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    if let Some(gid) = gid {
+        std::os::unix::fs::chown(path, None, Some(gid))
+            .map_err(|e| anyhow!("Failed to change the group of {}: {}", path.display(), e))?;
+    }
+    Ok((listener, socket_file))
+}
+
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Ok(_) => {
+            return Err(anyhow!(
+                "{} exists and is not a socket, refusing to replace it",
+                path.display()
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    // A socket still accepting connections belongs to a running instance
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(anyhow!("{} is in use by another process", path.display()));
+    }
+    std::fs::remove_file(path)?;
+    info!("Removed stale socket {}", path.display());
+    Ok(())
+}
+
+fn resolve_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    nix::unistd::Group::from_name(group)?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| anyhow!("Unknown group {}", group))
+}
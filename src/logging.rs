@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Result};
+use chrono::SecondsFormat;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::field::{Field, Visit};
+use tracing::{info, Event, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// When the log file is started over, besides on reaching its size limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Never,
+    /// At the start of every hour, UTC
+    Hourly,
+    /// At midnight, UTC
+    Daily,
+}
+
+impl LogRotation {
+    /// Index of the period `time` falls in, `None` if the file is never
+    /// rotated by time.
+    fn period(self, time: SystemTime) -> Option<u64> {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self {
+            LogRotation::Never => None,
+            LogRotation::Hourly => Some(secs / 3600),
+            LogRotation::Daily => Some(secs / 86400),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFileOptions {
+    pub path: PathBuf,
+    /// `EnvFilter` directives of the file, independent of stderr
+    pub level: String,
+    pub rotation: LogRotation,
+    /// Size the file is rotated at, 0 for no limit
+    pub max_bytes: u64,
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.<keep>`
+    pub keep: usize,
+}
+
+/// Install the global subscriber. Logs go to stderr, so the output of
+/// commands can be piped, and to `file` if given, each with its own filter.
+/// `level` takes `EnvFilter` directives, e.g. `info,aur_mirror_meta::syncer=debug`.
+pub fn init(format: LogFormat, level: &str, file: Option<&LogFileOptions>) -> Result<()> {
+    let mut layers = vec![layer(format, io::stderr, true)
+        .with_filter(parse_filter(level)?)
+        .boxed()];
+    if let Some(options) = file {
+        let log_file = Arc::new(LogFile::open(options.clone())?);
+        layers.push(
+            layer(format, log_file.clone(), false)
+                .with_filter(parse_filter(&options.level)?)
+                .boxed(),
+        );
+        tracing_subscriber::registry().with(layers).init();
+        spawn_reopen(log_file)?;
+        info!("Writing the log to {}", options.path.display());
+    } else {
+        tracing_subscriber::registry().with(layers).init();
+    }
+    Ok(())
+}
+
+fn parse_filter(level: &str) -> Result<EnvFilter> {
+    EnvFilter::builder()
+        .parse(level)
+        .map_err(|e| anyhow!("Invalid log level {}: {}", level, e))
+}
+
+fn layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonFormat)
+            .boxed(),
+    }
+}
+
+/// Reopen the log file on SIGHUP or SIGUSR1, after logrotate moved it.
+fn spawn_reopen(log_file: Arc<LogFile>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {}
+                _ = user1.recv() => {}
+            }
+            match log_file.reopen() {
+                Ok(()) => info!("Reopened the log file"),
+                Err(e) => eprintln!("Failed to reopen the log file: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Log file rotated by size and time. Every event is written in one call
+/// under the lock, which rotation takes too, so lines from concurrent tasks
+/// are neither interleaved nor lost, and never split across files.
+struct LogFile {
+    options: LogFileOptions,
+    state: Mutex<LogFileState>,
+}
+
+struct LogFileState {
+    file: File,
+    size: u64,
+    /// Rotation period the file was started in
+    period: Option<u64>,
+}
+
+impl LogFile {
+    fn open(options: LogFileOptions) -> Result<Self> {
+        let state = open_log_file(&options)
+            .map_err(|e| anyhow!("Failed to open log file {}: {}", options.path.display(), e))?;
+        Ok(Self {
+            options,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn reopen(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        *state = open_log_file(&self.options)?;
+        Ok(())
+    }
+
+    /// Shift the rotated files up by one, dropping the oldest, and start
+    /// the file over.
+    fn rotate(&self, state: &mut LogFileState) -> io::Result<()> {
+        let path = &self.options.path;
+        let rotated = |n: usize| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.options.keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for n in (1..self.options.keep).rev() {
+                if rotated(n).exists() {
+                    std::fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated(1))?;
+        }
+        *state = open_log_file(&self.options)?;
+        state.period = self.options.rotation.period(SystemTime::now());
+        Ok(())
+    }
+}
+
+fn open_log_file(options: &LogFileOptions) -> io::Result<LogFileState> {
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(&options.path)?;
+    let metadata = file.metadata()?;
+    // A file carried over from an earlier run belongs to the period it was
+    // last written in
+    let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+    Ok(LogFileState {
+        file,
+        size: metadata.len(),
+        period: options.rotation.period(modified),
+    })
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let period = self.options.rotation.period(SystemTime::now());
+        let full =
+            self.options.max_bytes > 0 && state.size + buf.len() as u64 > self.options.max_bytes;
+        if state.size > 0 && (full || period != state.period) {
+            // Writing on is better than losing the line; the next attempt
+            // is due with the next period or another `max_bytes`
+            if let Err(e) = self.rotate(&mut state) {
+                eprintln!("Failed to rotate the log file: {}", e);
+                state.period = period;
+                state.size = 0;
+            }
+        }
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+/// Writes each event as a flat JSON object of `timestamp` (RFC 3339, UTC),
+/// `level`, `target`, `module`, the fields of the enclosing spans, e.g.
+/// `request_id`, and the event's own fields, with the text as `message`.
+/// Inner spans and the event win over outer spans on a name clash.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut object = Map::new();
+        object.insert(
+            "timestamp".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        );
+        object.insert("level".to_string(), metadata.level().as_str().into());
+        object.insert("target".to_string(), metadata.target().into());
+        if let Some(module) = metadata.module_path() {
+            object.insert("module".to_string(), module.into());
+        }
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                // Span fields are kept formatted by `JsonFields`
+                let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+                    continue;
+                };
+                if let Ok(Value::Object(fields)) = serde_json::from_str(fields) {
+                    object.extend(fields);
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut object));
+        let line = serde_json::to_string(&object).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{}", line)
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.0.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
@@ -1,34 +1,105 @@
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueHint};
+use clap_complete::Shell;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
+mod access_log;
 mod app_state;
 mod aur_fetcher;
+mod branch_filter;
+mod client_ip;
+mod commands;
+mod concurrency_limiter;
+mod conditional;
 mod config;
 mod database;
+mod events;
+mod fields;
+mod freshness;
+mod listener;
+mod logging;
+mod metrics;
+mod openapi;
+mod proxy;
+mod query_cache;
+mod request_limiter;
+mod request_timeout;
 mod rpc_server;
+mod snapshot;
+mod snapshot_cache;
+mod srcinfo_cache;
 mod srcinfo_parse;
 mod supplement_fetcher;
+mod supplement_refresher;
+mod sync_lock;
+mod sync_scheduler;
 mod syncer;
+mod systemd;
+mod throttle;
+mod tls;
 mod types;
+mod web_ui;
+mod webhooks;
 
 use app_state::AppState;
-use config::Config;
-use rpc_server::RpcServer;
+use branch_filter::BranchFilter;
+use client_ip::{ClientIpOptions, IpAccessOptions};
+use commands::{DryRunMode, ExportFormat};
+use config::{Config, ConfigFileModel};
+use listener::ListenAddr;
+use logging::{LogFileOptions, LogFormat};
+use proxy::ProxyOptions;
+use rpc_server::{RpcServer, Shutdown};
 use syncer::Syncer;
+use types::{SearchType, SyncRunStatus};
+
+/// Exit code of `sync` when the run completed but some branches failed
+const EXIT_SYNC_PARTIAL: i32 = 2;
+/// Exit code of `serve` when in-flight requests outlived the drain timeout
+const EXIT_FORCED_SHUTDOWN: i32 = 3;
+/// Exit code of `sync` when the run was aborted, e.g. network down or the
+/// database locked
+const EXIT_SYNC_ABORTED: i32 = 4;
+/// Exit code of `sync` when the run was stopped by SIGINT or SIGTERM
+const EXIT_SYNC_INTERRUPTED: i32 = 5;
 
 #[derive(Parser)]
 #[command(name = "aur-mirror-meta")]
 #[command(about = "AUR Mirror Meta Tool")]
 struct Cli {
     /// Path to config file
-    #[arg(long)]
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
     config: Option<PathBuf>,
 
+    /// Path to the database file. Overrides `db_path` of the config file
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    db_path: Option<String>,
+
+    /// Open the database read-only, for the commands that only read it.
+    /// Overrides `db_read_only` of the config file
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Validate the configuration and print the effective settings, then
+    /// exit without running the command
+    #[arg(long, global = true)]
+    check_config: bool,
+
+    /// Log format. Overrides `log_format` of the config file
+    #[arg(long, global = true, value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Log filter, a level or per-module directives like
+    /// `info,aur_mirror_meta::syncer=debug`. Overrides `log_level` of the
+    /// config file
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
@@ -40,34 +111,213 @@ enum Commands {
     },
     /// Sync metadata from AUR GitHub Mirror
     Sync {
-        /// Source(s) for supplementing metadata (can be 'none', a file path, or a URL).
-        /// Can be specified multiple times for fallback sources.
-        #[arg(short = 's', long = "supplement-source", default_values_t = vec!["https://aur.archlinux.org/packages-meta-ext-v1.json.gz".to_string()])]
+        /// Source(s) for supplementing metadata (can be 'none', '-' for stdin, a file path,
+        /// a file:// URI, or a URL).
+        /// Can be specified multiple times for fallback sources. Overrides
+        /// `supplement_sources` of the config file
+        #[arg(short = 's', long = "supplement-source")]
         supplement_source: Vec<String>,
+        /// Run exactly one sync pass and exit, as `sync` does by default;
+        /// for scripts that want to say so
+        #[arg(long)]
+        once: bool,
+        /// Only refresh the package base of this package, even if its commit
+        /// didn't move; repeatable
+        #[arg(long = "package", value_name = "NAME")]
+        packages: Vec<String>,
+        /// Only refresh this package base, even if its commit didn't move;
+        /// repeatable
+        #[arg(long = "pkgbase", value_name = "NAME")]
+        pkgbases: Vec<String>,
+        /// Only report what a sync would do, without writing to the
+        /// database; `--dry-run=fetch` also downloads and parses the
+        /// `.SRCINFO` of new and changed branches
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "diff",
+            conflicts_with_all = ["packages", "pkgbases"]
+        )]
+        dry_run: Option<DryRunMode>,
+        /// Wait for a sync running in another process to finish instead of
+        /// failing
+        #[arg(long)]
+        wait: bool,
+        /// Print a summary of the run to stdout as a single JSON object
+        #[arg(long)]
+        json: bool,
     },
     /// Start HTTP RPC server
     Serve {
-        /// Address to bind to
-        #[arg(long, default_values_t = vec!["[::]:3000".to_string()])]
+        /// Address to bind to, repeatable; `https://<addr>` serves TLS,
+        /// `unix:<path>` listens on a Unix domain socket. Overrides `listen`
+        /// of the config file
+        #[arg(long, visible_alias = "listen")]
         bind: Vec<String>,
+        /// Source(s) for the periodic and admin-triggered supplement refresh,
+        /// same as for `sync`.
+        #[arg(short = 's', long = "supplement-source")]
+        supplement_source: Vec<String>,
+    },
+    /// Search the local database for packages
+    Search {
+        keyword: String,
+        /// Field to search
+        #[arg(long, default_value = "name-desc", value_parser = commands::search_type_parser())]
+        by: SearchType,
+        /// Print the results as JSON, like the RPC does
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show details of packages from the local database
+    Info {
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// Print the details as JSON, like the RPC does
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write a dump of the local database in one of the aurweb formats
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+        /// File to write, '-' for stdout; gzip-compressed when ending with .gz
+        #[arg(short, long, default_value = "-", value_hint = ValueHint::FilePath)]
+        output: String,
+    },
+    /// Replace the supplement metadata with a packages-meta dump
+    Import {
+        /// Source(s) of the dump, tried in order like for `sync`
+        #[arg(required = true, value_hint = ValueHint::AnyPath)]
+        sources: Vec<String>,
+    },
+    /// Print counts and data ages of the local database
+    Stats {
+        /// Print the stats as JSON, like `/api/stats` does
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the completion script of a shell to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
     let cli = Cli::parse();
+    // Needs neither config nor database, e.g. when packaging
+    if let Some(Commands::Completions { shell }) = cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(());
+    }
 
-    let config = Config::new(cli.config);
+    // Settings given as flags, which `--check-config` attributes to them
+    let from_cli: Vec<&str> = [
+        ("db_path", cli.db_path.is_some()),
+        ("db_read_only", cli.read_only),
+        ("log_format", cli.log_format.is_some()),
+        ("log_level", cli.log_level.is_some()),
+        (
+            "listen",
+            matches!(&cli.command, Some(Commands::Serve { bind, .. }) if !bind.is_empty()),
+        ),
+        (
+            "supplement_sources",
+            matches!(
+                &cli.command,
+                Some(Commands::Serve { supplement_source, .. } | Commands::Sync { supplement_source, .. })
+                    if !supplement_source.is_empty()
+            ),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, given)| given.then_some(key))
+    .collect();
+
+    let config = Config::new(cli.config)?;
+    let log_format = cli.log_format.unwrap_or_else(|| config.log_format());
+    let log_level = cli.log_level.unwrap_or_else(|| config.log_level());
+    let log_file = config.log_file().map(|path| LogFileOptions {
+        path,
+        level: config.log_file_level().unwrap_or_else(|| log_level.clone()),
+        rotation: config.log_file_rotation(),
+        max_bytes: config.log_file_max_bytes(),
+        keep: config.log_file_keep(),
+    });
+    logging::init(log_format, &log_level, log_file.as_ref())?;
+    access_log::install_panic_hook();
     if let Some(config_path) = config.config_path() {
         info!("Config file: {}", config_path.display());
     }
+    config.warn_unknown_keys();
+
+    // Command line arguments take precedence over the config
+    let (bind, supplement_source) = match &cli.command {
+        Some(Commands::Serve {
+            bind,
+            supplement_source,
+        }) => (bind.clone(), supplement_source.clone()),
+        Some(Commands::Sync {
+            supplement_source, ..
+        }) => (Vec::new(), supplement_source.clone()),
+        _ => (Vec::new(), Vec::new()),
+    };
+    let listen = if bind.is_empty() {
+        config.listen()
+    } else {
+        bind
+    };
+    let supplement_source = if supplement_source.is_empty() {
+        config.supplement_sources()
+    } else {
+        supplement_source
+    };
+    let db_path = cli.db_path.or_else(|| config.db_path());
+    // A dry run must not create, migrate or otherwise write the database
+    let dry_run = matches!(
+        cli.command,
+        Some(Commands::Sync {
+            dry_run: Some(_),
+            ..
+        })
+    );
+    let db_read_only = cli.read_only || config.db_read_only() || dry_run;
+    if cli.check_config {
+        return check_config(
+            &config,
+            db_path,
+            db_read_only,
+            log_format,
+            log_level,
+            listen,
+            supplement_source,
+            &from_cli,
+        );
+    }
+    let Some(command) = cli.command else {
+        Cli::command()
+            .error(ErrorKind::MissingSubcommand, "a command is required")
+            .exit();
+    };
 
-    let db_path = config
-        .db_path()
-        .ok_or(anyhow!("Database path is not configured."))?;
-    info!("Database file: {}", db_path);
+    let db_path = db_path.ok_or(anyhow!("Database path is not configured."))?;
+    if dry_run && !Path::new(&db_path).exists() {
+        return Err(anyhow!(
+            "Database file {} does not exist; a dry run needs an indexed database",
+            db_path
+        ));
+    }
+    if db_read_only {
+        info!("Database file: {} (read-only)", db_path);
+    } else {
+        info!("Database file: {}", db_path);
+    }
 
     let github_token = config.github_token().or_else(|| {
         debug!("GitHub token is not set. Try `gh auth token`.");
@@ -87,24 +337,179 @@ async fn main() -> Result<()> {
             })
     });
 
-    let app_state = AppState::new(&db_path, github_token).await?;
+    let app_state = AppState::from_config(&config, &db_path, db_read_only, github_token).await?;
 
-    match cli.command {
+    match command {
         Commands::Login { token } => {
             config.modify_file(|model| {
                 model.github_token = Some(token);
             })?;
             info!("GitHub token saved to config file.");
         }
-        Commands::Sync { supplement_source } => {
-            let syncer = Syncer::new(app_state);
-            syncer.sync(&supplement_source).await?;
+        Commands::Sync {
+            dry_run: Some(mode),
+            json,
+            ..
+        } => {
+            let syncer = Syncer::new(app_state)?;
+            match syncer
+                .dry_run(mode == DryRunMode::Fetch, &CancellationToken::new())
+                .await
+            {
+                Ok(report) => commands::print_dry_run(&report, json)?,
+                Err(e) => {
+                    error!("Dry run failed: {}", e);
+                    std::process::exit(EXIT_SYNC_ABORTED);
+                }
+            }
         }
-        Commands::Serve { bind } => {
-            let server = RpcServer::new(app_state);
-            server.run(bind.iter()).await?;
+        Commands::Sync {
+            packages,
+            pkgbases,
+            wait,
+            json,
+            ..
+        } => {
+            let systemd = app_state.systemd.clone();
+            systemd.clone().spawn_watchdog();
+            let syncer = Syncer::new(app_state)?;
+            let (scope, unknown_packages) = if packages.is_empty() && pkgbases.is_empty() {
+                (None, Vec::new())
+            } else {
+                let (scope, unknown) = syncer.resolve_scope(&pkgbases, &packages).await?;
+                for package in &unknown {
+                    warn!("⚠ Package {} is not indexed", package);
+                }
+                if scope.is_empty() {
+                    return Err(anyhow!("None of the given packages are indexed"));
+                }
+                (Some(scope), unknown)
+            };
+            // The first SIGINT or SIGTERM stops the sync at its next
+            // checkpoint, rolling back the batch being written; a second one
+            // exits at once, which rolls back the same way
+            let cancel = CancellationToken::new();
+            tokio::spawn({
+                let cancel = cancel.clone();
+                async move {
+                    rpc_server::shutdown_signal().await;
+                    warn!("Stopping the sync, interrupt again to exit at once");
+                    cancel.cancel();
+                    rpc_server::shutdown_signal().await;
+                    std::process::exit(EXIT_SYNC_INTERRUPTED);
+                }
+            });
+            let mut summary = syncer
+                .sync(scope.as_deref(), &supplement_source, wait, &cancel)
+                .await;
+            summary.unknown_packages = unknown_packages;
+            // Without a server, the service is "started" once the sync is done
+            systemd.ready();
+            if json {
+                println!("{}", serde_json::to_string(&summary)?);
+            }
+            info!(
+                "Sync summary: {} branches updated, {} failed, {} packages indexed, {} bytes fetched in {:.1}s",
+                summary.branches_updated,
+                summary.branches_failed,
+                summary.packages_indexed,
+                summary.bytes_fetched,
+                summary.duration_secs
+            );
+            if summary.status == SyncRunStatus::Cancelled {
+                warn!(
+                    "Sync interrupted after {} branches; the batch in progress was rolled back",
+                    summary.branches_updated
+                );
+                std::process::exit(EXIT_SYNC_INTERRUPTED);
+            }
+            if summary.status != SyncRunStatus::Succeeded {
+                if let Some(e) = &summary.error {
+                    error!("Sync failed: {}", e);
+                }
+                std::process::exit(EXIT_SYNC_ABORTED);
+            }
+            if summary.branches_failed > 0
+                || !summary.unknown_packages.is_empty()
+                || !summary.unknown_pkgbases.is_empty()
+            {
+                std::process::exit(EXIT_SYNC_PARTIAL);
+            }
         }
+        Commands::Serve { .. } => {
+            let server = RpcServer::new(app_state, supplement_source).await?;
+            let addrs: Vec<_> = listen.iter().map(|addr| ListenAddr::parse(addr)).collect();
+            if server.run(&addrs).await? == Shutdown::Forced {
+                std::process::exit(EXIT_FORCED_SHUTDOWN);
+            }
+        }
+        Commands::Search { keyword, by, json } => {
+            commands::search(&app_state, &keyword, by, json).await?;
+        }
+        Commands::Info { names, json } => {
+            commands::info(&app_state, &names, json).await?;
+        }
+        Commands::Export { format, output } => {
+            commands::export(&app_state, format, &output).await?;
+        }
+        Commands::Import { sources } => {
+            commands::import(&app_state, &sources).await?;
+        }
+        Commands::Stats { json } => {
+            commands::stats(&app_state, json).await?;
+        }
+        Commands::Completions { .. } => unreachable!("handled before loading the config"),
     }
 
     Ok(())
 }
+
+/// Check what can be checked without starting, i.e. the address ranges,
+/// API keys, branch patterns and proxy settings, then print the effective
+/// config, each key marked with where its value came from.
+#[allow(clippy::too_many_arguments)]
+fn check_config(
+    config: &Config,
+    db_path: Option<String>,
+    db_read_only: bool,
+    log_format: LogFormat,
+    log_level: String,
+    listen: Vec<String>,
+    supplement_sources: Vec<String>,
+    from_cli: &[&str],
+) -> Result<()> {
+    for range in config
+        .rate_limit_allowlist()
+        .iter()
+        .chain(&config.metrics_allowlist())
+    {
+        client_ip::parse_ip_range(range)?;
+    }
+    client_ip::ClientIpSource::new(&ClientIpOptions {
+        trusted_proxy_headers: config.trusted_proxy_headers(),
+        trusted_proxies: config.trusted_proxies(),
+    })?;
+    client_ip::IpAccess::new(&IpAccessOptions {
+        denylist: config.ip_denylist(),
+        allowlist: config.ip_allowlist(),
+    })?;
+    request_limiter::ApiKeys::new(&config.api_keys())?;
+    BranchFilter::new(config.branch_include(), config.branch_exclude())?;
+    proxy::PassThroughProxy::new(&ProxyOptions {
+        upstream: config.proxy_upstream(),
+        path_prefixes: config.proxy_path_prefixes(),
+        timeout: config.proxy_timeout(),
+        rate_limit: config.proxy_rate_limit(),
+    })?;
+    let effective = ConfigFileModel {
+        db_path,
+        db_read_only: Some(db_read_only),
+        log_format: Some(log_format),
+        log_file_level: Some(config.log_file_level().unwrap_or_else(|| log_level.clone())),
+        log_level: Some(log_level),
+        ..config.effective(listen, supplement_sources)
+    };
+    let effective = toml::to_string_pretty(&effective)?;
+    print!("{}", config.annotate_sources(&effective, from_cli));
+    Ok(())
+}
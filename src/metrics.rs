@@ -0,0 +1,178 @@
+use anyhow::Result;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Prometheus metrics shared by the HTTP layer, the fetcher and the syncer.
+pub struct Metrics {
+    registry: Registry,
+    /// Requests by matched route, method and status
+    pub http_requests: IntCounterVec,
+    /// Request latency by matched route
+    pub http_request_duration: HistogramVec,
+    /// Requests being handled right now
+    pub http_requests_in_flight: IntGauge,
+    /// Requests rejected by the concurrency limits, by matched route
+    pub http_requests_shed: IntCounterVec,
+    /// Requests aborted by their timeout, by matched route and what they
+    /// were waiting for
+    pub http_request_timeouts: IntCounterVec,
+    /// Requests sent with a valid API key, by key name and status
+    pub api_key_requests: IntCounterVec,
+    /// Bytes downloaded from upstream by fetch phase
+    pub fetch_bytes: IntCounterVec,
+    /// Duration of each phase of a sync run
+    pub sync_phase_duration: HistogramVec,
+    /// Finished sync runs by outcome
+    pub sync_runs: IntCounterVec,
+    /// Work queued at each stage of the running sync: chunks being fetched
+    /// (`fetch`), branches waiting to be parsed (`parse`) and to be written
+    /// (`write`)
+    pub sync_queue_depth: IntGaugeVec,
+    /// Query cache lookups by result (`hit`, `miss`)
+    pub query_cache_lookups: IntCounterVec,
+    pub query_coalesced: IntCounterVec,
+    /// Updated on scrape, see [`Metrics::render`]
+    pub db_pool_connections: IntGauge,
+    pub db_pool_idle_connections: IntGauge,
+    pub last_successful_sync: IntGauge,
+}
+
+/// See [`Metrics::track_in_flight`].
+pub struct InFlightGuard(IntGauge);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("aur_mirror".to_string()), None)?;
+        let http_requests = IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "HTTP requests by route, method and status",
+            ),
+            &["route", "method", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["route"],
+        )?;
+        let http_requests_in_flight =
+            IntGauge::new("http_requests_in_flight", "HTTP requests being handled")?;
+        let http_requests_shed = IntCounterVec::new(
+            Opts::new(
+                "http_requests_shed_total",
+                "HTTP requests rejected by the concurrency limits, by route",
+            ),
+            &["route"],
+        )?;
+        let http_request_timeouts = IntCounterVec::new(
+            Opts::new(
+                "http_request_timeouts_total",
+                "HTTP requests aborted by their timeout, by route and kind",
+            ),
+            &["route", "kind"],
+        )?;
+        let api_key_requests = IntCounterVec::new(
+            Opts::new(
+                "api_key_requests_total",
+                "HTTP requests with an API key by key and status",
+            ),
+            &["key", "status"],
+        )?;
+        let fetch_bytes = IntCounterVec::new(
+            Opts::new("fetch_bytes_total", "Bytes downloaded from the AUR mirror"),
+            &["phase"],
+        )?;
+        let sync_phase_duration = HistogramVec::new(
+            HistogramOpts::new("sync_phase_duration_seconds", "Duration of sync phases")
+                .buckets(vec![1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 1800.0, 3600.0]),
+            &["phase"],
+        )?;
+        let sync_runs = IntCounterVec::new(
+            Opts::new("sync_runs_total", "Finished sync runs by status"),
+            &["status"],
+        )?;
+        let sync_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "sync_queue_depth",
+                "Work queued at each stage of the running sync",
+            ),
+            &["stage"],
+        )?;
+        let query_cache_lookups = IntCounterVec::new(
+            Opts::new("query_cache_lookups_total", "Query cache lookups by result"),
+            &["result"],
+        )?;
+        let query_coalesced = IntCounterVec::new(
+            Opts::new(
+                "query_coalesced_total",
+                "Requests which waited for an identical query in flight",
+            ),
+            &["query"],
+        )?;
+        let db_pool_connections =
+            IntGauge::new("db_pool_connections", "Open database connections")?;
+        let db_pool_idle_connections =
+            IntGauge::new("db_pool_idle_connections", "Idle database connections")?;
+        let last_successful_sync = IntGauge::new(
+            "last_successful_sync_timestamp_seconds",
+            "Unix time the last successful sync finished",
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(http_requests_in_flight.clone()))?;
+        registry.register(Box::new(http_requests_shed.clone()))?;
+        registry.register(Box::new(http_request_timeouts.clone()))?;
+        registry.register(Box::new(api_key_requests.clone()))?;
+        registry.register(Box::new(fetch_bytes.clone()))?;
+        registry.register(Box::new(sync_phase_duration.clone()))?;
+        registry.register(Box::new(sync_runs.clone()))?;
+        registry.register(Box::new(sync_queue_depth.clone()))?;
+        registry.register(Box::new(query_cache_lookups.clone()))?;
+        registry.register(Box::new(query_coalesced.clone()))?;
+        registry.register(Box::new(db_pool_connections.clone()))?;
+        registry.register(Box::new(db_pool_idle_connections.clone()))?;
+        registry.register(Box::new(last_successful_sync.clone()))?;
+
+        Ok(Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            http_requests_in_flight,
+            http_requests_shed,
+            http_request_timeouts,
+            api_key_requests,
+            fetch_bytes,
+            sync_phase_duration,
+            sync_runs,
+            sync_queue_depth,
+            query_cache_lookups,
+            query_coalesced,
+            db_pool_connections,
+            db_pool_idle_connections,
+            last_successful_sync,
+        })
+    }
+
+    /// Count a request as in flight until the guard is dropped, which also
+    /// covers requests abandoned by their client.
+    pub fn track_in_flight(&self) -> InFlightGuard {
+        self.http_requests_in_flight.inc();
+        InFlightGuard(self.http_requests_in_flight.clone())
+    }
+
+    /// All metrics in the Prometheus text format. Gauges read from elsewhere
+    /// (database pool, sync runs) must be set by the caller beforehand.
+    pub fn render(&self) -> Result<String> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
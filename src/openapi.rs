@@ -0,0 +1,105 @@
+use crate::rpc_server;
+use crate::types::{
+    BranchFilterStats, DatabaseBranchSummary, DatabaseStats, DatabaseSyncRun, MirrorStats,
+    RpcPackageDetails, RpcPackageInfo, RpcResponse, RpcV5Response, SyncScheduleStats,
+};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::OpenApi as OpenApiDoc;
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "AUR Mirror Meta",
+        description = "Read-only mirror of the AUR RPC interface and package sources."
+    ),
+    paths(
+        rpc_server::handle_rpc_get,
+        rpc_server::handle_rpc_post,
+        rpc_server::handle_rpc_v5_get,
+        rpc_server::handle_rpc_v5_post,
+        rpc_server::handle_rpc_v5_path,
+        rpc_server::handle_suggest_path,
+        rpc_server::handle_suggest_pkgbase_path,
+        rpc_server::handle_v6_search,
+        rpc_server::handle_v6_info_get,
+        rpc_server::handle_v6_info_post,
+        rpc_server::handle_v6_info_path,
+        rpc_server::handle_packages_gz,
+        rpc_server::handle_pkgbase_gz,
+        rpc_server::handle_snapshot,
+        rpc_server::handle_cgit_plain,
+        rpc_server::handle_raw_file,
+        rpc_server::handle_srcinfo,
+        rpc_server::handle_git_info_refs,
+        rpc_server::handle_git_upload_pack_post,
+        rpc_server::handle_admin_supplement_refresh,
+        rpc_server::handle_admin_sync,
+        rpc_server::handle_admin_sync_status,
+        rpc_server::handle_admin_branches,
+        rpc_server::handle_events,
+        rpc_server::handle_stats,
+        rpc_server::handle_metrics,
+        rpc_server::handle_healthz,
+        rpc_server::handle_readyz,
+    ),
+    components(schemas(
+        RpcV5Response,
+        RpcResponse<RpcPackageInfo>,
+        RpcResponse<RpcPackageDetails>,
+        RpcPackageInfo,
+        RpcPackageDetails,
+        DatabaseSyncRun,
+        MirrorStats,
+        DatabaseStats,
+        SyncScheduleStats,
+        BranchFilterStats,
+        DatabaseBranchSummary,
+    )),
+    modifiers(&AdminToken),
+    tags(
+        (name = "rpc", description = "aurweb's RPC interface, version 5"),
+        (name = "rpc-v6", description = "Path-style v6 RPC routes"),
+        (name = "lists", description = "Name lists like aurweb's"),
+        (name = "git", description = "Package sources"),
+        (name = "admin", description = "Maintenance, needs the admin token"),
+        (name = "operations", description = "Monitoring and notifications"),
+    )
+)]
+struct ApiDoc;
+
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut OpenApiDoc) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+/// The description of the routes actually served: the v6 routes only when
+/// enabled, the admin routes only with a token configured and the
+/// `.SRCINFO` route only when they are stored.
+pub fn document(v6: bool, admin: bool, srcinfo: bool) -> OpenApiDoc {
+    let mut openapi = ApiDoc::openapi();
+    // Taken from Cargo.toml, which declares none
+    openapi.info.license = None;
+    openapi.paths.paths.retain(|path, _| {
+        (v6 || !path.starts_with("/api/v6/"))
+            && (admin || !path.starts_with("/admin/"))
+            && (srcinfo || !path.starts_with("/api/srcinfo/"))
+    });
+    if let Some(tags) = openapi.tags.as_mut() {
+        tags.retain(|tag| (v6 || tag.name != "rpc-v6") && (admin || tag.name != "admin"));
+    }
+    if !admin {
+        if let Some(components) = openapi.components.as_mut() {
+            components.security_schemes.clear();
+        }
+    }
+    openapi
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::TryStreamExt;
+use http_body::Body as _;
+use std::time::Duration;
+use tracing::warn;
+
+/// Headers describing a single connection, never forwarded, plus `Host`
+/// and `Authorization`, which may carry one of the mirror's API keys.
+const DROPPED_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "authorization",
+];
+
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    /// Base URL unmatched requests are forwarded to
+    pub upstream: String,
+    /// Path prefixes forwarded; empty disables the proxy
+    pub path_prefixes: Vec<String>,
+    /// Limit for the whole exchange, including the response body
+    pub timeout: Duration,
+    /// Whether proxied requests count towards the mirror's request limit
+    pub rate_limit: bool,
+}
+
+/// Marks responses of proxied requests, for the access log.
+#[derive(Debug, Clone, Copy)]
+pub struct Proxied;
+
+/// Forwards requests for paths the mirror doesn't serve itself to the AUR,
+/// so clients can use the mirror in place of its hostname.
+pub struct PassThroughProxy {
+    client: reqwest::Client,
+    upstream: String,
+    path_prefixes: Vec<String>,
+    timeout: Duration,
+}
+
+impl PassThroughProxy {
+    /// Returns `None` without any path prefix to forward.
+    pub fn new(options: &ProxyOptions) -> Result<Option<Self>> {
+        if options.path_prefixes.is_empty() {
+            return Ok(None);
+        }
+        let upstream = reqwest::Url::parse(&options.upstream)
+            .map_err(|e| anyhow!("Invalid proxy upstream {}: {}", options.upstream, e))?;
+        if !matches!(upstream.scheme(), "http" | "https") {
+            return Err(anyhow!(
+                "Proxy upstream {} is not an http(s) URL",
+                options.upstream
+            ));
+        }
+        // Redirects are the client's business, like any other response
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Some(Self {
+            client,
+            upstream: options.upstream.trim_end_matches('/').to_string(),
+            path_prefixes: options.path_prefixes.clone(),
+            timeout: options.timeout,
+        }))
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    pub fn forwards(&self, path: &str) -> bool {
+        self.path_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    /// Send `request` upstream and stream the answer back; the bodies are
+    /// passed through as they arrive in both directions.
+    pub async fn forward(&self, request: Request) -> Response {
+        let (parts, body) = request.into_parts();
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let mut upstream_request = self
+            .client
+            .request(parts.method, format!("{}{}", self.upstream, path))
+            .headers(forwarded_headers(parts.headers))
+            .timeout(self.timeout);
+        // A streamed body is sent chunked, which some servers refuse on GET
+        if body.size_hint().exact() != Some(0) {
+            upstream_request =
+                upstream_request.body(reqwest::Body::wrap_stream(body.into_data_stream()));
+        }
+        let upstream_response = match upstream_request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Proxying {} failed: {}", parts.uri.path(), e);
+                let status = if e.is_timeout() {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::BAD_GATEWAY
+                };
+                let mut response = status.into_response();
+                response.extensions_mut().insert(Proxied);
+                return response;
+            }
+        };
+
+        let mut response = Response::builder().status(upstream_response.status());
+        if let Some(headers) = response.headers_mut() {
+            *headers = forwarded_headers(upstream_response.headers().clone());
+        }
+        let body = upstream_response
+            .bytes_stream()
+            .inspect_err(|e| warn!("Proxied response body failed: {}", e));
+        let mut response = response
+            .body(Body::from_stream(body))
+            .unwrap_or_else(|_| StatusCode::BAD_GATEWAY.into_response());
+        response.extensions_mut().insert(Proxied);
+        response
+    }
+}
+
+fn forwarded_headers(mut headers: HeaderMap) -> HeaderMap {
+    for name in DROPPED_HEADERS {
+        headers.remove(*name);
+    }
+    headers
+}
@@ -0,0 +1,365 @@
+use crate::database::{DatabaseOps, PackageInfoStream};
+use crate::metrics::Metrics;
+use crate::request_timeout::keep_deadline;
+use crate::types::{
+    DatabasePackageDetailsWithSupplement, DatabasePackageInfoWithSupplement, SearchPage,
+    SearchType, SortBy,
+};
+use anyhow::{anyhow, Result};
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, StreamExt};
+use moka::future::Cache;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+pub struct QueryCacheOptions {
+    /// Most cached results; 0 disables the cache
+    pub capacity: u64,
+    pub ttl: Duration,
+}
+
+/// Search and info lookups answered from memory when the same query was
+/// run recently.
+///
+/// Entries are keyed by the data version, so results never outlive a sync
+/// commit or a supplement update, even one made by another process. On a
+/// version change the whole cache is dropped.
+///
+/// Identical queries running at the same time share one database query,
+/// cached or not. It runs on its own task, so it completes for the others
+/// even when the request that started it goes away.
+#[derive(Clone)]
+pub struct QueryCache {
+    db: DatabaseOps,
+    inner: Option<Arc<Inner>>,
+    in_flight: Arc<InFlight>,
+}
+
+struct Inner {
+    cache: Cache<(i64, QueryKey), CachedResult>,
+    generation: AtomicI64,
+    metrics: Arc<Metrics>,
+}
+
+/// Queries running right now, by data version (if known) and query.
+struct InFlight {
+    queries: Mutex<HashMap<(Option<i64>, QueryKey), SharedQuery>>,
+    metrics: Arc<Metrics>,
+}
+
+type SharedQuery = Shared<BoxFuture<'static, Result<CachedResult, Arc<anyhow::Error>>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum QueryKey {
+    /// At most `limit` matches of an unpaginated search
+    Search {
+        search_type: SearchType,
+        keyword: String,
+        sort: Option<SortBy>,
+        limit: u64,
+    },
+    Page {
+        search_type: SearchType,
+        keyword: String,
+        sort: Option<SortBy>,
+        page: SearchPage,
+    },
+    /// Names sorted and deduplicated, as their order doesn't matter
+    Info { names: Vec<String> },
+}
+
+impl QueryKey {
+    /// Label of the query kind in metrics
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Search { .. } => "search",
+            Self::Page { .. } => "page",
+            Self::Info { .. } => "info",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CachedResult {
+    Search(Arc<Vec<DatabasePackageInfoWithSupplement>>),
+    /// Number of all matches along with the page
+    Page(u64, Arc<Vec<DatabasePackageInfoWithSupplement>>),
+    Info(Arc<Vec<DatabasePackageDetailsWithSupplement>>),
+}
+
+impl QueryCache {
+    pub fn new(db: DatabaseOps, options: &QueryCacheOptions, metrics: Arc<Metrics>) -> Self {
+        let inner = (options.capacity > 0).then(|| {
+            Arc::new(Inner {
+                cache: Cache::builder()
+                    .max_capacity(options.capacity)
+                    .time_to_live(options.ttl)
+                    .build(),
+                generation: AtomicI64::new(0),
+                metrics: metrics.clone(),
+            })
+        });
+        let in_flight = Arc::new(InFlight {
+            queries: Mutex::new(HashMap::new()),
+            metrics,
+        });
+        Self {
+            db,
+            inner,
+            in_flight,
+        }
+    }
+
+    /// An unpaginated search as a stream, see
+    /// [`DatabaseOps::stream_search`]. Through the cache the matches are
+    /// held in memory anyway, so they are streamed from the cached list,
+    /// each copied only when it is read.
+    /// Only one match more than `max_results` is read, enough to tell that
+    /// there are too many; the total is then `max_results + 1`.
+    pub async fn stream_search(
+        &self,
+        search_type: SearchType,
+        keyword: &str,
+        sort: Option<SortBy>,
+        max_results: u64,
+    ) -> Result<(u64, Option<PackageInfoStream>)> {
+        if self.inner.is_none() {
+            return self
+                .db
+                .stream_search(search_type, keyword, sort, max_results)
+                .await;
+        }
+        let limit = max_results + 1;
+        let key = QueryKey::Search {
+            search_type,
+            keyword: keyword.to_string(),
+            sort,
+            limit,
+        };
+        let db = self.db.clone();
+        let keyword = keyword.to_string();
+        let query = async move {
+            let packages = db
+                .search_packages_up_to(search_type, &keyword, sort, limit)
+                .await?;
+            Ok(CachedResult::Search(Arc::new(packages)))
+        };
+        let packages = match self.get_or_run(key, query).await? {
+            CachedResult::Search(packages) => packages,
+            _ => unreachable!("search keys only hold search results"),
+        };
+        let total = packages.len() as u64;
+        Ok((
+            total,
+            (total <= max_results).then(|| {
+                futures::stream::iter((0..packages.len()).map(move |i| Ok(packages[i].clone())))
+                    .boxed()
+            }),
+        ))
+    }
+
+    pub async fn search_page(
+        &self,
+        search_type: SearchType,
+        keyword: &str,
+        sort: Option<SortBy>,
+        page: SearchPage,
+    ) -> Result<(u64, Vec<DatabasePackageInfoWithSupplement>)> {
+        let key = QueryKey::Page {
+            search_type,
+            keyword: keyword.to_string(),
+            sort,
+            page,
+        };
+        let db = self.db.clone();
+        let keyword = keyword.to_string();
+        let query = async move {
+            let (total, packages) = db.search_page(search_type, &keyword, sort, page).await?;
+            Ok(CachedResult::Page(total, Arc::new(packages)))
+        };
+        match self.get_or_run(key, query).await? {
+            CachedResult::Page(total, packages) => Ok((total, packages.as_ref().clone())),
+            _ => unreachable!("page keys only hold pages"),
+        }
+    }
+
+    pub async fn get_package_details(
+        &self,
+        names: &[String],
+    ) -> Result<Vec<DatabasePackageDetailsWithSupplement>> {
+        let mut sorted_names = names.to_vec();
+        sorted_names.sort();
+        sorted_names.dedup();
+        let key = QueryKey::Info {
+            names: sorted_names,
+        };
+        let db = self.db.clone();
+        let names = names.to_vec();
+        let query = async move {
+            let details = db.get_package_details(&names).await?;
+            Ok(CachedResult::Info(Arc::new(details)))
+        };
+        match self.get_or_run(key, query).await? {
+            CachedResult::Info(details) => Ok(details.as_ref().clone()),
+            _ => unreachable!("info keys only hold package details"),
+        }
+    }
+
+    /// The cached result, else the one of `query`, which is stored unless
+    /// it failed. While `query` runs, identical requests wait for it rather
+    /// than running their own.
+    async fn get_or_run(
+        &self,
+        key: QueryKey,
+        query: impl Future<Output = Result<CachedResult>> + Send + 'static,
+    ) -> Result<CachedResult> {
+        let current = self.current().await;
+        if let Some((inner, generation)) = &current {
+            let cached = inner.cache.get(&(*generation, key.clone())).await;
+            let result = if cached.is_some() { "hit" } else { "miss" };
+            inner
+                .metrics
+                .query_cache_lookups
+                .with_label_values(&[result])
+                .inc();
+            if let Some(cached) = cached {
+                return Ok(cached);
+            }
+        }
+        let flight_key = (current.as_ref().map(|(_, generation)| *generation), key);
+        let shared = {
+            let mut queries = self.in_flight.queries.lock().unwrap();
+            match queries.get(&flight_key) {
+                Some(shared) => {
+                    self.in_flight
+                        .metrics
+                        .query_coalesced
+                        .with_label_values(&[flight_key.1.kind()])
+                        .inc();
+                    shared.clone()
+                }
+                None => {
+                    let shared = self
+                        .in_flight
+                        .clone()
+                        .spawn(flight_key.clone(), current, query);
+                    queries.insert(flight_key, shared.clone());
+                    shared
+                }
+            }
+        };
+        shared.await.map_err(|e| anyhow!("{}", e))
+    }
+
+    /// The cache with the current data version, dropping older entries on a
+    /// change. `None` when disabled or the version is unknown.
+    async fn current(&self) -> Option<(Arc<Inner>, i64)> {
+        let inner = self.inner.clone()?;
+        let generation = match self.db.get_data_version().await {
+            Ok(data_version) => data_version?.generation,
+            Err(e) => {
+                warn!(
+                    "Failed to read the data version, bypassing the cache: {}",
+                    e
+                );
+                return None;
+            }
+        };
+        if inner.generation.swap(generation, Ordering::AcqRel) != generation {
+            debug!(
+                "Data version is now {}, clearing the query cache",
+                generation
+            );
+            inner.cache.invalidate_all();
+        }
+        Some((inner, generation))
+    }
+}
+
+impl InFlight {
+    /// Run `query` on its own task, bounded by the deadline of the current
+    /// request, caching the result and dropping the entry once it's done.
+    fn spawn(
+        self: Arc<Self>,
+        flight_key: (Option<i64>, QueryKey),
+        cache: Option<(Arc<Inner>, i64)>,
+        query: impl Future<Output = Result<CachedResult>> + Send + 'static,
+    ) -> SharedQuery {
+        let task = tokio::spawn(keep_deadline(async move {
+            let result = query.await;
+            if let (Ok(result), Some((inner, generation))) = (&result, cache) {
+                inner
+                    .cache
+                    .insert((generation, flight_key.1.clone()), result.clone())
+                    .await;
+            }
+            // Cached first, so later requests find the result either way
+            self.queries.lock().unwrap().remove(&flight_key);
+            result.map_err(Arc::new)
+        }));
+        async move { task.await.map_err(|e| Arc::new(anyhow::Error::from(e)))? }
+            .boxed()
+            .shared()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DatabasePackageDetails;
+    use futures::TryStreamExt;
+    use tempfile::TempDir;
+
+    async fn cache_over(dir: &TempDir, names: &[&str]) -> QueryCache {
+        let db_path = dir.path().join("db.sqlite");
+        let db = DatabaseOps::new(db_path.to_str().unwrap(), false, false)
+            .await
+            .unwrap();
+        let packages: Vec<DatabasePackageDetails> = names
+            .iter()
+            .map(|name| DatabasePackageDetails::test_package(name))
+            .collect();
+        let mut tx = db.begin_transaction().await.unwrap();
+        db.update_index_with_tx(&mut tx, &packages).await.unwrap();
+        db.bump_data_version_with_tx(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        let options = QueryCacheOptions {
+            capacity: 100,
+            ttl: Duration::from_secs(60),
+        };
+        QueryCache::new(db, &options, Arc::new(Metrics::new().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn cached_searches_read_one_match_past_the_limit() {
+        let dir = TempDir::new().unwrap();
+        let cache = cache_over(&dir, &["foo-a", "foo-b", "foo-c", "foo-d", "bar"]).await;
+
+        let (total, rows) = cache
+            .stream_search(SearchType::Name, "foo", None, 2)
+            .await
+            .unwrap();
+        assert_eq!(total, 3);
+        assert!(rows.is_none());
+        let inner = cache.inner.as_ref().unwrap();
+        inner.cache.run_pending_tasks().await;
+        let cached: Vec<_> = inner.cache.iter().collect();
+        assert!(matches!(
+            &cached[..],
+            [(_, CachedResult::Search(packages))] if packages.len() == 3
+        ));
+
+        let (total, rows) = cache
+            .stream_search(SearchType::Name, "foo", None, 4)
+            .await
+            .unwrap();
+        assert_eq!(total, 4);
+        let rows: Vec<_> = rows.unwrap().try_collect().await.unwrap();
+        assert_eq!(rows.len(), 4);
+    }
+}
@@ -0,0 +1,382 @@
+use crate::client_ip::{parse_ip_range, IpRange};
+use crate::database::DatabaseOps;
+use crate::types::DatabaseRequestCounter;
+use anyhow::{anyhow, Result};
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// How often the counters are written to the database
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// Most clients counted at once, so a flood of new addresses can't grow the
+/// counters without bound
+const MAX_CLIENTS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct RequestLimitOptions {
+    /// Requests allowed per client within `window`; 0 disables the limit
+    pub requests: u64,
+    pub window: Duration,
+    /// Addresses or CIDR ranges exempt from the limit
+    pub allowlist: Vec<String>,
+    /// Keys counted on their own instead of by client address
+    pub api_keys: Vec<ApiKeyConfig>,
+}
+
+/// An API key as configured; clients send the token itself in an
+/// `Authorization: Bearer` header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// Shown in metrics and the access log
+    pub name: String,
+    /// Hex SHA-256 of the token, so the config doesn't hold the secret
+    pub token_sha256: String,
+    /// Requests within the rate limit window; 0 or unset for no limit
+    #[serde(default)]
+    pub requests: u64,
+}
+
+/// A valid key sent with a request, added to the request's extensions.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub name: Arc<str>,
+    pub requests: u64,
+}
+
+/// The configured API keys by the hash of their token.
+pub struct ApiKeys(HashMap<String, ApiKey>);
+
+/// Per-client request limit over a sliding window, like aurweb's 4000
+/// requests per day.
+///
+/// Each client keeps the count of the current and the previous fixed window;
+/// the previous one is weighted by how much of it still overlaps the sliding
+/// window. The counters are persisted to the database periodically, so a
+/// restart doesn't hand abusers a fresh quota.
+pub struct RequestLimiter {
+    db: DatabaseOps,
+    requests: u64,
+    window_secs: i64,
+    allowlist: Vec<IpRange>,
+    counters: Mutex<HashMap<Client, Counter>>,
+}
+
+/// Whom a counter belongs to: requests with a valid API key count towards
+/// the key, all others towards their address. IPv6 clients are counted by
+/// their /64, as that is what a single host usually gets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    ApiKey(Arc<str>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    window_start: i64,
+    requests: u64,
+    previous_requests: u64,
+}
+
+impl RequestLimiter {
+    /// Returns `None` without any limit, neither by address nor of an API
+    /// key; otherwise restores the persisted counters.
+    pub async fn new(db: DatabaseOps, options: RequestLimitOptions) -> Result<Option<Self>> {
+        if options.requests == 0 && options.api_keys.iter().all(|key| key.requests == 0) {
+            return Ok(None);
+        }
+        let allowlist = options
+            .allowlist
+            .iter()
+            .map(|range| parse_ip_range(range))
+            .collect::<Result<Vec<_>>>()?;
+        let counters = db
+            .load_request_counters()
+            .await?
+            .into_iter()
+            .filter_map(|counter| {
+                let client = Client::parse(&counter.ip)?;
+                Some((
+                    client,
+                    Counter {
+                        window_start: counter.window_start,
+                        requests: counter.requests,
+                        previous_requests: counter.previous_requests,
+                    },
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+        info!(
+            "Limiting clients to {} requests per {:?}, restored {} counters",
+            options.requests,
+            options.window,
+            counters.len()
+        );
+        Ok(Some(Self {
+            db,
+            requests: options.requests,
+            window_secs: options.window.as_secs().max(1) as i64,
+            allowlist,
+            counters: Mutex::new(counters),
+        }))
+    }
+
+    /// Count a request of `ip`, or of `api_key` when it came with a valid
+    /// one, and tell whether it is within the limit.
+    pub fn check(&self, ip: IpAddr, api_key: Option<&ApiKey>) -> bool {
+        let (client, limit) = match api_key {
+            Some(api_key) => (Client::ApiKey(api_key.name.clone()), api_key.requests),
+            None if self.allowlist.iter().any(|range| range.contains(ip)) => return true,
+            None => (Client::ip(ip), self.requests),
+        };
+        if limit == 0 {
+            return true;
+        }
+        let now = unix_now();
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() >= MAX_CLIENTS && !counters.contains_key(&client) {
+            self.make_room(&mut counters, now);
+        }
+        let counter = counters.entry(client).or_insert(Counter {
+            window_start: now,
+            requests: 0,
+            previous_requests: 0,
+        });
+        counter.advance(now, self.window_secs);
+        counter.requests += 1;
+        counter.estimate(now, self.window_secs) <= limit as f64
+    }
+
+    /// Drop the counters that no longer affect the limit, then, if that
+    /// isn't enough, the eighth of the addresses with the fewest requests.
+    /// Those are the furthest from the limit, so the clients close to it
+    /// stay limited; API keys are never dropped.
+    fn make_room(&self, counters: &mut HashMap<Client, Counter>, now: i64) {
+        self.drop_expired(counters, now);
+        if counters.len() < MAX_CLIENTS {
+            return;
+        }
+        let mut estimates: Vec<f64> = counters
+            .iter()
+            .filter(|(client, _)| matches!(client, Client::Ip(_)))
+            .map(|(_, counter)| counter.estimate(now, self.window_secs))
+            .collect();
+        if estimates.is_empty() {
+            return;
+        }
+        let nth = (MAX_CLIENTS / 8).min(estimates.len() - 1);
+        let (_, &mut threshold, _) = estimates.select_nth_unstable_by(nth, f64::total_cmp);
+        counters.retain(|client, counter| {
+            matches!(client, Client::ApiKey(_))
+                || counter.estimate(now, self.window_secs) > threshold
+        });
+    }
+
+    fn drop_expired(&self, counters: &mut HashMap<Client, Counter>, now: i64) {
+        counters.retain(|_, counter| {
+            counter.advance(now, self.window_secs);
+            counter.requests > 0 || counter.previous_requests > 0
+        });
+    }
+
+    /// Drop counters that no longer affect the limit and store the others.
+    pub async fn persist(&self) -> Result<()> {
+        let now = unix_now();
+        let counters: Vec<DatabaseRequestCounter> = {
+            let mut counters = self.counters.lock().unwrap();
+            self.drop_expired(&mut counters, now);
+            counters
+                .iter()
+                .map(|(client, counter)| DatabaseRequestCounter {
+                    ip: client.to_string(),
+                    window_start: counter.window_start,
+                    requests: counter.requests,
+                    previous_requests: counter.previous_requests,
+                })
+                .collect()
+        };
+        self.db.store_request_counters(&counters).await
+    }
+
+    pub fn spawn_persist(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PERSIST_INTERVAL).await;
+                if let Err(e) = self.persist().await {
+                    error!("Failed to persist request counters: {}", e);
+                }
+            }
+        })
+    }
+}
+
+impl Client {
+    fn ip(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V6(ip) => Self::Ip(IpAddr::V6(Ipv6Addr::from_bits(
+                ip.to_bits() & (u128::MAX << 64),
+            ))),
+            ip => Self::Ip(ip),
+        }
+    }
+
+    /// Counters of API keys are stored as `key:<name>` in place of the
+    /// address.
+    fn parse(client: &str) -> Option<Self> {
+        match client.strip_prefix("key:") {
+            Some(name) => Some(Self::ApiKey(name.into())),
+            None => client.parse().ok().map(Self::ip),
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{}", ip),
+            Self::ApiKey(name) => write!(f, "key:{}", name),
+        }
+    }
+}
+
+impl ApiKeys {
+    pub fn new(keys: &[ApiKeyConfig]) -> Result<Self> {
+        let mut by_hash = HashMap::new();
+        for key in keys {
+            let hash = key.token_sha256.trim().to_ascii_lowercase();
+            if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return Err(anyhow!(
+                    "API key {} needs the hex SHA-256 of its token",
+                    key.name
+                ));
+            }
+            if by_hash
+                .values()
+                .any(|other: &ApiKey| *other.name == key.name)
+            {
+                return Err(anyhow!("API key {} is configured twice", key.name));
+            }
+            let api_key = ApiKey {
+                name: key.name.as_str().into(),
+                requests: key.requests,
+            };
+            if by_hash.insert(hash, api_key).is_some() {
+                return Err(anyhow!("API key {} reuses another key's token", key.name));
+            }
+        }
+        Ok(Self(by_hash))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The key whose token is sent as a bearer token, if any. Unknown
+    /// tokens are ignored, so such requests count by address.
+    pub fn identify(&self, headers: &HeaderMap) -> Option<&ApiKey> {
+        let token = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?
+            .trim();
+        let hash: String = Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.0.get(&hash)
+    }
+}
+
+impl Counter {
+    /// Move the fixed windows forward so `now` falls into the current one.
+    fn advance(&mut self, now: i64, window_secs: i64) {
+        let elapsed = now - self.window_start;
+        if elapsed >= 2 * window_secs {
+            self.previous_requests = 0;
+            self.requests = 0;
+            self.window_start = now - elapsed % window_secs;
+        } else if elapsed >= window_secs {
+            self.previous_requests = self.requests;
+            self.requests = 0;
+            self.window_start += window_secs;
+        }
+    }
+
+    /// Requests within the sliding window ending at `now`.
+    fn estimate(&self, now: i64, window_secs: i64) -> f64 {
+        let progress = (now - self.window_start) as f64 / window_secs as f64;
+        self.previous_requests as f64 * (1.0 - progress).max(0.0) + self.requests as f64
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tempfile::TempDir;
+
+    async fn limiter(dir: &TempDir, requests: u64) -> RequestLimiter {
+        let db_path = dir.path().join("db.sqlite");
+        let db = DatabaseOps::new(db_path.to_str().unwrap(), false, false)
+            .await
+            .unwrap();
+        let options = RequestLimitOptions {
+            requests,
+            window: Duration::from_secs(3600),
+            allowlist: Vec::new(),
+            api_keys: Vec::new(),
+        };
+        RequestLimiter::new(db, options).await.unwrap().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn ipv6_clients_are_counted_by_their_64() {
+        let dir = TempDir::new().unwrap();
+        let limiter = limiter(&dir, 2).await;
+        assert!(limiter.check(ip("2001:db8::1"), None));
+        assert!(limiter.check(ip("2001:db8::2"), None));
+        assert!(!limiter.check(ip("2001:db8::ffff:ffff:1"), None));
+        assert!(limiter.check(ip("2001:db8:0:1::1"), None));
+    }
+
+    #[tokio::test]
+    async fn ipv4_mapped_addresses_count_as_ipv4() {
+        let dir = TempDir::new().unwrap();
+        let limiter = limiter(&dir, 1).await;
+        assert!(limiter.check(ip("192.0.2.1"), None));
+        assert!(!limiter.check(ip("::ffff:192.0.2.1"), None));
+        assert!(limiter.check(ip("192.0.2.2"), None));
+    }
+
+    #[tokio::test]
+    async fn the_counters_are_capped_keeping_the_busiest_clients() {
+        let dir = TempDir::new().unwrap();
+        let limiter = limiter(&dir, 10).await;
+        let busy = ip("198.51.100.1");
+        for _ in 0..10 {
+            assert!(limiter.check(busy, None));
+        }
+        for i in 0..MAX_CLIENTS as u32 * 2 {
+            limiter.check(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)), None);
+            assert!(limiter.counters.lock().unwrap().len() <= MAX_CLIENTS);
+        }
+        assert!(!limiter.check(busy, None));
+    }
+}
@@ -0,0 +1,122 @@
+use sqlx::SqliteConnection;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time::error::Elapsed;
+
+/// SQLite virtual machine instructions between deadline checks
+const PROGRESS_CHECK_OPS: i32 = 10_000;
+
+/// Statements are interrupted this long after the deadline, so the timeout
+/// response wins over the handler's own error for the interrupted query
+const INTERRUPT_GRACE: Duration = Duration::from_millis(100);
+
+/// Routes whose handlers mostly wait for the git host rather than the
+/// database
+const UPSTREAM_ROUTES: &[&str] = &[
+    "/cgit/aur.git/snapshot/{snapshot_name}",
+    "/cgit/aur.git/plain/{*path}",
+    "/raw/{branch}/{*path}",
+    "/{branch}/info/refs",
+    "/{branch}/git-upload-pack",
+];
+
+tokio::task_local! {
+    /// Deadline of the request handled by the current task
+    static DEADLINE: Instant;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RequestTimeoutOptions {
+    /// Applies to routes without their own timeout; `None` disables it
+    pub default: Option<Duration>,
+    /// Timeouts by matched route pattern, e.g. `/rpc`; zero disables the
+    /// timeout of a route
+    pub routes: HashMap<String, Duration>,
+}
+
+impl RequestTimeoutOptions {
+    /// Whether any route has a timeout.
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || self.routes.values().any(|timeout| !timeout.is_zero())
+    }
+
+    /// Admin routes run refreshes to completion and never time out.
+    pub fn timeout_for(&self, route: &str) -> Option<Duration> {
+        if route.starts_with("/admin/") {
+            return None;
+        }
+        match self.routes.get(route) {
+            Some(timeout) => (!timeout.is_zero()).then_some(*timeout),
+            None => self.default,
+        }
+    }
+}
+
+/// What a timed-out request was most likely waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Database,
+    Upstream,
+}
+
+impl TimeoutKind {
+    pub fn of_route(route: &str) -> Self {
+        if UPSTREAM_ROUTES.contains(&route) {
+            Self::Upstream
+        } else {
+            Self::Database
+        }
+    }
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Database => write!(f, "database"),
+            Self::Upstream => write!(f, "upstream"),
+        }
+    }
+}
+
+/// Run `fut` until `timeout` elapses. Database connections acquired by it
+/// are armed with the deadline (see [`arm_connection`]), so their queries
+/// stop too instead of running on after `fut` was dropped.
+pub async fn with_deadline<F: Future>(timeout: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    let deadline = Instant::now() + timeout;
+    DEADLINE
+        .scope(deadline, tokio::time::timeout_at(deadline.into(), fut))
+        .await
+}
+
+/// `fut` bounded by the deadline of the current request, if any, for
+/// running it on another task.
+pub fn keep_deadline<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let deadline = DEADLINE.try_with(|deadline| *deadline).ok();
+    async move {
+        match deadline {
+            Some(deadline) => DEADLINE.scope(deadline, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/// Pool hook run when a connection is opened or handed out: within
+/// [`with_deadline`], make SQLite interrupt statements running past the
+/// deadline.
+pub async fn arm_connection(conn: &mut SqliteConnection) -> sqlx::Result<()> {
+    if let Ok(deadline) = DEADLINE.try_with(|deadline| *deadline + INTERRUPT_GRACE) {
+        conn.lock_handle()
+            .await?
+            .set_progress_handler(PROGRESS_CHECK_OPS, move || Instant::now() < deadline);
+    }
+    Ok(())
+}
+
+/// Pool hook run when a connection is returned, after an interrupted
+/// statement has stopped: remove the deadline for the next user.
+pub async fn disarm_connection(conn: &mut SqliteConnection) -> sqlx::Result<bool> {
+    conn.lock_handle().await?.remove_progress_handler();
+    Ok(true)
+}
@@ -1,47 +1,293 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
-    body::Body,
-    extract::{Path, Query, State},
-    http::{header, HeaderMap, StatusCode},
-    response::{Redirect, Response},
-    routing::{get, post},
+    body::{Body, Bytes},
+    extract::{
+        ConnectInfo, DefaultBodyLimit, MatchedPath, Path, Query, RawForm, RawQuery, Request, State,
+    },
+    handler::Handler,
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{any, get, post},
     Router,
 };
+use flate2::{write::GzEncoder, Compression};
+use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt};
+use http_body_util::LengthLimitError;
 use serde::Deserialize;
-use std::collections::HashMap;
-use tower_http::cors::CorsLayer;
-use tracing::{error, info};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::IntoFuture;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info, warn};
+use utoipa::IntoParams;
+use utoipa_swagger_ui::SwaggerUi;
 
-use crate::types::{RpcPackageDetails, RpcPackageInfo};
+use crate::conditional::Validators;
+use crate::types::{
+    DatabaseBranchSummary, DatabaseHistoryDetails, DatabaseHistoryVersion,
+    DatabasePackageDetailsWithSupplement, DatabasePackageInfoWithSupplement, DatabaseSrcInfo,
+    DatabaseSyncRun, MirrorStats, RpcPackageDetails, RpcPackageInfo, RpcV5Response, SearchPage,
+    SortBy, SortField, SupplementSourceValidators, SyncScheduleStats,
+};
 use crate::{
+    access_log::{log_request, AccessLog},
     app_state::AppState,
-    database::DatabaseOps,
+    aur_fetcher::{AurFetcher, FetchedTree},
+    branch_filter::BranchFilter,
+    client_ip::{parse_ip_range, ClientIp, ClientIpSource, IpAccess, IpRange},
+    concurrency_limiter::ConcurrencyLimiter,
+    database::{DatabaseOps, ReadSnapshot},
+    events::{EventHub, HEARTBEAT_INTERVAL},
+    fields::FieldSelection,
+    freshness::{MirrorFreshness, LAST_SYNC_HEADER, SUPPLEMENT_AGE_HEADER},
+    listener::{bind_tcp, bind_unix, tag_listener, ListenAddr, PeerAddr, UnixSocketOptions},
+    metrics::Metrics,
+    openapi,
+    proxy::PassThroughProxy,
+    query_cache::QueryCache,
+    request_limiter::{ApiKey, ApiKeys, RequestLimiter},
+    request_timeout::{with_deadline, RequestTimeoutOptions, TimeoutKind},
+    snapshot,
+    snapshot_cache::SnapshotCache,
+    supplement_fetcher::SupplementFetcher,
+    supplement_refresher::{AlreadyRunning, SupplementRefresher},
+    sync_lock::SyncLocked,
+    sync_scheduler::SyncScheduler,
+    syncer::{SyncTrigger, Syncer},
+    systemd::SystemdNotifier,
+    tls::{self, TlsOptions},
     types::{RpcResponse, SearchType},
+    web_ui,
 };
 
+/// Upload-pack endpoint of the AUR monorepo mirror
+const GITHUB_AUR_UPLOAD_PACK_URL: &str = "https://github.com/archlinux/aur.git/git-upload-pack";
+/// Upper bound for live supplement lookups within an info request
+const LIVE_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Compressed name lists are sent in chunks of about this size
+const NAME_LIST_CHUNK_BYTES: usize = 64 * 1024;
+/// Smaller responses aren't worth the compression overhead
+const COMPRESSION_MIN_BYTES: u16 = 1024;
+/// Number of names returned by a suggest request, as on aurweb
+const SUGGEST_LIMIT: u32 = 20;
+/// Searches matching more packages fail, as with aurweb's `max_rpc_results`
+const MAX_SEARCH_RESULTS: usize = 5000;
+/// Most results serialized into one chunk of a streamed search response
+const SEARCH_CHUNK_RESULTS: usize = 100;
+/// Page size of paginated searches without a `limit`
+const DEFAULT_SEARCH_PAGE_SIZE: u32 = 50;
+/// Larger `limit`s of paginated searches are lowered to this
+const MAX_SEARCH_PAGE_SIZE: u32 = 1000;
+/// Page size of `/admin/branches` without a `limit`
+const ADMIN_BRANCHES_PAGE_SIZE: u32 = 100;
+/// Larger `limit`s of `/admin/branches` are lowered to this
+const MAX_ADMIN_BRANCHES_PAGE_SIZE: u32 = 1000;
+/// `Allow` of RPC routes answering GET only; axum answers HEAD like GET.
+const ALLOW_GET: &str = "GET, HEAD";
+/// `Allow` of RPC routes also taking form bodies.
+const ALLOW_GET_POST: &str = "GET, HEAD, POST";
+
 #[derive(Clone)]
 pub struct RpcState {
     db: DatabaseOps,
+    /// Search and info lookups, through the cache when enabled
+    queries: QueryCache,
     client: reqwest::Client,
     github_token: Option<String>,
+    fetcher: AurFetcher,
+    live_lookup: Option<Arc<SupplementFetcher>>,
+    supplement_refresher: Arc<SupplementRefresher>,
+    admin_token: Option<String>,
+    max_info_args: usize,
+    /// Most names of an info request served in chunks of `max_info_args`;
+    /// not above `max_info_args` when splitting is off
+    max_split_info_args: usize,
+    history_window: Duration,
+    store_srcinfo: bool,
+    url_path_template: UrlPathTemplate,
+    /// Whether v5 results carry `CommitId` and `CommittedAt`
+    commit_fields: bool,
+    max_query_bytes: usize,
+    max_body_bytes: usize,
+    max_arg_params: usize,
+    git_upstream: GitUpstream,
+    metrics: Arc<Metrics>,
+    metrics_allowlist: Vec<IpRange>,
+    ready_max_sync_age: Option<Duration>,
+    sync_trigger: Arc<SyncTrigger>,
+    /// Runs syncs on an interval, when enabled
+    sync_scheduler: Option<Arc<SyncScheduler>>,
+    branch_filter: BranchFilter,
+    events: Arc<EventHub>,
+    concurrency_limiter: Option<Arc<ConcurrencyLimiter>>,
+    request_timeouts: Arc<RequestTimeoutOptions>,
+    /// Forwards requests no route matches, when enabled
+    proxy: Option<Arc<PassThroughProxy>>,
+    snapshot_cache: Option<Arc<SnapshotCache>>,
 }
 
 pub struct RpcServer {
     app: Router,
+    db: DatabaseOps,
+    sync_trigger: Arc<SyncTrigger>,
+    /// Fired on SIGINT/SIGTERM: stops accepting connections, ends event
+    /// streams and cancels the running sync
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+    unix_socket_options: UnixSocketOptions,
+    tls_options: Option<TlsOptions>,
+    redirect_http_to_https: bool,
+    systemd: Arc<SystemdNotifier>,
 }
 
-#[derive(Debug, Deserialize)]
+/// How serving ended after a shutdown signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// In-flight requests and the running sync finished within the drain
+    /// timeout
+    Clean,
+    /// The drain timeout elapsed and the remaining work was abandoned
+    Forced,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsOptions {
+    /// Origins allowed to call the server from a browser, or `*`
+    pub allowed_origins: Vec<String>,
+    /// How long browsers may cache a preflight response
+    pub max_age: Duration,
+}
+
+/// Where git requests for a pkgbase are sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum GitUpstream {
+    /// The AUR monorepo on GitHub, each branch presented as its own repository
+    #[default]
+    GitHub,
+    /// A host serving `<base>/<pkgbase>.git`, e.g. `https://aur.archlinux.org`
+    PerPackage(String),
+}
+
+impl GitUpstream {
+    pub fn parse(upstream: &str) -> Self {
+        let upstream = upstream.trim();
+        if upstream.is_empty() || upstream.eq_ignore_ascii_case("github") {
+            Self::GitHub
+        } else {
+            Self::PerPackage(upstream.trim_end_matches('/').to_string())
+        }
+    }
+}
+
+/// Pattern of the `URLPath` reported for a package, with `{pkgbase}` and
+/// `{pkgname}` substituted; either a path on this server or a full URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlPathTemplate(String);
+
+impl Default for UrlPathTemplate {
+    fn default() -> Self {
+        Self("/cgit/aur.git/snapshot/{pkgbase}.tar.gz".to_string())
+    }
+}
+
+impl UrlPathTemplate {
+    pub fn parse(template: &str) -> Self {
+        let template = template.trim();
+        if template.is_empty() {
+            Self::default()
+        } else {
+            Self(template.to_string())
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn render(&self, pkgbase: &str, pkgname: &str) -> String {
+        self.0
+            .replace("{pkgbase}", pkgbase)
+            .replace("{pkgname}", pkgname)
+    }
+}
+
+impl fmt::Display for GitUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GitHub => write!(f, "GitHub"),
+            Self::PerPackage(base_url) => write!(f, "{}", base_url),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct RpcQuery {
+    /// API version, `5`
     v: Option<String>,
+    /// `search`, `info`, `multiinfo`, `suggest` or `suggest-pkgbase`
     #[serde(rename = "type")]
     request_type: Option<String>,
+    /// Search field, `name-desc` by default
     #[serde(rename = "by")]
     search_by: Option<String>,
-    #[serde(default, rename = "arg")]
-    args0: Vec<String>,
-    #[serde(default, rename = "arg[]")]
-    args1: Vec<String>,
+    /// JSONP callback to wrap the response in
     callback: Option<String>,
+    /// Page size of a paginated search (extension)
+    limit: Option<String>,
+    /// Results to skip in a paginated search (extension)
+    offset: Option<String>,
+    /// Page of a paginated search, instead of `offset` (extension)
+    page: Option<String>,
+    /// Sort field of a search (extension)
+    sort: Option<String>,
+    /// `asc` or `desc` (extension)
+    order: Option<String>,
+    /// Comma-separated result fields to return, e.g. `Name,Version` (extension)
+    fields: Option<String>,
+    /// Unix timestamp to report the packages as of (extension, info only)
+    as_of: Option<String>,
+    /// `1` adds the raw `.SRCINFO` of each package (extension, info only)
+    include_srcinfo: Option<String>,
+}
+
+/// Parameters of the v6 routes, which carry the request type in the path.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct V6Params {
+    /// Search field, `name-desc` by default
+    #[serde(rename = "by")]
+    search_by: Option<String>,
+    /// Page size of a paginated search (extension)
+    limit: Option<String>,
+    /// Results to skip in a paginated search (extension)
+    offset: Option<String>,
+    /// Page of a paginated search, instead of `offset` (extension)
+    page: Option<String>,
+    /// Sort field of a search (extension)
+    sort: Option<String>,
+    /// `asc` or `desc` (extension)
+    order: Option<String>,
+    /// Comma-separated result fields to return, e.g. `Name,Version` (extension)
+    fields: Option<String>,
+    /// Unix timestamp to report the packages as of (extension, info only)
+    as_of: Option<String>,
+    /// `1` adds the raw `.SRCINFO` of each package (extension, info only)
+    include_srcinfo: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -51,298 +297,3195 @@ struct RpcForm {
     request_type: Option<String>,
     #[serde(rename = "by")]
     search_by: Option<String>,
-    #[serde(default, rename = "arg")]
-    args0: Vec<String>,
-    #[serde(default, rename = "arg[]")]
-    args1: Vec<String>,
+    limit: Option<String>,
+    offset: Option<String>,
+    page: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    fields: Option<String>,
+    as_of: Option<String>,
+    include_srcinfo: Option<String>,
+}
+
+/// The `arg` and `arg[]` parameters in the order they were sent. aurweb
+/// accepts both spellings, also mixed within one request, and clients
+/// differ in which one they use.
+struct RpcArgs(Vec<String>);
+
+impl RpcArgs {
+    /// Collect the args of a urlencoded query or form body.
+    fn parse(input: &[u8]) -> Self {
+        Self(
+            form_urlencoded::parse(input)
+                .filter(|(key, _)| is_arg_key(key))
+                .map(|(_, value)| value.into_owned())
+                .collect(),
+        )
+    }
+
+    fn from_query(query: Option<String>) -> Self {
+        Self::parse(query.unwrap_or_default().as_bytes())
+    }
+}
+
+fn is_arg_key(key: &str) -> bool {
+    key == "arg" || key == "arg[]"
+}
+
+/// Search and info parameters beyond the arg, kept as strings so invalid
+/// values get the error object instead of a bare 400. All but `by` are
+/// extensions over aurweb.
+#[derive(Debug, Default)]
+struct RpcParams {
+    search_by: Option<String>,
+    limit: Option<String>,
+    offset: Option<String>,
+    page: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    fields: Option<String>,
+    as_of: Option<String>,
+    include_srcinfo: Option<String>,
+}
+
+impl From<V6Params> for RpcParams {
+    fn from(params: V6Params) -> Self {
+        Self {
+            search_by: params.search_by,
+            limit: params.limit,
+            offset: params.offset,
+            page: params.page,
+            sort: params.sort,
+            order: params.order,
+            fields: params.fields,
+            as_of: params.as_of,
+            include_srcinfo: params.include_srcinfo,
+        }
+    }
 }
 
 impl RpcServer {
-    pub fn new(app_state: AppState) -> Self {
+    /// Build the server; supplement data is refreshed from
+    /// `supplement_sources` periodically, on admin request and by syncs
+    /// triggered through the admin endpoint.
+    pub async fn new(app_state: AppState, supplement_sources: Vec<String>) -> Result<Self> {
+        let shutdown = CancellationToken::new();
+        let sync_trigger = Arc::new(SyncTrigger::new(
+            Syncer::new(app_state.clone())?,
+            supplement_sources.clone(),
+            shutdown.clone(),
+        ));
+        let sync_scheduler =
+            SyncScheduler::new(sync_trigger.clone(), &app_state.sync_schedule_options)
+                .map(Arc::new);
+        if let Some(sync_scheduler) = &sync_scheduler {
+            info!("Syncing every {:?}", sync_scheduler.interval());
+            sync_scheduler.clone().spawn(shutdown.clone());
+        }
+        let live_lookup = if app_state.supplement_options.live_lookup {
+            info!("Live supplement lookup through the AUR RPC enabled");
+            Some(Arc::new(SupplementFetcher::new(
+                app_state.user_agent.clone(),
+                app_state.rate_limiter.clone(),
+                app_state.supplement_options.clone(),
+            )?))
+        } else {
+            None
+        };
+        let fetcher = AurFetcher::new(
+            app_state.github_token.clone(),
+            app_state.user_agent.clone(),
+            app_state.srcinfo_cache,
+            app_state.decode_cache_bytes,
+            app_state.sync_pipeline_options.decode_workers,
+            app_state.rate_limiter.clone(),
+            app_state.metrics.clone(),
+        );
+        let refresh_interval = app_state.supplement_options.refresh_interval;
+        let supplement_refresher = Arc::new(SupplementRefresher::new(
+            app_state.db.clone(),
+            SupplementFetcher::new(
+                app_state.user_agent,
+                app_state.rate_limiter,
+                app_state.supplement_options,
+            )?,
+            supplement_sources,
+        ));
+        if let Some(interval) = refresh_interval {
+            info!("Refreshing supplement data every {:?}", interval);
+            supplement_refresher.clone().spawn_periodic(interval);
+        }
+        let api_keys = ApiKeys::new(&app_state.request_limit_options.api_keys)?;
+        if !api_keys.is_empty() {
+            info!(
+                "Accepting {} API keys",
+                app_state.request_limit_options.api_keys.len()
+            );
+        }
+        let client_ip_source = Arc::new(ClientIpSource::new(&app_state.client_ip_options)?);
+        let ip_access = IpAccess::new(&app_state.ip_access_options)?.map(Arc::new);
+        if ip_access.is_some() {
+            info!(
+                "Restricting clients to {} allowed ranges (none for any) except {} denied ones",
+                app_state.ip_access_options.allowlist.len(),
+                app_state.ip_access_options.denylist.len()
+            );
+        }
+        let request_limiter =
+            RequestLimiter::new(app_state.db.clone(), app_state.request_limit_options)
+                .await?
+                .map(Arc::new);
+        if let Some(request_limiter) = &request_limiter {
+            request_limiter.clone().spawn_persist();
+        }
+        let events = Arc::new(
+            EventHub::new(
+                app_state.db.clone(),
+                app_state.sse_max_connections,
+                shutdown.clone(),
+            )
+            .await?,
+        );
+        events.clone().spawn_watch();
+        let freshness =
+            Arc::new(MirrorFreshness::new(app_state.db.clone(), app_state.stale_after).await?);
+        freshness.clone().spawn_watch();
+        info!("Serving git clones via {}", app_state.git_upstream);
+        if let Some(snapshot_cache) = &app_state.snapshot_cache {
+            snapshot_cache.clone().spawn_sweep(app_state.db.clone());
+        }
+        let metrics_allowlist = app_state
+            .metrics_allowlist
+            .iter()
+            .map(|range| parse_ip_range(range))
+            .collect::<Result<Vec<_>>>()?;
+        let cors = cors_layer(&app_state.cors_options)?;
+        let access_log = Arc::new(AccessLog::new(&app_state.access_log_options)?);
+        let web_ui = app_state.web_ui_prefix.map(|prefix| {
+            info!("Serving the web UI under {}", prefix);
+            web_ui::router(
+                app_state.db.clone(),
+                &prefix,
+                app_state.url_path_template.clone(),
+                app_state.store_srcinfo,
+            )
+        });
+        let proxy = PassThroughProxy::new(&app_state.proxy_options)?.map(Arc::new);
+        if let Some(proxy) = &proxy {
+            info!(
+                "Forwarding unmatched requests under {:?} to {}",
+                app_state.proxy_options.path_prefixes,
+                proxy.upstream()
+            );
+        }
+        let v6 = app_state.rpc_v6;
+        let max_body_bytes = app_state.rpc_max_body_bytes;
+        if app_state.query_cache_options.capacity > 0 {
+            info!(
+                "Caching up to {} query results for {:?}",
+                app_state.query_cache_options.capacity, app_state.query_cache_options.ttl
+            );
+        }
+        let queries = QueryCache::new(
+            app_state.db.clone(),
+            &app_state.query_cache_options,
+            app_state.metrics.clone(),
+        );
         let state = RpcState {
-            db: app_state.db,
+            queries,
+            db: app_state.db.clone(),
             client: reqwest::Client::new(),
             github_token: app_state.github_token,
+            fetcher,
+            live_lookup,
+            supplement_refresher,
+            admin_token: app_state.admin_token,
+            max_info_args: app_state.rpc_max_info_args,
+            max_split_info_args: app_state.rpc_max_split_info_args,
+            history_window: app_state.history_window,
+            store_srcinfo: app_state.store_srcinfo,
+            url_path_template: app_state.url_path_template,
+            commit_fields: app_state.rpc_commit_fields,
+            max_query_bytes: app_state.rpc_max_query_bytes,
+            max_body_bytes,
+            max_arg_params: app_state.rpc_max_arg_params,
+            git_upstream: app_state.git_upstream,
+            metrics: app_state.metrics,
+            metrics_allowlist,
+            ready_max_sync_age: app_state.ready_max_sync_age,
+            sync_trigger: sync_trigger.clone(),
+            sync_scheduler,
+            branch_filter: app_state.branch_filter,
+            events,
+            concurrency_limiter: ConcurrencyLimiter::new(&app_state.concurrency_options)
+                .map(Arc::new),
+            request_timeouts: Arc::new(app_state.request_timeout_options.clone()),
+            proxy,
+            snapshot_cache: app_state.snapshot_cache,
         };
 
-        let app = Router::new()
-            .route("/rpc", get(handle_rpc_get))
-            .route("/rpc", post(handle_rpc_post))
+        let rpc_method_router = get(handle_rpc_get)
+            .post(handle_rpc_post)
+            .fallback(|| async { rpc_method_not_allowed(None, ALLOW_GET_POST) })
+            .layer(DefaultBodyLimit::max(max_body_bytes));
+        let v5_get_only = || async { rpc_method_not_allowed(Some(5), ALLOW_GET) };
+        let mut rpc = Router::new()
+            // aurweb answers both, and older clients still send the slash
+            .route("/rpc", rpc_method_router.clone())
+            .route("/rpc/", rpc_method_router.clone())
+            // The PHP era's endpoint, still used by old scripts
+            .route("/rpc.php", rpc_method_router)
+            .route(
+                "/rpc/v5/suggest/{arg}",
+                get(handle_suggest_path).fallback(v5_get_only),
+            )
+            .route(
+                "/rpc/v5/suggest-pkgbase/{arg}",
+                get(handle_suggest_pkgbase_path).fallback(v5_get_only),
+            )
+            .route(
+                "/rpc/v5/{request_type}",
+                get(handle_rpc_v5_get)
+                    .post(handle_rpc_v5_post)
+                    .fallback(|| async { rpc_method_not_allowed(Some(5), ALLOW_GET_POST) })
+                    .layer(DefaultBodyLimit::max(max_body_bytes)),
+            )
+            .route(
+                "/rpc/v5/{request_type}/{arg}",
+                get(handle_rpc_v5_path).fallback(v5_get_only),
+            );
+        if v6 {
+            info!("Serving the v6 API under /api/v6");
+            let v6_get_only = || async { rpc_method_not_allowed(Some(6), ALLOW_GET) };
+            rpc = rpc
+                .route(
+                    "/api/v6/search/{arg}",
+                    get(handle_v6_search).fallback(v6_get_only),
+                )
+                .route(
+                    "/api/v6/info",
+                    get(handle_v6_info_get)
+                        .post(handle_v6_info_post)
+                        .fallback(|| async { rpc_method_not_allowed(Some(6), ALLOW_GET_POST) })
+                        .layer(DefaultBodyLimit::max(max_body_bytes)),
+                )
+                .route(
+                    "/api/v6/info/{arg}",
+                    get(handle_v6_info_path).fallback(v6_get_only),
+                )
+                .route("/api/v6/{*rest}", any(handle_v6_unknown));
+        }
+        rpc = rpc.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            enforce_rpc_input_limits,
+        ));
+        if let Some(request_limiter) = request_limiter.clone() {
+            rpc = rpc.route_layer(middleware::from_fn_with_state(
+                request_limiter,
+                enforce_request_limit,
+            ));
+        }
+        let rpc = rpc
+            .route_layer(middleware::from_fn_with_state(
+                freshness.clone(),
+                add_freshness_headers,
+            ))
+            .layer(compression_layer());
+        let lists = Router::new()
+            .route("/packages.gz", get(handle_packages_gz))
+            .route("/pkgbase.gz", get(handle_pkgbase_gz))
+            .route("/api/srcinfo/{pkgbase}", get(handle_srcinfo))
+            .route_layer(middleware::from_fn_with_state(
+                freshness,
+                add_freshness_headers,
+            ));
+
+        let mut app = Router::new()
+            .merge(rpc)
+            .merge(lists)
             .route(
                 "/cgit/aur.git/snapshot/{snapshot_name}",
                 get(handle_snapshot),
             )
+            .route("/cgit/aur.git/plain/{*path}", get(handle_cgit_plain))
+            .route("/raw/{branch}/{*path}", get(handle_raw_file))
             .route("/{branch}/info/refs", get(handle_git_info_refs))
             .route(
                 "/{branch}/git-upload-pack",
                 post(handle_git_upload_pack_post),
             )
-            .layer(CorsLayer::permissive())
+            .route(
+                "/admin/supplement/refresh",
+                post(handle_admin_supplement_refresh),
+            )
+            .route("/admin/sync", post(handle_admin_sync))
+            .route("/admin/sync/{run_id}", get(handle_admin_sync_status))
+            .route("/admin/branches", get(handle_admin_branches))
+            .route("/api/events", get(handle_events))
+            .route("/api/stats", get(handle_stats))
+            .route("/metrics", get(handle_metrics))
+            .route("/healthz", get(handle_healthz))
+            .route("/readyz", get(handle_readyz));
+        let openapi = openapi::document(v6, state.admin_token.is_some(), state.store_srcinfo);
+        if app_state.openapi_swagger_ui {
+            info!("Serving Swagger UI under /docs");
+            app = app.merge(SwaggerUi::new("/docs").url("/openapi.json", openapi));
+        } else {
+            let openapi = Bytes::from(openapi.to_json()?);
+            app = app.route(
+                "/openapi.json",
+                get(move || async move { ([(header::CONTENT_TYPE, "application/json")], openapi) }),
+            );
+        }
+        if let Some(web_ui) = web_ui {
+            app = app.merge(web_ui);
+        }
+        app = match request_limiter.filter(|_| app_state.proxy_options.rate_limit) {
+            Some(request_limiter) => app.fallback(handle_unmatched.layer(
+                middleware::from_fn_with_state(request_limiter, enforce_request_limit),
+            )),
+            None => app.fallback(handle_unmatched),
+        };
+        let mut app = app
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                enforce_request_timeout,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                enforce_concurrency_limit,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                state.metrics.clone(),
+                record_request_metrics,
+            ));
+        if let Some(cors) = cors {
+            app = app.layer(cors);
+        }
+        app = app.layer(CatchPanicLayer::new());
+        if !api_keys.is_empty() {
+            app = app.layer(middleware::from_fn_with_state(
+                Arc::new(api_keys),
+                identify_api_key,
+            ));
+        }
+        if let Some(ip_access) = ip_access {
+            app = app.layer(middleware::from_fn_with_state(ip_access, enforce_ip_access));
+        }
+        let app = app
+            .layer(middleware::from_fn_with_state(access_log, log_request))
+            .layer(middleware::from_fn_with_state(
+                client_ip_source,
+                resolve_client_ip,
+            ))
             .with_state(state);
 
-        Self { app }
+        Ok(Self {
+            app,
+            db: app_state.db,
+            sync_trigger,
+            shutdown,
+            drain_timeout: app_state.shutdown_drain_timeout,
+            unix_socket_options: app_state.unix_socket_options,
+            tls_options: app_state.tls_options,
+            redirect_http_to_https: app_state.redirect_http_to_https,
+            systemd: app_state.systemd,
+        })
     }
 
-    pub async fn run(self, addrs: impl Iterator<Item = impl AsRef<str>>) -> Result<()> {
-        futures::future::try_join_all(addrs.map(async |addr| -> Result<()> {
-            info!("Listening on http://{}", addr.as_ref());
-            let listener = tokio::net::TcpListener::bind(addr.as_ref()).await?;
-            axum::serve(listener, self.app.clone()).await?;
-            Ok(())
-        }))
-        .await?;
-        Ok(())
+    /// Serve on all addresses until one fails or SIGINT/SIGTERM arrives.
+    ///
+    /// On a signal, listeners stop accepting and the running sync is
+    /// cancelled; in-flight requests and the sync's rollback get the drain
+    /// timeout to finish before the database is closed. Unix socket files
+    /// are removed on return.
+    pub async fn run(self, addrs: &[ListenAddr]) -> Result<Shutdown> {
+        let tls_config = if addrs.iter().any(|addr| matches!(addr, ListenAddr::Tls(_))) {
+            let Some(tls_options) = &self.tls_options else {
+                return Err(anyhow!(
+                    "https:// listeners need tls_cert_path and tls_key_path"
+                ));
+            };
+            let tls_config = tls::load(tls_options).await?;
+            tls::spawn_reload(tls_config.clone(), tls_options.clone())?;
+            Some(tls_config)
+        } else {
+            None
+        };
+
+        let mut tcp_listeners = Vec::new();
+        let mut tls_listeners = Vec::new();
+        let mut unix_listeners = Vec::new();
+        let mut socket_files = Vec::new();
+        for addr in addrs {
+            match addr {
+                ListenAddr::Tcp(tcp_addr) | ListenAddr::Tls(tcp_addr) => {
+                    let v6_only = ListenAddr::needs_v6_only(tcp_addr, addrs);
+                    let listener = bind_tcp(tcp_addr, v6_only)
+                        .await
+                        .map_err(|e| anyhow!("Failed to bind {}: {}", addr, e))?;
+                    match addr {
+                        ListenAddr::Tls(_) => tls_listeners.push((addr, listener)),
+                        _ => tcp_listeners.push((addr, listener)),
+                    }
+                }
+                ListenAddr::Unix(path) => {
+                    let (listener, socket_file) = bind_unix(path, &self.unix_socket_options)?;
+                    socket_files.push(socket_file);
+                    unix_listeners.push((addr, listener));
+                }
+            }
+        }
+
+        let redirect_port = match tls_listeners.first() {
+            Some((_, listener)) if self.redirect_http_to_https => {
+                Some(listener.local_addr()?.port())
+            }
+            None if self.redirect_http_to_https => {
+                return Err(anyhow!("redirect_http_to_https needs an https:// listener"));
+            }
+            _ => None,
+        };
+
+        let mut servers = Vec::new();
+        for (addr, listener) in tcp_listeners {
+            let app = match redirect_port {
+                Some(port) => {
+                    info!("Redirecting {} to HTTPS", addr);
+                    tls::redirect_router(port)
+                }
+                None => self.app.clone(),
+            };
+            let app = tag_listener(app, addr);
+            servers.push(
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<PeerAddr>(),
+                )
+                .with_graceful_shutdown(self.shutdown.clone().cancelled_owned())
+                .into_future()
+                .boxed(),
+            );
+            info!("Listening on {}", addr);
+        }
+        if let Some(tls_config) = tls_config {
+            for (addr, listener) in tls_listeners {
+                let handle = axum_server::Handle::new();
+                let shutdown = self.shutdown.clone();
+                let shutdown_handle = handle.clone();
+                tokio::spawn(async move {
+                    shutdown.cancelled().await;
+                    shutdown_handle.graceful_shutdown(None);
+                });
+                let server = axum_server::from_tcp_rustls(listener.into_std()?, tls_config.clone())
+                    .handle(handle)
+                    .serve(
+                        tag_listener(self.app.clone(), addr)
+                            .into_make_service_with_connect_info::<PeerAddr>(),
+                    );
+                servers.push(
+                    async move {
+                        server.await?;
+                        Ok(())
+                    }
+                    .boxed(),
+                );
+                info!("Listening on {}", addr);
+            }
+        }
+        for (addr, listener) in unix_listeners {
+            servers.push(
+                axum::serve(
+                    listener,
+                    tag_listener(self.app.clone(), addr)
+                        .into_make_service_with_connect_info::<PeerAddr>(),
+                )
+                .with_graceful_shutdown(self.shutdown.clone().cancelled_owned())
+                .into_future()
+                .boxed(),
+            );
+            info!("Listening on {}", addr);
+        }
+        self.systemd.ready();
+        self.systemd
+            .status(&format!("Serving on {} addresses", addrs.len()));
+        self.systemd.clone().spawn_watchdog();
+        let mut servers = futures::future::try_join_all(servers);
+        tokio::select! {
+            result = &mut servers => {
+                result?;
+            }
+            _ = shutdown_signal() => {}
+        }
+        self.systemd.stopping();
+
+        info!(
+            "Shutting down, draining in-flight requests for up to {:?}",
+            self.drain_timeout
+        );
+        self.shutdown.cancel();
+        let drained = tokio::time::timeout(self.drain_timeout, async {
+            let (result, ()) = tokio::join!(servers, self.sync_trigger.wait_cancelled());
+            result
+        })
+        .await;
+        match drained {
+            Ok(result) => {
+                result?;
+                self.db.close().await;
+                info!("Shut down cleanly");
+                Ok(Shutdown::Clean)
+            }
+            Err(_) => {
+                // Closing the pool would wait for the abandoned requests
+                warn!("Drain timeout elapsed, abandoning in-flight requests");
+                Ok(Shutdown::Forced)
+            }
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/rpc",
+    tag = "rpc",
+    params(
+        RpcQuery,
+        ("arg" = Option<Vec<String>>, Query, description = "Search keywords or package names, may be repeated"),
+        ("arg[]" = Option<Vec<String>>, Query, description = "Same as `arg`; both spellings may be mixed"),
+    ),
+    responses(
+        (status = 200, description = "Results depending on the request type, or the error object; JavaScript when `callback` is given", body = RpcV5Response),
+        (status = 414, description = "Query string too long", body = RpcResponse<RpcPackageInfo>),
+    )
+)]
 async fn handle_rpc_get(
     State(state): State<RpcState>,
-    axum_extra::extract::Query(query): axum_extra::extract::Query<RpcQuery>,
-) -> Result<Response<String>, StatusCode> {
-    let all_args = query.args0.into_iter().chain(query.args1).collect();
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, StatusCode> {
+    let Some(query) = parse_query::<RpcQuery>(raw_query.as_deref()) else {
+        return Ok(invalid_query_response(5).into_response());
+    };
+    let RpcArgs(all_args) = RpcArgs::from_query(raw_query);
 
+    let params = RpcParams {
+        search_by: query.search_by,
+        limit: query.limit,
+        offset: query.offset,
+        page: query.page,
+        sort: query.sort,
+        order: query.order,
+        fields: query.fields,
+        as_of: query.as_of,
+        include_srcinfo: query.include_srcinfo,
+    };
     handle_rpc_request(
         query.v,
         query.request_type,
-        query.search_by,
+        params,
         all_args,
         query.callback,
+        &headers,
         state,
     )
     .await
 }
 
+#[utoipa::path(
+    post,
+    path = "/rpc",
+    tag = "rpc",
+    request_body(
+        description = "The parameters of `GET /rpc`, `arg[]` repeated as needed",
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, description = "Results depending on the request type, or the error object", body = RpcV5Response),
+        (status = 413, description = "Body too large", body = RpcResponse<RpcPackageInfo>),
+    )
+)]
 async fn handle_rpc_post(
     State(state): State<RpcState>,
-    axum_extra::extract::Form(form): axum_extra::extract::Form<RpcForm>,
-) -> Result<Response<String>, StatusCode> {
-    let all_args = form.args0.into_iter().chain(form.args1).collect();
+    headers: HeaderMap,
+    RawForm(body): RawForm,
+) -> Result<Response, StatusCode> {
+    let Ok(form) = serde_html_form::from_bytes::<RpcForm>(&body) else {
+        return Ok(invalid_form_response(5).into_response());
+    };
+    let RpcArgs(all_args) = RpcArgs::parse(&body);
 
+    let params = RpcParams {
+        search_by: form.search_by,
+        limit: form.limit,
+        offset: form.offset,
+        page: form.page,
+        sort: form.sort,
+        order: form.order,
+        fields: form.fields,
+        as_of: form.as_of,
+        include_srcinfo: form.include_srcinfo,
+    };
     handle_rpc_request(
         form.v,
         form.request_type,
-        form.search_by,
+        params,
         all_args,
         None, // POST doesn't support JSONP
+        &headers,
         state,
     )
     .await
 }
 
-async fn handle_rpc_request(
-    version: Option<String>,
-    request_type: Option<String>,
-    search_by: Option<String>,
-    args: Vec<String>,
-    callback: Option<String>,
-    state: RpcState,
-) -> Result<Response<String>, StatusCode> {
-    // Validate version
-    let version_num = match version {
-        None => {
-            let error = error_response("Please specify an API version.".to_string(), None);
-            return Ok(create_response(&error, callback));
-        }
-        Some(v) => match v.as_str() {
-            "5" => 5,
-            _ => {
-                let parsed_version = v.parse::<u32>().ok();
-                let error =
-                    error_response("Invalid version specified.".to_string(), parsed_version);
-                return Ok(create_response(&error, callback));
-            }
-        },
+/// gzip or brotli, as negotiated through `Accept-Encoding`. Responses
+/// which are already compressed are passed through as is.
+fn compression_layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().gzip(true).br(true).compress_when(
+        SizeAbove::new(COMPRESSION_MIN_BYTES)
+            .and(NotForContentType::const_new("application/gzip"))
+            .and(NotForContentType::const_new("application/x-gzip"))
+            .and(NotForContentType::IMAGES),
+    )
+}
+
+/// CORS for browser-based frontends; `None` if no origin is allowed.
+/// Preflight requests are answered by the layer itself, before routing.
+fn cors_layer(options: &CorsOptions) -> Result<Option<CorsLayer>> {
+    if options.allowed_origins.is_empty() {
+        return Ok(None);
+    }
+    let allow_origin = if options.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = options
+            .allowed_origins
+            .iter()
+            .map(|origin| header::HeaderValue::from_str(origin))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Invalid CORS origin: {}", e))?;
+        AllowOrigin::list(origins)
     };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+            .expose_headers([LAST_SYNC_HEADER, SUPPLEMENT_AGE_HEADER, header::WARNING])
+            .max_age(options.max_age),
+    ))
+}
 
-    // Validate request type
-    let req_type = match request_type {
-        None => {
-            let error = error_response(
-                "No request type/data specified.".to_string(),
-                Some(version_num),
-            );
-            return Ok(create_response(&error, callback));
-        }
-        Some(t) => t,
+/// Reject oversized RPC input before it is parsed: query strings over the
+/// limit get a 414, bodies over it a 413, both with aurweb's error. Requests
+/// repeating `arg`/`arg[]` too often fail like other over-long info requests,
+/// without collecting the names first.
+async fn enforce_rpc_input_limits(
+    State(state): State<RpcState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let version = if request.uri().path().starts_with("/api/v6/") {
+        6
+    } else {
+        5
     };
+    let query = request.uri().query().unwrap_or_default();
+    if query.len() > state.max_query_bytes {
+        return rpc_error(version, "Query string too long.", StatusCode::URI_TOO_LONG);
+    }
+    let query_args = count_arg_params(query.as_bytes());
 
-    match req_type.as_str() {
-        "search" => {
-            handle_search(
-                state,
-                search_by,
-                args.first().map(|s| s.as_str()).unwrap_or(""),
-                callback,
-            )
-            .await
-        }
-        "info" => handle_info(state, args, callback).await,
-        _ => {
-            let error = error_response(
-                "Incorrect request type specified.".to_string(),
-                Some(version_num),
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, state.max_body_bytes).await {
+        Ok(body) => body,
+        Err(e) if std::error::Error::source(&e).is_some_and(|e| e.is::<LengthLimitError>()) => {
+            return rpc_error(
+                version,
+                "Request body too large.",
+                StatusCode::PAYLOAD_TOO_LARGE,
             );
-            Ok(create_response(&error, callback))
         }
+        Err(_) => {
+            return rpc_error(version, "Invalid request body.", StatusCode::BAD_REQUEST);
+        }
+    };
+    if query_args + count_arg_params(&body) > state.max_arg_params {
+        return rpc_error(version, "Too many package results.", StatusCode::OK);
     }
+    next.run(Request::from_parts(parts, Body::from(body))).await
 }
 
-async fn handle_search(
-    state: RpcState,
-    search_by: Option<String>,
-    keyword: &str,
-    callback: Option<String>,
-) -> Result<Response<String>, StatusCode> {
-    if keyword.is_empty() {
-        let error = error_response("Query arg too small.".to_string(), Some(5));
-        return Ok(create_response(&error, callback));
-    }
-
-    let search_type = search_by.as_deref().unwrap_or("name-desc");
-    let search_enum = SearchType::from_str(search_type);
-    if search_enum.is_none() {
-        let error = error_response("Incorrect by field specified.".to_string(), Some(5));
-        return Ok(create_response(&error, callback));
-    }
-    let search_enum = search_enum.unwrap();
+/// Occurrences of `arg` and `arg[]` in a urlencoded query or form body.
+fn count_arg_params(input: &[u8]) -> usize {
+    form_urlencoded::parse(input)
+        .filter(|(key, _)| is_arg_key(key))
+        .count()
+}
 
-    match state.db.search_packages(search_enum, keyword).await {
-        Ok(rows) => {
-            let results: Vec<RpcPackageInfo> = rows
-                .into_iter()
-                .map(|row| RpcPackageInfo {
-                    id: 0,
-                    name: row.pkg_name.clone(),
-                    description: row.pkg_desc.clone().unwrap_or_default(),
-                    package_base: row.branch.clone(),
-                    package_base_id: 0,
-                    version: row.version.clone(),
-                    url: row.url.clone().unwrap_or_default(),
-                    url_path: format!("/cgit/aur.git/snapshot/{}.tar.gz", row.branch),
-                    maintainer: row.maintainer.unwrap_or_default(),
-                    num_votes: row.num_votes.unwrap_or(0) as u32,
-                    popularity: row.popularity.unwrap_or(0.0),
-                    first_submitted: row.first_submitted.unwrap_or(0),
-                    last_modified: row.last_modified.unwrap_or(0),
-                    out_of_date: row.out_of_date,
-                })
-                .collect();
+fn rpc_error(version: u32, message: &str, status: StatusCode) -> Response {
+    let error = error_response(message.to_string(), Some(version));
+    let mut response = create_response(&error, None);
+    *response.status_mut() = status;
+    response.into_response()
+}
 
-            let response = RpcResponse {
-                error: None,
-                result_count: results.len(),
-                results,
-                response_type: "search".to_string(),
-                version: Some(5),
-            };
+/// Reject clients over the request limit with aurweb's error and a 429.
+async fn enforce_request_limit(
+    State(request_limiter): State<Arc<RequestLimiter>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(peer, |client_ip| client_ip.0);
+    if request_limiter.check(ip, request.extensions().get::<ApiKey>()) {
+        return next.run(request).await;
+    }
+    let version = if request.uri().path().starts_with("/api/v6/") {
+        6
+    } else {
+        5
+    };
+    let error = error_response("Rate limit reached".to_string(), Some(version));
+    let mut response = create_response(&error, None);
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response.into_response()
+}
 
-            Ok(create_response(&response, callback))
-        }
-        Err(e) => {
-            error!("Database error during search: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
+/// Requests no route matches: forwarded upstream when the proxy covers
+/// their path, otherwise a plain 404 as before.
+async fn handle_unmatched(State(state): State<RpcState>, request: Request) -> Response {
+    match &state.proxy {
+        Some(proxy) if proxy.forwards(request.uri().path()) => proxy.forward(request).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
     }
 }
 
-async fn handle_info(
-    state: RpcState,
-    args: Vec<String>,
-    callback: Option<String>,
-) -> Result<Response<String>, StatusCode> {
-    if args.is_empty() {
-        let error = error_response("No request type/data specified.".to_string(), Some(5));
-        return Ok(create_response(&error, callback));
+/// Record the client address for the middleware below, once for all.
+async fn resolve_client_ip(
+    State(source): State<Arc<ClientIpSource>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client_ip = source.client_ip(request.headers(), peer);
+    request.extensions_mut().insert(ClientIp(client_ip));
+    next.run(request).await
+}
+
+/// Refuse clients that are denied or, in allowlist mode, not allowed.
+async fn enforce_ip_access(
+    State(ip_access): State<Arc<IpAccess>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(peer, |client_ip| client_ip.0);
+    if ip_access.allows(ip) {
+        return next.run(request).await;
     }
+    error_status_response(
+        request.uri().path(),
+        StatusCode::FORBIDDEN,
+        "Access denied.",
+    )
+}
 
-    match state.db.get_package_details(&args).await {
-        Ok(package_details) => {
-            let results: Vec<RpcPackageDetails> = package_details
-                .into_iter()
-                .map(|details| RpcPackageDetails {
-                    id: 0,
-                    name: details.info.pkg_name.clone(),
-                    description: details.info.pkg_desc.clone(),
-                    package_base: details.info.branch.clone(),
-                    package_base_id: 0,
-                    version: details.info.version.clone(),
-                    url: details.info.url.clone(),
-                    url_path: format!("/cgit/aur.git/snapshot/{}.tar.gz", details.info.branch),
-                    maintainer: details.info.maintainer,
-                    submitter: details.info.submitter,
-                    num_votes: details.info.num_votes.unwrap_or(0) as u32,
-                    popularity: details.info.popularity.unwrap_or(0.0),
-                    first_submitted: details.info.first_submitted.unwrap_or(0),
-                    last_modified: details.info.last_modified.unwrap_or(0),
-                    out_of_date: details.info.out_of_date,
-                    license: Vec::new(),
-                    depends: details.depends,
-                    makedepends: details.make_depends,
-                    optdepends: details.opt_depends,
-                    checkdepends: details.check_depends,
-                    provides: details.provides,
-                    conflicts: details.conflicts,
-                    replaces: details.replaces,
-                    groups: details.groups,
-                    keywords: details.keywords,
-                    co_maintainers: details.co_maintainers,
-                })
-                .collect();
+/// Tell API clients how fresh the data is.
+async fn add_freshness_headers(
+    State(freshness): State<Arc<MirrorFreshness>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    freshness.apply(response.headers_mut());
+    response
+}
 
-            let response = RpcResponse {
-                error: None,
-                result_count: results.len(),
-                results,
-                response_type: "multiinfo".to_string(),
-                version: Some(5),
-            };
+/// Attach the API key a request was sent with to the request, for the
+/// request limit and metrics, and to its response, for the access log.
+async fn identify_api_key(
+    State(api_keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(api_key) = api_keys.identify(request.headers()).cloned() else {
+        return next.run(request).await;
+    };
+    request.extensions_mut().insert(api_key.clone());
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(api_key);
+    response
+}
 
-            Ok(create_response(&response, callback))
-        }
-        Err(e) => {
-            error!("Database error during info lookup: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
+/// Shed requests over the concurrency limits with a 503 asking to retry
+/// shortly; RPC clients get it in aurweb's error format. Probes and metrics
+/// are never shed, so an overloaded server isn't restarted or left unseen.
+async fn enforce_concurrency_limit(
+    State(state): State<RpcState>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path.as_str();
+    let Some(limiter) = &state.concurrency_limiter else {
+        return next.run(request).await;
+    };
+    if matches!(route, "/healthz" | "/readyz" | "/metrics") {
+        return next.run(request).await;
     }
+    if let Some(_permits) = limiter.acquire(route).await {
+        return next.run(request).await;
+    }
+    state
+        .metrics
+        .http_requests_shed
+        .with_label_values(&[route])
+        .inc();
+    let mut response = unavailable_response(route, "Server busy, try again later.");
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, header::HeaderValue::from_static("1"));
+    response
 }
 
-async fn handle_snapshot(
+/// Abort handlers running past their route's timeout with a 503, telling
+/// slow queries apart from a slow git host.
+async fn enforce_request_timeout(
     State(state): State<RpcState>,
-    Path(snapshot_name): Path<String>,
-) -> Result<Redirect, StatusCode> {
-    let branch_name = snapshot_name.strip_suffix(".tar.gz");
-
-    if let Some(branch_name) = branch_name {
-        match state.db.get_branch_commit_id(branch_name).await {
-            Ok(Some(commit_id)) => {
-                let github_url = format!(
-                    "https://github.com/archlinux/aur/archive/{}.tar.gz",
-                    commit_id
-                );
-                Ok(Redirect::temporary(&github_url))
-            }
-            Ok(None) => Err(StatusCode::NOT_FOUND),
-            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-        }
-    } else {
-        Err(StatusCode::NOT_FOUND)
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path.as_str();
+    let Some(timeout) = state.request_timeouts.timeout_for(route) else {
+        return next.run(request).await;
+    };
+    if let Ok(response) = with_deadline(timeout, next.run(request)).await {
+        return response;
     }
+    let kind = TimeoutKind::of_route(route);
+    warn!(
+        "Request to {} timed out after {:?} waiting for the {}",
+        route, timeout, kind
+    );
+    state
+        .metrics
+        .http_request_timeouts
+        .with_label_values(&[route, &kind.to_string()])
+        .inc();
+    let message = match kind {
+        TimeoutKind::Database => "Database query timed out.",
+        TimeoutKind::Upstream => "Upstream request timed out.",
+    };
+    unavailable_response(route, message)
 }
 
-async fn handle_git_info_refs(
-    State(state): State<RpcState>,
-    Path(branch): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
-) -> Result<Response<String>, StatusCode> {
-    // Remove .git extension if present
-    let branch_name = branch.strip_suffix(".git").unwrap_or(&branch);
+/// A 503 with `message`, in aurweb's error format for RPC routes.
+fn unavailable_response(route: &str, message: &str) -> Response {
+    error_status_response(route, StatusCode::SERVICE_UNAVAILABLE, message)
+}
 
-    let service = match params.get("service") {
-        Some(s) => s,
-        None => {
-            return Ok(Response::builder()
-                .status(StatusCode::FORBIDDEN)
-                .body("Please upgrade your git client.".to_string())
+/// An error with `message`, in aurweb's error format for RPC routes or
+/// paths.
+fn error_status_response(route: &str, status: StatusCode, message: &str) -> Response {
+    let mut response = if route == "/rpc"
+        || route == "/rpc.php"
+        || route.starts_with("/rpc/")
+        || route.starts_with("/api/v6/")
+    {
+        let version = if route.starts_with("/api/v6/") { 6 } else { 5 };
+        let error = error_response(message.to_string(), Some(version));
+        create_response(&error, None).into_response()
+    } else {
+        format!("{}\n", message).into_response()
+    };
+    *response.status_mut() = status;
+    response
+}
+
+/// `/rpc/v5/{type}?arg[]=...`, aurweb's path form of `/rpc?v=5&type=...`.
+#[utoipa::path(
+    get,
+    path = "/rpc/v5/{request_type}",
+    tag = "rpc",
+    params(
+        ("request_type" = String, Path, description = "Request type as in `type`"),
+        RpcQuery,
+        ("arg" = Option<Vec<String>>, Query, description = "Search keywords or package names, may be repeated"),
+        ("arg[]" = Option<Vec<String>>, Query, description = "Same as `arg`; both spellings may be mixed"),
+    ),
+    responses(
+        (status = 200, description = "Results depending on the request type, or the error object; JavaScript when `callback` is given", body = RpcV5Response),
+    )
+)]
+async fn handle_rpc_v5_get(
+    State(state): State<RpcState>,
+    Path(request_type): Path<String>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, StatusCode> {
+    let Some(query) = parse_query::<RpcQuery>(raw_query.as_deref()) else {
+        return Ok(invalid_query_response(5).into_response());
+    };
+    let RpcArgs(args) = RpcArgs::from_query(raw_query);
+    handle_rpc_v5(request_type, args, query, &headers, state).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/rpc/v5/{request_type}",
+    tag = "rpc",
+    params(("request_type" = String, Path, description = "Request type as in `type`")),
+    request_body(
+        description = "The parameters of `GET /rpc`, `arg[]` repeated as needed",
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, description = "Results depending on the request type, or the error object", body = RpcV5Response),
+    )
+)]
+async fn handle_rpc_v5_post(
+    State(state): State<RpcState>,
+    Path(request_type): Path<String>,
+    headers: HeaderMap,
+    RawForm(body): RawForm,
+) -> Result<Response, StatusCode> {
+    let Ok(form) = serde_html_form::from_bytes::<RpcForm>(&body) else {
+        return Ok(invalid_form_response(5).into_response());
+    };
+    let RpcArgs(args) = RpcArgs::parse(&body);
+    let params = RpcParams {
+        search_by: form.search_by,
+        limit: form.limit,
+        offset: form.offset,
+        page: form.page,
+        sort: form.sort,
+        order: form.order,
+        fields: form.fields,
+        as_of: form.as_of,
+        include_srcinfo: form.include_srcinfo,
+    };
+    handle_rpc_request(
+        Some("5".to_string()),
+        Some(request_type),
+        params,
+        args,
+        None, // POST doesn't support JSONP
+        &headers,
+        state,
+    )
+    .await
+}
+
+/// `/rpc/v5/{type}/{arg}`, e.g. `/rpc/v5/info/firefox`; the arg is a single
+/// percent-decoded path segment, so `+` stays a plus.
+#[utoipa::path(
+    get,
+    path = "/rpc/v5/{request_type}/{arg}",
+    tag = "rpc",
+    params(
+        ("request_type" = String, Path, description = "Request type as in `type`"),
+        ("arg" = String, Path, description = "Search keywords or a package name"),
+        RpcQuery,
+    ),
+    responses(
+        (status = 200, description = "Results depending on the request type, or the error object; JavaScript when `callback` is given", body = RpcV5Response),
+    )
+)]
+async fn handle_rpc_v5_path(
+    State(state): State<RpcState>,
+    Path((request_type, arg)): Path<(String, String)>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, StatusCode> {
+    let Some(query) = parse_query::<RpcQuery>(raw_query.as_deref()) else {
+        return Ok(invalid_query_response(5).into_response());
+    };
+    handle_rpc_v5(request_type, vec![arg], query, &headers, state).await
+}
+
+/// Answer a path-style v5 request exactly like its query-string form; the
+/// path takes precedence over `v` and `type` in the query.
+async fn handle_rpc_v5(
+    request_type: String,
+    args: Vec<String>,
+    query: RpcQuery,
+    headers: &HeaderMap,
+    state: RpcState,
+) -> Result<Response, StatusCode> {
+    let params = RpcParams {
+        search_by: query.search_by,
+        limit: query.limit,
+        offset: query.offset,
+        page: query.page,
+        sort: query.sort,
+        order: query.order,
+        fields: query.fields,
+        as_of: query.as_of,
+        include_srcinfo: query.include_srcinfo,
+    };
+    handle_rpc_request(
+        Some("5".to_string()),
+        Some(request_type),
+        params,
+        args,
+        query.callback,
+        headers,
+        state,
+    )
+    .await
+}
+
+/// The error object with a 405 for methods an RPC route doesn't serve.
+fn rpc_method_not_allowed(version: Option<u32>, allow: &'static str) -> Response<String> {
+    let error = error_response("Method not allowed.".to_string(), version);
+    let mut response = create_response(&error, None);
+    *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    response
+        .headers_mut()
+        .insert(header::ALLOW, header::HeaderValue::from_static(allow));
+    response
+}
+
+async fn handle_rpc_request(
+    version: Option<String>,
+    request_type: Option<String>,
+    params: RpcParams,
+    args: Vec<String>,
+    callback: Option<String>,
+    headers: &HeaderMap,
+    state: RpcState,
+) -> Result<Response, StatusCode> {
+    // An invalid callback must not end up in the response, so answer plain JSON
+    if callback
+        .as_deref()
+        .is_some_and(|callback| !is_valid_callback(callback))
+    {
+        let error = error_response(
+            "Invalid callback name.".to_string(),
+            version.as_deref().and_then(|v| v.parse().ok()),
+        );
+        return Ok(create_response(&error, None).into_response());
+    }
+
+    let request = match validate_rpc_request(
+        version.as_deref(),
+        request_type.as_deref(),
+        params.search_by.as_deref(),
+        &args,
+    ) {
+        Ok(request) => request,
+        Err(error) => return Ok(create_response(&error, callback).into_response()),
+    };
+    let version_num = request.version;
+    let first_arg = args.first().map(|s| s.as_str()).unwrap_or("");
+
+    match request.request_type {
+        RpcRequestType::Search => {
+            handle_search(
+                state,
+                version_num,
+                "search",
+                params,
+                first_arg,
+                callback,
+                headers,
+            )
+            .await
+        }
+        RpcRequestType::MSearch => {
+            let params = RpcParams {
+                search_by: Some("maintainer".to_string()),
+                ..params
+            };
+            handle_search(
+                state,
+                version_num,
+                "msearch",
+                params,
+                first_arg,
+                callback,
+                headers,
+            )
+            .await
+        }
+        RpcRequestType::Info => {
+            handle_info(state, version_num, args, params, callback, headers).await
+        }
+        RpcRequestType::Suggest => handle_suggest(state, SuggestBy::Name, first_arg, callback)
+            .await
+            .map(IntoResponse::into_response),
+        RpcRequestType::SuggestPkgbase => {
+            handle_suggest(state, SuggestBy::PackageBase, first_arg, callback)
+                .await
+                .map(IntoResponse::into_response)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RpcRequestType {
+    Search,
+    /// Deprecated search by maintainer, still accepted by aurweb
+    MSearch,
+    Info,
+    Suggest,
+    SuggestPkgbase,
+}
+
+impl RpcRequestType {
+    fn from_str(request_type: &str) -> Option<Self> {
+        match request_type {
+            "search" => Some(Self::Search),
+            "msearch" => Some(Self::MSearch),
+            "info" | "multiinfo" => Some(Self::Info),
+            "suggest" => Some(Self::Suggest),
+            "suggest-pkgbase" => Some(Self::SuggestPkgbase),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ValidatedRpcRequest {
+    version: u32,
+    request_type: RpcRequestType,
+}
+
+/// Check a v5 request step by step in aurweb's order: version, `by`, type,
+/// then whether there is an arg at all. The first failing step decides the
+/// error, with aurweb's exact message; the version is echoed in it whenever
+/// it is a number.
+fn validate_rpc_request(
+    version: Option<&str>,
+    request_type: Option<&str>,
+    search_by: Option<&str>,
+    args: &[String],
+) -> Result<ValidatedRpcRequest, RpcResponse<()>> {
+    let error = |message: &str, version| Err(error_response(message.to_string(), version));
+
+    let version = match version.map(str::trim).filter(|v| !v.is_empty()) {
+        None => return error("Please specify an API version.", None),
+        Some(v) => match v.parse::<u32>() {
+            Ok(5) => 5,
+            parsed => return error("Invalid version specified.", parsed.ok()),
+        },
+    };
+    if search_by.is_some_and(|by| SearchType::from_str(by).is_none()) {
+        return error("Incorrect by field specified.", Some(version));
+    }
+    let Some(request_type) = request_type else {
+        return error("No request type/data specified.", Some(version));
+    };
+    let Some(request_type) = RpcRequestType::from_str(request_type) else {
+        return error("Incorrect request type specified.", Some(version));
+    };
+    if args.is_empty() {
+        return error("No request type/data specified.", Some(version));
+    }
+    Ok(ValidatedRpcRequest {
+        version,
+        request_type,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v6/search/{arg}",
+    tag = "rpc-v6",
+    params(("arg" = String, Path, description = "Search keywords"), V6Params),
+    responses(
+        (status = 200, description = "Matching packages, or the error object", body = RpcResponse<RpcPackageInfo>),
+    )
+)]
+async fn handle_v6_search(
+    State(state): State<RpcState>,
+    Path(arg): Path<String>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, StatusCode> {
+    let Some(params) = parse_query::<V6Params>(raw_query.as_deref()) else {
+        return Ok(invalid_query_response(6).into_response());
+    };
+    handle_search(state, 6, "search", params.into(), &arg, None, &headers).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v6/info",
+    tag = "rpc-v6",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated result fields to return, e.g. `Name,Version` (extension)"),
+        ("as_of" = Option<String>, Query, description = "Unix timestamp to report the packages as of (extension)"),
+        ("include_srcinfo" = Option<String>, Query, description = "`1` adds the raw `.SRCINFO` of each package (extension)"),
+        ("arg" = Option<Vec<String>>, Query, description = "Search keywords or package names, may be repeated"),
+        ("arg[]" = Option<Vec<String>>, Query, description = "Same as `arg`; both spellings may be mixed"),
+    ),
+    responses(
+        (status = 200, description = "The packages found, or the error object", body = RpcResponse<RpcPackageDetails>),
+    )
+)]
+async fn handle_v6_info_get(
+    State(state): State<RpcState>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, StatusCode> {
+    let Some(params) = parse_query::<V6Params>(raw_query.as_deref()) else {
+        return Ok(invalid_query_response(6).into_response());
+    };
+    let RpcArgs(all_args) = RpcArgs::from_query(raw_query);
+    handle_info(state, 6, all_args, params.into(), None, &headers).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v6/info",
+    tag = "rpc-v6",
+    request_body(
+        description = "`arg[]` repeated for every package name",
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, description = "The packages found, or the error object", body = RpcResponse<RpcPackageDetails>),
+    )
+)]
+async fn handle_v6_info_post(
+    State(state): State<RpcState>,
+    headers: HeaderMap,
+    RawForm(body): RawForm,
+) -> Result<Response, StatusCode> {
+    let Ok(params) = serde_html_form::from_bytes::<V6Params>(&body) else {
+        return Ok(invalid_form_response(6).into_response());
+    };
+    let RpcArgs(all_args) = RpcArgs::parse(&body);
+    handle_info(state, 6, all_args, params.into(), None, &headers).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v6/info/{arg}",
+    tag = "rpc-v6",
+    params(
+        ("arg" = String, Path, description = "Package name"),
+        ("fields" = Option<String>, Query, description = "Comma-separated result fields to return, e.g. `Name,Version` (extension)"),
+        ("as_of" = Option<String>, Query, description = "Unix timestamp to report the packages as of (extension)"),
+        ("include_srcinfo" = Option<String>, Query, description = "`1` adds the raw `.SRCINFO` of each package (extension)"),
+    ),
+    responses(
+        (status = 200, description = "The package, or the error object", body = RpcResponse<RpcPackageDetails>),
+    )
+)]
+async fn handle_v6_info_path(
+    State(state): State<RpcState>,
+    Path(arg): Path<String>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, StatusCode> {
+    let Some(params) = parse_query::<V6Params>(raw_query.as_deref()) else {
+        return Ok(invalid_query_response(6).into_response());
+    };
+    handle_info(state, 6, vec![arg], params.into(), None, &headers).await
+}
+
+async fn handle_v6_unknown() -> Response<String> {
+    let error = error_response("Incorrect request type specified.".to_string(), Some(6));
+    let mut response = create_response(&error, None);
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+async fn handle_search(
+    state: RpcState,
+    version: u32,
+    response_type: &'static str,
+    params: RpcParams,
+    keyword: &str,
+    callback: Option<String>,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let search_type = params.search_by.as_deref().unwrap_or("name-desc");
+    let search_enum = SearchType::from_str(search_type);
+    if search_enum.is_none() {
+        let error = error_response("Incorrect by field specified.".to_string(), Some(version));
+        return Ok(create_response(&error, callback).into_response());
+    }
+    let search_enum = search_enum.unwrap();
+
+    // Only pattern searches need a minimum length, dependency lookups are exact
+    if matches!(search_enum, SearchType::Name | SearchType::NameDesc) && keyword.chars().count() < 2
+    {
+        let error = error_response("Query arg too small.".to_string(), Some(version));
+        return Ok(create_response(&error, callback).into_response());
+    }
+
+    let (sort, page) = match search_sort(&params).and_then(|sort| Ok((sort, search_page(&params)?)))
+    {
+        Ok(options) => options,
+        Err(message) => {
+            let error = error_response(message.to_string(), Some(version));
+            return Ok(create_response(&error, callback).into_response());
+        }
+    };
+    let fields = match parse_fields(params.fields.as_deref(), RpcPackageInfo::FIELDS) {
+        Ok(fields) => fields,
+        Err(message) => {
+            let error = error_response(message.to_string(), Some(version));
+            return Ok(create_response(&error, callback).into_response());
+        }
+    };
+
+    // Any change of the data may change the results, so validate against
+    // the data version before searching at all
+    let validators = data_version_validators(&state.db).await;
+    if let Some(validators) = validators.as_ref().filter(|v| v.matches(headers)) {
+        return Ok(validators.not_modified().into_response());
+    }
+
+    // Pages are bounded and built in memory; unpaginated results may run
+    // into thousands, so they are serialized while they are read
+    let Some(page) = page else {
+        return Ok(stream_search_response(
+            &state,
+            version,
+            response_type,
+            StreamedSearch {
+                search_type: search_enum,
+                keyword,
+                sort,
+                fields,
+            },
+            callback,
+            validators,
+        )
+        .await);
+    };
+    // The v6 routes are ours, so they needn't match aurweb byte for byte
+    let commit_fields = state.commit_fields || version == 6;
+    // A page comes with the total read from the same snapshot
+    match state
+        .queries
+        .search_page(search_enum, keyword, sort, page)
+        .await
+    {
+        Ok((total, rows)) => {
+            let results: Vec<RpcPackageInfo> = rows
+                .into_iter()
+                .map(|row| rpc_package_info(&state.url_path_template, commit_fields, row))
+                .collect();
+
+            let response = RpcResponse {
+                error: None,
+                result_count: results.len(),
+                total: Some(total),
+                offset: Some(page.offset),
+                results,
+                response_type: response_type.to_string(),
+                version: Some(version),
+            };
+
+            let mut response = results_response(response, fields.as_ref(), callback);
+            if let Some(validators) = &validators {
+                validators.apply(&mut response);
+            }
+            Ok(response.into_response())
+        }
+        Err(e) => {
+            error!("Database error during search: {}", e);
+            Ok(internal_error_response(version, callback).into_response())
+        }
+    }
+}
+
+/// The query behind a streamed search response
+struct StreamedSearch<'a> {
+    search_type: SearchType,
+    keyword: &'a str,
+    sort: Option<SortBy>,
+    fields: Option<FieldSelection>,
+}
+
+/// An unpaginated search response, serialized in chunks as the matches
+/// are read, so neither the matches nor their JSON ever sit in memory as a
+/// whole. The match count comes first, as `resultcount` precedes the
+/// results and too many matches fail the request.
+async fn stream_search_response(
+    state: &RpcState,
+    version: u32,
+    response_type: &'static str,
+    search: StreamedSearch<'_>,
+    callback: Option<String>,
+    validators: Option<Validators>,
+) -> Response {
+    let StreamedSearch {
+        search_type,
+        keyword,
+        sort,
+        fields,
+    } = search;
+    let (total, rows) = match state
+        .queries
+        .stream_search(search_type, keyword, sort, MAX_SEARCH_RESULTS as u64)
+        .await
+    {
+        Ok(search) => search,
+        Err(e) => {
+            error!("Database error during search: {}", e);
+            return internal_error_response(version, callback).into_response();
+        }
+    };
+    let Some(rows) = rows else {
+        let error = error_response("Too many package results.".to_string(), Some(version));
+        return create_response(&error, callback).into_response();
+    };
+
+    let url_path_template = state.url_path_template.clone();
+    let commit_fields = state.commit_fields || version == 6;
+    let results = rows
+        .enumerate()
+        .ready_chunks(SEARCH_CHUNK_RESULTS)
+        .map(move |rows| {
+            let mut chunk = Vec::new();
+            for (index, row) in rows {
+                let info = rpc_package_info(&url_path_template, commit_fields, row?);
+                if index > 0 {
+                    chunk.push(b',');
+                }
+                match &fields {
+                    Some(fields) => serde_json::to_writer(&mut chunk, &fields.select_one(info)),
+                    None => serde_json::to_writer(&mut chunk, &info),
+                }?;
+            }
+            anyhow::Ok(Bytes::from(chunk))
+        })
+        .inspect_err(|e| error!("Database error while streaming a search: {}", e));
+    streamed_results_response(
+        version,
+        response_type,
+        total as usize,
+        callback,
+        results,
+        validators.as_ref(),
+    )
+}
+
+/// A response whose `results` are sent as the chunks of JSON `results`
+/// yields, comma-separated by their producer, with the envelope around
+/// them serialized up front.
+fn streamed_results_response(
+    version: u32,
+    response_type: &str,
+    result_count: usize,
+    callback: Option<String>,
+    results: impl futures::Stream<Item = Result<Bytes>> + Send + 'static,
+    validators: Option<&Validators>,
+) -> Response {
+    let envelope = RpcResponse::<()> {
+        error: None,
+        result_count,
+        total: None,
+        offset: None,
+        results: Vec::new(),
+        response_type: response_type.to_string(),
+        version: Some(version),
+    };
+    let envelope = serde_json::to_string(&envelope).unwrap();
+    // The results go where the empty list is, keeping the field order
+    let (head, tail) = envelope
+        .split_once("\"results\":[]")
+        .expect("the envelope has a results list");
+    let (content_type, head, tail) = match callback {
+        Some(callback) => (
+            "application/javascript",
+            format!("{}({}\"results\":[", callback, head),
+            format!("]{});", tail),
+        ),
+        None => (
+            "application/json",
+            format!("{}\"results\":[", head),
+            format!("]{}", tail),
+        ),
+    };
+    let body = futures::stream::once(async { anyhow::Ok(Bytes::from(head)) })
+        .chain(results)
+        .chain(futures::stream::once(async {
+            anyhow::Ok(Bytes::from(tail))
+        }));
+
+    let mut response = Response::new(Body::from_stream(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(content_type),
+    );
+    if let Some(validators) = validators {
+        validators.apply(&mut response);
+    }
+    response
+}
+
+pub fn rpc_package_info(
+    url_path_template: &UrlPathTemplate,
+    commit_fields: bool,
+    row: DatabasePackageInfoWithSupplement,
+) -> RpcPackageInfo {
+    let (commit_id, committed_at) = match commit_fields {
+        true => (Some(row.commit_id), Some(row.committed_at)),
+        false => (None, None),
+    };
+    RpcPackageInfo {
+        id: 0,
+        url_path: url_path_template.render(&row.branch, &row.pkg_name),
+        name: row.pkg_name,
+        description: row.pkg_desc.unwrap_or_default(),
+        package_base: row.branch,
+        package_base_id: 0,
+        version: row.version,
+        url: row.url.unwrap_or_default(),
+        maintainer: row.maintainer.unwrap_or_default(),
+        num_votes: row.num_votes.unwrap_or(0) as u32,
+        popularity: row.popularity.unwrap_or(0.0),
+        first_submitted: row.first_submitted.unwrap_or(0),
+        last_modified: row.last_modified.unwrap_or(0),
+        out_of_date: row.out_of_date,
+        supplement_from_base: row.supplement_from_base,
+        commit_id,
+        committed_at,
+    }
+}
+
+pub fn rpc_package_details(
+    url_path_template: &UrlPathTemplate,
+    commit_fields: bool,
+    details: DatabasePackageDetailsWithSupplement,
+) -> RpcPackageDetails {
+    let info = details.info;
+    RpcPackageDetails {
+        id: 0,
+        url_path: url_path_template.render(&info.branch, &info.pkg_name),
+        name: info.pkg_name,
+        description: info.pkg_desc,
+        package_base: info.branch,
+        package_base_id: 0,
+        version: info.version,
+        url: info.url,
+        maintainer: info.maintainer,
+        submitter: info.submitter,
+        num_votes: info.num_votes.unwrap_or(0) as u32,
+        popularity: info.popularity.unwrap_or(0.0),
+        first_submitted: info.first_submitted.unwrap_or(0),
+        last_modified: info.last_modified.unwrap_or(0),
+        out_of_date: info.out_of_date,
+        license: details.licenses,
+        depends: details.depends,
+        makedepends: details.make_depends,
+        optdepends: details.opt_depends,
+        checkdepends: details.check_depends,
+        provides: details.provides,
+        conflicts: details.conflicts,
+        replaces: details.replaces,
+        groups: details.groups,
+        keywords: details.keywords,
+        co_maintainers: details.co_maintainers,
+        supplement_from_base: info.supplement_from_base,
+        commit_id: commit_fields.then_some(info.commit_id),
+        committed_at: commit_fields.then_some(info.committed_at),
+        as_of_commit: None,
+        srcinfo: None,
+    }
+}
+
+/// The requested order of search results, `None` without `sort` and
+/// `order`. Only `order` sorts by name.
+fn search_sort(search: &RpcParams) -> Result<Option<SortBy>, &'static str> {
+    if search.sort.is_none() && search.order.is_none() {
+        return Ok(None);
+    }
+    let field = match &search.sort {
+        Some(sort) => SortField::from_str(sort).ok_or("Invalid sort field specified.")?,
+        None => SortField::Name,
+    };
+    let descending = match search.order.as_deref() {
+        Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Err("Invalid sort order specified."),
+        None => field.default_descending(),
+    };
+    Ok(Some(SortBy { field, descending }))
+}
+
+/// The requested window of search results, `None` without any pagination
+/// parameter. `page` counts from 1 in pages of `limit` results.
+fn search_page(search: &RpcParams) -> Result<Option<SearchPage>, &'static str> {
+    if search.limit.is_none() && search.offset.is_none() && search.page.is_none() {
+        return Ok(None);
+    }
+    let limit = match &search.limit {
+        Some(limit) => limit
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|&limit| limit > 0)
+            .ok_or("Invalid limit specified.")?
+            .min(MAX_SEARCH_PAGE_SIZE),
+        None => DEFAULT_SEARCH_PAGE_SIZE,
+    };
+    let offset = match (&search.offset, &search.page) {
+        (Some(_), Some(_)) => return Err("Specify either offset or page."),
+        (Some(offset), None) => offset
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| "Invalid offset specified.")?,
+        (None, Some(page)) => page
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .and_then(|page| page.checked_sub(1)?.checked_mul(limit))
+            .ok_or("Invalid page specified.")?,
+        (None, None) => 0,
+    };
+    Ok(Some(SearchPage { limit, offset }))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SuggestBy {
+    Name,
+    PackageBase,
+}
+
+#[utoipa::path(
+    get,
+    path = "/rpc/v5/suggest/{arg}",
+    tag = "rpc",
+    params(("arg" = String, Path, description = "Prefix of package names")),
+    responses((status = 200, description = "Up to 20 package names", body = Vec<String>))
+)]
+async fn handle_suggest_path(
+    State(state): State<RpcState>,
+    Path(arg): Path<String>,
+) -> Result<Response<String>, StatusCode> {
+    handle_suggest(state, SuggestBy::Name, &arg, None).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/rpc/v5/suggest-pkgbase/{arg}",
+    tag = "rpc",
+    params(("arg" = String, Path, description = "Prefix of package base names")),
+    responses((status = 200, description = "Up to 20 package base names", body = Vec<String>))
+)]
+async fn handle_suggest_pkgbase_path(
+    State(state): State<RpcState>,
+    Path(arg): Path<String>,
+) -> Result<Response<String>, StatusCode> {
+    handle_suggest(state, SuggestBy::PackageBase, &arg, None).await
+}
+
+/// Suggestions are a bare array of names, without the usual envelope.
+async fn handle_suggest(
+    state: RpcState,
+    by: SuggestBy,
+    prefix: &str,
+    callback: Option<String>,
+) -> Result<Response<String>, StatusCode> {
+    // Like aurweb, an empty prefix suggests nothing rather than everything
+    if prefix.is_empty() {
+        return Ok(create_response(&Vec::<String>::new(), callback));
+    }
+
+    let names = match by {
+        SuggestBy::Name => state.db.suggest_names(prefix, SUGGEST_LIMIT).await,
+        SuggestBy::PackageBase => state.db.suggest_pkgbases(prefix, SUGGEST_LIMIT).await,
+    };
+    match names {
+        Ok(names) => Ok(create_response(&names, callback)),
+        Err(e) => {
+            error!("Database error during suggest: {}", e);
+            Ok(internal_error_response(5, callback))
+        }
+    }
+}
+
+async fn handle_info(
+    state: RpcState,
+    version: u32,
+    args: Vec<String>,
+    params: RpcParams,
+    callback: Option<String>,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    if args.is_empty() {
+        let error = error_response("No request type/data specified.".to_string(), Some(version));
+        return Ok(create_response(&error, callback).into_response());
+    }
+
+    // Helpers may repeat names, which must neither count towards the limit
+    // nor show up twice in the results
+    let mut seen = HashSet::new();
+    let args: Vec<String> = args
+        .into_iter()
+        .filter(|arg| seen.insert(arg.clone()))
+        .collect();
+    if args.len() > state.max_info_args.max(state.max_split_info_args) {
+        let error = error_response("Too many package results.".to_string(), Some(version));
+        return Ok(create_response(&error, callback).into_response());
+    }
+    let fields = match parse_fields(params.fields.as_deref(), RpcPackageDetails::FIELDS) {
+        Ok(fields) => fields,
+        Err(message) => {
+            let error = error_response(message.to_string(), Some(version));
+            return Ok(create_response(&error, callback).into_response());
+        }
+    };
+    let as_of = match parse_as_of(params.as_of.as_deref(), state.history_window) {
+        Ok(as_of) => as_of,
+        Err(message) => {
+            let error = error_response(message, Some(version));
+            return Ok(create_response(&error, callback).into_response());
+        }
+    };
+    let include_srcinfo =
+        match parse_include_srcinfo(params.include_srcinfo.as_deref(), state.store_srcinfo) {
+            Ok(include_srcinfo) => include_srcinfo,
+            Err(message) => {
+                let error = error_response(message.to_string(), Some(version));
+                return Ok(create_response(&error, callback).into_response());
+            }
+        };
+
+    if let Some(fetcher) = &state.live_lookup {
+        // Live lookup is best effort, the info request is answered regardless
+        if let Err(e) = lookup_missing_supplements(&state.db, fetcher, &args).await {
+            warn!("Live supplement lookup failed: {}", e);
+        }
+    }
+
+    if args.len() > state.max_info_args {
+        let info = SplitInfo {
+            names: args,
+            fields,
+            as_of,
+            include_srcinfo,
+        };
+        return Ok(stream_split_info_response(&state, version, info, callback, headers).await);
+    }
+
+    match state.queries.get_package_details(&args).await {
+        Ok(package_details) => {
+            let supplement_source = match state.db.get_supplement_source_validators().await {
+                Ok(supplement_source) => supplement_source,
+                Err(e) => {
+                    error!("Database error during info lookup: {}", e);
+                    return Ok(internal_error_response(version, callback).into_response());
+                }
+            };
+            let validators = info_validators(
+                package_details.iter().map(|details| &details.info),
+                supplement_source.as_ref(),
+            );
+            if validators.matches(headers) {
+                return Ok(validators.not_modified().into_response());
+            }
+
+            let history = match as_of {
+                Some(as_of) => match state.db.get_history_versions(&args, as_of).await {
+                    Ok(history) => history_by_name(history),
+                    Err(e) => {
+                        error!("Database error during info lookup: {}", e);
+                        return Ok(internal_error_response(version, callback).into_response());
+                    }
+                },
+                None => HashMap::new(),
+            };
+            let package_details: Vec<_> = package_details
+                .into_iter()
+                .filter_map(|details| pin_to_time(details, as_of, &history))
+                .collect();
+            let srcinfos = if include_srcinfo {
+                match state
+                    .db
+                    .get_srcinfos(&pinned_branches(&package_details))
+                    .await
+                {
+                    Ok(srcinfos) => srcinfos_by_branch(srcinfos),
+                    Err(e) => {
+                        error!("Database error during info lookup: {}", e);
+                        return Ok(internal_error_response(version, callback).into_response());
+                    }
+                }
+            } else {
+                HashMap::new()
+            };
+            // The v6 routes are ours, so they needn't match aurweb byte for byte
+            let commit_fields = state.commit_fields || version == 6;
+            let results: Vec<RpcPackageDetails> = package_details
+                .into_iter()
+                .map(|(details, as_of_commit)| {
+                    info_result(
+                        &state.url_path_template,
+                        commit_fields,
+                        details,
+                        as_of_commit,
+                        &srcinfos,
+                    )
+                })
+                .collect();
+
+            let response = RpcResponse {
+                error: None,
+                result_count: results.len(),
+                total: None,
+                offset: None,
+                results,
+                response_type: "multiinfo".to_string(),
+                version: Some(version),
+            };
+
+            let mut response = results_response(response, fields.as_ref(), callback);
+            validators.apply(&mut response);
+            Ok(response.into_response())
+        }
+        Err(e) => {
+            error!("Database error during info lookup: {}", e);
+            Ok(internal_error_response(version, callback).into_response())
+        }
+    }
+}
+
+/// An info request above `max_info_args`, served in chunks of that size
+struct SplitInfo {
+    names: Vec<String>,
+    fields: Option<FieldSelection>,
+    as_of: Option<i64>,
+    include_srcinfo: bool,
+}
+
+/// An info request above `max_info_args`, read in chunks of that size from
+/// one read transaction so all chunks show the same sync. The chunks are
+/// read twice: first for the result count and validators, which precede
+/// the results, keeping only the package summaries, then once more while
+/// the results are serialized, so neither the details nor their JSON ever
+/// sit in memory as a whole.
+async fn stream_split_info_response(
+    state: &RpcState,
+    version: u32,
+    info: SplitInfo,
+    callback: Option<String>,
+    headers: &HeaderMap,
+) -> Response {
+    let SplitInfo {
+        names,
+        fields,
+        as_of,
+        include_srcinfo,
+    } = info;
+    let chunks: Vec<Vec<String>> = names
+        .chunks(state.max_info_args.max(1))
+        .map(<[String]>::to_vec)
+        .collect();
+    let summary = async {
+        let mut snapshot = state.db.read_snapshot().await?;
+        let mut infos = Vec::new();
+        for chunk in &chunks {
+            let details = snapshot.get_package_details(chunk).await?;
+            infos.extend(details.into_iter().map(|details| details.info));
+        }
+        let history = match as_of {
+            Some(as_of) => history_by_name(snapshot.get_history_versions(&names, as_of).await?),
+            None => HashMap::new(),
+        };
+        let supplement_source = state.db.get_supplement_source_validators().await?;
+        anyhow::Ok((snapshot, infos, history, supplement_source))
+    };
+    let (snapshot, infos, history, supplement_source) = match summary.await {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!("Database error during info lookup: {}", e);
+            return internal_error_response(version, callback).into_response();
+        }
+    };
+    let validators = info_validators(infos.iter(), supplement_source.as_ref());
+    if validators.matches(headers) {
+        return validators.not_modified().into_response();
+    }
+    let result_count = infos
+        .iter()
+        .filter(|info| has_version_at(info, as_of, &history))
+        .count();
+    drop(infos);
+
+    let chunks = SplitInfoChunks {
+        snapshot,
+        chunks: chunks.into_iter(),
+        as_of,
+        history,
+        include_srcinfo,
+        fields,
+        url_path_template: state.url_path_template.clone(),
+        commit_fields: state.commit_fields || version == 6,
+        written: 0,
+    };
+    let results = futures::stream::try_unfold(chunks, |mut chunks| async move {
+        Ok(chunks.next_chunk().await?.map(|chunk| (chunk, chunks)))
+    })
+    .inspect_err(|e| error!("Database error while streaming an info response: {}", e));
+    streamed_results_response(
+        version,
+        "multiinfo",
+        result_count,
+        callback,
+        results,
+        Some(&validators),
+    )
+}
+
+/// The remaining chunks of a split info request and what their results
+/// are built with.
+struct SplitInfoChunks {
+    snapshot: ReadSnapshot,
+    chunks: std::vec::IntoIter<Vec<String>>,
+    as_of: Option<i64>,
+    history: HashMap<String, DatabaseHistoryVersion>,
+    include_srcinfo: bool,
+    fields: Option<FieldSelection>,
+    url_path_template: UrlPathTemplate,
+    commit_fields: bool,
+    /// Results serialized so far, to place the separating commas
+    written: usize,
+}
+
+impl SplitInfoChunks {
+    /// The JSON of the next chunk's results, `None` after the last chunk.
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>> {
+        let Some(names) = self.chunks.next() else {
+            return Ok(None);
+        };
+        let package_details: Vec<_> = self
+            .snapshot
+            .get_package_details(&names)
+            .await?
+            .into_iter()
+            .filter_map(|details| pin_to_time(details, self.as_of, &self.history))
+            .collect();
+        let srcinfos = if self.include_srcinfo {
+            let branches = pinned_branches(&package_details);
+            srcinfos_by_branch(self.snapshot.get_srcinfos(&branches).await?)
+        } else {
+            HashMap::new()
+        };
+        let mut chunk = Vec::new();
+        for (details, as_of_commit) in package_details {
+            let result = info_result(
+                &self.url_path_template,
+                self.commit_fields,
+                details,
+                as_of_commit,
+                &srcinfos,
+            );
+            if self.written > 0 {
+                chunk.push(b',');
+            }
+            match &self.fields {
+                Some(fields) => serde_json::to_writer(&mut chunk, &fields.select_one(result)),
+                None => serde_json::to_writer(&mut chunk, &result),
+            }?;
+            self.written += 1;
+        }
+        Ok(Some(Bytes::from(chunk)))
+    }
+}
+
+/// An info result with the stored `.SRCINFO` of the very commit it
+/// reports, if any.
+fn info_result(
+    url_path_template: &UrlPathTemplate,
+    commit_fields: bool,
+    details: DatabasePackageDetailsWithSupplement,
+    as_of_commit: Option<String>,
+    srcinfos: &HashMap<String, DatabaseSrcInfo>,
+) -> RpcPackageDetails {
+    let commit_id = as_of_commit.as_ref().unwrap_or(&details.info.commit_id);
+    let srcinfo = srcinfos
+        .get(&details.info.branch)
+        .filter(|srcinfo| srcinfo.commit_id == *commit_id)
+        .map(|srcinfo| srcinfo.srcinfo.clone());
+    RpcPackageDetails {
+        as_of_commit,
+        srcinfo,
+        ..rpc_package_details(url_path_template, commit_fields, details)
+    }
+}
+
+/// The distinct package bases of `package_details`.
+fn pinned_branches(
+    package_details: &[(DatabasePackageDetailsWithSupplement, Option<String>)],
+) -> Vec<String> {
+    package_details
+        .iter()
+        .map(|(details, _)| details.info.branch.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn srcinfos_by_branch(srcinfos: Vec<DatabaseSrcInfo>) -> HashMap<String, DatabaseSrcInfo> {
+    srcinfos
+        .into_iter()
+        .map(|srcinfo| (srcinfo.branch.clone(), srcinfo))
+        .collect()
+}
+
+/// The `as_of` parameter; times before the history window are rejected
+/// since their versions may already be pruned.
+fn parse_as_of(as_of: Option<&str>, history_window: Duration) -> Result<Option<i64>, String> {
+    let Some(as_of) = as_of else {
+        return Ok(None);
+    };
+    let as_of: i64 = as_of
+        .trim()
+        .parse()
+        .map_err(|_| "Invalid as_of specified.".to_string())?;
+    let cutoff = SystemTime::now()
+        .checked_sub(history_window)
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64);
+    if as_of < cutoff {
+        return Err(format!(
+            "as_of is before the history window, which starts at {}.",
+            cutoff
+        ));
+    }
+    Ok(Some(as_of))
+}
+
+/// The `include_srcinfo` flag, which needs `store_srcinfo` to mean anything.
+fn parse_include_srcinfo(value: Option<&str>, stored: bool) -> Result<bool, &'static str> {
+    let include = match value {
+        None | Some("0") => false,
+        Some("1") => true,
+        Some(_) => return Err("Invalid include_srcinfo specified."),
+    };
+    if include && !stored {
+        return Err("SrcInfo is not stored on this mirror.");
+    }
+    Ok(include)
+}
+
+/// The package as of `as_of`, with the commit its data comes from: the
+/// current data if committed by then, else the `.SRCINFO` fields recorded
+/// in `history`. The aurweb fields stay current, as the history has none,
+/// except `LastModified` and `OutOfDate`, which only describe the current
+/// version. `None` for a package with neither, left out like unknown ones.
+/// Without `as_of` the package is returned as is.
+fn pin_to_time(
+    details: DatabasePackageDetailsWithSupplement,
+    as_of: Option<i64>,
+    history: &HashMap<String, DatabaseHistoryVersion>,
+) -> Option<(DatabasePackageDetailsWithSupplement, Option<String>)> {
+    let Some(as_of) = as_of else {
+        return Some((details, None));
+    };
+    if details.info.committed_at <= as_of {
+        let commit_id = details.info.commit_id.clone();
+        return Some((details, Some(commit_id)));
+    }
+    let pinned = history.get(&details.info.pkg_name)?.clone();
+    let DatabaseHistoryDetails {
+        pkg_desc,
+        url,
+        depends,
+        make_depends,
+        opt_depends,
+        check_depends,
+        provides,
+        conflicts,
+        replaces,
+        groups,
+        licenses,
+    } = pinned.details;
+    let details = DatabasePackageDetailsWithSupplement {
+        info: DatabasePackageInfoWithSupplement {
+            commit_id: pinned.commit_id.clone(),
+            committed_at: pinned.committed_at,
+            pkg_desc,
+            version: pinned.version,
+            url,
+            last_modified: None,
+            out_of_date: None,
+            ..details.info
+        },
+        depends,
+        make_depends,
+        opt_depends,
+        check_depends,
+        provides,
+        conflicts,
+        replaces,
+        groups,
+        licenses,
+        ..details
+    };
+    Some((details, Some(pinned.commit_id)))
+}
+
+/// Whether [`pin_to_time`] keeps the package of `info`.
+fn has_version_at(
+    info: &DatabasePackageInfoWithSupplement,
+    as_of: Option<i64>,
+    history: &HashMap<String, DatabaseHistoryVersion>,
+) -> bool {
+    as_of.is_none_or(|as_of| info.committed_at <= as_of || history.contains_key(&info.pkg_name))
+}
+
+fn history_by_name(
+    history: Vec<DatabaseHistoryVersion>,
+) -> HashMap<String, DatabaseHistoryVersion> {
+    history
+        .into_iter()
+        .map(|version| (version.pkg_name.clone(), version))
+        .collect()
+}
+
+/// Count requests and their latency per matched route; unmatched requests
+/// never reach a route layer, so arbitrary paths can't blow up the labels.
+async fn record_request_metrics(
+    State(metrics): State<Arc<Metrics>>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path.as_str().to_string();
+    let method = request.method().clone();
+    let api_key = request.extensions().get::<ApiKey>().cloned();
+    let timer = metrics
+        .http_request_duration
+        .with_label_values(&[route.as_str()])
+        .start_timer();
+    let in_flight = metrics.track_in_flight();
+    let response = next.run(request).await;
+    drop(in_flight);
+    timer.observe_duration();
+    metrics
+        .http_requests
+        .with_label_values(&[route.as_str(), method.as_str(), response.status().as_str()])
+        .inc();
+    if let Some(api_key) = api_key {
+        metrics
+            .api_key_requests
+            .with_label_values(&[&*api_key.name, response.status().as_str()])
+            .inc();
+    }
+    response
+}
+
+/// Prometheus scrape endpoint. The allowlist is checked against the peer
+/// address only, as proxy headers could be forged by anyone reaching the
+/// server directly.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 403, description = "Peer not in `metrics_allowlist`"),
+    )
+)]
+async fn handle_metrics(
+    State(state): State<RpcState>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+) -> Result<Response<String>, StatusCode> {
+    if !state.metrics_allowlist.is_empty()
+        && !state
+            .metrics_allowlist
+            .iter()
+            .any(|range| range.contains(peer))
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let (connections, idle_connections) = state.db.pool_stats();
+    state.metrics.db_pool_connections.set(connections as i64);
+    state
+        .metrics
+        .db_pool_idle_connections
+        .set(idle_connections as i64);
+    match state.db.get_last_successful_sync().await {
+        Ok(last_sync) => state
+            .metrics
+            .last_successful_sync
+            .set(last_sync.unwrap_or_default()),
+        Err(e) => warn!("Failed to read the last successful sync: {}", e),
+    }
+
+    let body = state.metrics.render().map_err(|e| {
+        error!("Failed to render metrics: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body)
+        .unwrap())
+}
+
+/// Liveness: the process is up and the database answers.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "The database answers", body = String, content_type = "text/plain"),
+        (status = 503, description = "The database is unavailable", body = String, content_type = "text/plain"),
+    )
+)]
+async fn handle_healthz(State(state): State<RpcState>) -> (StatusCode, &'static str) {
+    match state.db.ping().await {
+        Ok(()) => (StatusCode::OK, "ok\n"),
+        Err(e) => {
+            error!("Health check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable\n")
+        }
+    }
+}
+
+/// Server-sent events of package changes and finished syncs. Clients
+/// reconnecting with `Last-Event-ID` get the changes they missed first.
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    tag = "operations",
+    params(("Last-Event-ID" = Option<String>, Header, description = "Id of the last event received")),
+    responses(
+        (status = 200, description = "Stream of package changes and finished syncs", content_type = "text/event-stream"),
+        (status = 503, description = "Too many open streams"),
+    )
+)]
+async fn handle_events(State(state): State<RpcState>, headers: HeaderMap) -> Response {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse().ok());
+    match state.events.subscribe(last_event_id) {
+        Some(stream) => Sse::new(stream)
+            .keep_alive(KeepAlive::new().interval(HEARTBEAT_INTERVAL))
+            .into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many event stream connections",
+        )
+            .into_response(),
+    }
+}
+
+/// Package counts, data ages and the state of the sync schedule.
+#[utoipa::path(
+    get,
+    path = "/api/stats",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Overview of the mirror", body = MirrorStats),
+        (status = 500, description = "The database is unavailable"),
+    )
+)]
+async fn handle_stats(State(state): State<RpcState>) -> Result<Response<String>, StatusCode> {
+    let stats = collect_stats(&state).await.map_err(|e| {
+        error!("Failed to collect stats: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(create_response(&stats, None))
+}
+
+async fn collect_stats(state: &RpcState) -> Result<MirrorStats> {
+    let scheduler = state.sync_scheduler.as_ref();
+    Ok(MirrorStats {
+        database: state.db.get_stats().await?,
+        sync: SyncScheduleStats {
+            running: state.sync_trigger.running().await,
+            interval_secs: scheduler.map(|scheduler| scheduler.interval().as_secs()),
+            next_run_at: scheduler.and_then(|scheduler| scheduler.next_run_at()),
+        },
+        branch_filters: state.branch_filter.stats(),
+    })
+}
+
+/// Readiness: a sync has completed, recently enough to serve its data.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Ready to serve", content_type = "application/json"),
+        (status = 503, description = "No sync completed, or the last one is too old", content_type = "application/json"),
+    )
+)]
+async fn handle_readyz(State(state): State<RpcState>) -> Response<String> {
+    let last_sync = match state.db.get_last_successful_sync().await {
+        Ok(last_sync) => last_sync,
+        Err(e) => {
+            error!("Readiness check failed: {}", e);
+            return readiness_response(false, None, "Database unavailable".to_string());
+        }
+    };
+    let Some(last_sync) = last_sync else {
+        return readiness_response(false, None, "No sync has completed yet".to_string());
+    };
+    let age = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64 - last_sync)
+        .unwrap_or_default()
+        .max(0) as u64;
+    match state.ready_max_sync_age {
+        Some(max_age) if age > max_age.as_secs() => readiness_response(
+            false,
+            Some(last_sync),
+            format!(
+                "Last successful sync is {}s old, more than the allowed {}s",
+                age,
+                max_age.as_secs()
+            ),
+        ),
+        _ => readiness_response(true, Some(last_sync), "Ready".to_string()),
+    }
+}
+
+fn readiness_response(ready: bool, last_sync: Option<i64>, reason: String) -> Response<String> {
+    let body = serde_json::json!({
+        "ready": ready,
+        "last_successful_sync": last_sync,
+        "reason": reason,
+    });
+    let mut response = create_response(&body, None);
+    if !ready {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+/// Validators for responses that may depend on any package.
+async fn data_version_validators(db: &DatabaseOps) -> Option<Validators> {
+    match db.get_data_version().await {
+        Ok(data_version) => data_version.map(|data_version| {
+            Validators::from_parts(
+                [data_version.generation.to_string()],
+                Some(data_version.updated_at),
+            )
+        }),
+        Err(e) => {
+            warn!("Failed to read the data version: {}", e);
+            None
+        }
+    }
+}
+
+/// A plain list of names served gzip-compressed.
+#[derive(Debug, Clone, Copy)]
+enum NameList {
+    Packages,
+    PackageBases,
+}
+
+/// Names of all listed packages, like aurweb's `packages.gz`.
+#[utoipa::path(
+    get,
+    path = "/packages.gz",
+    tag = "lists",
+    responses(
+        (status = 200, description = "Gzipped names, one per line", content_type = "application/gzip"),
+        (status = 304, description = "Unchanged since the given validators"),
+    )
+)]
+async fn handle_packages_gz(
+    State(state): State<RpcState>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    handle_name_list(state, NameList::Packages, method, headers).await
+}
+
+/// All package bases with a listed package, like aurweb's `pkgbase.gz`.
+#[utoipa::path(
+    get,
+    path = "/pkgbase.gz",
+    tag = "lists",
+    responses(
+        (status = 200, description = "Gzipped names, one per line", content_type = "application/gzip"),
+        (status = 304, description = "Unchanged since the given validators"),
+    )
+)]
+async fn handle_pkgbase_gz(
+    State(state): State<RpcState>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    handle_name_list(state, NameList::PackageBases, method, headers).await
+}
+
+async fn handle_name_list(
+    state: RpcState,
+    list: NameList,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let validators = data_version_validators(&state.db).await;
+    if let Some(validators) = validators.as_ref().filter(|v| v.matches(&headers)) {
+        return validators.not_modified().into_response();
+    }
+
+    // HEAD gets the headers of GET without generating the list just to drop
+    // it; the length is unknown either way
+    let body = if method == Method::HEAD {
+        Body::from_stream(futures::stream::empty::<std::io::Result<Bytes>>())
+    } else {
+        name_list_body(state.db.clone(), list)
+    };
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/gzip"),
+    );
+    if let Some(validators) = &validators {
+        validators.apply(&mut response);
+    }
+    response
+}
+
+/// The gzipped list, compressed while reading so it never sits in memory as
+/// a whole.
+fn name_list_body(db: DatabaseOps, list: NameList) -> Body {
+    let (mut sender, receiver) = futures::channel::mpsc::channel::<std::io::Result<Bytes>>(4);
+    tokio::spawn(async move {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let mut names = match list {
+            NameList::Packages => db.stream_package_names(),
+            NameList::PackageBases => db.stream_pkgbases(),
+        };
+        while let Some(name) = names.next().await {
+            let written = match name {
+                Ok(name) => writeln!(encoder, "{}", name),
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            if let Err(e) = written {
+                error!("Failed to generate {:?} list: {}", list, e);
+                let _ = sender.send(Err(e)).await;
+                return;
+            }
+            if encoder.get_ref().len() >= NAME_LIST_CHUNK_BYTES {
+                let chunk = std::mem::take(encoder.get_mut());
+                if sender.send(Ok(chunk.into())).await.is_err() {
+                    return; // Client went away
+                }
+            }
+        }
+        let _ = sender.send(encoder.finish().map(Into::into)).await;
+    });
+    Body::from_stream(receiver)
+}
+
+/// Validators of an info response, derived from what the response is built
+/// from: the commit of every package plus its supplement fields, and the
+/// supplement archive they came from.
+fn info_validators<'a>(
+    infos: impl Iterator<Item = &'a DatabasePackageInfoWithSupplement> + Clone,
+    supplement_source: Option<&SupplementSourceValidators>,
+) -> Validators {
+    let optional = |value: Option<i64>| value.map(|value| value.to_string()).unwrap_or_default();
+    let packages = infos.clone().flat_map(|info| {
+        [
+            info.pkg_name.clone(),
+            info.commit_id.clone(),
+            optional(info.last_modified),
+            optional(info.num_votes),
+            info.popularity.unwrap_or_default().to_string(),
+            optional(info.out_of_date),
+            info.maintainer.clone().unwrap_or_default(),
+        ]
+    });
+    let source = supplement_source.into_iter().flat_map(|source| {
+        [
+            source.source.clone(),
+            source.etag.clone().unwrap_or_default(),
+            source.last_modified.clone().unwrap_or_default(),
+        ]
+    });
+    Validators::from_parts(
+        packages.chain(source),
+        infos.map(|info| info.committed_at).max(),
+    )
+}
+
+/// Look up packages among `package_names` that have no supplement data yet,
+/// or only expired live looked up data, and store what the AUR RPC returns.
+async fn lookup_missing_supplements(
+    db: &DatabaseOps,
+    fetcher: &SupplementFetcher,
+    package_names: &[String],
+) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let stale_before = now - fetcher.options().live_lookup_ttl.as_secs() as i64;
+    let missing = db
+        .get_names_lacking_supplement(package_names, stale_before)
+        .await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let supplements = tokio::time::timeout(LIVE_LOOKUP_TIMEOUT, fetcher.lookup_live(&missing))
+        .await
+        .map_err(|_| anyhow!("timed out after {:?}", LIVE_LOOKUP_TIMEOUT))??;
+    db.store_live_supplement_data(&supplements, now).await
+}
+
+/// Refresh supplement data right away. Requires `Authorization: Bearer
+/// <admin_token>`; without a configured token the endpoint doesn't exist.
+#[utoipa::path(
+    post,
+    path = "/admin/supplement/refresh",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Refreshed", body = String, content_type = "text/plain"),
+        (status = 409, description = "A refresh is already running"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `admin_token` configured"),
+    )
+)]
+async fn handle_admin_supplement_refresh(
+    State(state): State<RpcState>,
+    headers: HeaderMap,
+) -> Result<&'static str, StatusCode> {
+    check_admin_token(&state, &headers)?;
+
+    info!("Supplement refresh triggered via admin endpoint");
+    match state.supplement_refresher.try_refresh().await {
+        Ok(()) => Ok("Supplement data refreshed\n"),
+        Err(e) if e.is::<AlreadyRunning>() => Err(StatusCode::CONFLICT),
+        Err(e) => {
+            error!("Supplement refresh failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Admin endpoints require `Authorization: Bearer <admin_token>` and don't
+/// exist without a configured token. The tokens are compared by their
+/// SHA-256 in constant time, so the timing tells nothing about the token,
+/// not even its length.
+fn check_admin_token(state: &RpcState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(admin_token) = &state.admin_token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let admin_token = Sha256::digest(admin_token.as_bytes());
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| {
+            let token = Sha256::digest(token.as_bytes());
+            bool::from(token.as_slice().ct_eq(admin_token.as_slice()))
+        });
+    if !authorized {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AdminSyncRequest {
+    /// Limit the sync to these pkgbases
+    pkgbases: Option<Vec<String>>,
+    /// Limit the sync to the pkgbases of these packages
+    packages: Option<Vec<String>>,
+}
+
+/// Start a sync in the background and answer with its run id, or with the
+/// id of the sync already running. A sync limited to pkgbases or packages
+/// refreshes them even if their commit didn't move; packages that aren't
+/// indexed are listed as `unknown_packages`, pkgbases missing upstream are
+/// logged by the run.
+#[utoipa::path(
+    post,
+    path = "/admin/sync",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body(
+        description = "Optionally `{\"pkgbases\": [...], \"packages\": [...]}` to limit the sync",
+        content_type = "application/json",
+    ),
+    responses(
+        (status = 202, description = "`{\"id\": <run id>, \"started\": <bool>}`, plus `unknown_packages` when limited to packages", content_type = "application/json"),
+        (status = 400, description = "Malformed body"),
+        (status = 422, description = "None of the given packages are indexed"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `admin_token` configured"),
+    )
+)]
+async fn handle_admin_sync(
+    State(state): State<RpcState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response<String>, StatusCode> {
+    check_admin_token(&state, &headers)?;
+    let request: AdminSyncRequest = if body.is_empty() {
+        AdminSyncRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?
+    };
+
+    let (scope, unknown_packages) = match (&request.pkgbases, &request.packages) {
+        (None, None) => (None, None),
+        (pkgbases, packages) => {
+            let packages = packages.as_deref().unwrap_or_default();
+            let (scope, unknown) = state
+                .sync_trigger
+                .resolve_scope(pkgbases.as_deref().unwrap_or_default(), packages)
+                .await
+                .map_err(|e| {
+                    error!("Failed to resolve packages: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            if scope.is_empty() && !packages.is_empty() {
+                return Err(StatusCode::UNPROCESSABLE_ENTITY);
+            }
+            (Some(scope), request.packages.is_some().then_some(unknown))
+        }
+    };
+    let scope_len = scope.as_ref().map(Vec::len);
+    let (run_id, started) = state.sync_trigger.trigger(scope).await.map_err(|e| {
+        if e.is::<SyncLocked>() {
+            warn!("Not starting a sync: {}", e);
+            return StatusCode::CONFLICT;
+        }
+        error!("Failed to start sync: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if started {
+        match scope_len {
+            Some(len) => info!(
+                "Sync run {} for {} pkgbases triggered via admin endpoint",
+                run_id, len
+            ),
+            None => info!("Sync run {} triggered via admin endpoint", run_id),
+        }
+    }
+    let mut body = serde_json::json!({ "id": run_id, "started": started });
+    if let Some(unknown_packages) = unknown_packages {
+        body["unknown_packages"] = serde_json::json!(unknown_packages);
+    }
+    let mut response = create_response(&body, None);
+    *response.status_mut() = StatusCode::ACCEPTED;
+    response.headers_mut().insert(
+        header::LOCATION,
+        header::HeaderValue::from_str(&format!("/admin/sync/{}", run_id)).unwrap(),
+    );
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sync/{run_id}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("run_id" = i64, Path, description = "Id of the sync run")),
+    responses(
+        (status = 200, description = "The sync run", body = DatabaseSyncRun),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `admin_token` configured"),
+    )
+)]
+async fn handle_admin_sync_status(
+    State(state): State<RpcState>,
+    Path(run_id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Response<String>, StatusCode> {
+    check_admin_token(&state, &headers)?;
+    match state.db.get_sync_run(run_id).await {
+        Ok(Some(run)) => Ok(create_response(&run, None)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to read sync run {}: {}", run_id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AdminBranchesQuery {
+    /// Prefix of the branch names, ignoring ASCII case
+    #[serde(default)]
+    prefix: String,
+    /// Page size, 100 by default and at most 1000
+    limit: Option<u32>,
+    #[serde(default)]
+    offset: u32,
+    /// `upstream` to compare with the branches of the git host
+    compare: Option<String>,
+}
+
+/// Indexed branches with their commit, for triaging stale packages. With
+/// `compare=upstream` each one is annotated with the upstream commit, and
+/// upstream branches the mirror lacks are listed too.
+#[utoipa::path(
+    get,
+    path = "/admin/branches",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(AdminBranchesQuery),
+    responses(
+        (status = 200, description = "`{\"branches\": [...], \"limit\", \"offset\"}`, plus `upstream_only` when comparing", body = Vec<DatabaseBranchSummary>),
+        (status = 400, description = "Unknown `compare` value"),
+        (status = 502, description = "The git host failed"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No `admin_token` configured"),
+    )
+)]
+async fn handle_admin_branches(
+    State(state): State<RpcState>,
+    headers: HeaderMap,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<AdminBranchesQuery>,
+) -> Result<Response<String>, StatusCode> {
+    check_admin_token(&state, &headers)?;
+    let compare = match query.compare.as_deref() {
+        None => false,
+        Some("upstream") => true,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(ADMIN_BRANCHES_PAGE_SIZE)
+        .min(MAX_ADMIN_BRANCHES_PAGE_SIZE);
+    let branches = state
+        .db
+        .list_branches(&query.prefix, limit, query.offset)
+        .await
+        .map_err(|e| {
+            error!("Failed to list branches: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if !compare {
+        let body = serde_json::json!({
+            "branches": branches,
+            "limit": limit,
+            "offset": query.offset,
+        });
+        return Ok(create_response(&body, None));
+    }
+
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    let upstream = state
+        .fetcher
+        .fetch_branch_list(&cancel)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch upstream branch list: {}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+    let branches: Vec<_> = branches
+        .into_iter()
+        .map(|branch| {
+            let upstream_commit_id = upstream.get(&branch.branch);
+            let differs = upstream_commit_id != Some(&branch.commit_id);
+            let mut entry = serde_json::to_value(&branch).unwrap_or_default();
+            entry["upstream_commit_id"] = serde_json::json!(upstream_commit_id);
+            entry["differs"] = serde_json::json!(differs);
+            entry
+        })
+        .collect();
+    // Names the mirror lacks can't be paged along with the indexed ones, so
+    // they are only listed with the first page
+    let mut upstream_only = Vec::new();
+    if query.offset == 0 {
+        let prefix = query.prefix.to_ascii_lowercase();
+        let indexed = state.db.get_existing_commits().await.map_err(|e| {
+            error!("Failed to read branch commits: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        upstream_only = upstream
+            .keys()
+            .filter(|branch| branch.to_ascii_lowercase().starts_with(&prefix))
+            .filter(|branch| !indexed.contains_key(*branch))
+            .collect();
+        upstream_only.sort();
+        upstream_only.truncate(limit as usize);
+    }
+    let body = serde_json::json!({
+        "branches": branches,
+        "upstream_only": upstream_only,
+        "limit": limit,
+        "offset": query.offset,
+    });
+    Ok(create_response(&body, None))
+}
+
+async fn branch_commit_id(state: &RpcState, branch_name: &str) -> Result<String, StatusCode> {
+    match state.db.get_branch_commit_id(branch_name).await {
+        Ok(Some(commit_id)) => Ok(commit_id),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to look up branch {}: {}", branch_name, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Fetch the whole tree of a branch's commit; any failure is the upstream's.
+async fn fetch_branch_tree(
+    state: &RpcState,
+    branch_name: &str,
+    commit_id: &str,
+) -> Result<FetchedTree, StatusCode> {
+    // Stop fetching once the client goes away
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    state
+        .fetcher
+        .fetch_tree_files(commit_id, &cancel)
+        .await
+        .map_err(|e| {
+            error!(
+                "Failed to fetch tree of {} at {}: {}",
+                branch_name, commit_id, e
+            );
+            StatusCode::BAD_GATEWAY
+        })
+}
+
+/// A cgit-style snapshot of a pkgbase's current commit, assembled from its
+/// tree fetched upstream.
+#[utoipa::path(
+    get,
+    path = "/cgit/aur.git/snapshot/{snapshot_name}",
+    tag = "git",
+    params(("snapshot_name" = String, Path, description = "`<pkgbase>.tar.gz`")),
+    responses(
+        (status = 200, description = "The snapshot", content_type = "application/gzip"),
+        (status = 404, description = "Unknown package base"),
+        (status = 502, description = "The git host failed"),
+    )
+)]
+async fn handle_snapshot(
+    State(state): State<RpcState>,
+    Path(snapshot_name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let branch_name = snapshot_name
+        .strip_suffix(".tar.gz")
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let commit_id = branch_commit_id(&state, branch_name).await?;
+
+    // A snapshot never changes for a given commit, and gzip isn't compressed
+    // again, so the bytes are identical for every request
+    let validators = Validators::from_parts([branch_name, commit_id.as_str()], None).strong();
+    if validators.matches(&headers) {
+        return Ok(validators.not_modified().into_response());
+    }
+
+    let Some(cache) = state.snapshot_cache.clone() else {
+        let (tarball, committed_at) = build_snapshot(&state, branch_name, &commit_id).await?;
+        let validators = Validators {
+            last_modified: Some(committed_at),
+            ..validators
+        };
+        return snapshot_response(&snapshot_name, Body::from(tarball), &validators);
+    };
+    if let Some(response) = cached_snapshot_response(
+        &state,
+        &cache,
+        &snapshot_name,
+        branch_name,
+        &commit_id,
+        &validators,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+
+    // Concurrent first requests wait for one generation, then find it cached
+    let _generation = cache.lock_generation(branch_name, &commit_id).await;
+    if let Some(response) = cached_snapshot_response(
+        &state,
+        &cache,
+        &snapshot_name,
+        branch_name,
+        &commit_id,
+        &validators,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+    let (tarball, committed_at) = build_snapshot(&state, branch_name, &commit_id).await?;
+    if let Err(e) = cache.put(branch_name, &commit_id, &tarball).await {
+        warn!("Failed to cache snapshot of {}: {}", branch_name, e);
+    }
+    let validators = Validators {
+        last_modified: Some(committed_at),
+        ..validators
+    };
+    snapshot_response(&snapshot_name, Body::from(tarball), &validators)
+}
+
+/// The tarball of `branch_name` at `commit_id` and when that was committed.
+async fn build_snapshot(
+    state: &RpcState,
+    branch_name: &str,
+    commit_id: &str,
+) -> Result<(Vec<u8>, i64), StatusCode> {
+    let tree = fetch_branch_tree(state, branch_name, commit_id).await?;
+    let committed_at = tree.committed_at;
+    let branch = branch_name.to_string();
+    let tarball = tokio::task::spawn_blocking(move || snapshot::build_tarball(&branch, &tree))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|tarball| tarball)
+        .map_err(|e| {
+            error!("Failed to build snapshot of {}: {}", branch_name, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok((tarball, committed_at))
+}
+
+/// The snapshot streamed from the cache, if it's there.
+async fn cached_snapshot_response(
+    state: &RpcState,
+    cache: &SnapshotCache,
+    snapshot_name: &str,
+    branch_name: &str,
+    commit_id: &str,
+    validators: &Validators,
+) -> Result<Option<Response>, StatusCode> {
+    let Some(cached) = cache.get(branch_name, commit_id).await else {
+        return Ok(None);
+    };
+    let last_modified = state
+        .db
+        .get_branch_committed_at(branch_name)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let validators = Validators {
+        last_modified,
+        ..validators.clone()
+    };
+    let mut response = snapshot_response(
+        snapshot_name,
+        Body::from_stream(ReaderStream::new(cached.file)),
+        &validators,
+    )?;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_LENGTH, cached.size.into());
+    Ok(Some(response))
+}
+
+fn snapshot_response(
+    snapshot_name: &str,
+    body: Body,
+    validators: &Validators,
+) -> Result<Response, StatusCode> {
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-gzip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", snapshot_name),
+        )
+        .body(body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    validators.apply(&mut response);
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CgitPlainQuery {
+    /// Package base
+    h: Option<String>,
+}
+
+/// cgit's `plain/` view: `/cgit/aur.git/plain/<path>?h=<pkgbase>`.
+#[utoipa::path(
+    get,
+    path = "/cgit/aur.git/plain/{path}",
+    tag = "git",
+    params(("path" = String, Path, description = "File in the package base"), CgitPlainQuery),
+    responses(
+        (status = 200, description = "The file", content_type = "application/octet-stream"),
+        (status = 404, description = "Unknown package base or file"),
+        (status = 502, description = "The git host failed"),
+    )
+)]
+async fn handle_cgit_plain(
+    State(state): State<RpcState>,
+    Path(path): Path<String>,
+    Query(query): Query<CgitPlainQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let branch = query.h.ok_or(StatusCode::BAD_REQUEST)?;
+    serve_raw_file(state, &branch, &path, headers).await
+}
+
+/// A file of a package base's current commit.
+#[utoipa::path(
+    get,
+    path = "/raw/{branch}/{path}",
+    tag = "git",
+    params(
+        ("branch" = String, Path, description = "Package base"),
+        ("path" = String, Path, description = "File in the package base"),
+    ),
+    responses(
+        (status = 200, description = "The file", content_type = "application/octet-stream"),
+        (status = 404, description = "Unknown package base or file"),
+        (status = 502, description = "The git host failed"),
+    )
+)]
+async fn handle_raw_file(
+    State(state): State<RpcState>,
+    Path((branch, path)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    serve_raw_file(state, &branch, &path, headers).await
+}
+
+/// The stored `.SRCINFO` of a package base, tagged with its blob id.
+#[utoipa::path(
+    get,
+    path = "/api/srcinfo/{pkgbase}",
+    tag = "git",
+    params(("pkgbase" = String, Path, description = "Package base")),
+    responses(
+        (status = 200, description = "The `.SRCINFO` of the indexed commit", body = String, content_type = "text/plain"),
+        (status = 304, description = "Unchanged since the given ETag"),
+        (status = 404, description = "Unknown package base, or not stored yet"),
+    )
+)]
+async fn handle_srcinfo(
+    State(state): State<RpcState>,
+    Path(pkgbase): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if !state.store_srcinfo {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let srcinfo = state
+        .db
+        .get_srcinfos(&[pkgbase])
+        .await
+        .map_err(|e| {
+            error!("Database error during .SRCINFO lookup: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .pop()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let validators = Validators {
+        etag: srcinfo.blob_id,
+        strong: false,
+        last_modified: None,
+    };
+    if validators.matches(&headers) {
+        return Ok(validators.not_modified().into_response());
+    }
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from(srcinfo.srcinfo))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    validators.apply(&mut response);
+    Ok(response)
+}
+
+/// A file of a pkgbase's current commit as plain text, tagged with its blob
+/// id.
+async fn serve_raw_file(
+    state: RpcState,
+    branch_name: &str,
+    path: &str,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    // The path is only looked up in the tree, but don't let `..` pass as if
+    // it meant anything
+    if path
+        .split('/')
+        .any(|segment| matches!(segment, "" | "." | ".."))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let commit_id = branch_commit_id(&state, branch_name).await?;
+    let tree = fetch_branch_tree(&state, branch_name, &commit_id).await?;
+    let file = tree
+        .files
+        .into_iter()
+        .find(|file| file.path == path)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let validators = Validators {
+        etag: file.blob_id,
+        strong: false,
+        last_modified: Some(tree.committed_at),
+    };
+    if validators.matches(&headers) {
+        return Ok(validators.not_modified().into_response());
+    }
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from(file.content))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    validators.apply(&mut response);
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/{branch}/info/refs",
+    tag = "git",
+    params(("branch" = String, Path, description = "Package base")),
+    responses((status = 200, description = "Ref advertisement of the git smart HTTP protocol"))
+)]
+async fn handle_git_info_refs(
+    State(state): State<RpcState>,
+    Path(branch): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    // Remove .git extension if present
+    let branch_name = branch.strip_suffix(".git").unwrap_or(&branch);
+
+    let service = match params.get("service") {
+        Some(s) => s,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Please upgrade your git client."))
                 .unwrap());
         }
     };
@@ -350,25 +3493,39 @@ async fn handle_git_info_refs(
     if service != "git-upload-pack" {
         return Ok(Response::builder()
             .status(StatusCode::FORBIDDEN)
-            .body("Unsupported service".to_string())
+            .body(Body::from("Unsupported service"))
             .unwrap());
     }
 
     // Check if package exists and get commit ID
     match state.db.get_branch_commit_id(branch_name).await {
         Ok(Some(commit_id)) => {
-            let response_body = format!("001e# service=git-upload-pack\n000000e1{} HEAD\u{0000}multi_ack thin-pack side-band side-band-64k ofs-delta no-progress include-tag multi_ack_detailed no-done symref=HEAD:refs/heads/master object-format=sha1 agent=git/aur-mirror\n003f{} refs/heads/master\n0000",
-                commit_id,
-                commit_id
+            info!(
+                "Git ref discovery for {} via {}",
+                branch_name, state.git_upstream
             );
+            let base_url = match &state.git_upstream {
+                GitUpstream::GitHub => {
+                    let response_body = format!("001e# service=git-upload-pack\n000000e1{} HEAD\u{0000}multi_ack thin-pack side-band side-band-64k ofs-delta no-progress include-tag multi_ack_detailed no-done symref=HEAD:refs/heads/master object-format=sha1 agent=git/aur-mirror\n003f{} refs/heads/master\n0000",
+                        commit_id,
+                        commit_id
+                    );
 
-            Ok(Response::builder()
-                .header(
-                    header::CONTENT_TYPE,
-                    "application/x-git-upload-pack-advertisement",
-                )
-                .body(response_body)
-                .unwrap())
+                    return Ok(Response::builder()
+                        .header(
+                            header::CONTENT_TYPE,
+                            "application/x-git-upload-pack-advertisement",
+                        )
+                        .body(Body::from(response_body))
+                        .unwrap());
+                }
+                GitUpstream::PerPackage(base_url) => base_url,
+            };
+            let url = format!(
+                "{}/{}.git/info/refs?service=git-upload-pack",
+                base_url, branch_name
+            );
+            proxy_git_request(&state, state.client.get(url), &headers, Body::empty()).await
         }
         Ok(None) => Err(StatusCode::NOT_FOUND),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
@@ -379,12 +3536,80 @@ fn error_response(message: String, version: Option<u32>) -> RpcResponse<()> {
     RpcResponse::<()> {
         error: Some(message),
         result_count: 0,
+        total: None,
+        offset: None,
         results: Vec::new(),
         response_type: "error".to_string(),
         version,
     }
 }
 
+/// The error object for failures on our side. Like every other error it
+/// comes with a 200, so it is marked uncacheable instead.
+fn internal_error_response(version: u32, callback: Option<String>) -> Response<String> {
+    let error = error_response("Internal server error.".to_string(), Some(version));
+    let mut response = create_response(&error, callback);
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-store"),
+    );
+    response
+}
+
+/// The error object for a form body that doesn't parse, e.g. one that
+/// repeats `type`.
+fn invalid_form_response(version: u32) -> Response<String> {
+    let error = error_response("Invalid request body.".to_string(), Some(version));
+    create_response(&error, None)
+}
+
+/// The error object for a query string that doesn't parse, e.g. one that
+/// repeats `v`; without the parsed `callback` it is always plain JSON.
+fn invalid_query_response(version: u32) -> Response<String> {
+    let error = error_response("Invalid query string.".to_string(), Some(version));
+    create_response(&error, None)
+}
+
+/// The parameters of a query string, parsed like a form body so failures
+/// can be answered with the error object rather than a plain-text 400.
+fn parse_query<T: serde::de::DeserializeOwned>(raw_query: Option<&str>) -> Option<T> {
+    serde_html_form::from_str(raw_query.unwrap_or_default()).ok()
+}
+
+/// The `fields` parameter checked against the fields of the result type.
+fn parse_fields(
+    fields: Option<&str>,
+    known: &[&'static str],
+) -> Result<Option<FieldSelection>, &'static str> {
+    fields
+        .map(|fields| FieldSelection::parse(fields, known))
+        .transpose()
+}
+
+/// Like [`create_response`], with the results reduced to the selected
+/// fields if any were selected; the envelope is always complete.
+fn results_response<T: serde::Serialize>(
+    response: RpcResponse<T>,
+    fields: Option<&FieldSelection>,
+    callback: Option<String>,
+) -> Response<String> {
+    match fields {
+        Some(fields) => create_response(
+            &response.map_results(|results| fields.select(results)),
+            callback,
+        ),
+        None => create_response(&response, callback),
+    }
+}
+
+/// Same character set and length limit as aurweb accepts for JSONP callbacks.
+fn is_valid_callback(callback: &str) -> bool {
+    (1..=128).contains(&callback.len())
+        && callback
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'(' | b')'))
+}
+
 fn create_response<T: serde::Serialize>(data: &T, callback: Option<String>) -> Response<String> {
     let json = serde_json::to_string(data).unwrap();
 
@@ -404,48 +3629,849 @@ fn create_response<T: serde::Serialize>(data: &T, callback: Option<String>) -> R
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/{branch}/git-upload-pack",
+    tag = "git",
+    params(("branch" = String, Path, description = "Package base")),
+    responses((status = 200, description = "Pack of the git smart HTTP protocol"))
+)]
 async fn handle_git_upload_pack_post(
     State(state): State<RpcState>,
     Path(branch): Path<String>,
     headers: HeaderMap,
     body: Body,
-) -> Result<Response<Body>, StatusCode> {
+) -> Result<Response, StatusCode> {
     let branch_name = branch.strip_suffix(".git").unwrap_or(&branch);
 
     // Check if package exists and get commit ID
     match state.db.get_branch_commit_id(branch_name).await {
         Ok(Some(_)) => {
-            let mut req = state
-                .client
-                .post("https://github.com/archlinux/aur.git/git-upload-pack");
-            for (key, value) in headers.iter() {
-                match *key {
-                    header::HOST => {
-                        // Skip
-                    }
-                    header::AUTHORIZATION => {
-                        // Skip
-                    }
-                    _ => {
-                        req = req.header(key, value.clone());
-                    }
+            info!(
+                "Git upload-pack for {} via {}",
+                branch_name, state.git_upstream
+            );
+            let url = match &state.git_upstream {
+                GitUpstream::GitHub => GITHUB_AUR_UPLOAD_PACK_URL.to_string(),
+                GitUpstream::PerPackage(base_url) => {
+                    format!("{}/{}.git/git-upload-pack", base_url, branch_name)
                 }
+            };
+            proxy_git_request(&state, state.client.post(url), &headers, body).await
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Relay a git smart-HTTP request upstream, streaming both bodies.
+async fn proxy_git_request(
+    state: &RpcState,
+    mut req: reqwest::RequestBuilder,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    for (key, value) in headers.iter() {
+        match *key {
+            header::HOST => {
+                // Skip
+            }
+            header::AUTHORIZATION => {
+                // Skip
+            }
+            _ => {
+                req = req.header(key, value.clone());
+            }
+        }
+    }
+    // The token is only meant for GitHub
+    if let (GitUpstream::GitHub, Some(token)) = (&state.git_upstream, &state.github_token) {
+        req = req.basic_auth(token, None::<&str>);
+    }
+    let upstream = req
+        .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+        .send()
+        .await
+        .map_err(|e| {
+            error!("Git upstream request failed: {}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+    let mut response_builder = Response::builder().status(upstream.status());
+    *response_builder.headers_mut().unwrap() = upstream.headers().clone();
+    response_builder
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::types::{DatabasePackageDetails, DatabaseSupplementData};
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    /// The RPC router over a fresh database, served on a free local port.
+    struct TestServer {
+        url: String,
+        client: reqwest::Client,
+        _dir: TempDir,
+    }
+
+    /// A package, of its own branch unless given one; listed packages are
+    /// also given supplement data, unlisted ones are committed too long
+    /// before it.
+    struct TestPackage {
+        branch: String,
+        name: String,
+        listed: bool,
+        maintainer: Option<String>,
+    }
+
+    /// Commit and modification time of the listed packages, fixed so the
+    /// responses are too
+    const TIMESTAMP: i64 = 1_700_000_000;
+
+    fn listed(name: impl Into<String>) -> TestPackage {
+        let name = name.into();
+        TestPackage {
+            branch: name.clone(),
+            name,
+            listed: true,
+            maintainer: None,
+        }
+    }
+
+    fn unlisted(name: impl Into<String>) -> TestPackage {
+        TestPackage {
+            listed: false,
+            ..listed(name)
+        }
+    }
+
+    impl TestPackage {
+        fn of(self, branch: &str) -> Self {
+            Self {
+                branch: branch.to_string(),
+                ..self
+            }
+        }
+
+        fn maintained_by(self, maintainer: &str) -> Self {
+            Self {
+                maintainer: Some(maintainer.to_string()),
+                ..self
+            }
+        }
+    }
+
+    impl TestServer {
+        async fn start(config: &str, packages: &[TestPackage]) -> Self {
+            let dir = TempDir::new().unwrap();
+            let config_path = dir.path().join("config.toml");
+            std::fs::write(&config_path, config).unwrap();
+            let db_path = dir.path().join("db.sqlite");
+            let app_state = AppState::from_config(
+                &Config::new(Some(config_path)).unwrap(),
+                db_path.to_str().unwrap(),
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+
+            let db = &app_state.db;
+            let mut tx = db.begin_transaction().await.unwrap();
+            let details: Vec<DatabasePackageDetails> = packages
+                .iter()
+                .map(|package| DatabasePackageDetails {
+                    branch: package.branch.clone(),
+                    committed_at: if package.listed { TIMESTAMP } else { 0 },
+                    ..DatabasePackageDetails::test_package(&package.name)
+                })
+                .collect();
+            for package in &details {
+                db.update_branch_commit_with_tx(&mut tx, &package.branch, &package.commit_id)
+                    .await
+                    .unwrap();
             }
-            if let Some(token) = state.github_token.as_deref() {
-                req = req.basic_auth(token, None::<&str>);
+            db.update_index_with_tx(&mut tx, &details).await.unwrap();
+            tx.commit().await.unwrap();
+            let supplements: Vec<DatabaseSupplementData> = packages
+                .iter()
+                .filter(|package| package.listed)
+                .map(|package| DatabaseSupplementData {
+                    pkgname: package.name.clone(),
+                    version: "1.0-1".to_string(),
+                    popularity: 0.0,
+                    num_votes: 0,
+                    out_of_date: None,
+                    maintainer: package.maintainer.clone(),
+                    submitter: None,
+                    co_maintainers: Vec::new(),
+                    keywords: Vec::new(),
+                    licenses: Vec::new(),
+                    package_base: package.branch.clone(),
+                    first_submitted: TIMESTAMP,
+                    last_modified: TIMESTAMP,
+                })
+                .collect();
+            db.store_supplement_data(&supplements, None, true)
+                .await
+                .unwrap();
+
+            let server = RpcServer::new(app_state, Vec::new()).await.unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let app = tag_listener(server.app.clone(), &ListenAddr::Tcp(addr.to_string()));
+            tokio::spawn(async move {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<PeerAddr>(),
+                )
+                .await
+            });
+            Self {
+                url: format!("http://{}", addr),
+                client: reqwest::Client::builder().no_proxy().build().unwrap(),
+                _dir: dir,
             }
-            let upstream = req
-                .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+        }
+
+        async fn get(&self, path: &str) -> (StatusCode, String) {
+            let response = self
+                .client
+                .get(format!("{}{}", self.url, path))
                 .send()
                 .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            let mut response_builder = Response::builder().status(upstream.status());
-            *response_builder.headers_mut().unwrap() = upstream.headers().clone();
-            response_builder
-                .body(Body::from_stream(upstream.bytes_stream()))
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+                .unwrap();
+            (response.status(), response.text().await.unwrap())
         }
-        Ok(None) => Err(StatusCode::NOT_FOUND),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+
+        async fn post_form(&self, path: &str, form: &str) -> (StatusCode, String) {
+            let response = self
+                .client
+                .post(format!("{}{}", self.url, path))
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(form.to_string())
+                .send()
+                .await
+                .unwrap();
+            (response.status(), response.text().await.unwrap())
+        }
+    }
+
+    async fn get_json(server: &TestServer, path: &str) -> serde_json::Value {
+        let (status, body) = server.get(path).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", path, body);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn suggest_pkgbase_with_an_empty_prefix_suggests_nothing() {
+        let server = TestServer::start("", &[listed("alpha"), listed("beta")]).await;
+        let (status, body) = server.get("/rpc?v=5&type=suggest-pkgbase&arg=").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "[]");
+    }
+
+    #[tokio::test]
+    async fn suggest_pkgbase_leaves_out_unlisted_packages() {
+        let server = TestServer::start(
+            "",
+            &[listed("foo-bar"), unlisted("foo-gone"), unlisted("zap")],
+        )
+        .await;
+        assert_eq!(
+            get_json(&server, "/rpc/v5/suggest-pkgbase/foo").await,
+            serde_json::json!(["foo-bar"])
+        );
+        // A prefix matching only unlisted packages suggests nothing at all
+        assert_eq!(
+            get_json(&server, "/rpc/v5/suggest-pkgbase/za").await,
+            serde_json::json!([])
+        );
+        assert_eq!(
+            get_json(&server, "/rpc/v5/suggest-pkgbase/foo-g").await,
+            serde_json::json!([])
+        );
+    }
+
+    fn info_query(names: &[String]) -> String {
+        let mut query = "/rpc?v=5&type=info".to_string();
+        for name in names {
+            query.push_str("&arg[]=");
+            query.push_str(name);
+        }
+        query
+    }
+
+    fn numbered(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("pkg{:03}", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn info_without_names_is_an_error() {
+        let server = TestServer::start("", &[listed("alpha")]).await;
+        let response = get_json(&server, &info_query(&[])).await;
+        assert_eq!(response["type"], "error");
+        assert_eq!(response["error"], "No request type/data specified.");
+    }
+
+    #[tokio::test]
+    async fn info_with_one_name() {
+        let server = TestServer::start("", &[listed("alpha"), listed("beta")]).await;
+        let response = get_json(&server, "/rpc?v=5&type=info&arg=alpha").await;
+        assert_eq!(response["type"], "multiinfo");
+        assert_eq!(response["resultcount"], 1);
+        assert_eq!(response["results"][0]["Name"], "alpha");
+    }
+
+    #[tokio::test]
+    async fn info_with_600_names_is_over_the_limit() {
+        let names = numbered(600);
+        let packages: Vec<TestPackage> = names.iter().map(listed).collect();
+        let server = TestServer::start("", &packages).await;
+        let response = get_json(&server, &info_query(&names)).await;
+        assert_eq!(response["type"], "error");
+        assert_eq!(response["error"], "Too many package results.");
+
+        // Repeats don't count towards the limit
+        let mut repeated = names[..500].to_vec();
+        repeated.extend_from_slice(&names[..100]);
+        let response = get_json(&server, &info_query(&repeated)).await;
+        assert_eq!(response["type"], "multiinfo");
+        assert_eq!(response["resultcount"], 500);
+    }
+
+    #[tokio::test]
+    async fn info_with_600_names_is_split_when_allowed() {
+        let names = numbered(600);
+        let packages: Vec<TestPackage> = names.iter().map(listed).collect();
+        let server = TestServer::start("rpc_max_split_info_args = 600\n", &packages).await;
+        let response = get_json(&server, &info_query(&names)).await;
+        assert_eq!(response["type"], "multiinfo");
+        assert_eq!(response["resultcount"], 600);
+    }
+
+    #[tokio::test]
+    async fn split_info_answers_like_unsplit_info() {
+        let names = numbered(250);
+        let packages: Vec<TestPackage> = names.iter().map(listed).collect();
+        let split = TestServer::start(
+            "rpc_max_info_args = 100\nrpc_max_split_info_args = 300\n",
+            &packages,
+        )
+        .await;
+        let whole = TestServer::start("rpc_max_info_args = 300\n", &packages).await;
+        let mut requested = names.clone();
+        requested.push("missing".to_string());
+
+        for query in [
+            info_query(&requested),
+            format!("{}&fields=Name,Version", info_query(&requested)),
+        ] {
+            let response = get_sorted(&split, &query).await;
+            assert_eq!(response["resultcount"], 250, "{}", query);
+            assert_eq!(response, get_sorted(&whole, &query).await, "{}", query);
+        }
+        let (_, body) = split
+            .get(&format!("{}&callback=cb", info_query(&requested)))
+            .await;
+        assert!(body.starts_with("cb({") && body.ends_with("});"));
+    }
+
+    #[tokio::test]
+    async fn as_of_pins_the_srcinfo_fields() {
+        let server = TestServer::start(
+            "history_days = 100000\nrpc_commit_fields = true\n",
+            &[listed("alpha"), listed("beta")],
+        )
+        .await;
+        let db = DatabaseOps::new(
+            server._dir.path().join("db.sqlite").to_str().unwrap(),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        let mut tx = db.begin_transaction().await.unwrap();
+        db.store_history_with_tx(
+            &mut tx,
+            &[DatabasePackageDetails {
+                commit_id: "1".repeat(40),
+                committed_at: TIMESTAMP - 200,
+                pkg_desc: Some("Old".to_string()),
+                version: "0.9-1".to_string(),
+                depends: vec!["glibc".to_string()],
+                ..DatabasePackageDetails::test_package("alpha")
+            }],
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let response = get_json(
+            &server,
+            &format!(
+                "/rpc/v5/info?arg[]=alpha&arg[]=beta&as_of={}",
+                TIMESTAMP - 100
+            ),
+        )
+        .await;
+        assert_eq!(response["resultcount"], 1);
+        let alpha = &response["results"][0];
+        assert_eq!(alpha["Version"], "0.9-1");
+        assert_eq!(alpha["Description"], "Old");
+        assert_eq!(alpha["Depends"], serde_json::json!(["glibc"]));
+        assert_eq!(alpha["CommitId"], "1".repeat(40));
+        assert_eq!(alpha["CommittedAt"], TIMESTAMP - 200);
+        assert_eq!(alpha["AsOfCommit"], "1".repeat(40));
+        assert_eq!(alpha["LastModified"], 0);
+        assert_eq!(alpha["OutOfDate"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn post_info_with_400_names() {
+        let names = numbered(400);
+        let packages: Vec<TestPackage> = names.iter().map(listed).collect();
+        let server = TestServer::start("", &packages).await;
+        let form: String = names
+            .iter()
+            .map(|name| format!("&arg[]={}", name))
+            .collect();
+
+        for (path, form) in [
+            ("/rpc", format!("v=5&type=info{}", form)),
+            ("/rpc/v5/info", form[1..].to_string()),
+        ] {
+            let (status, body) = server.post_form(path, &form).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+            let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(response["type"], "multiinfo", "{}", path);
+            assert_eq!(response["resultcount"], 400, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_methods_are_not_allowed() {
+        let server = TestServer::start("", &[]).await;
+        let response = server
+            .client
+            .put(format!("{}/rpc?v=5&type=info&arg=alpha", server.url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(response["type"], "error");
+    }
+
+    /// Error responses as aurweb sends them, byte for byte.
+    #[tokio::test]
+    async fn errors_match_aurweb() {
+        let server = TestServer::start("", &[listed("alpha")]).await;
+        let error = |version: &str, message: &str| {
+            format!(
+                r#"{{"version":{},"type":"error","resultcount":0,"results":[],"error":"{}"}}"#,
+                version, message
+            )
+        };
+        let cases = [
+            ("/rpc", error("null", "Please specify an API version.")),
+            (
+                "/rpc?v=&type=info&arg=alpha",
+                error("null", "Please specify an API version."),
+            ),
+            (
+                "/rpc?v=6&type=info&arg=alpha",
+                error("6", "Invalid version specified."),
+            ),
+            (
+                "/rpc?v=banana&type=info&arg=alpha",
+                error("null", "Invalid version specified."),
+            ),
+            ("/rpc?v=5", error("5", "No request type/data specified.")),
+            (
+                "/rpc?v=5&type=search",
+                error("5", "No request type/data specified."),
+            ),
+            (
+                "/rpc?v=5&type=nope&arg=alpha",
+                error("5", "Incorrect request type specified."),
+            ),
+            (
+                "/rpc?v=5&type=search&arg=a",
+                error("5", "Query arg too small."),
+            ),
+            (
+                "/rpc?v=5&type=search&by=nope&arg=alpha",
+                error("5", "Incorrect by field specified."),
+            ),
+            (
+                "/rpc?v=5&type=search&by=nope",
+                error("5", "Incorrect by field specified."),
+            ),
+            (
+                "/rpc?v=5&type=info&arg=alpha&callback=a%3Bb",
+                error("5", "Invalid callback name."),
+            ),
+            (
+                "/rpc?v=5&v=5&type=info&arg=alpha",
+                error("5", "Invalid query string."),
+            ),
+            (
+                "/rpc/v5/info?arg=alpha&v=5&v=6",
+                error("5", "Invalid query string."),
+            ),
+            (
+                "/rpc/v5/info/alpha?by=name&by=name",
+                error("5", "Invalid query string."),
+            ),
+        ];
+        for (path, expected) in cases {
+            let (status, body) = server.get(path).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(body, expected, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn web_search_far_past_the_end_shows_the_last_page() {
+        let server = TestServer::start("", &[listed("alpha"), listed("alpine")]).await;
+        let (status, body) = server.get("/web?q=al&page=4294967295").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert!(body.contains("Page 1 of 1."), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn unparsable_forms_get_the_error_object() {
+        let server = TestServer::start("", &[listed("alpha")]).await;
+        for path in ["/rpc", "/rpc/v5/info"] {
+            let (status, body) = server
+                .post_form(path, "v=5&type=info&type=search&arg=alpha")
+                .await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(
+                body,
+                r#"{"version":5,"type":"error","resultcount":0,"results":[],"error":"Invalid request body."}"#,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pkgbase_gz_lists_each_base_once_in_order() {
+        let server = TestServer::start(
+            "",
+            &[
+                listed("zeta"),
+                listed("python-foo").of("foo"),
+                listed("foo-docs").of("foo"),
+                listed("alpha"),
+                unlisted("gone"),
+            ],
+        )
+        .await;
+        let response = server
+            .client
+            .get(format!("{}/pkgbase.gz", server.url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut list = String::new();
+        flate2::read::GzDecoder::new(&response.bytes().await.unwrap()[..])
+            .read_to_string(&mut list)
+            .unwrap();
+        assert_eq!(list, "alpha\nfoo\nzeta\n");
+    }
+
+    const SMALL_INPUT_LIMITS: &str =
+        "rpc_max_query_bytes = 64\nrpc_max_body_bytes = 64\nrpc_max_arg_params = 3\n";
+
+    /// An info request for one made-up name, exactly `len` bytes long.
+    fn info_of_length(len: usize) -> String {
+        let request = "v=5&type=info&arg=";
+        format!("{}{}", request, "a".repeat(len - request.len()))
+    }
+
+    #[tokio::test]
+    async fn query_strings_up_to_the_limit_are_accepted() {
+        let server = TestServer::start(SMALL_INPUT_LIMITS, &[]).await;
+        let response = get_json(&server, &format!("/rpc?{}", info_of_length(64))).await;
+        assert_eq!(response["type"], "multiinfo");
+
+        let (status, body) = server.get(&format!("/rpc?{}", info_of_length(65))).await;
+        assert_eq!(status, StatusCode::URI_TOO_LONG);
+        assert_eq!(
+            body,
+            r#"{"version":5,"type":"error","resultcount":0,"results":[],"error":"Query string too long."}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn bodies_up_to_the_limit_are_accepted() {
+        let server = TestServer::start(SMALL_INPUT_LIMITS, &[]).await;
+        let (status, body) = server.post_form("/rpc", &info_of_length(64)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""type":"multiinfo""#), "{}", body);
+
+        let (status, body) = server.post_form("/rpc", &info_of_length(65)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body,
+            r#"{"version":5,"type":"error","resultcount":0,"results":[],"error":"Request body too large."}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn arg_params_up_to_the_limit_are_accepted() {
+        let server = TestServer::start(SMALL_INPUT_LIMITS, &[]).await;
+        let response = get_json(&server, "/rpc?v=5&type=info&arg=a&arg[]=b&arg=c").await;
+        assert_eq!(response["type"], "multiinfo");
+
+        let response = get_json(&server, "/rpc?v=5&type=info&arg=a&arg[]=b&arg=c&arg=d").await;
+        assert_eq!(response["error"], "Too many package results.");
+        // Query and body count together
+        let (status, body) = server
+            .post_form("/rpc?arg=a&arg=b", "v=5&type=info&arg=c&arg=d")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("Too many package results."), "{}", body);
+    }
+
+    fn names(response: &serde_json::Value) -> Vec<&str> {
+        let mut names: Vec<&str> = response["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["Name"].as_str().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The requests as yay (Go's sorted, escaped `url.Values`), paru (raur,
+    /// against `/rpc/`) and aurutils (the `/rpc/v5` routes) send them.
+    #[tokio::test]
+    async fn helper_query_strings_are_understood() {
+        let server = TestServer::start(
+            "",
+            &[
+                listed("yay"),
+                listed("paru"),
+                listed("paru-bin"),
+                listed("aurutils"),
+            ],
+        )
+        .await;
+
+        let info = [
+            "/rpc?arg%5B%5D=paru&arg%5B%5D=yay&type=info&v=5",
+            "/rpc/?v=5&type=info&arg%5B%5D=paru&arg%5B%5D=yay",
+            "/rpc/v5/info?arg[]=paru&arg[]=yay",
+            "/rpc?v=5&type=info&arg=paru&arg=yay",
+            "/rpc?v=5&type=info&arg=paru&arg[]=yay",
+        ];
+        for path in info {
+            let response = get_json(&server, path).await;
+            assert_eq!(names(&response), ["paru", "yay"], "{}", path);
+        }
+        let (status, body) = server
+            .post_form("/rpc/v5/info", "arg[]=paru&arg[]=yay")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(names(&response), ["paru", "yay"]);
+
+        let search = [
+            "/rpc?arg=paru&by=name-desc&type=search&v=5",
+            "/rpc/?v=5&type=search&by=name&arg=paru",
+            "/rpc/v5/search/paru?by=name",
+            // Search takes the first arg, like aurweb
+            "/rpc?v=5&type=search&arg=paru&arg=yay",
+        ];
+        for path in search {
+            let response = get_json(&server, path).await;
+            assert_eq!(names(&response), ["paru", "paru-bin"], "{}", path);
+        }
+    }
+
+    /// Each row is a query and aurweb's answer: its error output, or the
+    /// request type when the request is valid.
+    #[test]
+    fn requests_are_validated_in_aurweb_order() {
+        let error = |version: &str, message: &str| {
+            Err(format!(
+                r#"{{"version":{},"type":"error","resultcount":0,"results":[],"error":"{}"}}"#,
+                version, message
+            ))
+        };
+        let no_version = error("null", "Please specify an API version.");
+        let invalid_version = |version| error(version, "Invalid version specified.");
+        let no_type = error("5", "No request type/data specified.");
+        let bad_type = error("5", "Incorrect request type specified.");
+        let bad_by = error("5", "Incorrect by field specified.");
+        let cases = [
+            ("", no_version.clone()),
+            ("type=info&arg=foo", no_version.clone()),
+            ("v=&type=info&arg=foo", no_version.clone()),
+            ("v=+&type=info&arg=foo", no_version.clone()),
+            ("type=search&by=nope&arg=foo", no_version),
+            ("v=4&type=search&arg=foo", invalid_version("4")),
+            ("v=6&type=info&arg=foo", invalid_version("6")),
+            ("v=banana&type=info&arg=foo", invalid_version("null")),
+            ("v=-5&type=info&arg=foo", invalid_version("null")),
+            ("v=4&by=nope", invalid_version("4")),
+            ("v=5&type=search&by=nope&arg=foo", bad_by.clone()),
+            ("v=5&by=nope", bad_by.clone()),
+            ("v=5&type=nope&by=nope&arg=foo", bad_by),
+            ("v=5", no_type.clone()),
+            ("v=5&arg=foo", no_type.clone()),
+            ("v=5&type=nope&arg=foo", bad_type.clone()),
+            ("v=5&type=nope", bad_type),
+            ("v=5&type=info", no_type.clone()),
+            ("v=5&type=search&by=name", no_type),
+            ("v=5&type=info&arg=foo", Ok(RpcRequestType::Info)),
+            (
+                "v=5&type=multiinfo&arg=foo&arg=bar",
+                Ok(RpcRequestType::Info),
+            ),
+            (
+                "v=+5+&type=search&by=maintainer&arg=foo",
+                Ok(RpcRequestType::Search),
+            ),
+            ("v=5&type=msearch&arg=foo", Ok(RpcRequestType::MSearch)),
+            ("v=5&type=suggest&arg=f", Ok(RpcRequestType::Suggest)),
+            (
+                "v=5&type=suggest-pkgbase&arg=f",
+                Ok(RpcRequestType::SuggestPkgbase),
+            ),
+        ];
+        for (query, expected) in cases {
+            let param = |key: &str| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(k, _)| k == key)
+                    .map(|(_, value)| value.into_owned())
+            };
+            let RpcArgs(args) = RpcArgs::parse(query.as_bytes());
+            let validated = validate_rpc_request(
+                param("v").as_deref(),
+                param("type").as_deref(),
+                param("by").as_deref(),
+                &args,
+            )
+            .map(|request| request.request_type)
+            .map_err(|error| serde_json::to_string(&error).unwrap());
+            assert_eq!(validated, expected, "{}", query);
+        }
+    }
+
+    /// A search result of the packages from [`legacy_server`]
+    fn search_result(name: &str, base: &str, maintainer: &str) -> serde_json::Value {
+        serde_json::json!({
+            "ID": 0,
+            "Name": name,
+            "Description": "",
+            "PackageBase": base,
+            "PackageBaseID": 0,
+            "Version": "1.0-1",
+            "URL": "",
+            "URLPath": format!("/cgit/aur.git/snapshot/{}.tar.gz", base),
+            "Maintainer": maintainer,
+            "NumVotes": 0,
+            "Popularity": 0.0,
+            "FirstSubmitted": TIMESTAMP,
+            "LastModified": TIMESTAMP,
+            "OutOfDate": null,
+        })
+    }
+
+    async fn legacy_server() -> TestServer {
+        TestServer::start(
+            "",
+            &[
+                listed("foo").maintained_by("alice"),
+                listed("foo-docs").of("foo").maintained_by("alice"),
+                listed("bar").maintained_by("bob"),
+            ],
+        )
+        .await
+    }
+
+    /// The response with its results in name order, as the fixtures list them
+    async fn get_sorted(server: &TestServer, path: &str) -> serde_json::Value {
+        let mut response = get_json(server, path).await;
+        if let Some(results) = response["results"].as_array_mut() {
+            results.sort_by(|a, b| a["Name"].as_str().cmp(&b["Name"].as_str()));
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn msearch_answers_like_aurweb() {
+        let server = legacy_server().await;
+        let alice = serde_json::json!({
+            "version": 5,
+            "type": "msearch",
+            "resultcount": 2,
+            "results": [
+                search_result("foo", "foo", "alice"),
+                search_result("foo-docs", "foo", "alice"),
+            ],
+        });
+        for path in [
+            "/rpc/?v=5&type=msearch&arg=alice",
+            "/rpc.php?v=5&type=msearch&arg=ALICE",
+            "/rpc?v=5&type=msearch&arg=alice&by=name",
+        ] {
+            assert_eq!(get_sorted(&server, path).await, alice, "{}", path);
+        }
+        assert_eq!(
+            get_sorted(&server, "/rpc?v=5&type=search&by=maintainer&arg=bob").await,
+            serde_json::json!({
+                "version": 5,
+                "type": "search",
+                "resultcount": 1,
+                "results": [search_result("bar", "bar", "bob")],
+            })
+        );
+        assert_eq!(
+            get_sorted(&server, "/rpc?v=5&type=msearch&arg=nobody").await,
+            serde_json::json!({
+                "version": 5,
+                "type": "msearch",
+                "resultcount": 0,
+                "results": [],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_php_answers_like_rpc() {
+        let server = legacy_server().await;
+        for query in [
+            "v=5&type=multiinfo&arg[]=foo&arg[]=bar",
+            "v=5&type=info&arg=foo&arg=bar",
+        ] {
+            let legacy = get_sorted(&server, &format!("/rpc.php?{}", query)).await;
+            assert_eq!(legacy["type"], "multiinfo");
+            assert_eq!(names(&legacy), ["bar", "foo"]);
+            assert_eq!(
+                legacy,
+                get_sorted(&server, &format!("/rpc?{}", query)).await
+            );
+        }
+        // Old scripts without `v` get aurweb's answer to that
+        assert_eq!(
+            server.get("/rpc.php?type=multiinfo&arg[]=foo").await,
+            (
+                StatusCode::OK,
+                r#"{"version":null,"type":"error","resultcount":0,"results":[],"error":"Please specify an API version."}"#
+                    .to_string()
+            )
+        );
     }
 }
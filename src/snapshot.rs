@@ -0,0 +1,49 @@
+use crate::aur_fetcher::{FetchedTree, TreeFileKind};
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+use tar::{Builder, EntryType, Header};
+
+/// Build a `.tar.gz` of a pkgbase's tree like cgit's snapshots: every file
+/// lives under a `<pkgbase>/` directory and carries the commit time.
+pub fn build_tarball(pkgbase: &str, tree: &FetchedTree) -> Result<Vec<u8>> {
+    let mtime = tree.committed_at.max(0) as u64;
+    let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    let mut header = new_header(EntryType::Directory, 0o755, mtime);
+    builder.append_data(&mut header, format!("{}/", pkgbase), std::io::empty())?;
+    for file in &tree.files {
+        let path = format!("{}/{}", pkgbase, file.path);
+        match file.kind {
+            TreeFileKind::Symlink => {
+                let target = String::from_utf8_lossy(&file.content);
+                let mut header = new_header(EntryType::Symlink, 0o777, mtime);
+                builder.append_link(&mut header, path, target.as_ref())?;
+            }
+            TreeFileKind::Regular | TreeFileKind::Executable => {
+                let mode = if file.kind == TreeFileKind::Executable {
+                    0o755
+                } else {
+                    0o644
+                };
+                let mut header = new_header(EntryType::Regular, mode, mtime);
+                header.set_size(file.content.len() as u64);
+                builder.append_data(&mut header, path, file.content.as_slice())?;
+            }
+        }
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+fn new_header(entry_type: EntryType, mode: u32, mtime: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_size(0);
+    // Same owner names as `git archive`
+    let _ = header.set_username("root");
+    let _ = header.set_groupname("root");
+    header
+}
@@ -0,0 +1,230 @@
+use crate::database::DatabaseOps;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::OwnedMutexGuard;
+use tracing::{debug, error, info};
+
+/// How often entries of moved-on package bases and excess entries are removed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Package base and commit id of a snapshot
+type SnapshotKey = (String, String);
+
+/// On-disk cache of generated snapshot tarballs, stored as
+/// `<pkgbase>/<commit id>.tar.gz`.
+///
+/// A snapshot of a commit never changes, so entries are never invalidated;
+/// the periodic sweep removes those of commits no longer indexed for their
+/// package base and then the least recently used ones until the cache fits
+/// into its size budget.
+pub struct SnapshotCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Held while a snapshot is generated, so concurrent first requests
+    /// wait for it instead of generating it again
+    generating: Mutex<HashMap<SnapshotKey, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// A cached snapshot opened for reading.
+pub struct CachedSnapshot {
+    pub file: tokio::fs::File,
+    pub size: u64,
+}
+
+/// Holds the generation lock of a snapshot until dropped.
+pub struct GenerationGuard<'a> {
+    cache: &'a SnapshotCache,
+    key: SnapshotKey,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl SnapshotCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            generating: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `None` for names that aren't safe as a file name; such package
+    /// bases aren't cached.
+    fn entry_path(&self, pkgbase: &str, commit_id: &str) -> Option<PathBuf> {
+        let safe = |name: &str| {
+            !name.is_empty()
+                && !name.starts_with('.')
+                && name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"@._+-".contains(&byte))
+        };
+        (safe(pkgbase) && safe(commit_id))
+            .then(|| self.dir.join(pkgbase).join(format!("{}.tar.gz", commit_id)))
+    }
+
+    pub async fn get(&self, pkgbase: &str, commit_id: &str) -> Option<CachedSnapshot> {
+        let path = self.entry_path(pkgbase, commit_id)?;
+        let file = tokio::fs::File::open(&path).await.ok()?;
+        let size = file.metadata().await.ok()?.len();
+        // Refresh mtime so eviction drops the least recently used entries first
+        let _ = touch(&path);
+        Some(CachedSnapshot { file, size })
+    }
+
+    pub async fn put(&self, pkgbase: &str, commit_id: &str, tarball: &[u8]) -> Result<()> {
+        let path = self
+            .entry_path(pkgbase, commit_id)
+            .ok_or_else(|| anyhow!("Package base {} can't be cached", pkgbase))?;
+        let parent = path
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid cache path {}", path.display()))?;
+        tokio::fs::create_dir_all(parent).await?;
+
+        // Write to a unique temp file then rename, so readers never observe
+        // a partially written entry
+        let tmp_path = parent.join(format!(".{}.{}.tmp", commit_id, std::process::id()));
+        tokio::fs::write(&tmp_path, tarball).await?;
+        if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Wait until no other request generates this snapshot, then hold it
+    /// off others until the guard is dropped. The cache should be checked
+    /// again once this returns.
+    pub async fn lock_generation(&self, pkgbase: &str, commit_id: &str) -> GenerationGuard<'_> {
+        let key = (pkgbase.to_string(), commit_id.to_string());
+        let lock = self
+            .generating
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.clone().lock_owned().await;
+        GenerationGuard {
+            cache: self,
+            key,
+            lock,
+            _guard: guard,
+        }
+    }
+
+    /// Sweep right away and then every few minutes.
+    pub fn spawn_sweep(self: Arc<Self>, db: DatabaseOps) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.sweep(&db).await {
+                    error!("Failed to sweep the snapshot cache: {}", e);
+                }
+                tokio::time::sleep(SWEEP_INTERVAL).await;
+            }
+        })
+    }
+
+    /// Remove entries of commits no longer indexed for their package base,
+    /// then the least recently used entries until the cache fits into the
+    /// size budget.
+    pub async fn sweep(&self, db: &DatabaseOps) -> Result<()> {
+        let current = db.get_existing_commits().await?;
+        let dir = self.dir.clone();
+        let max_bytes = self.max_bytes;
+        tokio::task::spawn_blocking(move || sweep_blocking(&dir, max_bytes, &current)).await?
+    }
+}
+
+impl Drop for GenerationGuard<'_> {
+    fn drop(&mut self) {
+        let mut generating = self.cache.generating.lock().unwrap();
+        // Only the map and this guard still refer to it, so nobody waits
+        if Arc::strong_count(&self.lock) == 2 {
+            generating.remove(&self.key);
+        }
+    }
+}
+
+struct CacheEntryMeta {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+fn sweep_blocking(dir: &Path, max_bytes: u64, current: &HashMap<String, String>) -> Result<()> {
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut outdated = 0usize;
+    let mut alive = Vec::new();
+    for pkgbase_dir in read_dir {
+        let pkgbase_dir = pkgbase_dir?;
+        if !pkgbase_dir.metadata()?.is_dir() {
+            continue;
+        }
+        let pkgbase = pkgbase_dir.file_name().to_string_lossy().into_owned();
+        let current_commit = current.get(&pkgbase);
+        for entry in std::fs::read_dir(pkgbase_dir.path())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Leftovers of interrupted writes count as outdated too
+            let is_current = name
+                .strip_suffix(".tar.gz")
+                .is_some_and(|commit_id| current_commit.is_some_and(|c| c == commit_id));
+            if !is_current {
+                if std::fs::remove_file(entry.path()).is_ok() {
+                    outdated += 1;
+                }
+                continue;
+            }
+            alive.push(CacheEntryMeta {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+        // Fails unless empty, which is fine
+        let _ = std::fs::remove_dir(pkgbase_dir.path());
+    }
+
+    let mut evicted = 0usize;
+    let mut total_bytes: u64 = alive.iter().map(|entry| entry.size).sum();
+    if total_bytes > max_bytes {
+        alive.sort_by_key(|entry| entry.modified);
+        for entry in alive {
+            if total_bytes <= max_bytes {
+                break;
+            }
+            if std::fs::remove_file(&entry.path).is_ok() {
+                total_bytes -= entry.size;
+                evicted += 1;
+            }
+        }
+    }
+
+    if outdated > 0 || evicted > 0 {
+        info!(
+            "Removed {} outdated and {} least recently used snapshots ({} bytes remaining)",
+            outdated, evicted, total_bytes
+        );
+    } else {
+        debug!(
+            "Snapshot cache holds {} bytes, nothing to evict",
+            total_bytes
+        );
+    }
+    Ok(())
+}
+
+fn touch(path: &Path) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
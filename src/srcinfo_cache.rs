@@ -0,0 +1,222 @@
+use crate::aur_fetcher::FetchedSrcInfo;
+use anyhow::{anyhow, Result};
+use gix_hash::oid;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+const COMMITS_DIR: &str = "commits";
+const BLOBS_DIR: &str = "blobs";
+
+/// Numbers the temp files of this process, so concurrent writes of the same
+/// entry never share one
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// On-disk cache of decoded `.SRCINFO` contents keyed by commit id, plus the
+/// raw `.SRCINFO` blobs keyed by blob id.
+///
+/// Both ids are content-addressed, so entries never need invalidation;
+/// they are only removed by [`SrcInfoCache::evict`] to bound disk usage.
+#[derive(Clone, Debug)]
+pub struct SrcInfoCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+}
+
+impl SrcInfoCache {
+    pub fn new(dir: PathBuf, max_bytes: u64, max_age: Duration) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            max_age,
+        }
+    }
+
+    fn entry_path(&self, kind: &str, id: &oid) -> PathBuf {
+        let hex = id.to_string();
+        let (prefix, rest) = hex.split_at(2);
+        self.dir.join(kind).join(prefix).join(rest)
+    }
+
+    pub async fn get(&self, commit_id: &oid) -> Option<FetchedSrcInfo> {
+        let path = self.entry_path(COMMITS_DIR, commit_id);
+        let content = read_entry(&path).await?;
+        let entry = parse_entry(&content);
+        if entry.is_none() {
+            warn!("Ignoring corrupted .SRCINFO cache entry {}", path.display());
+        }
+        entry
+    }
+
+    pub async fn put(&self, commit_id: &oid, srcinfo: &FetchedSrcInfo) -> Result<()> {
+        let content = format!("{}\n{}", srcinfo.committed_at, srcinfo.srcinfo_text);
+        write_entry(&self.entry_path(COMMITS_DIR, commit_id), &content).await
+    }
+
+    pub async fn get_blob(&self, blob_id: &oid) -> Option<String> {
+        read_entry(&self.entry_path(BLOBS_DIR, blob_id)).await
+    }
+
+    pub async fn put_blob(&self, blob_id: &oid, srcinfo_text: &str) -> Result<()> {
+        write_entry(&self.entry_path(BLOBS_DIR, blob_id), srcinfo_text).await
+    }
+
+    /// Remove entries older than the max age, then the least recently used
+    /// entries until the cache fits into the size budget.
+    pub async fn evict(&self) -> Result<()> {
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || cache.evict_blocking()).await?
+    }
+
+    fn evict_blocking(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        collect_entries(&self.dir.join(COMMITS_DIR), &mut entries)?;
+        collect_entries(&self.dir.join(BLOBS_DIR), &mut entries)?;
+
+        let now = SystemTime::now();
+        let mut removed = 0usize;
+        let (expired, mut alive): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| {
+            now.duration_since(entry.modified).unwrap_or_default() > self.max_age
+        });
+        for entry in expired {
+            if std::fs::remove_file(&entry.path).is_ok() {
+                removed += 1;
+            }
+        }
+
+        let mut total_bytes: u64 = alive.iter().map(|entry| entry.size).sum();
+        if total_bytes > self.max_bytes {
+            alive.sort_by_key(|entry| entry.modified);
+            for entry in alive {
+                if total_bytes <= self.max_bytes {
+                    break;
+                }
+                if std::fs::remove_file(&entry.path).is_ok() {
+                    total_bytes -= entry.size;
+                    removed += 1;
+                }
+            }
+        }
+
+        if removed > 0 {
+            info!(
+                "Evicted {} .SRCINFO cache entries ({} bytes remaining)",
+                removed, total_bytes
+            );
+        } else {
+            debug!(
+                ".SRCINFO cache holds {} bytes, nothing to evict",
+                total_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+struct CacheEntryMeta {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+async fn read_entry(path: &Path) -> Option<String> {
+    let content = tokio::fs::read_to_string(path).await.ok()?;
+    // Refresh mtime so eviction drops the least recently used entries first
+    let path = path.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || touch(&path)).await;
+    Some(content)
+}
+
+async fn write_entry(path: &Path, content: &str) -> Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid cache path {}", path.display()))?;
+    tokio::fs::create_dir_all(parent).await?;
+
+    // Write to a unique temp file then rename, so concurrent writers and
+    // readers never observe a partially written entry
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let tmp_path = parent.join(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    tokio::fs::write(&tmp_path, content).await?;
+    if let Err(e) = tokio::fs::rename(&tmp_path, path).await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn parse_entry(content: &str) -> Option<FetchedSrcInfo> {
+    let (committed_at, srcinfo_text) = content.split_once('\n')?;
+    Some(FetchedSrcInfo {
+        srcinfo_text: srcinfo_text.to_string(),
+        committed_at: committed_at.parse().ok()?,
+    })
+}
+
+fn touch(path: &Path) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+fn collect_entries(dir: &Path, entries: &mut Vec<CacheEntryMeta>) -> Result<()> {
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry?;
+        let metadata = dir_entry.metadata()?;
+        if metadata.is_dir() {
+            collect_entries(&dir_entry.path(), entries)?;
+        } else {
+            entries.push(CacheEntryMeta {
+                path: dir_entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gix_hash::ObjectId;
+
+    #[tokio::test]
+    async fn concurrent_writes_of_one_entry_all_succeed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SrcInfoCache::new(dir.path().to_path_buf(), 0, Duration::ZERO);
+        let blob_id = ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567").unwrap();
+        let writes: Vec<_> = (0..32)
+            .map(|_| {
+                let cache = cache.clone();
+                tokio::spawn(async move { cache.put_blob(&blob_id, "pkgbase = foo\n").await })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        assert_eq!(
+            cache.get_blob(&blob_id).await.as_deref(),
+            Some("pkgbase = foo\n")
+        );
+        let mut entries = Vec::new();
+        collect_entries(dir.path(), &mut entries).unwrap();
+        assert_eq!(entries.len(), 1, "temp files left behind");
+    }
+}
@@ -1,108 +1,1470 @@
-use crate::types::{DatabaseSupplementData, RpcPackageDetails};
+use crate::throttle::{RateLimiter, ThrottledRead};
+use crate::types::{DatabaseSupplementData, RpcPackageDetails, SupplementSourceValidators};
 use anyhow::{anyhow, Result};
+use async_tempfile::TempFile;
 use flate2::read::GzDecoder;
-use reqwest::Client;
-use std::io::Read;
-use tracing::{info, warn};
+use futures::{AsyncReadExt as _, TryStreamExt};
+use reqwest::{header, Client};
+use serde::de::{Deserializer as _, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt as _;
+use tracing::{debug, info, warn};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const STDIN_SOURCE: &str = "-";
+const LIVE_LOOKUP_URL: &str = "https://aur.archlinux.org/rpc/v5/info";
+const LIVE_LOOKUP_BATCH_SIZE: usize = 100;
+/// Minimum gap between two live lookup requests, to stay polite to aurweb
+const LIVE_LOOKUP_INTERVAL: Duration = Duration::from_secs(1);
+/// Downloads log their progress whenever this many more bytes arrived
+const DOWNLOAD_PROGRESS_STEP: u64 = 16 * 1024 * 1024;
+
+/// Numbers the temp files of this process, so concurrent cache writes never
+/// share one
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub enum SupplementFetchOutcome {
+    Fetched {
+        data: Vec<DatabaseSupplementData>,
+        validators: SupplementSourceValidators,
+        freshness: Freshness,
+        report: ParseReport,
+        /// Older than the max data age; listing status should not be
+        /// recomputed from it
+        stale: bool,
+    },
+    /// The source that served the stored data answered `304 Not Modified`
+    NotModified { source: String },
+}
+
+/// What to do with supplement data whose newest record is older than the
+/// configured max age, e.g. an archive served from a stale CDN cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StaleDataAction {
+    /// Treat the source as failed and try the next one
+    #[default]
+    Reject,
+    /// Store the data but keep the current listing status
+    KeepListing,
+}
+
+/// Variant of the AUR metadata archive, detected from its first record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SupplementFormat {
+    /// `packages-meta-ext-v1.json`
+    #[default]
+    Ext,
+    /// `packages-meta-v1.json`, lacking submitter, co-maintainers, keywords
+    /// and licenses
+    Basic,
+}
+
+/// Format of parsed supplement data and the records dropped on the way.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseReport {
+    pub format: SupplementFormat,
+    /// Elements not matching the package schema
+    pub malformed: usize,
+    /// Records with an empty name or negative timestamps
+    pub invalid: usize,
+    /// Older records of a package name seen more than once
+    pub duplicates: usize,
+}
+
+impl ParseReport {
+    fn add(&mut self, other: ParseReport) {
+        if other.format == SupplementFormat::Ext {
+            self.format = SupplementFormat::Ext;
+        }
+        self.malformed += other.malformed;
+        self.invalid += other.invalid;
+        self.duplicates += other.duplicates;
+    }
+}
+
+impl fmt::Display for ParseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.format {
+            SupplementFormat::Ext => "ext format",
+            SupplementFormat::Basic => "basic format",
+        };
+        write!(
+            f,
+            "{}, {} malformed, {} invalid, {} duplicate records dropped",
+            format, self.malformed, self.invalid, self.duplicates
+        )
+    }
+}
+
+/// A record of `packages-meta-v1.json`, which carries everything of the ext
+/// variant except submitter, co-maintainers, keywords and licenses.
+#[derive(Deserialize)]
+struct BasicPackageMeta {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "PackageBase")]
+    package_base: String,
+    #[serde(rename = "Version")]
+    version: String,
+    #[serde(rename = "NumVotes")]
+    num_votes: u32,
+    #[serde(rename = "Popularity")]
+    popularity: f64,
+    #[serde(rename = "OutOfDate", default)]
+    out_of_date: Option<i64>,
+    #[serde(rename = "Maintainer", default)]
+    maintainer: Option<String>,
+    #[serde(rename = "FirstSubmitted")]
+    first_submitted: i64,
+    #[serde(rename = "LastModified")]
+    last_modified: i64,
+}
+
+impl From<BasicPackageMeta> for DatabaseSupplementData {
+    fn from(item: BasicPackageMeta) -> Self {
+        Self {
+            pkgname: item.name,
+            version: item.version,
+            popularity: item.popularity,
+            num_votes: item.num_votes,
+            out_of_date: item.out_of_date,
+            maintainer: item.maintainer,
+            submitter: None,
+            co_maintainers: Vec::new(),
+            keywords: Vec::new(),
+            licenses: Vec::new(),
+            package_base: item.package_base,
+            first_submitted: item.first_submitted,
+            last_modified: item.last_modified,
+        }
+    }
+}
+
+/// Whether fetched supplement data is current.
+pub enum Freshness {
+    /// Freshly fetched from a source
+    Fresh,
+    /// Every source failed; loaded from the copy cached by an earlier sync
+    Cached,
+}
+
+enum Download {
+    Modified {
+        spooled: TempFile,
+        validators: SupplementSourceValidators,
+    },
+    NotModified,
+}
+
+/// Where a supplement source is read from.
+enum Location {
+    Url(String),
+    Path(PathBuf),
+    /// `-`, read once from standard input
+    Stdin,
+}
 
 pub struct SupplementFetcher {
     client: Client,
+    user_agent: String,
+    rate_limiter: Option<RateLimiter>,
+    options: SupplementOptions,
+    stdin_consumed: AtomicBool,
+    last_live_lookup: tokio::sync::Mutex<Option<Instant>>,
+}
+
+/// Tuning of supplement fetching, read from the config file.
+#[derive(Debug, Clone)]
+pub struct SupplementOptions {
+    /// Retries per source for transient failures
+    pub retries: u32,
+    /// Merge all sources instead of using the first that succeeds
+    pub merge: bool,
+    /// Fraction of malformed records a source may contain before it fails
+    pub max_bad_fraction: f64,
+    /// Timeout for establishing a connection to a source
+    pub connect_timeout: Duration,
+    /// Timeout for a whole download, body included
+    pub timeout: Duration,
+    /// Largest raw download accepted from a source
+    pub max_bytes: u64,
+    /// Where the raw data of the last successful fetch is kept
+    pub cache_path: Option<PathBuf>,
+    /// Oldest cached copy still used when every source fails
+    pub cache_max_age: Duration,
+    /// Max age of the newest record before data counts as stale
+    pub max_data_age: Duration,
+    pub stale_data_action: StaleDataAction,
+    /// Look packages lacking supplement data up through the AUR RPC
+    pub live_lookup: bool,
+    /// How long live looked up data is used before looking it up again
+    pub live_lookup_ttl: Duration,
+    /// Interval of the periodic refresh while serving, if enabled
+    pub refresh_interval: Option<Duration>,
 }
 
+/// Expected SHA-256 digest of a supplement source, given as a `|sha256:...`
+/// suffix on the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Checksum {
+    /// Digest of the raw (possibly compressed) bytes
+    Raw(String),
+    /// Digest of the decompressed JSON payload
+    Payload(String),
+    /// Raw digest published in a companion `<source>.sha256` file
+    Sidecar,
+}
+
+#[derive(Deserialize)]
+struct LiveLookupResponse {
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    results: Vec<serde_json::Value>,
+}
+
+/// A non-success HTTP status, kept typed so retries can tell 5xx from 4xx.
+#[derive(Debug)]
+struct HttpStatusError(reqwest::StatusCode);
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP error: {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
 impl SupplementFetcher {
-    pub fn new() -> Self {
-        Self {
-            client: Client::new(),
+    pub fn new(
+        user_agent: String,
+        rate_limiter: Option<RateLimiter>,
+        options: SupplementOptions,
+    ) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(options.connect_timeout)
+            .timeout(options.timeout)
+            .build()?;
+        Ok(Self {
+            client,
+            user_agent,
+            rate_limiter,
+            options,
+            stdin_consumed: AtomicBool::new(false),
+            last_live_lookup: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn options(&self) -> &SupplementOptions {
+        &self.options
+    }
+
+    /// Look packages up one batch at a time through the official AUR RPC,
+    /// for packages indexed since the last supplement archive was fetched.
+    /// Requests are spaced by [`LIVE_LOOKUP_INTERVAL`] across all callers.
+    pub async fn lookup_live(
+        &self,
+        package_names: &[String],
+    ) -> Result<Vec<DatabaseSupplementData>> {
+        let mut supplements = Vec::new();
+        for batch in package_names.chunks(LIVE_LOOKUP_BATCH_SIZE) {
+            let response = {
+                let mut last_live_lookup = self.last_live_lookup.lock().await;
+                if let Some(last) = *last_live_lookup {
+                    tokio::time::sleep_until((last + LIVE_LOOKUP_INTERVAL).into()).await;
+                }
+                *last_live_lookup = Some(Instant::now());
+                self.client
+                    .get(LIVE_LOOKUP_URL)
+                    .header(header::USER_AGENT, &self.user_agent)
+                    .query(&batch.iter().map(|name| ("arg[]", name)).collect::<Vec<_>>())
+                    .send()
+                    .await?
+            };
+            if !response.status().is_success() {
+                return Err(HttpStatusError(response.status()).into());
+            }
+            let response: LiveLookupResponse = response.json().await?;
+            if let Some(error) = response.error {
+                return Err(anyhow!("AUR RPC error: {}", error));
+            }
+            for value in response.results {
+                match serde_json::from_value::<RpcPackageDetails>(value) {
+                    Ok(item) => supplements.push(item.into()),
+                    Err(e) => debug!("Skipping malformed AUR RPC result: {}", e),
+                }
+            }
         }
+        debug!(
+            "Looked up {} of {} packages through the AUR RPC",
+            supplements.len(),
+            package_names.len()
+        );
+        Ok(supplements)
     }
 
+    /// Fetch from the first source that succeeds. `previous` describes the
+    /// source of the currently stored data, whose validators are sent along
+    /// so an unchanged archive is not downloaded again. Data in the basic
+    /// format is only used if none of the later sources serves the ext one.
+    ///
+    /// The raw data is cached after each successful fetch, and the cached copy
+    /// is returned as [`Freshness::Cached`] when every source fails.
     pub async fn fetch_supplement_data(
         &self,
         sources: &[String],
-    ) -> Result<Vec<DatabaseSupplementData>> {
+        previous: Option<&SupplementSourceValidators>,
+    ) -> Result<SupplementFetchOutcome> {
+        check_stdin_sources(sources)?;
+        let mut basic_outcome = None;
         for source in sources {
             if source == "none" {
                 continue;
             }
 
             info!("Attempting to fetch supplement data from: {}", source);
-            match self.fetch_from_source(source).await {
-                Ok(data) => {
+            let previous = previous.filter(|previous| &previous.source == source);
+            match self
+                .fetch_from_source_with_retries(source, previous, true)
+                .await
+            {
+                Ok((outcome, attempts)) => {
+                    if let SupplementFetchOutcome::Fetched { data, report, .. } = &outcome {
+                        info!(
+                            "Successfully fetched {} supplement records from {} after {} attempt(s) ({})",
+                            data.len(),
+                            source,
+                            attempts,
+                            report
+                        );
+                        if report.format == SupplementFormat::Basic {
+                            info!(
+                                "{} lacks submitters, co-maintainers, keywords and licenses, looking for a source with the ext format...",
+                                source
+                            );
+                            basic_outcome.get_or_insert(outcome);
+                            continue;
+                        }
+                    }
+                    return Ok(outcome);
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to fetch supplement data from {}: {}. Trying next source...",
+                        source, e
+                    );
+                }
+            }
+        }
+
+        match basic_outcome {
+            Some(outcome) => Ok(outcome),
+            None => self.load_cache().await,
+        }
+    }
+
+    /// Fetch every source and merge the records, later sources overriding
+    /// earlier ones per package name. Failing sources are skipped.
+    ///
+    /// The merged records are cached like the raw data of a single source,
+    /// and the cached copy is returned as [`Freshness::Cached`] when every
+    /// source fails.
+    pub async fn fetch_merged_supplement_data(
+        &self,
+        sources: &[String],
+    ) -> Result<SupplementFetchOutcome> {
+        check_stdin_sources(sources)?;
+        let mut merged = HashMap::<String, DatabaseSupplementData>::new();
+        let mut merged_report = ParseReport::default();
+        let mut merged_stale = false;
+        let mut fetched_sources = Vec::new();
+        for source in sources {
+            if source == "none" {
+                continue;
+            }
+
+            info!("Fetching supplement data to merge from: {}", source);
+            // Conditional requests can't be used, since the merged set needs every source
+            match self
+                .fetch_from_source_with_retries(source, None, false)
+                .await
+            {
+                Ok((
+                    SupplementFetchOutcome::Fetched {
+                        data,
+                        report,
+                        stale,
+                        ..
+                    },
+                    attempts,
+                )) => {
+                    merged_report.add(report);
+                    merged_stale |= stale;
+                    let total = data.len();
+                    let mut overridden = 0;
+                    for mut record in data {
+                        if let Some(earlier) = merged.remove(&record.pkgname) {
+                            overridden += 1;
+                            if report.format == SupplementFormat::Basic {
+                                // Keep what only an earlier ext source knows
+                                record.submitter = earlier.submitter;
+                                record.co_maintainers = earlier.co_maintainers;
+                                record.keywords = earlier.keywords;
+                                record.licenses = earlier.licenses;
+                            }
+                        }
+                        merged.insert(record.pkgname.clone(), record);
+                    }
                     info!(
-                        "Successfully fetched {} supplement records from {}",
-                        data.len(),
-                        source
+                        "Merged {} supplement records from {} after {} attempt(s) ({} new, {} overriding earlier sources; {})",
+                        total,
+                        source,
+                        attempts,
+                        total - overridden,
+                        overridden,
+                        report
                     );
-                    return Ok(data);
+                    fetched_sources.push(source.as_str());
+                }
+                Ok((SupplementFetchOutcome::NotModified { .. }, _)) => {
+                    // No validators are sent when merging, so this means a misbehaving server
+                    warn!("Unexpected 304 from {} while merging, skipping it", source);
                 }
                 Err(e) => {
                     warn!(
-                        "Failed to fetch supplement data from {}: {}. Trying next source...",
+                        "Failed to fetch supplement data from {}: {}. Merging the remaining sources...",
                         source, e
                     );
                 }
             }
         }
 
-        Err(anyhow!(
-            "Failed to fetch supplement data from all provided sources"
-        ))
+        if fetched_sources.is_empty() {
+            return self.load_cache().await;
+        }
+        let data: Vec<_> = merged.into_values().collect();
+        if !merged_stale {
+            self.write_cache(&data).await;
+        }
+        Ok(SupplementFetchOutcome::Fetched {
+            data,
+            validators: SupplementSourceValidators {
+                source: fetched_sources.join(" + "),
+                ..Default::default()
+            },
+            freshness: Freshness::Fresh,
+            report: merged_report,
+            stale: merged_stale,
+        })
     }
 
-    async fn fetch_from_source(&self, source: &str) -> Result<Vec<DatabaseSupplementData>> {
-        let raw_data = if source.starts_with("http://") || source.starts_with("https://") {
-            self.fetch_from_url(source).await?
-        } else {
-            self.fetch_from_file(source).await?
+    /// Retry transient failures of one source with exponential backoff,
+    /// returning the outcome and the number of attempts it took.
+    async fn fetch_from_source_with_retries(
+        &self,
+        source: &str,
+        previous: Option<&SupplementSourceValidators>,
+        cache_raw: bool,
+    ) -> Result<(SupplementFetchOutcome, u32)> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch_from_source(source, previous, cache_raw).await {
+                Ok(outcome) => return Ok((outcome, attempt)),
+                Err(e) if attempt <= self.options.retries && is_retryable(&e) => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+                    warn!(
+                        "Attempt {} to fetch supplement data from {} failed: {}. Retrying in {:?}...",
+                        attempt, source, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Fetch and parse one source, copying its raw data to the cache path
+    /// afterwards if `cache_raw` is set. Data that is rejected or flagged as
+    /// stale is never cached, so it can't replace the last good copy.
+    async fn fetch_from_source(
+        &self,
+        source: &str,
+        previous: Option<&SupplementSourceValidators>,
+        cache_raw: bool,
+    ) -> Result<SupplementFetchOutcome> {
+        let (location, checksum) = parse_source_spec(source)?;
+        let location = parse_location(location)?;
+        let checksum = match checksum {
+            Some(Checksum::Sidecar) => {
+                Some(Checksum::Raw(self.fetch_sidecar_digest(&location).await?))
+            }
+            checksum => checksum,
+        };
+        // Spooled downloads are kept alive until they are cached
+        let spooled;
+        let ((data, report), validators, raw_path) = match location {
+            Location::Url(url) => match self.fetch_from_url(&url, previous).await? {
+                Download::Modified {
+                    spooled: download,
+                    validators,
+                } => {
+                    spooled = download;
+                    let parsed = self.parse_spooled(&spooled, checksum).await?;
+                    (parsed, validators, spooled.file_path().to_path_buf())
+                }
+                Download::NotModified => {
+                    return Ok(SupplementFetchOutcome::NotModified {
+                        source: source.to_string(),
+                    })
+                }
+            },
+            Location::Path(path) => {
+                let parsed =
+                    parse_file(path.clone(), checksum, self.options.max_bad_fraction).await?;
+                (parsed, SupplementSourceValidators::default(), path)
+            }
+            Location::Stdin => {
+                spooled = self.spool_stdin().await?;
+                let parsed = self.parse_spooled(&spooled, checksum).await?;
+                (
+                    parsed,
+                    SupplementSourceValidators::default(),
+                    spooled.file_path().to_path_buf(),
+                )
+            }
         };
+        let stale = self.check_data_age(source, &data)?;
+        if cache_raw && !stale {
+            self.update_cache(&raw_path).await;
+        }
+        Ok(SupplementFetchOutcome::Fetched {
+            data,
+            validators: SupplementSourceValidators {
+                source: source.to_string(),
+                ..validators
+            },
+            freshness: Freshness::Fresh,
+            report,
+            stale,
+        })
+    }
 
-        let decompressed_data = self.decompress_if_needed(&raw_data)?;
-        self.parse_json(&decompressed_data)
+    /// Log the age of the newest record. Data older than the max age fails
+    /// the source or is flagged stale, depending on the configured action.
+    fn check_data_age(&self, source: &str, data: &[DatabaseSupplementData]) -> Result<bool> {
+        let Some(newest) = data.iter().map(|record| record.last_modified).max() else {
+            return Ok(false);
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let age = Duration::from_secs(now.saturating_sub(newest).max(0) as u64);
+        info!(
+            "Newest supplement record from {} was modified {} hours ago",
+            source,
+            age.as_secs() / 3600
+        );
+        if age <= self.options.max_data_age {
+            return Ok(false);
+        }
+        match self.options.stale_data_action {
+            StaleDataAction::Reject => Err(anyhow!(
+                "Supplement data from {} is {} hours old, beyond the max age of {} hours",
+                source,
+                age.as_secs() / 3600,
+                self.options.max_data_age.as_secs() / 3600
+            )),
+            StaleDataAction::KeepListing => {
+                warn!(
+                    "⚠ Supplement data from {} is {} hours old, beyond the max age of {} hours; listing status will be kept",
+                    source,
+                    age.as_secs() / 3600,
+                    self.options.max_data_age.as_secs() / 3600
+                );
+                Ok(true)
+            }
+        }
+    }
+
+    /// Replace the cached raw data with `raw_path`. Failing to do so only
+    /// costs the fallback, so it is logged rather than returned.
+    async fn update_cache(&self, raw_path: &Path) {
+        let Some(cache_path) = &self.options.cache_path else {
+            return;
+        };
+        if let Err(e) = copy_atomically(raw_path, cache_path).await {
+            warn!(
+                "Failed to cache supplement data at {}: {}",
+                cache_path.display(),
+                e
+            );
+        }
+    }
+
+    /// Replace the cached raw data with `data` in the ext format, for merged
+    /// data that has no raw copy of its own. Failing to do so only costs the
+    /// fallback, so it is logged rather than returned.
+    async fn write_cache(&self, data: &[DatabaseSupplementData]) {
+        let Some(cache_path) = &self.options.cache_path else {
+            return;
+        };
+        let records: Vec<_> = data.iter().map(ext_record).collect();
+        let path = cache_path.clone();
+        let written = tokio::task::spawn_blocking(move || write_json_atomically(&records, &path))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|written| written);
+        if let Err(e) = written {
+            warn!(
+                "Failed to cache supplement data at {}: {}",
+                cache_path.display(),
+                e
+            );
+        }
+    }
+
+    /// Load the cached copy of the last successful fetch, unless it is older
+    /// than the configured max age.
+    async fn load_cache(&self) -> Result<SupplementFetchOutcome> {
+        let all_failed = "Failed to fetch supplement data from all provided sources";
+        let Some(cache_path) = &self.options.cache_path else {
+            return Err(anyhow!(all_failed));
+        };
+        let modified = match tokio::fs::metadata(cache_path).await {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(anyhow!("{} and no cached copy exists", all_failed));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > self.options.cache_max_age {
+            return Err(anyhow!(
+                "{}; the cached copy at {} is {} hours old, beyond the max age of {} hours",
+                all_failed,
+                cache_path.display(),
+                age.as_secs() / 3600,
+                self.options.cache_max_age.as_secs() / 3600
+            ));
+        }
+
+        warn!(
+            "⚠ {}, falling back to the cached copy at {} from {} hours ago",
+            all_failed,
+            cache_path.display(),
+            age.as_secs() / 3600
+        );
+        // The cache only ever holds data that already parsed successfully
+        let (data, report) =
+            parse_file(cache_path.clone(), None, self.options.max_bad_fraction).await?;
+        let stale = self.check_data_age(&cache_path.display().to_string(), &data)?;
+        Ok(SupplementFetchOutcome::Fetched {
+            data,
+            report,
+            stale,
+            validators: SupplementSourceValidators {
+                source: cache_path.display().to_string(),
+                ..Default::default()
+            },
+            freshness: Freshness::Cached,
+        })
     }
 
-    async fn fetch_from_url(&self, url: &str) -> Result<Vec<u8>> {
-        let response = self.client.get(url).send().await?;
+    async fn parse_spooled(
+        &self,
+        spooled: &TempFile,
+        checksum: Option<Checksum>,
+    ) -> Result<(Vec<DatabaseSupplementData>, ParseReport)> {
+        parse_file(
+            spooled.file_path().to_path_buf(),
+            checksum,
+            self.options.max_bad_fraction,
+        )
+        .await
+    }
+
+    /// Copy standard input into a temp file, so piped data goes through the
+    /// same sniffing and parsing as a downloaded archive. Stdin can only be
+    /// drained once per invocation.
+    async fn spool_stdin(&self) -> Result<TempFile> {
+        if self.stdin_consumed.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!(
+                "Standard input was already consumed by an earlier supplement source"
+            ));
+        }
+        let mut spooled = TempFile::new().await?;
+        let copied = tokio::io::copy(&mut tokio::io::stdin(), &mut spooled).await?;
+        debug!("Read {} bytes of supplement data from stdin", copied);
+        Ok(spooled)
+    }
+
+    /// Read the hex digest from the companion `<location>.sha256` file, which
+    /// may use the `sha256sum` output format.
+    async fn fetch_sidecar_digest(&self, location: &Location) -> Result<String> {
+        let (sidecar, content) = match location {
+            Location::Url(url) => {
+                let sidecar = format!("{}.sha256", url);
+                let response = self
+                    .client
+                    .get(&sidecar)
+                    .header(header::USER_AGENT, &self.user_agent)
+                    .send()
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(HttpStatusError(response.status()).into());
+                }
+                let content = response.text().await?;
+                (sidecar, content)
+            }
+            Location::Path(path) => {
+                let mut sidecar = path.clone().into_os_string();
+                sidecar.push(".sha256");
+                let content = tokio::fs::read_to_string(&sidecar).await?;
+                (sidecar.to_string_lossy().into_owned(), content)
+            }
+            Location::Stdin => {
+                return Err(anyhow!(
+                    "A checksum sidecar can't be used with standard input"
+                ))
+            }
+        };
+        let digest = content
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow!("Empty checksum file {}", sidecar))?;
+        parse_sha256_hex(digest)
+    }
+
+    /// Download `url` into a temp file, so the payload never has to be held
+    /// in memory as a whole.
+    async fn fetch_from_url(
+        &self,
+        url: &str,
+        previous: Option<&SupplementSourceValidators>,
+    ) -> Result<Download> {
+        let mut request_builder = self
+            .client
+            .get(url)
+            .header(header::USER_AGENT, &self.user_agent);
+        if let Some(etag) = previous.and_then(|previous| previous.etag.as_ref()) {
+            request_builder = request_builder.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = previous.and_then(|previous| previous.last_modified.as_ref()) {
+            request_builder = request_builder.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = request_builder.send().await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(Download::NotModified);
+        }
         if !response.status().is_success() {
-            return Err(anyhow!("HTTP error: {}", response.status()));
-        }
-        Ok(response.bytes().await?.to_vec())
-    }
-
-    async fn fetch_from_file(&self, path: &str) -> Result<Vec<u8>> {
-        Ok(tokio::fs::read(path).await?)
-    }
-
-    fn decompress_if_needed(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Check for gzip magic bytes (1f 8b)
-        if data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b {
-            info!("Detected gzip compression, decompressing...");
-            let mut decoder = GzDecoder::new(data);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
-        } else {
-            Ok(data.to_vec())
-        }
-    }
-
-    fn parse_json(&self, data: &[u8]) -> Result<Vec<DatabaseSupplementData>> {
-        let aur_data: Vec<RpcPackageDetails> = serde_json::from_slice(data)?;
-
-        Ok(aur_data
-            .into_iter()
-            .map(|item| DatabaseSupplementData {
-                pkgname: item.name,
-                version: item.version,
-                popularity: item.popularity,
-                num_votes: item.num_votes,
-                out_of_date: item.out_of_date,
-                maintainer: item.maintainer,
-                submitter: item.submitter,
-                co_maintainers: item.co_maintainers,
-                keywords: item.keywords,
-                first_submitted: item.first_submitted,
-                last_modified: item.last_modified,
-            })
-            .collect())
+            return Err(HttpStatusError(response.status()).into());
+        }
+        let max_bytes = self.options.max_bytes;
+        let content_length = response.content_length();
+        if let Some(content_length) = content_length {
+            if content_length > max_bytes {
+                return Err(anyhow!(
+                    "Supplement download of {} bytes exceeds the limit of {} bytes",
+                    content_length,
+                    max_bytes
+                ));
+            }
+        }
+        let header_value = |name: header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let validators = SupplementSourceValidators {
+            source: url.to_string(),
+            etag: header_value(header::ETAG),
+            last_modified: header_value(header::LAST_MODIFIED),
+        };
+        let body = response
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .into_async_read();
+        let mut body = ThrottledRead::new(body, self.rate_limiter.clone());
+        let mut spooled = TempFile::new().await?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0u64;
+        let mut next_progress = DOWNLOAD_PROGRESS_STEP;
+        loop {
+            let read = body.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            received += read as u64;
+            // Content-Length may be missing or wrong, so enforce the limit on
+            // the stream as well
+            if received > max_bytes {
+                return Err(anyhow!(
+                    "Supplement download exceeds the limit of {} bytes",
+                    max_bytes
+                ));
+            }
+            spooled.write_all(&buf[..read]).await?;
+            if received >= next_progress {
+                match content_length {
+                    Some(total) => info!(
+                        "Downloaded {} of {} MiB from {}",
+                        received / 1024 / 1024,
+                        total / 1024 / 1024,
+                        url
+                    ),
+                    None => info!("Downloaded {} MiB from {}", received / 1024 / 1024, url),
+                }
+                next_progress += DOWNLOAD_PROGRESS_STEP;
+            }
+        }
+        spooled.flush().await?;
+        // A connection closed early can look like a complete body; report it
+        // here rather than as a decompression or JSON error later on
+        if let Some(total) = content_length.filter(|&total| total != received) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Supplement download from {} is truncated: received {} of {} bytes",
+                    url, received, total
+                ),
+            )
+            .into());
+        }
+        debug!("Downloaded {} bytes from {}", received, url);
+        Ok(Download::Modified {
+            spooled,
+            validators,
+        })
+    }
+}
+
+/// Timeouts, connection failures and 5xx/429 responses are worth retrying;
+/// anything else (404, malformed data, missing file) fails the same way again.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(status) = cause.downcast_ref::<HttpStatusError>() {
+            return status.0.is_server_error()
+                || status.0 == reqwest::StatusCode::TOO_MANY_REQUESTS;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout() || e.is_connect() || e.is_request() || e.is_body();
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            // Body stream failures surface as io errors wrapping reqwest errors
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::UnexpectedEof
+                    | std::io::ErrorKind::BrokenPipe
+            ) || e
+                .get_ref()
+                .is_some_and(|inner| inner.is::<reqwest::Error>());
+        }
+        false
+    })
+}
+
+/// Copy `from` next to `to` and rename it into place, so an interrupted copy
+/// never replaces a good cache with a truncated one.
+async fn copy_atomically(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = tmp_path_for(to);
+    tokio::fs::copy(from, &tmp_path).await?;
+    if let Err(e) = tokio::fs::rename(&tmp_path, to).await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Write `value` as JSON next to `to` and rename it into place, like
+/// [`copy_atomically`].
+fn write_json_atomically(value: &impl Serialize, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = tmp_path_for(to);
+    let write = || -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        serde_json::to_writer(&mut out, value)?;
+        std::io::Write::flush(&mut out)?;
+        std::fs::rename(&tmp_path, to)?;
+        Ok(())
+    };
+    write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })
+}
+
+/// A temp file next to `path`, unique within and across processes.
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(tmp_path)
+}
+
+/// A record in the layout of `packages-meta-ext-v1.json`, with the fields
+/// the supplement data doesn't keep left empty.
+fn ext_record(record: &DatabaseSupplementData) -> serde_json::Value {
+    serde_json::json!({
+        "ID": 0,
+        "Name": record.pkgname,
+        "PackageBase": record.package_base,
+        "PackageBaseID": 0,
+        "Version": record.version,
+        "Description": null,
+        "URL": null,
+        "URLPath": "",
+        "Maintainer": record.maintainer,
+        "Submitter": record.submitter,
+        "NumVotes": record.num_votes,
+        "Popularity": record.popularity,
+        "FirstSubmitted": record.first_submitted,
+        "LastModified": record.last_modified,
+        "OutOfDate": record.out_of_date,
+        "License": record.licenses,
+        "Keywords": record.keywords,
+        "CoMaintainers": record.co_maintainers,
+    })
+}
+
+/// Classify a source location: `-` is standard input, `http(s)://` a URL,
+/// `file://` a URI for a local path, and anything else a plain path.
+fn parse_location(location: &str) -> Result<Location> {
+    if location == STDIN_SOURCE {
+        Ok(Location::Stdin)
+    } else if location.starts_with("http://") || location.starts_with("https://") {
+        Ok(Location::Url(location.to_string()))
+    } else if location.starts_with("file://") {
+        let path = reqwest::Url::parse(location)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| anyhow!("Invalid file URI '{}'", location))?;
+        Ok(Location::Path(path))
+    } else {
+        Ok(Location::Path(PathBuf::from(location)))
+    }
+}
+
+/// Standard input can only be read once, so reject source lists naming it twice
+/// before anything is fetched.
+fn check_stdin_sources(sources: &[String]) -> Result<()> {
+    let stdin_sources = sources
+        .iter()
+        .filter(|source| {
+            parse_source_spec(source).is_ok_and(|(location, _)| location == STDIN_SOURCE)
+        })
+        .count();
+    if stdin_sources > 1 {
+        return Err(anyhow!(
+            "Standard input ('-') can only be given once as a supplement source, got it {} times",
+            stdin_sources
+        ));
+    }
+    Ok(())
+}
+
+/// Split a source like `https://host/meta.json.gz|sha256:<hex>` into its
+/// location and optional checksum. Supported suffixes are `sha256:<hex>`,
+/// `sha256-payload:<hex>` and `sha256:sidecar`.
+fn parse_source_spec(source: &str) -> Result<(&str, Option<Checksum>)> {
+    let Some((location, checksum)) = source.rsplit_once('|') else {
+        return Ok((source, None));
+    };
+    let checksum = match checksum.split_once(':') {
+        Some(("sha256", "sidecar")) => Checksum::Sidecar,
+        Some(("sha256", digest)) => Checksum::Raw(parse_sha256_hex(digest)?),
+        Some(("sha256-payload", digest)) => Checksum::Payload(parse_sha256_hex(digest)?),
+        _ => return Err(anyhow!("Unsupported checksum specification '{}'", checksum)),
+    };
+    Ok((location, Some(checksum)))
+}
+
+fn parse_sha256_hex(digest: &str) -> Result<String> {
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid SHA-256 digest '{}'", digest));
+    }
+    Ok(digest.to_ascii_lowercase())
+}
+
+fn verify_digest(kind: &str, expected: &str, hasher: Sha256) -> Result<()> {
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != expected {
+        return Err(anyhow!(
+            "{} checksum mismatch: expected {}, got {}",
+            kind,
+            expected,
+            actual
+        ));
+    }
+    info!("{} checksum verified", kind);
+    Ok(())
+}
+
+/// Reader feeding everything read through it into a SHA-256 hasher.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Parse a (possibly compressed) supplement file on a blocking thread.
+async fn parse_file(
+    path: PathBuf,
+    checksum: Option<Checksum>,
+    max_bad_fraction: f64,
+) -> Result<(Vec<DatabaseSupplementData>, ParseReport)> {
+    tokio::task::spawn_blocking(move || {
+        parse_file_blocking(&path, checksum.as_ref(), max_bad_fraction)
+    })
+    .await?
+}
+
+fn parse_file_blocking(
+    path: &Path,
+    checksum: Option<&Checksum>,
+    max_bad_fraction: f64,
+) -> Result<(Vec<DatabaseSupplementData>, ParseReport)> {
+    if let Some(Checksum::Raw(expected)) = checksum {
+        // Verify before decompressing anything
+        let mut hashing = HashingReader {
+            inner: std::fs::File::open(path)?,
+            hasher: Sha256::new(),
+        };
+        std::io::copy(&mut hashing, &mut std::io::sink())?;
+        verify_digest("Raw", expected, hashing.hasher)?;
+    }
+
+    let reader = decompress_if_needed(BufReader::new(std::fs::File::open(path)?))?;
+    match checksum {
+        Some(Checksum::Payload(expected)) => {
+            let mut hashing = HashingReader {
+                inner: reader,
+                hasher: Sha256::new(),
+            };
+            let parsed = parse_json(BufReader::new(&mut hashing), max_bad_fraction)?;
+            // Trailing whitespace the parser did not consume is part of the payload too
+            std::io::copy(&mut hashing, &mut std::io::sink())?;
+            verify_digest("Payload", expected, hashing.hasher)?;
+            Ok(parsed)
+        }
+        _ => parse_json(BufReader::new(reader), max_bad_fraction),
+    }
+}
+
+fn decompress_if_needed<R: BufRead + Send + 'static>(
+    mut reader: R,
+) -> Result<Box<dyn Read + Send>> {
+    let head = reader.fill_buf()?;
+    if head.starts_with(GZIP_MAGIC) {
+        info!("Detected gzip compression, decompressing...");
+        Ok(Box::new(DecompressErrors::new(
+            "gzip",
+            GzDecoder::new(reader),
+        )))
+    } else if head.starts_with(ZSTD_MAGIC) {
+        info!("Detected zstd compression, decompressing...");
+        Ok(Box::new(DecompressErrors::new(
+            "zstd",
+            zstd::stream::read::Decoder::with_buffer(reader)?,
+        )))
+    } else if head.starts_with(XZ_MAGIC) {
+        info!("Detected xz compression, decompressing...");
+        Ok(Box::new(DecompressErrors::new(
+            "xz",
+            xz2::read::XzDecoder::new(reader),
+        )))
+    } else {
+        let first_byte = head.iter().find(|b| !b.is_ascii_whitespace());
+        if !matches!(first_byte, Some(b'[') | Some(b'{')) {
+            return Err(anyhow!(
+                "Unrecognized supplement data format (expected JSON, gzip, zstd or xz), starts with {:02x?}",
+                &head[..head.len().min(8)]
+            ));
+        }
+        Ok(Box::new(reader))
+    }
+}
+
+/// Decoder wrapper naming the compression in its errors, so a stream that
+/// ends mid-member is reported as truncated rather than as broken JSON.
+///
+/// The JSON parser reads up to EOF (see [`serde_json::Deserializer::end`]),
+/// which makes the decoder check the trailer and checksum of the last member.
+struct DecompressErrors<R> {
+    format: &'static str,
+    inner: R,
+}
+
+impl<R> DecompressErrors<R> {
+    fn new(format: &'static str, inner: R) -> Self {
+        Self { format, inner }
+    }
+}
+
+impl<R: Read> Read for DecompressErrors<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            let message = if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                format!(
+                    "{} stream ends mid-member, the data is truncated",
+                    self.format
+                )
+            } else {
+                format!("{} stream is corrupt: {}", self.format, e)
+            };
+            std::io::Error::new(e.kind(), message)
+        })
+    }
+}
+
+/// Read failures (like a truncated compressed stream) are not the JSON's fault.
+fn json_error(e: serde_json::Error) -> anyhow::Error {
+    if e.is_io() {
+        anyhow!("Failed to read supplement data: {}", e)
+    } else {
+        anyhow!("Invalid supplement JSON: {}", e)
+    }
+}
+
+/// Parse the JSON array element by element, converting each package as soon
+/// as it is read instead of materializing the whole document first.
+///
+/// Elements that don't match the package schema or carry an empty name or
+/// negative timestamps are skipped; the source only fails when more than
+/// `max_bad_fraction` of them are bad. Of several records with the same name
+/// the one modified last is kept.
+fn parse_json<R: Read>(
+    reader: R,
+    max_bad_fraction: f64,
+) -> Result<(Vec<DatabaseSupplementData>, ParseReport)> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let mut supplements = HashMap::<String, DatabaseSupplementData>::new();
+    let mut report = ParseReport::default();
+    let mut format = None;
+    (&mut de)
+        .deserialize_seq(PackageArrayVisitor(|value: serde_json::Value| {
+            let name = value
+                .get("Name")
+                .and_then(|name| name.as_str())
+                .map(str::to_string);
+            // Every ext record has a keyword list, if only an empty one
+            let format = *format.get_or_insert_with(|| {
+                if value.get("Keywords").is_some() {
+                    SupplementFormat::Ext
+                } else {
+                    SupplementFormat::Basic
+                }
+            });
+            let record = match format {
+                SupplementFormat::Ext => {
+                    serde_json::from_value::<RpcPackageDetails>(value).map(Into::into)
+                }
+                SupplementFormat::Basic => {
+                    serde_json::from_value::<BasicPackageMeta>(value).map(Into::into)
+                }
+            };
+            match record {
+                Ok(record) => add_record(&mut supplements, &mut report, record),
+                Err(e) => {
+                    report.malformed += 1;
+                    debug!(
+                        "Skipping malformed supplement record {}: {}",
+                        name.as_deref().unwrap_or("<unnamed>"),
+                        e
+                    );
+                }
+            }
+        }))
+        .map_err(json_error)?;
+    de.end().map_err(json_error)?;
+    report.format = format.unwrap_or_default();
+
+    let bad_records = report.malformed + report.invalid;
+    if bad_records > 0 || report.duplicates > 0 {
+        warn!(
+            "⚠ Parsed {} supplement records: {}",
+            supplements.len(),
+            report
+        );
+    }
+    if bad_records > 0 {
+        let total = supplements.len() + report.duplicates + bad_records;
+        if bad_records as f64 > total as f64 * max_bad_fraction {
+            return Err(anyhow!(
+                "{} of {} supplement records are malformed or invalid, more than the tolerated {:.1}%",
+                bad_records,
+                total,
+                max_bad_fraction * 100.0
+            ));
+        }
+    }
+    Ok((supplements.into_values().collect(), report))
+}
+
+/// Validate a parsed record and add it, replacing an earlier record of the
+/// same name only if it was modified later.
+fn add_record(
+    supplements: &mut HashMap<String, DatabaseSupplementData>,
+    report: &mut ParseReport,
+    record: DatabaseSupplementData,
+) {
+    let negative_timestamp = record.first_submitted < 0
+        || record.last_modified < 0
+        || record
+            .out_of_date
+            .is_some_and(|out_of_date| out_of_date < 0);
+    if record.pkgname.trim().is_empty() || negative_timestamp {
+        report.invalid += 1;
+        debug!("Skipping invalid supplement record {:?}", record.pkgname);
+        return;
+    }
+    match supplements.entry(record.pkgname.clone()) {
+        Entry::Occupied(mut existing) => {
+            report.duplicates += 1;
+            debug!("Duplicate supplement record {}", record.pkgname);
+            if record.last_modified > existing.get().last_modified {
+                existing.insert(record);
+            }
+        }
+        Entry::Vacant(slot) => {
+            slot.insert(record);
+        }
+    }
+}
+
+struct PackageArrayVisitor<F>(F);
+
+impl<'de, F: FnMut(serde_json::Value)> Visitor<'de> for PackageArrayVisitor<F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of package metadata")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(item) = seq.next_element::<serde_json::Value>()? {
+            (self.0)(item);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn record(name: &str, last_modified: i64) -> serde_json::Value {
+        serde_json::json!({
+            "ID": 1,
+            "Name": name,
+            "PackageBase": name,
+            "PackageBaseID": 1,
+            "Version": "1.0-1",
+            "Description": null,
+            "URL": null,
+            "URLPath": format!("/cgit/aur.git/snapshot/{}.tar.gz", name),
+            "Maintainer": "someone",
+            "Submitter": "someone",
+            "NumVotes": 1,
+            "Popularity": 0.5,
+            "FirstSubmitted": 1,
+            "LastModified": last_modified,
+            "OutOfDate": null,
+            "License": ["MIT"],
+            "Keywords": [],
+            "CoMaintainers": [],
+        })
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn options(cache_path: &Path) -> SupplementOptions {
+        SupplementOptions {
+            retries: 0,
+            merge: false,
+            max_bad_fraction: 0.0,
+            connect_timeout: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            max_bytes: u64::MAX,
+            cache_path: Some(cache_path.to_path_buf()),
+            cache_max_age: Duration::from_secs(3600),
+            max_data_age: Duration::from_secs(3600),
+            stale_data_action: StaleDataAction::Reject,
+            live_lookup: false,
+            live_lookup_ttl: Duration::ZERO,
+            refresh_interval: None,
+        }
+    }
+
+    fn write_dump(dir: &Path, name: &str, records: &[serde_json::Value]) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, serde_json::to_vec(records).unwrap()).unwrap();
+        path.display().to_string()
+    }
+
+    fn names(outcome: &SupplementFetchOutcome) -> Vec<&str> {
+        let SupplementFetchOutcome::Fetched { data, .. } = outcome else {
+            panic!("nothing fetched");
+        };
+        let mut names: Vec<_> = data.iter().map(|r| r.pkgname.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn merged_data_is_cached_and_used_when_every_source_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher =
+            SupplementFetcher::new("test".to_string(), None, options(&dir.path().join("cache")))
+                .unwrap();
+        let sources = [
+            write_dump(dir.path(), "a.json", &[record("a", now())]),
+            write_dump(dir.path(), "b.json", &[record("b", now())]),
+        ];
+        let merged = fetcher
+            .fetch_merged_supplement_data(&sources)
+            .await
+            .unwrap();
+        assert_eq!(names(&merged), ["a", "b"]);
+
+        let missing = [dir.path().join("missing.json").display().to_string()];
+        let cached = fetcher
+            .fetch_merged_supplement_data(&missing)
+            .await
+            .unwrap();
+        assert_eq!(names(&cached), ["a", "b"]);
+        assert!(matches!(
+            cached,
+            SupplementFetchOutcome::Fetched {
+                freshness: Freshness::Cached,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn stale_data_never_replaces_the_cached_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = [write_dump(
+            dir.path(),
+            "good.json",
+            &[record("good", now())],
+        )];
+        let stale = [write_dump(dir.path(), "stale.json", &[record("stale", 1)])];
+        let missing = [dir.path().join("missing.json").display().to_string()];
+        for action in [StaleDataAction::Reject, StaleDataAction::KeepListing] {
+            let options = SupplementOptions {
+                stale_data_action: action,
+                ..options(&dir.path().join(format!("cache-{:?}", action)))
+            };
+            let fetcher = SupplementFetcher::new("test".to_string(), None, options).unwrap();
+            fetcher.fetch_supplement_data(&good, None).await.unwrap();
+
+            let outcome = fetcher.fetch_supplement_data(&stale, None).await.unwrap();
+            if action == StaleDataAction::Reject {
+                // Rejected, so the fallback serves the last good copy
+                assert_eq!(names(&outcome), ["good"]);
+            } else {
+                assert_eq!(names(&outcome), ["stale"]);
+            }
+
+            let cached = fetcher.fetch_supplement_data(&missing, None).await.unwrap();
+            assert_eq!(names(&cached), ["good"], "{:?}", action);
+        }
+    }
+
+    /// Peak resident set size of this process, in bytes.
+    fn peak_rss() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status
+            .lines()
+            .find(|line| line.starts_with("VmHWM:"))
+            .unwrap();
+        let kib: u64 = line
+            .trim_start_matches("VmHWM:")
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .unwrap();
+        kib * 1024
+    }
+
+    #[test]
+    fn large_dump_is_parsed_in_bounded_memory() {
+        const RECORDS: usize = 20_000;
+        // Descriptions aren't kept, so every record is mostly padding the
+        // parser must not hold on to
+        let mut template = record("{name}", 1);
+        template["Description"] = "x".repeat(5000).into();
+        let template = template.to_string();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = GzEncoder::new(file.reopen().unwrap(), flate2::Compression::fast());
+        let mut payload_bytes = 0;
+        encoder.write_all(b"[").unwrap();
+        for i in 0..RECORDS {
+            let json = template.replace("{name}", &format!("pkg{}", i));
+            if i > 0 {
+                encoder.write_all(b",").unwrap();
+            }
+            encoder.write_all(json.as_bytes()).unwrap();
+            payload_bytes += json.len() as u64;
+        }
+        encoder.write_all(b"]").unwrap();
+        encoder.finish().unwrap();
+
+        let before = peak_rss();
+        let (data, report) = parse_file_blocking(file.path(), None, 0.0).unwrap();
+        let growth = peak_rss().saturating_sub(before);
+
+        assert_eq!(data.len(), RECORDS);
+        assert_eq!(report.format, SupplementFormat::Ext);
+        assert!(
+            growth < payload_bytes / 4,
+            "peak RSS grew by {} bytes parsing {} bytes",
+            growth,
+            payload_bytes
+        );
     }
 }
@@ -0,0 +1,135 @@
+use crate::{
+    database::DatabaseOps,
+    supplement_fetcher::{Freshness, SupplementFetchOutcome, SupplementFetcher},
+};
+use anyhow::Result;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// Fetches supplement data from the configured sources and stores it, both
+/// as the last step of a sync and periodically while serving.
+pub struct SupplementRefresher {
+    db: DatabaseOps,
+    fetcher: SupplementFetcher,
+    sources: Vec<String>,
+    /// Held for the whole refresh, so scheduled and manual runs never overlap
+    running: Mutex<()>,
+}
+
+/// A refresh was requested while another one is still running.
+#[derive(Debug)]
+pub struct AlreadyRunning;
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A supplement refresh is already running")
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+impl SupplementRefresher {
+    pub fn new(db: DatabaseOps, fetcher: SupplementFetcher, sources: Vec<String>) -> Self {
+        Self {
+            db,
+            fetcher,
+            sources,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Refresh, waiting for a refresh already in progress to finish first.
+    pub async fn refresh(&self) -> Result<()> {
+        let _running = self.running.lock().await;
+        self.refresh_locked().await
+    }
+
+    /// Refresh unless one is already in progress, which fails with
+    /// [`AlreadyRunning`] instead of queueing up.
+    pub async fn try_refresh(&self) -> Result<()> {
+        let _running = self.running.try_lock().map_err(|_| AlreadyRunning)?;
+        self.refresh_locked().await
+    }
+
+    /// Refresh every `interval`, shifted by up to a tenth of it so several
+    /// mirrors don't hit the sources in lockstep. Failures are logged and
+    /// retried at the next tick.
+    pub fn spawn_periodic(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval + jitter(interval / 10)).await;
+                info!("Starting scheduled supplement refresh");
+                if let Err(e) = self.refresh().await {
+                    error!("Scheduled supplement refresh failed: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn refresh_locked(&self) -> Result<()> {
+        if !self.sources.iter().any(|x| x != "none") {
+            info!("Skipping supplement data fetch");
+            return Ok(());
+        }
+
+        info!("Fetching supplement data...");
+        let outcome = if self.fetcher.options().merge {
+            self.fetcher
+                .fetch_merged_supplement_data(&self.sources)
+                .await
+        } else {
+            let previous = self.db.get_supplement_source_validators().await?;
+            self.fetcher
+                .fetch_supplement_data(&self.sources, previous.as_ref())
+                .await
+        };
+        match outcome {
+            Ok(SupplementFetchOutcome::NotModified { source }) => {
+                info!(
+                    "Supplement data from {} not modified since last fetch",
+                    source
+                );
+            }
+            Ok(SupplementFetchOutcome::Fetched {
+                data: supplements,
+                validators,
+                freshness,
+                stale,
+                ..
+            }) => {
+                if !supplements.is_empty() {
+                    info!("Storing {} supplement records...", supplements.len());
+                    // Listing status is still recomputed from cached data, which
+                    // is bounded by the cache max age; only the validators of the
+                    // live source are kept, so the next fetch asks it again
+                    let validators = match freshness {
+                        Freshness::Fresh => Some(&validators),
+                        Freshness::Cached => None,
+                    };
+                    self.db
+                        .store_supplement_data(&supplements, validators, !stale)
+                        .await?;
+                    info!("Supplement data stored successfully");
+                } else {
+                    info!("No supplement data to store");
+                }
+            }
+            Err(e) => {
+                warn!("⚠ {}. Continuing without supplements.", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A pseudo-random duration below `max`; no need for a proper RNG here.
+pub fn jitter(max: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.subsec_nanos() as u64)
+        .unwrap_or_default();
+    Duration::from_nanos(nanos % max.as_nanos().max(1) as u64)
+}
@@ -0,0 +1,159 @@
+use crate::aur_fetcher::Cancelled;
+use crate::database::DatabaseOps;
+use crate::types::DatabaseSyncLock;
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// How often the holder of the lock shows it is still alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Age of the last heartbeat at which the holder is taken for crashed
+const STALE_AFTER: Duration = Duration::from_secs(60);
+/// How often a waiting sync checks whether the lock is free
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returned when another process holds the lock and the caller doesn't
+/// wait. Callers can detect it with `err.is::<SyncLocked>()`.
+#[derive(Debug, Clone)]
+pub struct SyncLocked(pub DatabaseSyncLock);
+
+impl fmt::Display for SyncLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Another sync is running: {}", describe(&self.0))
+    }
+}
+
+impl std::error::Error for SyncLocked {}
+
+/// Advisory lock held for the whole of a sync, so processes sharing a
+/// database, e.g. `serve` and `sync` run by cron, never index at once. It is
+/// a row of the database itself, kept alive by a heartbeat; a holder that
+/// stopped beating, or whose process is gone on this host, is taken for
+/// crashed and the lock broken.
+pub struct SyncLock {
+    db: DatabaseOps,
+    token: String,
+    heartbeat: JoinHandle<()>,
+}
+
+impl SyncLock {
+    /// Take the lock, waiting until it is free with `wait`, or failing with
+    /// [`SyncLocked`] otherwise. Should the lock get lost later, because the
+    /// heartbeat stalled and another process broke it, `cancel` is fired so
+    /// the sync stops.
+    pub async fn acquire(db: &DatabaseOps, wait: bool, cancel: &CancellationToken) -> Result<Self> {
+        let token = Uuid::new_v4().simple().to_string();
+        let pid = std::process::id() as i64;
+        let hostname = hostname();
+        let mut waiting = false;
+        loop {
+            let now = unix_now();
+            let current = db.get_sync_lock().await?;
+            let stale = current
+                .as_ref()
+                .filter(|current| is_stale(current, &hostname, now));
+            if let Some(stale) = stale {
+                warn!("⚠ Breaking the stale sync lock of {}", describe(stale));
+            }
+            let stale_token = stale.map(|stale| stale.token.as_str());
+            if db
+                .try_acquire_sync_lock(&token, pid, &hostname, now, stale_token)
+                .await?
+            {
+                break;
+            }
+            // Released in between, try again right away
+            let Some(current) = db.get_sync_lock().await? else {
+                continue;
+            };
+            if !wait {
+                return Err(SyncLocked(current).into());
+            }
+            if !waiting {
+                info!("Waiting for the sync lock of {}", describe(&current));
+                waiting = true;
+            }
+            tokio::select! {
+                _ = cancel.cancelled() => return Err(Cancelled.into()),
+                _ = tokio::time::sleep(WAIT_POLL_INTERVAL) => {}
+            }
+        }
+        let heartbeat = tokio::spawn({
+            let db = db.clone();
+            let token = token.clone();
+            let cancel = cancel.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                    match db.heartbeat_sync_lock(&token, unix_now()).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            error!("Lost the sync lock to another process, stopping the sync");
+                            cancel.cancel();
+                            return;
+                        }
+                        Err(e) => warn!("⚠ Failed to renew the sync lock: {}", e),
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            db: db.clone(),
+            token,
+            heartbeat,
+        })
+    }
+
+    /// Give up the lock. A lock dropped without this is left to go stale.
+    pub async fn release(self) {
+        self.heartbeat.abort();
+        if let Err(e) = self.db.release_sync_lock(&self.token).await {
+            warn!("⚠ Failed to release the sync lock: {}", e);
+        }
+    }
+}
+
+impl Drop for SyncLock {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+/// Whether the holder stopped its heartbeat, or runs on this host and its
+/// process is gone.
+fn is_stale(lock: &DatabaseSyncLock, hostname: &str, now: i64) -> bool {
+    if lock.heartbeat_at < now - STALE_AFTER.as_secs() as i64 {
+        return true;
+    }
+    lock.hostname == hostname
+        && i32::try_from(lock.pid)
+            .is_ok_and(|pid| kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH))
+}
+
+fn describe(lock: &DatabaseSyncLock) -> String {
+    let since = chrono::DateTime::from_timestamp(lock.acquired_at, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default();
+    format!("pid {} on {}, since {}", lock.pid, lock.hostname, since)
+}
+
+fn hostname() -> String {
+    nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+        .unwrap_or_default()
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
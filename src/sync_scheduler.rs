@@ -0,0 +1,116 @@
+use crate::supplement_refresher::jitter;
+use crate::sync_lock::SyncLocked;
+use crate::syncer::SyncTrigger;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncScheduleOptions {
+    /// Time between the starts of two syncs; `None` disables the scheduler
+    pub interval: Option<Duration>,
+    /// Upper bound of the random delay added to each run
+    pub jitter: Duration,
+    /// Least time between the end of a run that overran the interval and
+    /// the start of the next one
+    pub min_spacing: Duration,
+    /// Run the first sync right after startup instead of one interval later
+    pub on_start: bool,
+}
+
+/// Runs full syncs through the [`SyncTrigger`] while serving, so no
+/// external cron is needed.
+pub struct SyncScheduler {
+    trigger: Arc<SyncTrigger>,
+    interval: Duration,
+    jitter: Duration,
+    min_spacing: Duration,
+    on_start: bool,
+    /// Unix time the next run is due, 0 while one is running
+    next_run_at: AtomicI64,
+}
+
+impl SyncScheduler {
+    /// Returns `None` when no interval is configured.
+    pub fn new(trigger: Arc<SyncTrigger>, options: &SyncScheduleOptions) -> Option<Self> {
+        Some(Self {
+            trigger,
+            interval: options.interval?,
+            jitter: options.jitter,
+            min_spacing: options.min_spacing,
+            on_start: options.on_start,
+            next_run_at: AtomicI64::new(0),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Unix time the next run is due; `None` while a run is in progress.
+    pub fn next_run_at(&self) -> Option<i64> {
+        Some(self.next_run_at.load(Ordering::Relaxed)).filter(|&at| at > 0)
+    }
+
+    /// Start a sync every interval, each shifted by a random jitter so
+    /// mirrors restarted together don't hit GitHub in lockstep. A tick is
+    /// skipped while another sync, e.g. one triggered through the admin
+    /// endpoint, is still running; after a run that overran the interval
+    /// the next one waits at least the minimum spacing.
+    pub fn spawn(self: Arc<Self>, cancel: CancellationToken) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut delay = if self.on_start {
+                Duration::ZERO
+            } else {
+                self.interval
+            };
+            loop {
+                let delay_with_jitter = delay + jitter(self.jitter);
+                self.next_run_at.store(
+                    unix_now() + delay_with_jitter.as_secs() as i64,
+                    Ordering::Relaxed,
+                );
+                tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    _ = tokio::time::sleep(delay_with_jitter) => {}
+                }
+                self.next_run_at.store(0, Ordering::Relaxed);
+
+                let started = Instant::now();
+                match self.trigger.trigger(None).await {
+                    Ok((run_id, true)) => {
+                        info!("Scheduled sync run {} started", run_id);
+                        let mut finished = self.trigger.subscribe_finished();
+                        tokio::select! {
+                            _ = cancel.cancelled() => return,
+                            _ = finished.wait_for(|&finished| finished >= run_id) => {}
+                        }
+                    }
+                    Ok((run_id, false)) => {
+                        info!(
+                            "Sync run {} is still running, skipping the scheduled sync",
+                            run_id
+                        );
+                    }
+                    Err(e) if e.is::<SyncLocked>() => {
+                        warn!("Skipping the scheduled sync: {}", e);
+                    }
+                    Err(e) => error!("Failed to start the scheduled sync: {}", e),
+                }
+                delay = self
+                    .interval
+                    .saturating_sub(started.elapsed())
+                    .max(self.min_spacing);
+            }
+        })
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
@@ -1,17 +1,207 @@
 use crate::{
-    app_state::AppState, aur_fetcher::AurFetcher, database::DatabaseOps,
-    srcinfo_parse::ParsedSrcInfo, supplement_fetcher::SupplementFetcher,
-    types::DatabasePackageDetails,
+    app_state::AppState,
+    aur_fetcher::{AurFetcher, Cancelled, UpstreamError},
+    branch_filter::BranchFilter,
+    database::DatabaseOps,
+    metrics::Metrics,
+    srcinfo_parse::ParsedSrcInfo,
+    supplement_fetcher::{SupplementFetcher, SupplementOptions},
+    supplement_refresher::SupplementRefresher,
+    sync_lock::SyncLock,
+    systemd::SystemdNotifier,
+    types::{DatabasePackageChange, DatabasePackageDetails, DatabaseSrcInfo, SyncRunStatus},
+    webhooks::WebhookDispatcher,
 };
-use anyhow::Result;
-use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use prometheus::HistogramTimer;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn, Instrument};
 
+/// Commits per upload-pack request
 const BATCH_SIZE: usize = 3000;
+/// How long recorded package changes are kept
+const CHANGES_RETENTION: Duration = Duration::from_secs(30 * 86400);
+
+/// Starts syncs in the background on request, at most one at a time.
+pub struct SyncTrigger {
+    syncer: Syncer,
+    supplement_sources: Vec<String>,
+    /// Id of the run in progress
+    running: Mutex<Option<i64>>,
+    /// Task of the run in progress, awaited on shutdown
+    task: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Id of the last run started here that finished
+    finished: watch::Sender<i64>,
+    /// Cancels the run in progress and refuses new ones
+    cancel: CancellationToken,
+}
+
+impl SyncTrigger {
+    pub fn new(syncer: Syncer, supplement_sources: Vec<String>, cancel: CancellationToken) -> Self {
+        Self {
+            syncer,
+            supplement_sources,
+            running: Mutex::new(None),
+            task: std::sync::Mutex::new(None),
+            finished: watch::Sender::new(0),
+            cancel,
+        }
+    }
+
+    /// Wait for the sync in progress, if any, to stop after `cancel` fired,
+    /// which rolls back its open transaction.
+    pub async fn wait_cancelled(&self) {
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+
+    /// Id of the run in progress, if any.
+    pub async fn running(&self) -> Option<i64> {
+        *self.running.lock().await
+    }
+
+    /// Follows the id of the last run started here that finished.
+    pub fn subscribe_finished(&self) -> watch::Receiver<i64> {
+        self.finished.subscribe()
+    }
+
+    /// See [`Syncer::resolve_scope`].
+    pub async fn resolve_scope(
+        &self,
+        pkgbases: &[String],
+        packages: &[String],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        self.syncer.resolve_scope(pkgbases, packages).await
+    }
+
+    /// Start a sync, optionally limited to the package bases in `scope`,
+    /// and return its run id. While a sync is running, its id is returned
+    /// instead and `scope` is ignored; the flag tells whether a new run was
+    /// started. Fails with `SyncLocked` while another process syncs.
+    pub async fn trigger(self: &Arc<Self>, scope: Option<Vec<String>>) -> Result<(i64, bool)> {
+        let mut running = self.running.lock().await;
+        if let Some(run_id) = *running {
+            return Ok((run_id, false));
+        }
+        if self.cancel.is_cancelled() {
+            return Err(anyhow!("Shutting down, not starting a sync"));
+        }
+        // Stopped on shutdown, or by the lock if another process breaks it
+        let cancel = self.cancel.child_token();
+        let lock = SyncLock::acquire(&self.syncer.db, false, &cancel).await?;
+        let run_id = match self.syncer.db.start_sync_run().await {
+            Ok(run_id) => run_id,
+            Err(e) => {
+                lock.release().await;
+                return Err(e);
+            }
+        };
+        *running = Some(run_id);
+        let this = self.clone();
+        let task = tokio::spawn(async move {
+            let result = this
+                .syncer
+                .sync_recorded(
+                    run_id,
+                    scope.as_deref(),
+                    &this.supplement_sources,
+                    &cancel,
+                    &mut SyncCounts::default(),
+                )
+                .await;
+            lock.release().await;
+            if let Err(e) = result {
+                error!("Sync run {} failed: {}", run_id, e);
+            }
+            *this.running.lock().await = None;
+            this.finished.send_replace(run_id);
+            this.syncer.notify_changes(run_id).await;
+        });
+        *self.task.lock().unwrap() = Some(task);
+        Ok((run_id, true))
+    }
+}
 
 pub struct Syncer {
     db: DatabaseOps,
     fetcher: AurFetcher,
+    metrics: Arc<Metrics>,
+    systemd: Arc<SystemdNotifier>,
+    webhooks: Option<WebhookDispatcher>,
+    history_branches: Vec<String>,
+    history_window: Duration,
+    store_srcinfo: bool,
+    supplement_options: SupplementOptions,
+    branch_filter: BranchFilter,
+    pipeline_options: SyncPipelineOptions,
+}
+
+/// Outcome of a one-shot sync, printed by `sync --json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncSummary {
+    /// `None` when the run couldn't even be recorded
+    pub run_id: Option<i64>,
+    /// `succeeded`, `failed` or `cancelled`, as recorded for the run
+    pub status: SyncRunStatus,
+    pub branches_updated: usize,
+    /// Branches whose `.SRCINFO` couldn't be fetched; the run still
+    /// succeeds without them
+    pub branches_failed: usize,
+    pub packages_indexed: usize,
+    /// Id of the interrupted run this one continued
+    pub resumed_from: Option<i64>,
+    /// Requested packages that aren't indexed, for a selective sync
+    pub unknown_packages: Vec<String>,
+    /// Requested package bases missing upstream, for a selective sync
+    pub unknown_pkgbases: Vec<String>,
+    pub bytes_fetched: u64,
+    pub duration_secs: f64,
+    pub error: Option<String>,
+}
+
+/// What a sync would do, reported by `sync --dry-run`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunReport {
+    pub branches_upstream: usize,
+    pub branches_new: usize,
+    pub branches_changed: usize,
+    /// Indexed branches the branch filters exclude, which a full sync
+    /// deletes; branches gone upstream stay indexed
+    pub branches_deleted: usize,
+    /// Packages indexed now for the changed branches, plus the average per
+    /// indexed branch for each new one
+    pub packages_estimate: usize,
+    /// Outcome of downloading and parsing, with `--dry-run=fetch`
+    pub fetch: Option<DryRunFetch>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DryRunFetch {
+    pub branches_fetched: usize,
+    pub branches_failed: usize,
+    /// Fetched branches whose `.SRCINFO` yields no packages
+    pub branches_empty: usize,
+    pub packages_parsed: usize,
+    pub bytes_fetched: u64,
+}
+
+/// Progress of a run, kept up to date while it goes so a failed run still
+/// reports what it committed.
+#[derive(Debug, Default)]
+pub struct SyncCounts {
+    pub branches_updated: usize,
+    pub branches_failed: usize,
+    pub packages_indexed: usize,
+    pub unknown_pkgbases: Vec<String>,
+    pub resumed_from: Option<i64>,
 }
 
 struct SrcInfoTuple {
@@ -21,120 +211,498 @@ struct SrcInfoTuple {
     committed_at: i64,
 }
 
+/// A branch ready to be written to the index.
+struct ParsedBranch {
+    branch: String,
+    commit: String,
+    srcinfo_text: String,
+    packages: Vec<DatabasePackageDetails>,
+}
+
+/// Sizes of the sync pipeline: fetching chunks of commits, decoding their
+/// packs, parsing the `.SRCINFO`s and writing them to the index.
+#[derive(Debug, Clone)]
+pub struct SyncPipelineOptions {
+    /// Chunks fetched and decoded at once
+    pub fetch_concurrency: usize,
+    /// Threads decoding each pack
+    pub decode_workers: usize,
+    /// Branches indexed per transaction
+    pub write_batch_size: usize,
+}
+
 impl Syncer {
-    pub fn new(app_state: AppState) -> Self {
-        let fetcher = AurFetcher::new(app_state.github_token);
-        Self {
+    pub fn new(app_state: AppState) -> Result<Self> {
+        let webhooks = WebhookDispatcher::new(app_state.webhooks, app_state.user_agent.clone())?;
+        let fetcher = AurFetcher::new(
+            app_state.github_token,
+            app_state.user_agent,
+            app_state.srcinfo_cache,
+            app_state.decode_cache_bytes,
+            app_state.sync_pipeline_options.decode_workers,
+            app_state.rate_limiter,
+            app_state.metrics.clone(),
+        );
+        Ok(Self {
             db: app_state.db,
             fetcher,
+            metrics: app_state.metrics,
+            systemd: app_state.systemd,
+            webhooks,
+            history_branches: app_state.history_branches,
+            history_window: app_state.history_window,
+            store_srcinfo: app_state.store_srcinfo,
+            supplement_options: app_state.supplement_options,
+            branch_filter: app_state.branch_filter,
+            pipeline_options: app_state.sync_pipeline_options,
+        })
+    }
+
+    /// Run a full sync, or with a `scope` a selective one of those package
+    /// bases. When `cancel` fires, fetching stops at the next checkpoint,
+    /// the open index transaction is rolled back and [`Cancelled`] is
+    /// returned; batches committed before that point stay consistent.
+    ///
+    /// Every run is recorded in the `sync_runs` table with its outcome. The
+    /// run holds the [`SyncLock`]; while another process holds it, the sync
+    /// waits for it with `wait_for_lock`, or fails with `SyncLocked`
+    /// without recording a run.
+    pub async fn sync(
+        &self,
+        scope: Option<&[String]>,
+        supplement_sources: &[String],
+        wait_for_lock: bool,
+        cancel: &CancellationToken,
+    ) -> SyncSummary {
+        let started = Instant::now();
+        let bytes_before = self.fetcher.stats().snapshot().total_bytes();
+        let mut counts = SyncCounts::default();
+        // Also fired by the lock if another process breaks it
+        let cancel = cancel.child_token();
+        let (run_id, result) = match SyncLock::acquire(&self.db, wait_for_lock, &cancel).await {
+            Ok(lock) => {
+                let recorded = match self.db.start_sync_run().await {
+                    Ok(run_id) => {
+                        let result = self
+                            .sync_recorded(run_id, scope, supplement_sources, &cancel, &mut counts)
+                            .await;
+                        (Some(run_id), result)
+                    }
+                    Err(e) => (None, Err(e)),
+                };
+                lock.release().await;
+                if let Some(run_id) = recorded.0 {
+                    self.notify_changes(run_id).await;
+                }
+                recorded
+            }
+            Err(e) => (None, Err(e)),
+        };
+        let (status, error) = run_status(&result);
+        SyncSummary {
+            run_id,
+            status,
+            branches_updated: counts.branches_updated,
+            branches_failed: counts.branches_failed,
+            packages_indexed: counts.packages_indexed,
+            resumed_from: counts.resumed_from,
+            unknown_packages: Vec::new(),
+            unknown_pkgbases: counts.unknown_pkgbases,
+            bytes_fetched: self.fetcher.stats().snapshot().total_bytes() - bytes_before,
+            duration_secs: started.elapsed().as_secs_f64(),
+            error,
         }
     }
 
-    pub async fn sync(&self, supplement_sources: &[String]) -> Result<()> {
-        info!("Starting sync operation...");
+    /// Package bases of a selective sync: `pkgbases` as given, plus the
+    /// package base of each of `packages`, deduplicated. Package names that
+    /// aren't indexed are returned separately.
+    pub async fn resolve_scope(
+        &self,
+        pkgbases: &[String],
+        packages: &[String],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let package_branches = self.db.get_package_branches(packages).await?;
+        let mut scope = pkgbases.to_vec();
+        let mut unknown = Vec::new();
+        for package in packages {
+            match package_branches.get(package) {
+                Some(branch) => scope.push(branch.clone()),
+                None => unknown.push(package.clone()),
+            }
+        }
+        scope.sort();
+        scope.dedup();
+        Ok((scope, unknown))
+    }
+
+    /// Compare the upstream branch list to the indexed commits without
+    /// writing anything or fetching packs. With `fetch`, the `.SRCINFO`
+    /// of every new or changed branch is also downloaded and parsed, to
+    /// check the fetcher against an upstream, but still not stored.
+    pub async fn dry_run(&self, fetch: bool, cancel: &CancellationToken) -> Result<DryRunReport> {
+        info!("Fetching branch list from AUR Mirror...");
+        let mut branches = self.fetcher.fetch_branch_list(cancel).await?;
+        branches.retain(|branch, _| self.branch_filter.matches(branch));
+        let existing_commits = self.db.get_existing_commits().await?;
+        let package_counts = self.db.get_package_counts().await?;
+        let average_packages = if package_counts.is_empty() {
+            1.0
+        } else {
+            package_counts.values().sum::<i64>() as f64 / package_counts.len() as f64
+        };
+
+        let mut report = DryRunReport {
+            branches_upstream: branches.len(),
+            branches_new: 0,
+            branches_changed: 0,
+            branches_deleted: existing_commits
+                .keys()
+                .filter(|branch| !self.branch_filter.matches(branch))
+                .count(),
+            packages_estimate: 0,
+            fetch: None,
+        };
+        let mut estimate = 0.0;
+        let mut to_process = Vec::new();
+        for (branch, commit) in branches {
+            match existing_commits.get(&branch) {
+                Some(existing) if *existing == commit => continue,
+                Some(_) => {
+                    report.branches_changed += 1;
+                    estimate += package_counts.get(&branch).copied().unwrap_or(1) as f64;
+                }
+                None => {
+                    report.branches_new += 1;
+                    estimate += average_packages;
+                }
+            }
+            to_process.push((branch, commit));
+        }
+        report.packages_estimate = estimate.round() as usize;
+        info!(
+            "Would process {} new and {} changed branches",
+            report.branches_new, report.branches_changed
+        );
+
+        if fetch {
+            report.fetch = Some(self.dry_run_fetch(&to_process, cancel).await?);
+        }
+        Ok(report)
+    }
+
+    async fn dry_run_fetch(
+        &self,
+        to_process: &[(String, String)],
+        cancel: &CancellationToken,
+    ) -> Result<DryRunFetch> {
+        let bytes_before = self.fetcher.stats().snapshot().total_bytes();
+        let mut fetch = DryRunFetch::default();
+        for chunk in to_process.chunks(BATCH_SIZE) {
+            let srcinfo_data = match self
+                .fetcher
+                .fetch_srcinfo_batch(chunk.iter().map(|(_, commit)| commit), cancel)
+                .await
+            {
+                Ok(srcinfo_data) => srcinfo_data,
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => {
+                    error!("Error fetching batch: {}", e);
+                    fetch.branches_failed += chunk.len();
+                    continue;
+                }
+            };
+            for ((branch, commit), fetched_srcinfo) in chunk.iter().zip(srcinfo_data) {
+                let Some(data) = fetched_srcinfo else {
+                    warn!(
+                        branch = %branch,
+                        "⚠ No srcinfo found for branch {} ({})",
+                        branch,
+                        &commit[..8]
+                    );
+                    fetch.branches_failed += 1;
+                    continue;
+                };
+                let packages =
+                    srcinfo_to_db_models(branch, commit, data.committed_at, &data.srcinfo_text)
+                        .count();
+                if packages == 0 {
+                    warn!(
+                        branch = %branch,
+                        "⚠ No packages found for branch {} ({})",
+                        branch,
+                        &commit[..8]
+                    );
+                    fetch.branches_empty += 1;
+                }
+                fetch.branches_fetched += 1;
+                fetch.packages_parsed += packages;
+            }
+            info!(
+                "Fetched {} branches, {} packages parsed",
+                fetch.branches_fetched, fetch.packages_parsed
+            );
+        }
+        fetch.bytes_fetched = self.fetcher.stats().snapshot().total_bytes() - bytes_before;
+        Ok(fetch)
+    }
+
+    /// Run the sync already recorded as `run_id`. With a `scope`, only those
+    /// branches are refreshed, even if their commit didn't move, in a single
+    /// transaction, and supplement data isn't refreshed. The caller notifies
+    /// about the changes with `notify_changes` once it released the lock,
+    /// since webhook retries can take a while.
+    async fn sync_recorded(
+        &self,
+        run_id: i64,
+        scope: Option<&[String]>,
+        supplement_sources: &[String],
+        cancel: &CancellationToken,
+        counts: &mut SyncCounts,
+    ) -> Result<()> {
+        self.systemd.watch_sync(self.fetcher.stats().clone());
+        let result = self
+            .sync_inner(run_id, scope, supplement_sources, cancel, counts)
+            .instrument(tracing::info_span!("sync", run_id))
+            .await;
+        self.systemd.unwatch_sync();
+        self.metrics.sync_queue_depth.reset();
+        if result.is_ok() {
+            if let Err(e) = self.db.clear_sync_progress(run_id).await {
+                warn!(
+                    "⚠ Failed to clear the progress of sync run {}: {}",
+                    run_id, e
+                );
+            }
+        }
+        let (status, error) = run_status(&result);
+        self.systemd.status(&format!(
+            "Sync run {} {}: {} branches updated, {} packages indexed",
+            run_id,
+            status.as_str(),
+            counts.branches_updated,
+            counts.packages_indexed
+        ));
+        self.metrics
+            .sync_runs
+            .with_label_values(&[status.as_str()])
+            .inc();
+        if let Err(e) = self
+            .db
+            .finish_sync_run(run_id, status, error.as_deref())
+            .await
+        {
+            error!("Failed to record the outcome of sync run {}: {}", run_id, e);
+        }
+        result
+    }
+
+    async fn sync_inner(
+        &self,
+        run_id: i64,
+        scope: Option<&[String]>,
+        supplement_sources: &[String],
+        cancel: &CancellationToken,
+        counts: &mut SyncCounts,
+    ) -> Result<()> {
+        match scope {
+            Some(scope) => info!("Starting sync operation for {} branches...", scope.len()),
+            None => info!("Starting sync operation..."),
+        }
 
         if self.fetcher.github_token().is_none() {
             warn!("⚠ No GitHub token configured. You may hit rate limits.");
         }
 
         info!("Fetching branch list from AUR Mirror...");
+        self.systemd.status("Syncing: fetching the branch list");
         // Fetch branch list
-        let branches = self.fetcher.fetch_branch_list().await?;
+        let timer = self.phase_timer("branch_list");
+        let mut branches = self.fetcher.fetch_branch_list(cancel).await?;
+        timer.observe_duration();
+        let upstream_branches = branches.len();
+        branches.retain(|branch, _| self.branch_filter.matches(branch));
+        if branches.len() < upstream_branches {
+            info!(
+                "{} branches excluded by the branch filters",
+                upstream_branches - branches.len()
+            );
+        }
+        if let Some(scope) = scope {
+            branches.retain(|branch, _| scope.contains(branch));
+            for pkgbase in scope {
+                if !self.branch_filter.matches(pkgbase) {
+                    warn!(
+                        "⚠ Package base {} is excluded by the branch filters",
+                        pkgbase
+                    );
+                    counts.unknown_pkgbases.push(pkgbase.clone());
+                } else if !branches.contains_key(pkgbase) {
+                    warn!("⚠ Package base {} not found upstream", pkgbase);
+                    counts.unknown_pkgbases.push(pkgbase.clone());
+                }
+            }
+        }
 
         info!(
             "Found {} branches, comparing to existing...",
             branches.len()
         );
-        let existing_commits = self.db.get_existing_commits().await?;
+        let mut existing_commits = self.db.get_existing_commits().await?;
+        // On the first sync every package would count as changed
+        let record_changes = !existing_commits.is_empty();
+        // A full sync continues where an interrupted one left off: the
+        // branches it didn't get to are indexed at their current commit
+        let mut interrupted = None;
+        let mut remaining = HashSet::new();
+        if scope.is_none() {
+            self.prune_excluded(&mut existing_commits).await?;
+            if let Some((run, branches)) = self.db.get_interrupted_sync_run(run_id).await? {
+                info!(
+                    "Resuming interrupted sync run {} with {} branches left",
+                    run,
+                    branches.len()
+                );
+                interrupted = Some(run);
+                remaining.extend(branches);
+            }
+        }
+        let history_tips = branches
+            .iter()
+            .filter(|(branch, _)| self.history_branches.contains(branch))
+            .map(|(branch, commit)| (branch.clone(), commit.clone()))
+            .collect::<Vec<_>>();
+        // A selective sync is asked for because the stored data looks stale,
+        // so its branches are refreshed whether their commit moved or not
         let to_process = branches
             .into_iter()
-            .filter(|(branch, commit)| existing_commits.get(branch) != Some(commit))
+            .filter(|(branch, commit)| {
+                scope.is_some()
+                    || remaining.contains(branch)
+                    || existing_commits.get(branch) != Some(commit)
+            })
             .collect::<Vec<_>>();
+        if scope.is_none() {
+            self.db
+                .start_sync_plan(run_id, interrupted, &to_process)
+                .await?;
+            counts.resumed_from = interrupted;
+        }
 
         info!("Need to process {} updated branches", to_process.len());
         if to_process.is_empty() {
             info!("All branches are up to date");
+            self.sync_history(&history_tips, cancel).await?;
+            info!("Fetch summary: {}", self.fetcher.stats().snapshot());
             // Still fetch supplement data even if no updates
-            self.fetch_and_store_supplements(supplement_sources).await?;
+            if scope.is_none() {
+                self.fetch_and_store_supplements(supplement_sources).await?;
+            }
             return Ok(());
         }
 
-        let (db_sender, mut db_receiver) = mpsc::channel::<SrcInfoTuple>(BATCH_SIZE * 2);
-
-        let fetcher = self.fetcher.clone();
-        let fetch_task = tokio::spawn(async move {
-            for chunk in to_process.chunks(BATCH_SIZE) {
-                let commits = chunk.iter().map(|(_, commit)| commit.as_str());
-                match fetcher.fetch_srcinfo_batch(commits).await {
-                    Ok(srcinfo_data) => {
-                        for ((branch, commit), fetched_srcinfo) in chunk.iter().zip(srcinfo_data) {
-                            match fetched_srcinfo {
-                                None => {
-                                    warn!(
-                                        "⚠ No srcinfo found for branch {} ({})",
-                                        branch,
-                                        &commit[..8]
-                                    );
-                                }
-                                Some(data) => {
-                                    if let Err(e) = db_sender
-                                        .send(SrcInfoTuple {
-                                            branch: branch.clone(),
-                                            commit: commit.clone(),
-                                            srcinfo_text: data.srcinfo_text,
-                                            committed_at: data.committed_at,
-                                        })
-                                        .await
-                                    {
-                                        error!("Failed to send srcinfo to database task: {}", e);
-                                        break;
-                                    }
-                                }
-                            };
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error fetching batch: {}", e);
-                    }
-                }
-            }
-            // Close the sender to signal we're done
-            drop(db_sender);
-        });
+        let index_timer = self.phase_timer("index");
+        let total_branches = to_process.len();
+        self.systemd
+            .status(&format!("Syncing: indexing {} branches", total_branches));
+        let write_batch_size = self.pipeline_options.write_batch_size;
+        // Bounded queues between the stages keep memory flat, whichever
+        // stage is the bottleneck
+        let (fetched_sender, fetched_receiver) =
+            mpsc::channel::<SrcInfoTuple>(write_batch_size * 2);
+        let (parsed_sender, mut parsed_receiver) =
+            mpsc::channel::<ParsedBranch>(write_batch_size * 2);
+        // The stages log within the span of the run
+        let fetch_task = tokio::spawn(
+            fetch_stage(
+                self.fetcher.clone(),
+                to_process,
+                self.pipeline_options.fetch_concurrency,
+                fetched_sender,
+                self.metrics.clone(),
+                cancel.clone(),
+            )
+            .in_current_span(),
+        );
+        let parse_task = tokio::spawn(
+            parse_stage(fetched_receiver, parsed_sender, self.metrics.clone()).in_current_span(),
+        );
+        let write_depth = self.metrics.sync_queue_depth.with_label_values(&["write"]);
 
         let mut processed_packages = 0;
-        let mut srcinfo_batch: Vec<SrcInfoTuple> = Vec::with_capacity(BATCH_SIZE);
-        let mut packages_batch: Vec<DatabasePackageDetails> =
-            Vec::with_capacity((BATCH_SIZE + (BATCH_SIZE + 3)) >> 2);
+        let mut parsed_batch: Vec<ParsedBranch> = Vec::with_capacity(write_batch_size);
+        let mut packages_batch: Vec<DatabasePackageDetails> = Vec::new();
         loop {
-            srcinfo_batch.clear();
+            parsed_batch.clear();
             packages_batch.clear();
 
-            let count = db_receiver.recv_many(&mut srcinfo_batch, BATCH_SIZE).await;
+            // A selective sync is indexed in one go, so it applies atomically
+            let count = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => break,
+                count = recv_batch(
+                    &mut parsed_receiver,
+                    &mut parsed_batch,
+                    write_batch_size,
+                    scope.is_some(),
+                ) => count,
+            };
+            write_depth.set(parsed_receiver.len() as i64);
             if count == 0 {
                 break; // Channel closed
             }
 
             let mut tx = self.db.begin_transaction().await?;
-            for SrcInfoTuple {
+            let mut changes = Vec::new();
+            for ParsedBranch {
                 branch,
                 commit,
                 srcinfo_text,
-                committed_at,
-            } in srcinfo_batch.iter()
+                packages,
+            } in parsed_batch.iter_mut()
             {
+                let old_versions = if record_changes {
+                    self.db.get_branch_versions_with_tx(&mut tx, branch).await?
+                } else {
+                    HashMap::new()
+                };
+                // The commit is recorded in the transaction of the packages,
+                // so it never runs ahead of the indexed data
                 self.db.clear_index_with_tx(&mut tx, branch).await?;
                 self.db
                     .update_branch_commit_with_tx(&mut tx, branch, commit)
                     .await?;
-
-                let branch_packages =
-                    srcinfo_to_db_models(branch, commit, *committed_at, srcinfo_text);
+                if self.store_srcinfo {
+                    let srcinfo = DatabaseSrcInfo {
+                        branch: branch.clone(),
+                        commit_id: commit.clone(),
+                        blob_id: srcinfo_blob_id(srcinfo_text)?,
+                        srcinfo: srcinfo_text.clone(),
+                    };
+                    self.db.store_srcinfo_with_tx(&mut tx, &srcinfo).await?;
+                }
 
                 let before_len = packages_batch.len();
-                packages_batch.extend(branch_packages);
+                packages_batch.append(packages);
+                if record_changes {
+                    let recorded_at = unix_now();
+                    changes.extend(packages_batch[before_len..].iter().map(|pkg| {
+                        DatabasePackageChange {
+                            id: 0,
+                            sync_run_id: run_id,
+                            branch: pkg.branch.clone(),
+                            pkg_name: pkg.pkg_name.clone(),
+                            old_version: old_versions.get(&pkg.pkg_name).cloned(),
+                            new_version: pkg.version.clone(),
+                            commit_id: pkg.commit_id.clone(),
+                            committed_at: pkg.committed_at,
+                            recorded_at,
+                        }
+                    }));
+                }
                 if before_len == packages_batch.len() {
                     warn!(
+                        branch = %branch,
                         "⚠ No packages found for branch {} ({})",
                         branch,
                         &commit[..8]
@@ -149,45 +717,384 @@ impl Syncer {
                 processed_packages += packages_batch.len();
             }
 
+            if cancel.is_cancelled() {
+                // Dropping the transaction rolls back the whole batch
+                drop(tx);
+                break;
+            }
+            self.db.store_changes_with_tx(&mut tx, &changes).await?;
+            let synced: Vec<&str> = parsed_batch.iter().map(|p| p.branch.as_str()).collect();
+            self.db
+                .mark_synced_with_tx(&mut tx, run_id, &synced)
+                .await?;
+            self.db.bump_data_version_with_tx(&mut tx).await?;
             tx.commit().await?;
+            counts.branches_updated += count;
+            counts.packages_indexed += packages_batch.len();
+            self.systemd.sync_progress();
+            self.systemd.status(&format!(
+                "Syncing: indexed {} of {} branches, {} packages",
+                counts.branches_updated, total_branches, counts.packages_indexed
+            ));
 
             info!("Processed {} packages", processed_packages);
         }
 
-        fetch_task.await?;
+        // Unblock the other stages if we stopped reading early
+        drop(parsed_receiver);
+        let fetch_result = fetch_task.await?;
+        parse_task.await?;
+        if cancel.is_cancelled() {
+            info!(
+                "Sync cancelled after indexing {} packages",
+                processed_packages
+            );
+            return Err(Cancelled.into());
+        }
+        counts.branches_failed += fetch_result?;
+        index_timer.observe_duration();
+
+        self.sync_history(&history_tips, cancel).await?;
 
-        // Fetch and store supplement data
-        self.fetch_and_store_supplements(supplement_sources).await?;
+        if let Some(cache) = self.fetcher.srcinfo_cache() {
+            if let Err(e) = cache.evict().await {
+                warn!("⚠ Failed to evict .SRCINFO cache entries: {}", e);
+            }
+        }
+        info!("Fetch summary: {}", self.fetcher.stats().snapshot());
+
+        // Fetch and store supplement data; scoped runs are meant to be quick
+        // and leave the archive to the next full sync
+        if scope.is_none() {
+            self.fetch_and_store_supplements(supplement_sources).await?;
+        }
 
         info!("✅ Sync completed successfully");
 
         Ok(())
     }
 
-    async fn fetch_and_store_supplements(&self, supplement_sources: &[String]) -> Result<()> {
-        if !supplement_sources.iter().any(|x| x != "none") {
-            info!("Skipping supplement data fetch");
+    /// Remove the indexed branches the branch filters exclude, so changing
+    /// the filters takes effect with the next full sync; branches newly
+    /// included are picked up as new ones by the same sync.
+    async fn prune_excluded(&self, existing_commits: &mut HashMap<String, String>) -> Result<()> {
+        let excluded: Vec<String> = existing_commits
+            .keys()
+            .filter(|branch| !self.branch_filter.matches(branch))
+            .cloned()
+            .collect();
+        if excluded.is_empty() {
             return Ok(());
         }
+        info!(
+            "Pruning {} indexed branches excluded by the branch filters",
+            excluded.len()
+        );
+        let mut tx = self.db.begin_transaction().await?;
+        for branch in &excluded {
+            self.db.delete_branch_with_tx(&mut tx, branch).await?;
+        }
+        self.db.bump_data_version_with_tx(&mut tx).await?;
+        tx.commit().await?;
+        for branch in &excluded {
+            existing_commits.remove(branch);
+        }
+        Ok(())
+    }
 
-        info!("Fetching supplement data...");
-        let fetcher = SupplementFetcher::new();
-        match fetcher.fetch_supplement_data(supplement_sources).await {
-            Ok(supplements) => {
-                if !supplements.is_empty() {
-                    info!("Storing {} supplement records...", supplements.len());
-                    self.db.store_supplement_data(&supplements).await?;
-                    info!("Supplement data stored successfully");
-                } else {
-                    info!("No supplement data to store");
-                }
+    /// Record the recent version history of the branches configured for
+    /// history tracking, fetching only branches whose tip moved since the
+    /// last recorded history commit.
+    async fn sync_history(
+        &self,
+        tips: &[(String, String)],
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        if self.history_branches.is_empty() {
+            return Ok(());
+        }
+        let _timer = self.phase_timer("history");
+        self.systemd.status("Syncing: fetching package history");
+
+        let since = SystemTime::now()
+            .checked_sub(self.history_window)
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i64);
+
+        // Fetched again when the tip moved or the window was widened; a tip
+        // without commits in the window records no rows, so the tip itself
+        // is what is compared
+        let mut outdated = Vec::new();
+        for (branch, commit) in tips {
+            let up_to_date =
+                self.db
+                    .get_history_tip(branch)
+                    .await?
+                    .is_some_and(|(fetched, fetched_since)| {
+                        &fetched == commit && fetched_since <= since
+                    });
+            if !up_to_date {
+                outdated.push((branch, commit));
             }
-            Err(e) => {
-                warn!("⚠ {}. Continuing without supplements.", e);
+        }
+
+        let mut tx = self.db.begin_transaction().await?;
+        if !outdated.is_empty() {
+            info!("Fetching history of {} branches...", outdated.len());
+            let histories = self
+                .fetcher
+                .fetch_srcinfo_history(outdated.iter().map(|(_, commit)| commit), since, cancel)
+                .await?;
+            for ((branch, commit), history) in outdated.iter().zip(histories) {
+                let packages = history
+                    .iter()
+                    .flat_map(|entry| {
+                        srcinfo_to_db_models(
+                            branch,
+                            &entry.commit_id,
+                            entry.srcinfo.committed_at,
+                            &entry.srcinfo.srcinfo_text,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                self.db.store_history_with_tx(&mut tx, &packages).await?;
+                self.db
+                    .set_history_tip_with_tx(&mut tx, branch, commit, since)
+                    .await?;
             }
         }
+        self.db
+            .prune_history_with_tx(&mut tx, since, &self.history_branches)
+            .await?;
+        tx.commit().await?;
         Ok(())
     }
+
+    async fn fetch_and_store_supplements(&self, supplement_sources: &[String]) -> Result<()> {
+        let _timer = self.phase_timer("supplement");
+        self.systemd.status("Syncing: fetching supplement data");
+        // Supplement downloads are bounded by their own timeouts and don't
+        // go through the fetcher whose progress the watchdog follows
+        self.systemd.unwatch_sync();
+        let fetcher = SupplementFetcher::new(
+            self.fetcher.user_agent().to_string(),
+            self.fetcher.rate_limiter().cloned(),
+            self.supplement_options.clone(),
+        )?;
+        SupplementRefresher::new(self.db.clone(), fetcher, supplement_sources.to_vec())
+            .refresh()
+            .await
+    }
+
+    /// Send the changes recorded by a run to the webhooks and drop changes
+    /// past retention. Failures are only logged. Also called for failed
+    /// runs, as batches committed before a failure changed packages all the
+    /// same.
+    async fn notify_changes(&self, run_id: i64) {
+        if let Some(webhooks) = &self.webhooks {
+            match self.db.get_changes_for_run(run_id).await {
+                Ok(changes) if !changes.is_empty() => webhooks.dispatch(run_id, &changes).await,
+                Ok(_) => {}
+                Err(e) => error!("Failed to read changes of sync run {}: {}", run_id, e),
+            }
+        }
+        let retention_start = unix_now() - CHANGES_RETENTION.as_secs() as i64;
+        if let Err(e) = self.db.prune_changes(retention_start).await {
+            warn!("⚠ Failed to prune recorded package changes: {}", e);
+        }
+    }
+
+    /// Observes the phase's duration when dropped or explicitly stopped.
+    fn phase_timer(&self, phase: &str) -> HistogramTimer {
+        self.metrics
+            .sync_phase_duration
+            .with_label_values(&[phase])
+            .start_timer()
+    }
+}
+
+/// The status a run ends with, and the error recorded for a failed one.
+fn run_status(result: &Result<()>) -> (SyncRunStatus, Option<String>) {
+    match result {
+        Ok(()) => (SyncRunStatus::Succeeded, None),
+        Err(e) if e.is::<Cancelled>() => (SyncRunStatus::Cancelled, None),
+        Err(e) => (SyncRunStatus::Failed, Some(e.to_string())),
+    }
+}
+
+/// Fetch and decode the `.SRCINFO`s of `to_process`, up to `concurrency`
+/// chunks at once, and pass them on in the order they arrive. Returns the
+/// number of branches whose `.SRCINFO` couldn't be fetched.
+async fn fetch_stage(
+    fetcher: AurFetcher,
+    to_process: Vec<(String, String)>,
+    concurrency: usize,
+    sender: mpsc::Sender<SrcInfoTuple>,
+    metrics: Arc<Metrics>,
+    cancel: CancellationToken,
+) -> Result<usize> {
+    let in_flight = metrics.sync_queue_depth.with_label_values(&["fetch"]);
+    let parse_depth = metrics.sync_queue_depth.with_label_values(&["parse"]);
+    let mut chunks = futures::stream::iter(to_process.chunks(BATCH_SIZE).map(<[_]>::to_vec))
+        .map(|chunk| {
+            let fetcher = fetcher.clone();
+            let cancel = cancel.clone();
+            let in_flight = in_flight.clone();
+            // A task per chunk, so decoding one doesn't hold up the others
+            tokio::spawn(
+                async move {
+                    in_flight.inc();
+                    let result = fetch_chunk(&fetcher, chunk, &cancel).await;
+                    in_flight.dec();
+                    result
+                }
+                .in_current_span(),
+            )
+        })
+        .buffer_unordered(concurrency.max(1));
+
+    let mut failed = 0usize;
+    while let Some(result) = chunks.next().await {
+        let (fetched, chunk_failed) = result??;
+        failed += chunk_failed;
+        for srcinfo in fetched {
+            if sender.send(srcinfo).await.is_err() {
+                // The write stage stopped early
+                return Ok(failed);
+            }
+            parse_depth.set((sender.max_capacity() - sender.capacity()) as i64);
+        }
+    }
+    Ok(failed)
+}
+
+/// Fetch one chunk of branches, returning the `.SRCINFO`s found and the
+/// number of branches that failed; only cancellation and failing to re-list
+/// the branches are errors.
+async fn fetch_chunk(
+    fetcher: &AurFetcher,
+    mut chunk: Vec<(String, String)>,
+    cancel: &CancellationToken,
+) -> Result<(Vec<SrcInfoTuple>, usize)> {
+    if cancel.is_cancelled() {
+        return Err(Cancelled.into());
+    }
+    let mut result = fetcher
+        .fetch_srcinfo_batch(chunk.iter().map(|(_, commit)| commit), cancel)
+        .await
+        .map(|srcinfo_data| srcinfo_data.collect::<Vec<_>>());
+    if result.as_ref().is_err_and(is_not_our_ref) {
+        warn!("⚠ Branches moved upstream during sync, re-listing refs and retrying batch");
+        let branches = fetcher.fetch_branch_list(cancel).await?;
+        // Branches deleted in the meantime are dropped from the batch
+        chunk = chunk
+            .into_iter()
+            .filter_map(|(branch, _)| {
+                let commit = branches.get(&branch)?.clone();
+                Some((branch, commit))
+            })
+            .collect();
+        result = fetcher
+            .fetch_srcinfo_batch(chunk.iter().map(|(_, commit)| commit), cancel)
+            .await
+            .map(|srcinfo_data| srcinfo_data.collect::<Vec<_>>());
+    }
+    let srcinfo_data = match result {
+        Ok(srcinfo_data) => srcinfo_data,
+        Err(e) if e.is::<Cancelled>() => return Err(e),
+        Err(e) => {
+            error!("Error fetching batch: {}", e);
+            return Ok((Vec::new(), chunk.len()));
+        }
+    };
+    let mut fetched = Vec::with_capacity(chunk.len());
+    let mut failed = 0;
+    for ((branch, commit), fetched_srcinfo) in chunk.into_iter().zip(srcinfo_data) {
+        match fetched_srcinfo {
+            None => {
+                warn!(
+                    branch = %branch,
+                    "⚠ No srcinfo found for branch {} ({})",
+                    branch,
+                    &commit[..8]
+                );
+                failed += 1;
+            }
+            Some(data) => fetched.push(SrcInfoTuple {
+                branch,
+                commit,
+                srcinfo_text: data.srcinfo_text,
+                committed_at: data.committed_at,
+            }),
+        }
+    }
+    Ok((fetched, failed))
+}
+
+/// Parse fetched `.SRCINFO`s into index rows until the fetch stage is done.
+async fn parse_stage(
+    mut receiver: mpsc::Receiver<SrcInfoTuple>,
+    sender: mpsc::Sender<ParsedBranch>,
+    metrics: Arc<Metrics>,
+) {
+    let parse_depth = metrics.sync_queue_depth.with_label_values(&["parse"]);
+    let write_depth = metrics.sync_queue_depth.with_label_values(&["write"]);
+    while let Some(srcinfo) = receiver.recv().await {
+        parse_depth.set(receiver.len() as i64);
+        let packages = srcinfo_to_db_models(
+            &srcinfo.branch,
+            &srcinfo.commit,
+            srcinfo.committed_at,
+            &srcinfo.srcinfo_text,
+        )
+        .collect();
+        let parsed = ParsedBranch {
+            branch: srcinfo.branch,
+            commit: srcinfo.commit,
+            srcinfo_text: srcinfo.srcinfo_text,
+            packages,
+        };
+        if sender.send(parsed).await.is_err() {
+            return;
+        }
+        write_depth.set((sender.max_capacity() - sender.capacity()) as i64);
+    }
+}
+
+/// Receive the next batch of up to `size` items, or with `all` everything
+/// until the previous stage is done; returns the number of items received,
+/// 0 once the channel closed.
+async fn recv_batch<T>(
+    receiver: &mut mpsc::Receiver<T>,
+    batch: &mut Vec<T>,
+    size: usize,
+    all: bool,
+) -> usize {
+    if !all {
+        return receiver.recv_many(batch, size).await;
+    }
+    let mut count = 0;
+    loop {
+        match receiver.recv_many(batch, size).await {
+            0 => return count,
+            received => count += received,
+        }
+    }
+}
+
+fn is_not_our_ref(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UpstreamError>()
+        .is_some_and(UpstreamError::is_not_our_ref)
+}
+
+/// The git blob id of a `.SRCINFO`, as its ETag.
+fn srcinfo_blob_id(srcinfo_text: &str) -> Result<String> {
+    let blob_id = gix_object::compute_hash(
+        gix_hash::Kind::Sha1,
+        gix_object::Kind::Blob,
+        srcinfo_text.as_bytes(),
+    )?;
+    Ok(blob_id.to_string())
 }
 
 fn srcinfo_to_db_models(
@@ -209,6 +1116,7 @@ fn srcinfo_to_db_models(
             version: pkg.version(),
             url: pkg.first_prop("url").map(|s| s.to_string()),
             groups: pkg.prop("groups"),
+            licenses: pkg.prop("license"),
             depends: pkg.flatten_arch_prop("depends"),
             make_depends: pkg.flatten_arch_prop("makedepends"),
             opt_depends: pkg.flatten_arch_prop("optdepends"),
@@ -218,3 +1126,10 @@ fn srcinfo_to_db_models(
             replaces: pkg.flatten_arch_prop("replaces"),
         })
 }
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,133 @@
+use crate::aur_fetcher::FetchStats;
+use sd_notify::NotifyState;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Reports readiness, status and liveness to systemd; every call is a no-op
+/// unless the service manager set `NOTIFY_SOCKET`.
+pub struct SystemdNotifier {
+    enabled: bool,
+    /// Progress of the running sync, `None` while none is watched
+    sync: Mutex<Option<SyncProgress>>,
+}
+
+struct SyncProgress {
+    stats: Arc<FetchStats>,
+    /// Fetch counters as of `last_progress`
+    counters: u64,
+    last_progress: Instant,
+}
+
+impl Default for SystemdNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemdNotifier {
+    pub fn new() -> Self {
+        Self {
+            enabled: std::env::var_os("NOTIFY_SOCKET").is_some(),
+            sync: Mutex::new(None),
+        }
+    }
+
+    /// Startup is finished: the listeners are bound, or for a one-shot
+    /// sync, the sync is done.
+    pub fn ready(&self) {
+        self.notify(&[NotifyState::Ready]);
+    }
+
+    pub fn stopping(&self) {
+        self.notify(&[NotifyState::Stopping]);
+    }
+
+    /// Free-form status shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.notify(&[NotifyState::Status(status)]);
+    }
+
+    /// Until [`Self::unwatch_sync`], the watchdog is only fed while the sync
+    /// makes progress, i.e. while data arrives through `stats` or it calls
+    /// [`Self::sync_progress`].
+    pub fn watch_sync(&self, stats: Arc<FetchStats>) {
+        let counters = progress_counters(&stats);
+        *self.sync.lock().unwrap() = Some(SyncProgress {
+            stats,
+            counters,
+            last_progress: Instant::now(),
+        });
+    }
+
+    /// The running sync committed a batch or moved on to its next phase.
+    pub fn sync_progress(&self) {
+        if let Some(sync) = self.sync.lock().unwrap().as_mut() {
+            sync.last_progress = Instant::now();
+        }
+    }
+
+    /// The sync finished, or entered a phase bounded by its own timeouts.
+    pub fn unwatch_sync(&self) {
+        *self.sync.lock().unwrap() = None;
+    }
+
+    /// With `WatchdogSec=` set on the unit, send `WATCHDOG=1` at half the
+    /// timeout from a task of the runtime serving requests, so a stalled
+    /// runtime trips the watchdog. Pings are withheld while a running sync
+    /// made no progress for the whole timeout, so a wedged fetch does too.
+    pub fn spawn_watchdog(self: Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let mut usec = 0;
+        if !self.enabled || !sd_notify::watchdog_enabled(false, &mut usec) {
+            return None;
+        }
+        let timeout = Duration::from_micros(usec);
+        debug!("systemd watchdog enabled with a timeout of {:?}", timeout);
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(timeout / 2);
+            loop {
+                interval.tick().await;
+                match self.sync_stalled_for() {
+                    Some(stalled) if stalled >= timeout => {
+                        warn!(
+                            "⚠ Sync made no progress for {:?}, withholding the watchdog ping",
+                            stalled
+                        );
+                    }
+                    _ => self.notify(&[NotifyState::Watchdog]),
+                }
+            }
+        }))
+    }
+
+    /// How long the running sync hasn't made progress, `None` without one.
+    fn sync_stalled_for(&self) -> Option<Duration> {
+        let mut sync = self.sync.lock().unwrap();
+        let sync = sync.as_mut()?;
+        let counters = progress_counters(&sync.stats);
+        if counters != sync.counters {
+            sync.counters = counters;
+            sync.last_progress = Instant::now();
+        }
+        Some(sync.last_progress.elapsed())
+    }
+
+    fn notify(&self, state: &[NotifyState]) {
+        if !self.enabled {
+            return;
+        }
+        if let Err(e) = sd_notify::notify(false, state) {
+            warn!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+/// Moves whenever fetched data arrives or is decoded.
+fn progress_counters(stats: &FetchStats) -> u64 {
+    let stats = stats.snapshot();
+    stats.total_bytes()
+        + stats.commits_decoded
+        + stats.trees_decoded
+        + stats.blobs_decoded
+        + stats.other_objects_decoded
+}
@@ -0,0 +1,153 @@
+use futures::io::AsyncRead;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+/// Token bucket limiting the download rate of every reader sharing it.
+///
+/// The bucket holds at most one second worth of bytes. Reads are charged
+/// after they complete, so the bucket may go into debt by one read buffer;
+/// the next read then waits until the debt is paid off.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Returns `None` for a limit of 0, which disables throttling.
+    pub fn new(bytes_per_sec: u64) -> Option<Self> {
+        (bytes_per_sec > 0).then(|| Self {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            })),
+        })
+    }
+
+    /// How long to wait before the next read may start, if at all.
+    fn delay(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill =
+            now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec as f64;
+        bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec as f64);
+        bucket.last_refill = now;
+        (bucket.tokens < 0.0)
+            .then(|| Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec as f64))
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// [`AsyncRead`] adapter throttled by an optional [`RateLimiter`].
+pub struct ThrottledRead<R> {
+    inner: R,
+    limiter: Option<RateLimiter>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ThrottledRead<R> {
+    pub fn new(inner: R, limiter: Option<RateLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            sleep: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledRead<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(limiter) = &this.limiter else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match limiter.delay() {
+                Some(delay) => this.sleep = Some(Box::pin(tokio::time::sleep(delay))),
+                None => break,
+            }
+        }
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        limiter.consume(read);
+        Poll::Ready(Ok(read))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, Cursor};
+
+    const RATE: u64 = 20_000;
+
+    async fn read_all(limiter: Option<RateLimiter>, len: usize) -> usize {
+        let mut reader = ThrottledRead::new(Cursor::new(vec![0u8; len]), limiter);
+        let mut buf = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf).await.unwrap() {
+                0 => return total,
+                read => total += read,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unlimited_reads_are_not_delayed() {
+        assert!(RateLimiter::new(0).is_none());
+        let started = Instant::now();
+        assert_eq!(read_all(None, 1_000_000).await, 1_000_000);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn reads_are_held_to_the_rate_after_the_initial_burst() {
+        // One second worth of bytes is available at once, the other two take
+        // two seconds
+        let started = Instant::now();
+        assert_eq!(
+            read_all(RateLimiter::new(RATE), 3 * RATE as usize).await,
+            60_000
+        );
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(3000), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn readers_sharing_a_limiter_share_the_rate() {
+        let limiter = RateLimiter::new(RATE);
+        let started = Instant::now();
+        let (a, b) = tokio::join!(
+            read_all(limiter.clone(), 3 * RATE as usize / 2),
+            read_all(limiter.clone(), 3 * RATE as usize / 2)
+        );
+        assert_eq!(a + b, 60_000);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(3000), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Result};
+use axum::extract::State;
+use axum::http::{header, uri::Authority, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// PEM certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM private key of the leaf certificate
+    pub key_path: PathBuf,
+}
+
+/// Load the certificate and key for the HTTPS listeners.
+pub async fn load(options: &TlsOptions) -> Result<RustlsConfig> {
+    // Fails when another rustls user installed a provider first, which is fine
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&options.cert_path, &options.key_path)
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Failed to load TLS certificate {}: {}",
+                options.cert_path.display(),
+                e
+            )
+        })
+}
+
+/// Reload the certificate on SIGHUP or when either file changes, so renewals
+/// take effect without a restart. A failed reload keeps the previous
+/// certificate; a renewal replacing the files one by one may fail halfway
+/// and is picked up with the next change.
+pub fn spawn_reload(
+    config: RustlsConfig,
+    options: TlsOptions,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        let mut modified = modified_times(&options);
+        loop {
+            let reload = tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading TLS certificate");
+                    true
+                }
+                _ = tokio::time::sleep(RELOAD_CHECK_INTERVAL) => {
+                    let changed = modified_times(&options) != modified;
+                    if changed {
+                        info!("TLS certificate files changed, reloading");
+                    }
+                    changed
+                }
+            };
+            if !reload {
+                continue;
+            }
+            modified = modified_times(&options);
+            match config
+                .reload_from_pem_file(&options.cert_path, &options.key_path)
+                .await
+            {
+                Ok(()) => info!("Reloaded TLS certificate"),
+                Err(e) => error!(
+                    "Failed to reload TLS certificate, keeping the previous one: {}",
+                    e
+                ),
+            }
+        }
+    }))
+}
+
+fn modified_times(options: &TlsOptions) -> [Option<SystemTime>; 2] {
+    [&options.cert_path, &options.key_path].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+}
+
+/// Router answering every request with a permanent redirect to the same
+/// URL on the HTTPS listener at `https_port`.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port)
+}
+
+async fn redirect_to_https(
+    State(https_port): State<u16>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let host = uri.authority().cloned().or_else(|| {
+        headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+    });
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let authority = match https_port {
+        443 => host.host().to_string(),
+        port => format!("{}:{}", host.host(), port),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
@@ -1,18 +1,60 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize)]
+/// Fields are serialized in declaration order, which matches aurweb's output.
+///
+/// Errors use the same envelope: `type` is `error`, `results` is empty and
+/// `error` holds the message.
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RpcResponse<T> {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    /// The requested version, null when missing or not a number
+    #[schema(required = true)]
+    pub version: Option<u32>,
+    /// The request type (`multiinfo` for info requests), or `error`
+    #[serde(rename = "type")]
+    #[schema(example = "search")]
+    pub response_type: String,
     #[serde(rename = "resultcount")]
     pub result_count: usize,
+    /// Matches across all pages, only for paginated searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
     pub results: Vec<T>,
-    #[serde(rename = "type")]
-    pub response_type: String,
-    pub version: Option<u32>,
+    /// Only in the error variant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+impl<T> RpcResponse<T> {
+    /// The same envelope around transformed results.
+    pub fn map_results<U>(self, f: impl FnOnce(Vec<T>) -> Vec<U>) -> RpcResponse<U> {
+        RpcResponse {
+            version: self.version,
+            response_type: self.response_type,
+            result_count: self.result_count,
+            total: self.total,
+            offset: self.offset,
+            results: f(self.results),
+            error: self.error,
+        }
+    }
+}
+
+/// Body of `/rpc` and the v5 routes, whose shape depends on the request
+/// type. Only used to describe the API.
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum RpcV5Response {
+    Search(RpcResponse<RpcPackageInfo>),
+    Info(RpcResponse<RpcPackageDetails>),
+    /// `suggest` and `suggest-pkgbase`: up to 20 names
+    Suggest(Vec<String>),
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RpcPackageInfo {
     #[serde(rename = "ID")]
     pub id: u32,
@@ -41,16 +83,53 @@ pub struct RpcPackageInfo {
     #[serde(rename = "LastModified")]
     pub last_modified: i64,
     #[serde(rename = "OutOfDate")]
+    #[schema(required = true)]
     pub out_of_date: Option<i64>,
+    /// Extension: supplement fields were taken from another package of the same base
+    #[serde(
+        rename = "SupplementFromPackageBase",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub supplement_from_base: bool,
+    /// Extension: the indexed commit of the package base
+    #[serde(rename = "CommitId", skip_serializing_if = "Option::is_none")]
+    pub commit_id: Option<String>,
+    /// Extension: the commit time of `CommitId`
+    #[serde(rename = "CommittedAt", skip_serializing_if = "Option::is_none")]
+    pub committed_at: Option<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl RpcPackageInfo {
+    /// Serialized field names, for the `fields` parameter
+    pub const FIELDS: &'static [&'static str] = &[
+        "ID",
+        "Name",
+        "Description",
+        "PackageBase",
+        "PackageBaseID",
+        "Version",
+        "URL",
+        "URLPath",
+        "Maintainer",
+        "NumVotes",
+        "Popularity",
+        "FirstSubmitted",
+        "LastModified",
+        "OutOfDate",
+        "SupplementFromPackageBase",
+        "CommitId",
+        "CommittedAt",
+    ];
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct RpcPackageDetails {
     #[serde(rename = "ID")]
     pub id: u32,
     #[serde(rename = "Name")]
     pub name: String,
     #[serde(rename = "Description", default)]
+    #[schema(required = true)]
     pub description: Option<String>,
     #[serde(rename = "PackageBase")]
     pub package_base: String,
@@ -59,12 +138,15 @@ pub struct RpcPackageDetails {
     #[serde(rename = "Version")]
     pub version: String,
     #[serde(rename = "URL")]
+    #[schema(required = true)]
     pub url: Option<String>,
     #[serde(rename = "URLPath")]
     pub url_path: String,
     #[serde(rename = "Maintainer", default)]
+    #[schema(required = true)]
     pub maintainer: Option<String>,
     #[serde(rename = "Submitter", default)]
+    #[schema(required = true)]
     pub submitter: Option<String>,
     #[serde(rename = "NumVotes")]
     pub num_votes: u32,
@@ -75,6 +157,7 @@ pub struct RpcPackageDetails {
     #[serde(rename = "LastModified")]
     pub last_modified: i64,
     #[serde(rename = "OutOfDate", default)]
+    #[schema(required = true)]
     pub out_of_date: Option<i64>,
     #[serde(rename = "License", default)]
     pub license: Vec<String>,
@@ -98,14 +181,76 @@ pub struct RpcPackageDetails {
     pub keywords: Vec<String>,
     #[serde(rename = "CoMaintainers", default)]
     pub co_maintainers: Vec<String>,
+    /// Extension: supplement fields were taken from another package of the same base
+    #[serde(
+        rename = "SupplementFromPackageBase",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub supplement_from_base: bool,
+    /// Extension: the indexed commit of the package base
+    #[serde(rename = "CommitId", default, skip_serializing_if = "Option::is_none")]
+    pub commit_id: Option<String>,
+    /// Extension: the commit time of `CommitId`
+    #[serde(
+        rename = "CommittedAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub committed_at: Option<i64>,
+    /// Extension: only with `as_of`, the commit the reported version comes from
+    #[serde(
+        rename = "AsOfCommit",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub as_of_commit: Option<String>,
+    /// Extension: only with `include_srcinfo`, the raw `.SRCINFO` of the package base
+    #[serde(rename = "SrcInfo", default, skip_serializing_if = "Option::is_none")]
+    pub srcinfo: Option<String>,
+}
+
+impl RpcPackageDetails {
+    /// Serialized field names, for the `fields` parameter
+    pub const FIELDS: &'static [&'static str] = &[
+        "ID",
+        "Name",
+        "Description",
+        "PackageBase",
+        "PackageBaseID",
+        "Version",
+        "URL",
+        "URLPath",
+        "Maintainer",
+        "Submitter",
+        "NumVotes",
+        "Popularity",
+        "FirstSubmitted",
+        "LastModified",
+        "OutOfDate",
+        "License",
+        "Depends",
+        "MakeDepends",
+        "OptDepends",
+        "CheckDepends",
+        "Provides",
+        "Conflicts",
+        "Replaces",
+        "Groups",
+        "Keywords",
+        "CoMaintainers",
+        "SupplementFromPackageBase",
+        "CommitId",
+        "CommittedAt",
+        "AsOfCommit",
+        "SrcInfo",
+    ];
 }
 
 #[derive(Debug, Clone)]
 pub struct DatabasePackageInfoWithSupplement {
     pub branch: String,
-    #[allow(unused)]
     pub commit_id: String,
-    #[allow(unused)]
     pub committed_at: i64,
     pub pkg_name: String,
     pub pkg_desc: Option<String>,
@@ -119,6 +264,8 @@ pub struct DatabasePackageInfoWithSupplement {
     pub submitter: Option<String>,
     pub first_submitted: Option<i64>,
     pub last_modified: Option<i64>,
+    /// Supplement data matched by package base instead of package name
+    pub supplement_from_base: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -132,6 +279,8 @@ pub struct DatabasePackageDetailsWithSupplement {
     pub conflicts: Vec<String>,
     pub replaces: Vec<String>,
     pub groups: Vec<String>,
+    /// From `.SRCINFO`, backfilled from supplement data when it has none
+    pub licenses: Vec<String>,
     // Supplemented metadata
     pub keywords: Vec<String>,
     pub co_maintainers: Vec<String>,
@@ -154,9 +303,76 @@ pub struct DatabasePackageDetails {
     pub conflicts: Vec<String>,
     pub replaces: Vec<String>,
     pub groups: Vec<String>,
+    pub licenses: Vec<String>,
+}
+
+#[cfg(test)]
+impl DatabasePackageDetails {
+    /// Version `1.0-1` of `name`, alone in a package base of its name, with
+    /// no optional fields or lists, committed at time 0.
+    pub fn test_package(name: &str) -> Self {
+        Self {
+            branch: name.to_string(),
+            commit_id: "0".repeat(40),
+            committed_at: 0,
+            pkg_name: name.to_string(),
+            pkg_desc: None,
+            version: "1.0-1".to_string(),
+            url: None,
+            depends: Vec::new(),
+            make_depends: Vec::new(),
+            opt_depends: Vec::new(),
+            check_depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: Vec::new(),
+            groups: Vec::new(),
+            licenses: Vec::new(),
+        }
+    }
+}
+
+/// Field a search is sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SortField {
+    Popularity,
+    Votes,
+    Name,
+    Modified,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+impl SortField {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "popularity" => Some(Self::Popularity),
+            "votes" => Some(Self::Votes),
+            "name" => Some(Self::Name),
+            "modified" => Some(Self::Modified),
+            _ => None,
+        }
+    }
+
+    /// Descending for the numbers, where the highest are the interesting ones.
+    pub fn default_descending(self) -> bool {
+        self != Self::Name
+    }
+}
+
+/// Order of search results; ties are broken by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SortBy {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+/// A window into the results of a search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SearchPage {
+    pub limit: u32,
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SearchType {
     Name,
     NameDesc,
@@ -164,9 +380,22 @@ pub enum SearchType {
     MakeDepends,
     OptDepends,
     CheckDepends,
+    /// Exact match of the maintainer of the package base
+    Maintainer,
 }
 
 impl SearchType {
+    /// Names accepted by `from_str`
+    pub const NAMES: [&'static str; 7] = [
+        "name",
+        "name-desc",
+        "maintainer",
+        "depends",
+        "makedepends",
+        "optdepends",
+        "checkdepends",
+    ];
+
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
             "name" => Some(Self::Name),
@@ -175,6 +404,7 @@ impl SearchType {
             "makedepends" => Some(Self::MakeDepends),
             "optdepends" => Some(Self::OptDepends),
             "checkdepends" => Some(Self::CheckDepends),
+            "maintainer" => Some(Self::Maintainer),
             _ => None,
         }
     }
@@ -191,6 +421,221 @@ pub struct DatabaseSupplementData {
     pub submitter: Option<String>,
     pub co_maintainers: Vec<String>,
     pub keywords: Vec<String>,
+    pub licenses: Vec<String>,
+    pub package_base: String,
     pub first_submitted: i64,
     pub last_modified: i64,
 }
+
+impl From<RpcPackageDetails> for DatabaseSupplementData {
+    fn from(item: RpcPackageDetails) -> Self {
+        Self {
+            pkgname: item.name,
+            version: item.version,
+            popularity: item.popularity,
+            num_votes: item.num_votes,
+            out_of_date: item.out_of_date,
+            maintainer: item.maintainer,
+            submitter: item.submitter,
+            co_maintainers: item.co_maintainers,
+            keywords: item.keywords,
+            licenses: item.license,
+            package_base: item.package_base,
+            first_submitted: item.first_submitted,
+            last_modified: item.last_modified,
+        }
+    }
+}
+
+/// HTTP cache validators of the supplement source that served the stored data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SupplementSourceValidators {
+    pub source: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Persisted state of a client's request limit counter.
+#[derive(Debug, Clone)]
+pub struct DatabaseRequestCounter {
+    pub ip: String,
+    pub window_start: i64,
+    pub requests: u64,
+    pub previous_requests: u64,
+}
+
+/// Outcome of a sync run as stored in `sync_runs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncRunStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl SyncRunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// A package added or updated by a sync, as recorded in `pkg_changes`.
+#[derive(Debug, Clone)]
+pub struct DatabasePackageChange {
+    /// Assigned when stored; ignored on insert
+    pub id: i64,
+    pub sync_run_id: i64,
+    pub branch: String,
+    pub pkg_name: String,
+    /// `None` for packages new to the index
+    pub old_version: Option<String>,
+    pub new_version: String,
+    pub commit_id: String,
+    pub committed_at: i64,
+    pub recorded_at: i64,
+}
+
+/// A row of `sync_runs`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DatabaseSyncRun {
+    pub id: i64,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub status: String,
+    pub error: Option<String>,
+    /// Id of the interrupted run this one continued
+    pub resumed_from: Option<i64>,
+}
+
+/// The row of `sync_lock`, naming the process whose sync holds the lock.
+#[derive(Debug, Clone)]
+pub struct DatabaseSyncLock {
+    pub token: String,
+    pub pid: i64,
+    pub hostname: String,
+    pub acquired_at: i64,
+    pub heartbeat_at: i64,
+}
+
+/// Overview of the mirror, served by `/api/stats`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MirrorStats {
+    #[serde(flatten)]
+    pub database: DatabaseStats,
+    pub sync: SyncScheduleStats,
+    /// Patterns limiting the synced branches, `None` when all are synced
+    pub branch_filters: Option<BranchFilterStats>,
+}
+
+/// What the database alone tells about the mirror.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DatabaseStats {
+    /// Listed packages
+    pub packages: i64,
+    /// Package bases with at least one listed package
+    pub pkgbases: i64,
+    /// Indexed branches, listed or not
+    pub branches: i64,
+    /// Package bases without a maintainer, per the supplement data
+    pub orphans: i64,
+    /// Package bases flagged out-of-date, per the supplement data
+    pub out_of_date: i64,
+    /// When the last successful sync finished
+    pub last_successful_sync: Option<i64>,
+    /// Newest `LastModified` of the supplement data
+    pub supplement_last_modified: Option<i64>,
+    /// Size of the database file, without its WAL
+    pub db_size_bytes: i64,
+    /// Rows of `pkg_history`
+    pub history_rows: i64,
+}
+
+/// What the `stats` command prints: `/api/stats` without the scheduler
+/// state, which only the server knows.
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalStats {
+    #[serde(flatten)]
+    pub database: DatabaseStats,
+    pub branch_filters: Option<BranchFilterStats>,
+}
+
+/// Globs or `re:` regexes from `branch_include` and `branch_exclude`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BranchFilterStats {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// State of the syncs run by the server itself.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SyncScheduleStats {
+    /// Id of the sync run in progress, scheduled or triggered
+    pub running: Option<i64>,
+    /// Interval of the built-in scheduler, `None` when disabled
+    pub interval_secs: Option<u64>,
+    /// When the next scheduled sync is due, `None` while one is running
+    pub next_run_at: Option<i64>,
+}
+
+/// A row of `branch_commits` with what is indexed from that commit.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DatabaseBranchSummary {
+    pub branch: String,
+    pub commit_id: String,
+    /// Commit time of the indexed commit
+    pub committed_at: Option<i64>,
+    pub package_count: i64,
+    /// When the commit was indexed; unknown for branches indexed before
+    /// this was recorded
+    pub indexed_at: Option<i64>,
+}
+
+/// The raw `.SRCINFO` of a branch as of its indexed commit.
+#[derive(Debug, Clone)]
+pub struct DatabaseSrcInfo {
+    pub branch: String,
+    pub commit_id: String,
+    pub blob_id: String,
+    pub srcinfo: String,
+}
+
+/// The newest `pkg_history` row of a package at some point in time.
+#[derive(Debug, Clone)]
+pub struct DatabaseHistoryVersion {
+    pub pkg_name: String,
+    pub commit_id: String,
+    pub committed_at: i64,
+    pub version: String,
+    pub details: DatabaseHistoryDetails,
+}
+
+/// The `.SRCINFO` fields of a package besides its version, kept with each
+/// `pkg_history` row as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseHistoryDetails {
+    pub pkg_desc: Option<String>,
+    pub url: Option<String>,
+    pub depends: Vec<String>,
+    pub make_depends: Vec<String>,
+    pub opt_depends: Vec<String>,
+    pub check_depends: Vec<String>,
+    pub provides: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub replaces: Vec<String>,
+    pub groups: Vec<String>,
+    pub licenses: Vec<String>,
+}
+
+/// Bumped whenever indexed or supplement data changes, to validate cached
+/// responses that may depend on any package.
+#[derive(Debug, Clone, Copy)]
+pub struct DatabaseDataVersion {
+    pub generation: i64,
+    pub updated_at: i64,
+}
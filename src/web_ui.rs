@@ -0,0 +1,417 @@
+use crate::database::DatabaseOps;
+use crate::rpc_server::UrlPathTemplate;
+use crate::types::{
+    DatabasePackageDetailsWithSupplement, DatabasePackageInfoWithSupplement, SearchPage,
+    SearchType, SortBy, SortField,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::DateTime;
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+use tracing::error;
+
+/// Search results shown per page
+const PAGE_SIZE: u32 = 50;
+const SEARCH_FIELDS: &[(&str, &str)] = &[
+    ("name-desc", "Name, Description"),
+    ("name", "Name Only"),
+    ("depends", "Depends"),
+    ("makedepends", "Make Depends"),
+    ("optdepends", "Optional Depends"),
+    ("checkdepends", "Check Depends"),
+    ("maintainer", "Maintainer"),
+];
+const SORT_FIELDS: &[(&str, &str)] = &[
+    ("name", "Name"),
+    ("popularity", "Popularity"),
+    ("votes", "Votes"),
+    ("modified", "Last Modified"),
+];
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 0 auto; max-width: 72em; padding: 1em; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.3em 0.5em; text-align: left; vertical-align: top; }
+th { background: #f4f4f4; }
+.out-of-date { color: #c00; }
+.error { color: #c00; }
+.banner { background: #fdd; border: 1px solid #c00; padding: 0.5em; }
+";
+
+#[derive(Clone)]
+struct WebUiState {
+    db: DatabaseOps,
+    /// Path the UI is mounted at, without a trailing slash
+    prefix: String,
+    url_path_template: UrlPathTemplate,
+    /// Whether `/api/srcinfo` is served, so package pages link to it
+    store_srcinfo: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    q: Option<String>,
+    by: Option<String>,
+    page: Option<u32>,
+    sort: Option<String>,
+    order: Option<String>,
+}
+
+/// The search form's fields, with defaults filled in.
+struct SearchForm {
+    query: String,
+    by: String,
+    sort: String,
+    descending: bool,
+}
+
+struct ResultPage {
+    packages: Vec<DatabasePackageInfoWithSupplement>,
+    total: u64,
+    /// Counting from 1
+    page: u32,
+    pages: u32,
+}
+
+/// Server-rendered search and package pages for browsers, to be merged at
+/// the root: the search under `prefix`, package pages under `/packages`
+/// like aurweb's and under `prefix` too.
+pub fn router<S: Clone + Send + Sync + 'static>(
+    db: DatabaseOps,
+    prefix: &str,
+    url_path_template: UrlPathTemplate,
+    store_srcinfo: bool,
+) -> Router<S> {
+    let state = WebUiState {
+        db,
+        prefix: prefix.trim_end_matches('/').to_string(),
+        url_path_template,
+        store_srcinfo,
+    };
+    let pages = Router::new()
+        .route("/", get(handle_search_page))
+        .route("/packages/{name}", get(handle_package_page))
+        .with_state(state.clone());
+    if state.prefix.is_empty() {
+        return pages;
+    }
+    Router::new()
+        .route("/packages/{name}", get(handle_package_page))
+        .with_state(state.clone())
+        .nest(&state.prefix, pages)
+}
+
+impl WebUiState {
+    fn search_url(&self) -> String {
+        if self.prefix.is_empty() {
+            "/".to_string()
+        } else {
+            self.prefix.clone()
+        }
+    }
+
+    fn package_url(&self, name: &str) -> String {
+        format!("/packages/{}", url_encode(name))
+    }
+}
+
+async fn handle_search_page(
+    State(state): State<WebUiState>,
+    Query(params): Query<SearchParams>,
+) -> Response {
+    let sort = params.sort.unwrap_or_else(|| "name".to_string());
+    let descending = match params.order.as_deref() {
+        Some(order) => order == "desc",
+        None => SortField::from_str(&sort).is_some_and(SortField::default_descending),
+    };
+    let form = SearchForm {
+        query: params.q.unwrap_or_default().trim().to_string(),
+        by: params.by.unwrap_or_else(|| "name-desc".to_string()),
+        sort,
+        descending,
+    };
+    let page = params.page.unwrap_or(1).max(1);
+
+    let results = if form.query.is_empty() {
+        None
+    } else {
+        Some(search(&state.db, &form, page).await)
+    };
+    let body = html! {
+        form method="get" action=(state.search_url()) {
+            input type="search" name="q" value=(form.query) placeholder="Search packages" autofocus;
+            " "
+            select name="by" {
+                @for (value, label) in SEARCH_FIELDS {
+                    option value=(value) selected[*value == form.by] { (label) }
+                }
+            }
+            " Sort by "
+            select name="sort" {
+                @for (value, label) in SORT_FIELDS {
+                    option value=(value) selected[*value == form.sort] { (label) }
+                }
+            }
+            " "
+            select name="order" {
+                option value="asc" selected[!form.descending] { "Ascending" }
+                option value="desc" selected[form.descending] { "Descending" }
+            }
+            " "
+            button type="submit" { "Go" }
+        }
+        @match results {
+            None => {}
+            Some(Err(message)) => p.error { (message) },
+            Some(Ok(results)) => (results_table(&state, &results, &form)),
+        }
+    };
+    Html(layout("Package Search", body).into_string()).into_response()
+}
+
+impl SearchForm {
+    /// Search URL for another page of the same results.
+    fn page_url(&self, state: &WebUiState, page: u32) -> String {
+        format!(
+            "{}?q={}&by={}&sort={}&order={}&page={}",
+            state.search_url(),
+            url_encode(&self.query),
+            url_encode(&self.by),
+            url_encode(&self.sort),
+            if self.descending { "desc" } else { "asc" },
+            page
+        )
+    }
+}
+
+/// One page of results, or a message for the user.
+async fn search(db: &DatabaseOps, form: &SearchForm, page: u32) -> Result<ResultPage, String> {
+    let search_type =
+        SearchType::from_str(&form.by).ok_or_else(|| "Unknown search field.".to_string())?;
+    let field = SortField::from_str(&form.sort).ok_or_else(|| "Unknown sort field.".to_string())?;
+    if matches!(search_type, SearchType::Name | SearchType::NameDesc)
+        && form.query.chars().count() < 2
+    {
+        return Err("Search terms need at least 2 characters.".to_string());
+    }
+    let database_error = |e: anyhow::Error| {
+        error!("Database error during web search: {}", e);
+        "Search failed, please try again later.".to_string()
+    };
+    let sort = Some(SortBy {
+        field,
+        descending: form.descending,
+    });
+    let search_page = |page: u32| {
+        db.search_page(
+            search_type,
+            &form.query,
+            sort,
+            SearchPage {
+                limit: PAGE_SIZE,
+                offset: (page - 1) * PAGE_SIZE,
+            },
+        )
+    };
+    // Pages whose offset doesn't fit are past the end anyway
+    let page = page.min(u32::MAX / PAGE_SIZE);
+    let (mut total, mut packages) = search_page(page).await.map_err(database_error)?;
+    let mut pages = total.div_ceil(PAGE_SIZE as u64).max(1) as u32;
+    // Past the end, show the last page instead
+    let page = if page > pages {
+        let last = pages;
+        (total, packages) = search_page(last).await.map_err(database_error)?;
+        pages = total.div_ceil(PAGE_SIZE as u64).max(1) as u32;
+        last
+    } else {
+        page
+    };
+    Ok(ResultPage {
+        packages,
+        total,
+        page,
+        pages,
+    })
+}
+
+fn results_table(state: &WebUiState, results: &ResultPage, form: &SearchForm) -> Markup {
+    html! {
+        p { (results.total) " packages found. Page " (results.page) " of " (results.pages) "." }
+        @if !results.packages.is_empty() {
+            table {
+                thead {
+                    tr { th { "Name" } th { "Version" } th { "Votes" } th { "Popularity" } th { "Description" } }
+                }
+                tbody {
+                    @for package in &results.packages {
+                        tr {
+                            td { a href=(state.package_url(&package.pkg_name)) { (package.pkg_name) } }
+                            td.out-of-date[package.out_of_date.is_some()] { (package.version) }
+                            td { (package.num_votes.unwrap_or(0)) }
+                            td { (format!("{:.2}", package.popularity.unwrap_or(0.0))) }
+                            td { (package.pkg_desc.as_deref().unwrap_or_default()) }
+                        }
+                    }
+                }
+            }
+            p {
+                @if results.page > 1 {
+                    a href=(form.page_url(state, results.page - 1)) { "« Previous" }
+                    " "
+                }
+                @if results.page < results.pages {
+                    a href=(form.page_url(state, results.page + 1)) { "Next »" }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_package_page(
+    State(state): State<WebUiState>,
+    Path(name): Path<String>,
+) -> Response {
+    let details = match state
+        .db
+        .get_package_details(std::slice::from_ref(&name))
+        .await
+    {
+        Ok(details) => details.into_iter().next(),
+        Err(e) => {
+            error!("Database error during web package lookup: {}", e);
+            let body = html! { p.error { "Lookup failed, please try again later." } };
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Html(layout(&name, body).into_string()),
+            )
+                .into_response();
+        }
+    };
+    let Some(details) = details else {
+        let body = html! {
+            p { "Package " (name) " was not found." }
+            p { a href=(state.search_url()) { "Back to search" } }
+        };
+        return (
+            StatusCode::NOT_FOUND,
+            Html(layout(&name, body).into_string()),
+        )
+            .into_response();
+    };
+    Html(layout(&details.info.pkg_name, package_details(&state, &details)).into_string())
+        .into_response()
+}
+
+fn package_details(state: &WebUiState, details: &DatabasePackageDetailsWithSupplement) -> Markup {
+    let info = &details.info;
+    html! {
+        p { a href=(state.search_url()) { "« Search" } }
+        @if let Some(out_of_date) = info.out_of_date {
+            p.banner { "This package has been flagged out of date since " (date(out_of_date)) "." }
+        }
+        table {
+            tr { th { "Version" } td.out-of-date[info.out_of_date.is_some()] { (info.version) } }
+            tr { th { "Description" } td { (info.pkg_desc.as_deref().unwrap_or_default()) } }
+            @if let Some(url) = &info.url {
+                tr { th { "Upstream URL" } td { a href=(url) rel="nofollow" { (url) } } }
+            }
+            tr { th { "Package Base" } td {
+                (info.branch) " ("
+                a href=(state.url_path_template.render(&url_encode(&info.branch), &url_encode(&info.pkg_name))) { "snapshot" }
+                @if state.store_srcinfo {
+                    ", " a href=(format!("/api/srcinfo/{}", url_encode(&info.branch))) { ".SRCINFO" }
+                }
+                ")"
+            } }
+            (list_row("Keywords", &details.keywords))
+            (list_row("Licenses", &details.licenses))
+            (list_row("Groups", &details.groups))
+            (list_row("Provides", &details.provides))
+            (list_row("Conflicts", &details.conflicts))
+            (list_row("Replaces", &details.replaces))
+            tr { th { "Maintainer" } td { (info.maintainer.as_deref().unwrap_or("None")) } }
+            (list_row("Co-Maintainers", &details.co_maintainers))
+            @if let Some(submitter) = &info.submitter {
+                tr { th { "Submitter" } td { (submitter) } }
+            }
+            tr { th { "Votes" } td { (info.num_votes.unwrap_or(0)) } }
+            tr { th { "Popularity" } td { (format!("{:.2}", info.popularity.unwrap_or(0.0))) } }
+            @if let Some(first_submitted) = info.first_submitted.filter(|&time| time > 0) {
+                tr { th { "First Submitted" } td { (date(first_submitted)) } }
+            }
+            // The commit time stands in until the supplement data knows the version
+            @let last_updated = info.last_modified.filter(|&time| time > 0).unwrap_or(info.committed_at);
+            tr { th { "Last Updated" } td { (date(last_updated)) } }
+        }
+        (dependency_list(state, "Dependencies", &details.depends))
+        (dependency_list(state, "Make Dependencies", &details.make_depends))
+        (dependency_list(state, "Check Dependencies", &details.check_depends))
+        (dependency_list(state, "Optional Dependencies", &details.opt_depends))
+    }
+}
+
+fn list_row(label: &str, items: &[String]) -> Markup {
+    html! {
+        @if !items.is_empty() {
+            tr { th { (label) } td { (items.join(", ")) } }
+        }
+    }
+}
+
+/// Dependencies linked to their package pages; version constraints and
+/// optdepends reasons stay as plain text.
+fn dependency_list(state: &WebUiState, label: &str, depends: &[String]) -> Markup {
+    html! {
+        @if !depends.is_empty() {
+            h3 { (label) " (" (depends.len()) ")" }
+            ul {
+                @for depend in depends {
+                    @let split = depend.find(['<', '>', '=', ':']).unwrap_or(depend.len());
+                    @let (name, rest) = depend.split_at(split);
+                    li { a href=(state.package_url(name)) { (name) } (rest) }
+                }
+            }
+        }
+    }
+}
+
+fn layout(title: &str, body: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) " - AUR Mirror" }
+                style { (STYLE) }
+            }
+            body {
+                h1 { (title) }
+                (body)
+            }
+        }
+    }
+}
+
+fn date(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|date| date.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+/// Percent-encode everything but unreserved characters.
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,204 @@
+use crate::branch_filter::compile_globs;
+use crate::types::DatabasePackageChange;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::{header, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const DELIVERY_ATTEMPTS: u32 = 4;
+/// Doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A webhook notified of package updates after each sync.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key for the `X-Hub-Signature-256` HMAC of the body
+    pub secret: Option<String>,
+    /// Globs (`*`, `?`) of package names to notify about; empty means all
+    #[serde(default)]
+    pub packages: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    sync_run_id: i64,
+    packages: Vec<ChangedPackage<'a>>,
+}
+
+/// A changed package as sent to webhooks and event stream clients.
+#[derive(Serialize)]
+pub struct ChangedPackage<'a> {
+    /// Stable across retries, so receivers can drop duplicate deliveries
+    change_id: i64,
+    name: &'a str,
+    pkgbase: &'a str,
+    old_version: Option<&'a str>,
+    new_version: &'a str,
+    commit_id: &'a str,
+    committed_at: i64,
+}
+
+impl<'a> ChangedPackage<'a> {
+    pub fn new(change: &'a DatabasePackageChange) -> Self {
+        Self {
+            change_id: change.id,
+            name: &change.pkg_name,
+            pkgbase: &change.branch,
+            old_version: change.old_version.as_deref(),
+            new_version: &change.new_version,
+            commit_id: &change.commit_id,
+            committed_at: change.committed_at,
+        }
+    }
+}
+
+/// Delivers the package changes of a sync run to the configured webhooks.
+///
+/// Delivery failures are retried with exponential backoff and logged, but
+/// never reported to the caller: a broken hook must not fail a sync.
+pub struct WebhookDispatcher {
+    client: Client,
+    user_agent: String,
+    /// Each hook with its `packages` globs in one regex
+    hooks: Vec<(WebhookConfig, Option<Regex>)>,
+}
+
+impl WebhookDispatcher {
+    /// Returns `None` without any configured hook.
+    pub fn new(hooks: Vec<WebhookConfig>, user_agent: String) -> Result<Option<Self>> {
+        if hooks.is_empty() {
+            return Ok(None);
+        }
+        let client = Client::builder().timeout(DELIVERY_TIMEOUT).build()?;
+        let hooks = hooks
+            .into_iter()
+            .map(|hook| {
+                let packages = compile_globs(&hook.packages)?;
+                Ok((hook, packages))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Self {
+            client,
+            user_agent,
+            hooks,
+        }))
+    }
+
+    pub async fn dispatch(&self, sync_run_id: i64, changes: &[DatabasePackageChange]) {
+        futures::future::join_all(
+            self.hooks.iter().map(|(hook, packages)| {
+                self.deliver(hook, packages.as_ref(), sync_run_id, changes)
+            }),
+        )
+        .await;
+    }
+
+    async fn deliver(
+        &self,
+        hook: &WebhookConfig,
+        packages: Option<&Regex>,
+        sync_run_id: i64,
+        changes: &[DatabasePackageChange],
+    ) {
+        let packages: Vec<_> = changes
+            .iter()
+            .filter(|change| packages.is_none_or(|packages| packages.is_match(&change.pkg_name)))
+            .map(ChangedPackage::new)
+            .collect();
+        if packages.is_empty() {
+            return;
+        }
+        let count = packages.len();
+        let body = match serde_json::to_vec(&Payload {
+            sync_run_id,
+            packages,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize webhook payload: {}", e);
+                return;
+            }
+        };
+
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            match self.post(hook, &body).await {
+                Ok(status) => {
+                    info!(
+                        "Delivered {} package changes of sync run {} to webhook {} ({})",
+                        count, sync_run_id, hook.url, status
+                    );
+                    return;
+                }
+                Err(DeliveryError::Permanent(e)) => {
+                    error!("Webhook delivery to {} rejected: {}", hook.url, e);
+                    return;
+                }
+                Err(DeliveryError::Transient(e)) if attempt < DELIVERY_ATTEMPTS => {
+                    warn!(
+                        "Webhook delivery to {} failed (attempt {}/{}): {}, retrying in {:?}",
+                        hook.url, attempt, DELIVERY_ATTEMPTS, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(DeliveryError::Transient(e)) => {
+                    error!(
+                        "Giving up webhook delivery to {} after {} attempts: {}",
+                        hook.url, DELIVERY_ATTEMPTS, e
+                    );
+                }
+            }
+        }
+    }
+
+    async fn post(&self, hook: &WebhookConfig, body: &[u8]) -> Result<StatusCode, DeliveryError> {
+        let mut request = self
+            .client
+            .post(&hook.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::USER_AGENT, &self.user_agent)
+            .body(body.to_vec());
+        if let Some(secret) = &hook.secret {
+            request = request.header("X-Hub-Signature-256", sign(secret, body));
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| DeliveryError::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status)
+        } else if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            // Sending the same payload again won't help
+            Err(DeliveryError::Permanent(anyhow!("HTTP {}", status)))
+        } else {
+            Err(DeliveryError::Transient(anyhow!("HTTP {}", status)))
+        }
+    }
+}
+
+enum DeliveryError {
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+/// `sha256=<hex HMAC>`, the format GitHub uses for its webhooks.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", digest)
+}